@@ -1,29 +1,1210 @@
-use argh::FromArgs;
+use std::time::Duration;
+use argh::{EarlyExit, FromArgs};
+use crate::batch::Collision;
+use crate::checksum::{parse_checksum, ExpectedDigest};
+use crate::config;
+use crate::error::{self, AppError};
+use crate::filesystem::{self, FileAllocation, IoBackend};
+use crate::extract::Selector;
+use crate::glob;
+use crate::logfile;
+use crate::progress;
+use crate::scheduler::{Recurring, StartAt};
+use crate::sequence;
+use crate::server::IpRange;
+use crate::watch::KeepPrevious;
+use unicode_width::UnicodeWidthChar;
 
 /// The following structure defines command line arguments for a concurrent network downloader utility.
 ///
-/// The 'url' field maps to the URI to be downloaded.
-/// The 'output' field maps to the optional output file path.
-/// The 'connections' field maps to the number of concurrent connections (default is 1, max is 100).
+/// The 'url' field maps to the URIs to be downloaded, several of them or a pattern of URLs making a batch.
+/// The 'urls' field maps to the URIs given without -u, downloaded after those of -u.
+/// The 'input_file' field maps to the optional file of URLs downloaded as a batch.
+/// The 'manifest' field maps to the optional file mapping the URLs of a batch to their outputs and checksums.
+/// The 'recursive' and 'level' fields map to whether the pages linked from the URL are downloaded too, and how many links deep.
+/// The 'page_requisites' field maps to whether the images, stylesheets and scripts of the pages are downloaded too.
+/// The 'extract_links' field maps to the optional CSS selector or JSONPath of the links of an index downloaded as a batch.
+/// The 'spider' field maps to whether the URLs are only checked, without downloading them.
+/// The 'dry_run' field maps to whether the plan of the download is printed instead of downloading the file.
+/// The 'no_robots' field maps to whether -r and -p ignore the robots.txt of the website.
+/// The 'accept', 'reject', 'include_directories' and 'exclude_directories' fields map to the filters of the files of a batch.
+/// The 'report' field maps to the optional file, or `-` for standard output, the JSON report of a batch is written to.
+/// The 'jobs' field maps to how many files of a batch are downloaded at the same time.
+/// The 'quota' field maps to the optional number of bytes after which no further download of a batch or --watch starts.
+/// The 'total_connections' field maps to the optional limit of the connections all files of a batch use together.
+/// The 'output' field maps to the optional output file path, or the directory or `#1` template of a batch.
+/// The 'output_template' field maps to the optional layout of the files named after their URL.
+/// The 'dir' field maps to the optional directory files named after their URL are saved in.
+/// The 'connections' field maps to the number of concurrent connections (default is 1, max is 100, or auto).
 /// The 'background' field maps to whether the task should run in the background.
-#[derive(FromArgs)]
+/// The 'start_at' field maps to the optional local time the download waits for before it starts.
+/// The 'pinned_pubkey' field maps to the optional public key pins of the server.
+/// The 'fifo' field maps to whether the output is streamed in order instead of merged from parts.
+/// The 'tls_min_version', 'tls_max_version' and 'ciphers' fields map to the optional TLS policy.
+/// The 'verbose' field maps to how many times -v is given, printing informational, debug and trace messages.
+/// The 'debug_http' field maps to whether the headers of every request and response are logged.
+/// The 'print_effective_url' field maps to whether the URL after redirects, IP address and protocol are printed.
+/// The 'quiet' field maps to whether only errors are printed.
+/// The 'progress' and 'progress_file' fields map to how progress is shown, and where JSON progress is written.
+/// The 'progress_style', 'progress_chars' and 'no_color' fields map to the look of the bars.
+/// The 'tui' field maps to whether the downloads are shown in an interactive terminal interface.
+/// The 'log_file', 'log_max_size', 'log_rotate' and 'log_keep' fields map to the optional file the output is written to, and when it is rotated.
+/// The 'log_format' field maps to whether log messages are written as text or as JSON objects.
+/// The 'no_hsts' field maps to whether the persistent HSTS database is ignored.
+/// The 'no_history' field maps to whether the download is left out of the download history.
+/// The 'notify_webhook' field maps to the optional URL the outcome of each download is posted to.
+/// The 'exec' and 'exec_on_error' fields map to the optional shell commands run once a download completed or failed.
+/// The 'statsd' and 'pushgateway' fields map to the optional final metrics destinations.
+/// The 'auto_extension' field maps to whether a file extension is derived from the Content-Type.
+/// The 'event_log' field maps to the optional file receiving the event log of a failed download.
+/// The 'max_filesize' field maps to the optional size limit of the downloaded file.
+/// The 'cache_dir' field maps to the optional directory of cached downloads.
+/// The 'mirror' field maps to the other URLs the same file is downloaded from.
+/// The 'mirrors' field maps to whether mirrors advertised by the server are used.
+/// The 'dns_cache_ttl' field maps to the optional lifetime of cached DNS answers.
+/// The 'method' and 'data' fields map to the optional request method and JSON body.
+/// The 'headers_file' field maps to the optional file of user agents and per-host headers.
+/// The 'header' field maps to the extra headers sent with every request.
+/// The 'proxy' field maps to the optional proxy every request goes through.
+/// The 'config' field maps to the optional file the defaults of the options are read from.
+/// The 'profile' field maps to the optional profile of the config file the defaults are taken from.
+/// The 'user', 'bearer_token' and 'netrc' fields map to the optional credentials of the requests.
+/// The 'deny_host' field maps to the hosts no request may be sent to.
+/// The 'file_allocation' field maps to how the output of a segmented download is reserved.
+/// The 'io_backend' and 'mmap' fields map to how the ranges of a segmented download are written.
+/// The 'min_split_size' field maps to the smallest range a segmented download splits the file into.
+/// The 'tries' and 'retry_wait' fields map to how often and after how long a failed range is tried again.
+/// The 'stall_timeout' field maps to how long a connection may deliver nothing before it is reopened.
+/// The 'connect_timeout', 'read_timeout' and 'max_time' fields map to the optional time limits of connecting, reading and the whole download.
+/// The 'buffer_size' field maps to how many received bytes are collected into one write of the output.
+/// The 'limit_rate' and 'limit_rate_per_conn' fields map to the optional bandwidth limits of the download and of each connection.
+/// The 'checksum' field maps to the optional hash the finished file must match.
+/// The 'auto_checksum' field maps to whether a checksum published next to the file is looked for and verified.
+/// The 'signature' and 'keyring' fields map to the optional detached OpenPGP signature of the file and the keys it must be made with.
+/// The 'verify_boundaries' field maps to whether the bytes where the ranges meet are fetched again and compared.
+/// The 'dedupe_content' field maps to whether files of a batch with the content of one already saved are copied instead of downloaded.
+/// The 'skip_unchanged' field maps to whether existing outputs of the same size and version as the remote file are kept.
+/// The 'halt_on_error' and 'keep_going' fields map to whether a batch stops starting downloads once one failed.
+/// The 'on_collision' field maps to what a batch does with downloads that would be saved to the same file.
+/// The 'no_clobber' field maps to whether downloads whose output already exists are skipped.
+/// The 'force' field maps to whether an existing output is replaced without asking.
+/// The 'continue_download' field maps to whether an existing partial output is appended to.
+/// The 'watch' and 'keep_previous' fields map to the optional interval the URL is checked again after, and the previous versions kept.
+/// The 'extract', 'extract_dir' and 'remove_archive' fields map to whether a downloaded archive is unpacked, where to, and whether it is deleted then.
+#[derive(Clone, FromArgs)]
 /// A non-interactive concurrent network downloader
+#[argh(note = "Subcommands:\n  get <url>       download files, also what rtget <url> does without a subcommand\n  status [<file>] show the interrupted downloads in a directory or of files\n  check <url>     probe a URL and show how it would be downloaded\n  bench <url>     compare the throughput of different numbers of connections\n  diagnose <url>  find out at which stage connecting to a URL fails\n  replay <log>    pretty-print the event log of a failed download\n  resume <file>   continue an interrupted download, optionally from --new-url\n  daemon          download what clients add over an aria2-compatible JSON-RPC interface\n  ctl <action>    add, list, pause, resume or cancel the downloads of the daemon\n  history         list the past downloads with their outcome, size and checksum")]
 pub struct CommandLineArgs {
-    /// the URI to download
+    /// the URI to download, required unless -i or --manifest is given; repeated, or with ranges like [001-120] or [a-z], it downloads a batch
     #[argh(option, short = 'u')]
-    pub url: String,
+    pub url: Vec<String>,
+
+    /// the URIs to download, like -u
+    #[argh(positional)]
+    pub urls: Vec<String>,
+
+    /// file with one URL to download per line, or - for standard input
+    #[argh(option, short = 'i')]
+    pub input_file: Option<String>,
+
+    /// TSV, CSV or JSON file mapping each URL to its output and optional checksum, downloaded as a batch, or - for standard input
+    #[argh(option)]
+    pub manifest: Option<String>,
+
+    /// download the website of -u: every page and file it links to on the same host, saved as <host>/<path>
+    #[argh(switch, short = 'r')]
+    pub recursive: bool,
+
+    /// download the page of -u with the images, stylesheets and scripts it needs, saved as <host>/<path>; with -r those of every page
+    #[argh(switch, short = 'p')]
+    pub page_requisites: bool,
+
+    /// download the links of the index page -u as a batch: those of the elements a CSS selector like a[href$=".iso"] matches, or the strings a JSONPath like $.assets[*].url selects in a JSON index
+    #[argh(option)]
+    pub extract_links: Option<String>,
+
+    /// how many links deep -r follows from the start page, default is 5, 0 for no limit
+    #[argh(option, short = 'l', default = "5")]
+    pub level: usize,
+
+    /// check that the URLs exist without downloading them, printing one line of JSON per URL; with -r every link of the website
+    #[argh(switch)]
+    pub spider: bool,
+
+    /// print where the file would be saved, its size, the byte ranges of each connection and the mirrors used, without downloading it
+    #[argh(switch)]
+    pub dry_run: bool,
+
+    /// write a JSON report of every download of a batch to this file, or to standard output for -
+    #[argh(option)]
+    pub report: Option<String>,
 
-    /// output file path, optional
+    /// start no further download of a batch once one failed, letting those under way finish
+    #[argh(switch)]
+    pub halt_on_error: bool,
+
+    /// download every URL of a batch even when some fail, and exit with an error at the end if any did; the default
+    #[argh(switch)]
+    pub keep_going: bool,
+
+    /// when URLs of a batch would be saved to the same file: number the later ones, e.g. file-1.iso, which is the default, add a hash of their URL, or fail before downloading
+    #[argh(option, from_str_fn(parse_collision), default = "Collision::Number")]
+    pub on_collision: Collision,
+
+    /// with -r or -p, follow links the robots.txt of the website disallows and do not wait its Crawl-delay
+    #[argh(switch)]
+    pub no_robots: bool,
+
+    /// comma-separated file names or wildcard patterns of a batch to download, e.g. '*.iso,*.img'; others are skipped
+    #[argh(option, short = 'A')]
+    pub accept: Vec<String>,
+
+    /// comma-separated file names or wildcard patterns of a batch to skip
+    #[argh(option, short = 'R')]
+    pub reject: Vec<String>,
+
+    /// comma-separated directories of a batch to download from, with their subdirectories, e.g. /pub/iso
+    #[argh(option, short = 'I')]
+    pub include_directories: Vec<String>,
+
+    /// comma-separated directories of a batch to skip, with their subdirectories
+    #[argh(option, short = 'X')]
+    pub exclude_directories: Vec<String>,
+
+    /// number of files of a batch downloaded at the same time, default is 1
+    #[argh(option, short = 'j', default = "1")]
+    pub jobs: usize,
+
+    /// most connections the files of a batch use together, optional; fewer files run at once so each keeps its -c connections
+    #[argh(option)]
+    pub total_connections: Option<usize>,
+
+    /// output file path, optional; for a batch the directory the files are saved in, or a name with #1, #2 for the values of the ranges of -u
     #[argh(option, short = 'o')]
     pub output: Option<String>,
 
-    /// number of concurrent connections, default is 1, max number of connections is 100
-    #[argh(option, default = "1", short = 'c')]
-    pub connections: u8,
+    /// where files named after their URL are saved, from {{host}}, {{path}} and {{filename}}, e.g. {{host}}/{{filename}}; below the directory of -o for a batch
+    #[argh(option, from_str_fn(parse_output_template))]
+    pub output_template: Option<String>,
+
+    /// directory files named after their URL are saved in, created if needed; -o takes precedence
+    #[argh(option)]
+    pub dir: Option<String>,
+
+    /// number of concurrent connections, default is 1, max number of connections is 100; auto starts with a few and adds more while the download gets faster
+    #[argh(option, from_str_fn(parse_connections), default = "Connections::Fixed(1)", short = 'c')]
+    pub connections: Connections,
+
+    /// smallest range the file is split into, default is 1M; small files use fewer connections than asked for
+    #[argh(option, from_str_fn(parse_size), default = "DEFAULT_MIN_SPLIT_SIZE")]
+    pub min_split_size: u64,
+
+    /// times to try each range before giving up, default is 5; a failed range resumes after the bytes it already got
+    #[argh(option, default = "5")]
+    pub tries: u32,
+
+    /// seconds to wait before the first retry of a range, default is 1; the wait doubles with every retry, up to a minute
+    #[argh(option, default = "1")]
+    pub retry_wait: u64,
+
+    /// seconds a connection may deliver no data before it is dropped and its range requested again, default is 30, 0 waits forever
+    #[argh(option, default = "30")]
+    pub stall_timeout: u64,
+
+    /// seconds connecting to a server may take
+    #[argh(option)]
+    pub connect_timeout: Option<u64>,
+
+    /// seconds a single read from a connection may take, including the wait for the response headers
+    #[argh(option)]
+    pub read_timeout: Option<u64>,
+
+    /// seconds the whole download may take; what was downloaded by then is kept for resuming
+    #[argh(option)]
+    pub max_time: Option<u64>,
+
+    /// received bytes collected into one write of the output, default is 256K; writes end on multiples of it, 0 writes every piece as it arrives
+    #[argh(option, from_str_fn(parse_size), default = "DEFAULT_BUFFER_SIZE")]
+    pub buffer_size: u64,
+
+    /// limit the whole download to this many bytes per second; accepts K, M, G and T suffixes, e.g. 500K
+    #[argh(option, from_str_fn(parse_size))]
+    pub limit_rate: Option<u64>,
+
+    /// limit each connection to this many bytes per second; accepts K, M, G and T suffixes, e.g. 100K
+    #[argh(option, from_str_fn(parse_size))]
+    pub limit_rate_per_conn: Option<u64>,
+
+    /// verify the finished file against this hash, e.g. sha256=<hex>; also md5, sha1, sha512 and blake3
+    #[argh(option, from_str_fn(parse_checksum))]
+    pub checksum: Option<ExpectedDigest>,
+
+    /// verify the finished file against a checksum published next to it, like <url>.sha256 or SHA256SUMS
+    #[argh(switch)]
+    pub auto_checksum: bool,
+
+    /// verify the finished file against this detached OpenPGP signature, a URL or a path; needs --keyring
+    #[argh(option)]
+    pub signature: Option<String>,
+
+    /// file of the public keys --signature must be made with, as exported by gpg --export
+    #[argh(option)]
+    pub keyring: Option<String>,
 
     /// run in the background
     #[argh(switch, short = 'b')]
     pub background: bool,
+
+    /// wait until this local time to start, like 02:00 or 2024-12-24 18:30
+    #[argh(option, from_str_fn(parse_start_at))]
+    pub start_at: Option<StartAt>,
+
+    /// pin the server public key, e.g. sha256//<base64>; separate multiple pins with ';'
+    #[argh(option)]
+    pub pinned_pubkey: Option<String>,
+
+    /// stream the download into the output in order (implied when the output is a named pipe)
+    #[argh(switch)]
+    pub fifo: bool,
+
+    /// lowest TLS version to negotiate (1.2 or 1.3)
+    #[argh(option)]
+    pub tls_min_version: Option<String>,
+
+    /// highest TLS version to negotiate (1.2 or 1.3)
+    #[argh(option)]
+    pub tls_max_version: Option<String>,
+
+    /// comma separated allowlist of TLS cipher suites, e.g. TLS13_AES_256_GCM_SHA384
+    #[argh(option)]
+    pub ciphers: Option<String>,
+
+    /// print informational messages, such as the negotiated TLS session of each connection; -vv adds debug and -vvv trace messages
+    #[argh(switch, short = 'v')]
+    pub verbose: u8,
+
+    /// log the method, URL and headers of every request and the status and headers of every response, with credentials redacted
+    #[argh(switch)]
+    pub debug_http: bool,
+
+    /// print the URL after redirects, the IP address connected to and the negotiated protocol of the download, separated by tabs
+    #[argh(switch)]
+    pub print_effective_url: bool,
+
+    /// print nothing but errors: no progress, messages or warnings
+    #[argh(switch, short = 'q')]
+    pub quiet: bool,
+
+    /// how progress is shown: bars, compact for a single bar per download, or json for a JSON object per download every second
+    #[argh(option, from_str_fn(parse_progress_format), default = "progress::Format::Bars")]
+    pub progress: progress::Format,
+
+    /// with --progress json, file the objects are written to instead of standard output
+    #[argh(option)]
+    pub progress_file: Option<String>,
+
+    /// fields of the bars: minimal, classic or detailed, default is classic
+    #[argh(option, from_str_fn(parse_progress_style), default = "progress::Style::Classic")]
+    pub progress_style: progress::Style,
+
+    /// characters of the filled part, the tip and the empty part of the bars, default is "#>-"
+    #[argh(option, from_str_fn(parse_progress_chars), default = "String::from(\"#>-\")")]
+    pub progress_chars: String,
+
+    /// draw bars and messages without colors, as does the NO_COLOR environment variable
+    #[argh(switch)]
+    pub no_color: bool,
+
+    /// show the downloads in a full-screen table where they can be paused, cancelled and reordered
+    #[argh(switch)]
+    pub tui: bool,
+
+    /// write all output to this file instead of the terminal, rotating it as --log-max-size and --log-rotate say (Unix only)
+    #[argh(option)]
+    pub log_file: Option<String>,
+
+    /// with --log-file, size past which the file is rotated, e.g. 50M, or 0 for no limit, default is 10M
+    #[argh(option, from_str_fn(parse_size), default = "10 << 20")]
+    pub log_max_size: u64,
+
+    /// with --log-file, also rotate the file after every interval, like 1h or 1d
+    #[argh(option, from_str_fn(parse_interval))]
+    pub log_rotate: Option<Duration>,
+
+    /// with --log-file, number of rotated files kept, default is 5
+    #[argh(option, default = "5")]
+    pub log_keep: usize,
+
+    /// how log messages are written: text, or json for a JSON object per message with the fields of its download, part, range and attempt
+    #[argh(option, from_str_fn(parse_log_format), default = "logfile::Format::Text")]
+    pub log_format: logfile::Format,
+
+    /// do not upgrade known HSTS hosts to HTTPS nor remember new ones
+    #[argh(switch)]
+    pub no_hsts: bool,
+
+    /// do not record the download in the history rtget history shows
+    #[argh(switch)]
+    pub no_history: bool,
+
+    /// URL to POST a JSON description of each finished or failed download to, for chat bots and pipelines
+    #[argh(option)]
+    pub notify_webhook: Option<String>,
+
+    /// shell command run once a download completed, with {{}} or {{path}}, {{url}} and {{sha256}} replaced by those of the file
+    #[argh(option)]
+    pub exec: Option<String>,
+
+    /// shell command run once a download failed, with {{url}} and {{error}} replaced by those of the download
+    #[argh(option)]
+    pub exec_on_error: Option<String>,
+
+    /// send the final transfer metrics to a statsd daemon at host:port
+    #[argh(option)]
+    pub statsd: Option<String>,
+
+    /// push the final transfer metrics to a Prometheus Pushgateway URL
+    #[argh(option)]
+    pub pushgateway: Option<String>,
+
+    /// append a file extension derived from the Content-Type when the URL has no useful one
+    #[argh(switch)]
+    pub auto_extension: bool,
+
+    /// write a replayable event log to this file if the download fails
+    #[argh(option)]
+    pub event_log: Option<String>,
+
+    /// abort if the file is larger than this many bytes; accepts K, M, G and T suffixes, e.g. 500M
+    #[argh(option, from_str_fn(parse_size))]
+    pub max_filesize: Option<u64>,
+
+    /// keep a copy of each download in this directory and reuse it while the server reports it unchanged
+    #[argh(option)]
+    pub cache_dir: Option<String>,
+
+    /// another URL of the same file to fetch ranges from, repeatable; mirrors of another size are left out
+    #[argh(option)]
+    pub mirror: Vec<String>,
+
+    /// also fetch ranges from mirrors advertised in `Link: <url>; rel=duplicate` headers
+    #[argh(switch)]
+    pub mirrors: bool,
+
+    /// seconds to reuse resolved addresses for, 0 resolves on every connection; by default they are kept for the whole download
+    #[argh(option)]
+    pub dns_cache_ttl: Option<u64>,
+
+    /// HTTP method of the request, e.g. POST; anything but GET is downloaded over a single connection
+    #[argh(option)]
+    pub method: Option<String>,
+
+    /// JSON request body, or @file to read it from a file; implies --method POST
+    #[argh(option)]
+    pub data: Option<String>,
+
+    /// file of user agents to rotate per host and headers to send per host, default is ~/.rtget-headers if it exists
+    #[argh(option)]
+    pub headers_file: Option<String>,
+
+    /// header to send with every request, as "Name: value", replacing one of the same name from --headers-file; repeatable
+    #[argh(option)]
+    pub header: Vec<String>,
+
+    /// proxy every request goes through, e.g. http://proxy.example.com:3128 or socks5://127.0.0.1:1080, instead of those of HTTPS_PROXY and HTTP_PROXY
+    #[argh(option)]
+    pub proxy: Option<String>,
+
+    /// file to read the defaults of these options from instead of ~/.config/rtget/config.toml
+    // Read before the arguments are parsed, by config::defaults
+    #[allow(dead_code)]
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// profile of the config file to take the defaults from, [profile.<name>] on top of the settings outside of profiles
+    // Read before the arguments are parsed, by config::defaults
+    #[allow(dead_code)]
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// credentials for HTTP basic authentication as user:password, only sent to the host of the URL
+    #[argh(option)]
+    pub user: Option<String>,
+
+    /// bearer token, or @file to read it from a file that is read again when the server rejects the token
+    #[argh(option)]
+    pub bearer_token: Option<String>,
+
+    /// take the credentials of each host from ~/.netrc
+    #[argh(switch)]
+    pub netrc: bool,
+
+    /// never send a request to this host or its subdomains, e.g. a mirror or a tracker; can be repeated
+    #[argh(option)]
+    pub deny_host: Vec<String>,
+
+    /// how to reserve the output before the ranges are written: none, trunc (default, sparse) or falloc (allocate the disk space)
+    #[argh(option, from_str_fn(parse_file_allocation), default = "FileAllocation::default()")]
+    pub file_allocation: FileAllocation,
+
+    /// how the ranges are written to the output: std (default), uring (batched through io_uring on Linux) or mmap
+    #[argh(option, from_str_fn(parse_io_backend), default = "IoBackend::default()")]
+    pub io_backend: IoBackend,
+
+    /// write the ranges into a shared memory map of the output, same as --io-backend mmap
+    #[argh(switch)]
+    pub mmap: bool,
+
+    /// after a segmented download, fetch the bytes where the ranges meet again and compare them with the output
+    #[argh(switch)]
+    pub verify_boundaries: bool,
+
+    /// in a batch, save a file whose Digest or ETag matches one already downloaded as a link to it or a copy, instead of downloading it again
+    #[argh(switch)]
+    pub dedupe_content: bool,
+
+    /// skip the download when its output is the same size and version as the remote file, going by its ETag or Last-Modified, and replace it otherwise
+    #[argh(switch)]
+    pub skip_unchanged: bool,
+
+    /// skip the download when its output already exists, instead of saving it as file.1, file.2 and so on
+    #[argh(switch)]
+    pub no_clobber: bool,
+
+    /// replace an existing output without asking what to do with it, even on a terminal
+    #[argh(switch)]
+    pub force: bool,
+
+    /// continue a partial output left by an interrupted single-connection download, e.g. by wget, instead of starting over
+    #[argh(switch, long = "continue")]
+    pub continue_download: bool,
+
+    /// check the URL again after every interval, like 30s, 10m, 6h or 1d, and download it again whenever it changed
+    #[argh(option, from_str_fn(parse_interval))]
+    pub watch: Option<Duration>,
+
+    /// with --watch, keep this many previous versions as file.1, file.2 and so on, or every one with dated
+    #[argh(option, from_str_fn(parse_keep_previous))]
+    pub keep_previous: Option<KeepPrevious>,
+
+    /// unpack downloaded tar, zip, gz, xz, zst and bz2 files once verified
+    #[argh(switch)]
+    pub extract: bool,
+
+    /// with --extract, directory the archive is unpacked into instead of the one it was saved in
+    #[argh(option)]
+    pub extract_dir: Option<String>,
+
+    /// with --extract, delete the archive once unpacked
+    #[argh(switch)]
+    pub remove_archive: bool,
+
+    /// stop starting the downloads of a batch or --watch once they downloaded this many bytes, e.g. 10G
+    #[argh(option, from_str_fn(parse_size))]
+    pub quota: Option<u64>,
+}
+
+/// Smallest range a segmented download splits the file into by default
+pub const DEFAULT_MIN_SPLIT_SIZE: u64 = 1024 * 1024;
+
+/// Bytes collected into one write of the output by default
+pub const DEFAULT_BUFFER_SIZE: u64 = 256 * 1024;
+
+/// Most ranges a file of `size` bytes is split into when no range is smaller than `min_split_size`.
+pub fn max_ranges(size: u64, min_split_size: u64) -> usize {
+    size.div_ceil(min_split_size.max(1)).clamp(1, usize::MAX as u64) as usize
+}
+
+impl CommandLineArgs {
+    /// Checks that there is something to download, a URL or an input file of them.
+    ///
+    /// Options that describe a single file cannot be combined with an input file.
+    pub fn check_sources(&self) -> Result<(), String> {
+        if let Some(selector) = &self.extract_links {
+            Selector::parse(selector)?;
+        }
+        match (self.url.is_empty(), &self.input_file, &self.manifest) {
+            (true, None, None) => Err("either a URL, -i or --manifest is required".to_string()),
+            (_, Some(input_file), Some(manifest)) if input_file == "-" && manifest == "-" => {
+                Err("-i and --manifest cannot both read standard input".to_string())
+            }
+            _ if (self.recursive || self.page_requisites) && (self.url.len() != 1 || self.input_file.is_some() || self.manifest.is_some()) => {
+                Err("-r and -p download the website or the page of a single -u".to_string())
+            }
+            _ if self.extract_links.is_some() && (self.url.len() != 1 || self.input_file.is_some() || self.manifest.is_some() || self.recursive || self.page_requisites) => {
+                Err("--extract-links downloads the links of the index page of a single -u, without -r or -p".to_string())
+            }
+            _ if self.background && (self.input_file.as_deref() == Some("-") || self.manifest.as_deref() == Some("-")) => {
+                Err("-b continues without a terminal, so -i and --manifest cannot read standard input".to_string())
+            }
+            _ if self.total_connections == Some(0) => Err("--total-connections must be at least 1".to_string()),
+            _ if self.is_batch() && (self.checksum.is_some() || self.signature.is_some() || self.continue_download) => {
+                Err("--checksum, --signature and --continue describe a single file and cannot be used with a batch".to_string())
+            }
+            _ if self.no_clobber && self.continue_download => Err("--no-clobber skips existing outputs, which --continue appends to".to_string()),
+            _ if self.force && self.no_clobber => Err("--force replaces existing outputs, which --no-clobber skips".to_string()),
+            _ if self.report.is_some() && (!self.is_batch() || self.spider) => {
+                Err("--report describes the downloads of a batch; --spider prints its own JSON".to_string())
+            }
+            _ if self.halt_on_error && self.keep_going => Err("--halt-on-error and --keep-going are opposite policies".to_string()),
+            _ if self.is_batch() && self.dry_run => Err("--dry-run plans the download of a single file; check the URLs of a batch with --spider".to_string()),
+            _ if self.watch.is_some() && (self.is_batch() || self.spider || self.dry_run || self.no_clobber || self.continue_download) => {
+                Err("--watch keeps the single file of -u up to date, without a batch, --spider, --dry-run, --no-clobber or --continue".to_string())
+            }
+            _ if self.keep_previous.is_some() && self.watch.is_none() => Err("--keep-previous keeps the versions --watch replaces".to_string()),
+            _ if (self.extract_dir.is_some() || self.remove_archive) && !self.extract => Err("--extract-dir and --remove-archive go with --extract".to_string()),
+            _ if self.quota.is_some() && !self.is_batch() && self.watch.is_none() => Err("--quota limits the downloads of a batch or --watch".to_string()),
+            _ if self.quiet && self.verbose > 0 => Err("-q and -v cannot be used together".to_string()),
+            _ if self.progress_file.is_some() && self.progress != progress::Format::Json => Err("--progress-file goes with --progress json".to_string()),
+            _ if self.tui && (self.background || self.spider || self.watch.is_some() || self.dry_run || self.recursive || self.page_requisites || self.quota.is_some() || self.report.is_some()) => {
+                Err("--tui downloads the files of -u, -i, --manifest or --extract-links, without -b, --spider, --watch, --dry-run, -r, -p, --quota or --report".to_string())
+            }
+            _ if self.tui && self.log_file.is_some() => Err("--tui draws on the terminal, which --log-file takes the output away from".to_string()),
+            _ if self.tui && self.progress == progress::Format::Json && self.progress_file.is_none() => Err("--tui draws on standard output, so --progress json needs --progress-file".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether several files are downloaded: the URLs of -i or --manifest, several -u, those a pattern or FTP wildcard in -u expands into, the links of an index with --extract-links, or a website with -r or a page with -p.
+    pub fn is_batch(&self) -> bool {
+        self.recursive || self.page_requisites || self.extract_links.is_some() || self.input_file.is_some() || self.manifest.is_some() || self.url.len() > 1 || self.url.iter().any(|url| !matches!(sequence::expand(url), Ok(None))) || self.url.iter().any(|url| glob::pattern(url).is_some())
+    }
+}
+
+/// How many connections a download uses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connections {
+    /// Exactly this many
+    Fixed(u8),
+    /// A few at first, and more as long as each one makes the download faster
+    Auto,
+}
+
+impl Connections {
+    // Connections a download with `--connections auto` starts with
+    const AUTO_INITIAL: usize = 2;
+
+    /// Number of connections the download starts with.
+    pub fn initial(self) -> usize {
+        match self {
+            Connections::Fixed(connections) => connections.max(1) as usize,
+            Connections::Auto => Connections::AUTO_INITIAL,
+        }
+    }
+
+    /// Number of connections a download of `size` bytes starts with, one per range of at least `min_split_size`.
+    pub fn initial_for(self, size: u64, min_split_size: u64) -> usize {
+        self.initial().min(max_ranges(size, min_split_size))
+    }
+}
+
+/// Parses a number of connections or `auto`.
+pub fn parse_connections(value: &str) -> Result<Connections, String> {
+    match value.trim() {
+        auto if auto.eq_ignore_ascii_case("auto") => Ok(Connections::Auto),
+        count => count.parse().map(Connections::Fixed).map_err(|_| format!("invalid number of connections {}, expected 1 to 100 or auto", value)),
+    }
+}
+
+/// Parses a comma-separated list of connection counts, e.g. `1,4,16`.
+pub fn parse_connection_counts(value: &str) -> Result<Vec<usize>, String> {
+    value
+        .split(',')
+        .map(|count| match count.trim().parse() {
+            Ok(count) if (1..=100).contains(&count) => Ok(count),
+            _ => Err(format!("invalid number of connections {}, expected 1 to 100", count.trim())),
+        })
+        .collect()
+}
+
+/// Parses a `--start-at` time, see [`StartAt::parse`].
+pub fn parse_start_at(value: &str) -> Result<StartAt, String> {
+    StartAt::parse(value)
+}
+
+/// Parses a `--schedule` of the daemon, see [`Recurring::parse`].
+pub fn parse_schedule(value: &str) -> Result<Recurring, String> {
+    Recurring::parse(value)
+}
+
+/// Parses an `--rpc-allow-ip` address or network, see [`IpRange::parse`].
+pub fn parse_ip_range(value: &str) -> Result<IpRange, String> {
+    IpRange::parse(value)
+}
+
+/// Parses an `--output-template`, rejecting placeholders other than {host}, {path} and {filename}.
+pub fn parse_output_template(value: &str) -> Result<String, String> {
+    filesystem::check_output_template(value).map(|()| value.to_string())
+}
+
+/// Parses an interval in seconds with an optional s, m, h or d suffix, e.g. `90` or `10m`.
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (digits, unit) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit = match suffix.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86_400,
+                _ => return Err(format!("invalid interval {}, expected a number with an optional s, m, h or d suffix", value)),
+            };
+            (&value[..index], unit)
+        }
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(count) if count > 0 => Ok(Duration::from_secs(count.saturating_mul(unit))),
+        _ => Err(format!("invalid interval {}, expected a number with an optional s, m, h or d suffix", value)),
+    }
+}
+
+/// Parses an `--on-collision` policy, see [`Collision::parse`].
+pub fn parse_collision(value: &str) -> Result<Collision, String> {
+    Collision::parse(value)
+}
+
+/// Parses a `--keep-previous`, see [`KeepPrevious::parse`].
+pub fn parse_keep_previous(value: &str) -> Result<KeepPrevious, String> {
+    KeepPrevious::parse(value)
+}
+
+/// Parses a byte count with an optional binary K, M, G or T suffix, e.g. `1500` or `2G`.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let shift = match suffix.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix in {}", value)),
+            };
+            (&value[..index], shift)
+        }
+        _ => (value, 0),
+    };
+    let number: u64 = digits.parse().map_err(|_| format!("invalid size: {}", value))?;
+    number.checked_mul(1 << shift).ok_or_else(|| format!("size is too large: {}", value))
+}
+
+/// Parses the name of a file allocation method: `none`, `trunc` or `falloc`.
+pub fn parse_file_allocation(value: &str) -> Result<FileAllocation, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "none" => Ok(FileAllocation::None),
+        "trunc" => Ok(FileAllocation::Trunc),
+        "falloc" => Ok(FileAllocation::Falloc),
+        _ => Err(format!("unknown file allocation {}, expected none, trunc or falloc", value)),
+    }
+}
+
+/// Parses the name of a disk I/O backend: `std`, `uring` or `mmap`.
+pub fn parse_io_backend(value: &str) -> Result<IoBackend, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "std" => Ok(IoBackend::Std),
+        "uring" | "io_uring" => Ok(IoBackend::Uring),
+        "mmap" => Ok(IoBackend::Mmap),
+        _ => Err(format!("unknown I/O backend {}, expected std, uring or mmap", value)),
+    }
+}
+
+/// Parses the format of --progress.
+pub fn parse_progress_format(value: &str) -> Result<progress::Format, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "bars" | "bar" => Ok(progress::Format::Bars),
+        "compact" => Ok(progress::Format::Compact),
+        "json" => Ok(progress::Format::Json),
+        _ => Err(format!("unknown progress format {}, expected bars, compact or json", value)),
+    }
+}
+
+/// Parses the format of --log-format.
+pub fn parse_log_format(value: &str) -> Result<logfile::Format, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "text" => Ok(logfile::Format::Text),
+        "json" => Ok(logfile::Format::Json),
+        _ => Err(format!("unknown log format {}, expected text or json", value)),
+    }
+}
+
+/// Parses the style of --progress-style.
+pub fn parse_progress_style(value: &str) -> Result<progress::Style, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "minimal" => Ok(progress::Style::Minimal),
+        "classic" => Ok(progress::Style::Classic),
+        "detailed" => Ok(progress::Style::Detailed),
+        _ => Err(format!("unknown progress style {}, expected minimal, classic or detailed", value)),
+    }
+}
+
+/// Parses the characters of --progress-chars, at least two of the same width.
+pub fn parse_progress_chars(value: &str) -> Result<String, String> {
+    let widths: Vec<usize> = value.chars().map(|c| c.width().unwrap_or(0)).collect();
+    match widths.as_slice() {
+        [first, _, ..] if *first > 0 && widths.iter().all(|width| width == first) => Ok(value.to_string()),
+        _ => Err(format!("invalid progress characters {}, expected at least two of the same width like #>-", value)),
+    }
+}
+
+/// Arguments of `rtget replay`.
+#[derive(FromArgs)]
+/// Pretty-print the event log of a failed download as a timeline
+pub struct ReplayArgs {
+    /// the event log written by --event-log
+    #[argh(positional)]
+    pub log: String,
+}
+
+/// Arguments of `rtget check`.
+#[derive(FromArgs)]
+/// Probe a URL and report how it would be downloaded, without downloading anything
+pub struct CheckArgs {
+    /// the URI to check
+    #[argh(positional)]
+    pub url: String,
+
+    /// number of concurrent connections to plan for, default is 1
+    #[argh(option, default = "1", short = 'c')]
+    pub connections: u8,
+
+    /// smallest range to plan for, default is 1M
+    #[argh(option, from_str_fn(parse_size), default = "DEFAULT_MIN_SPLIT_SIZE")]
+    pub min_split_size: u64,
+}
+
+/// Arguments of `rtget bench`.
+#[derive(FromArgs)]
+/// Download a URL over different numbers of connections, discarding the data, and report the throughput of each
+pub struct BenchArgs {
+    /// the URI to benchmark
+    #[argh(positional)]
+    pub url: String,
+
+    /// comma-separated numbers of connections to try, default is 1,2,4,8,16
+    #[argh(option, short = 'c', from_str_fn(parse_connection_counts), default = "vec![1, 2, 4, 8, 16]")]
+    pub connections: Vec<usize>,
+
+    /// seconds each configuration downloads for at most, default is 10
+    #[argh(option, default = "10")]
+    pub seconds: u64,
+
+    /// only download the first bytes of the file, with an optional K, M, G or T suffix
+    #[argh(option, from_str_fn(parse_size))]
+    pub bytes: Option<u64>,
+}
+
+/// Arguments of `rtget diagnose`.
+#[derive(FromArgs)]
+/// Check DNS, TCP, TLS and HTTP in turn and report which stage fails
+pub struct DiagnoseArgs {
+    /// the URI to diagnose
+    #[argh(positional)]
+    pub url: String,
+}
+
+/// Arguments of `rtget resume`.
+#[derive(FromArgs)]
+/// Continue the interrupted download of a file, optionally from another URL serving the same content
+pub struct ResumeArgs {
+    /// the output file of the interrupted download
+    #[argh(positional)]
+    pub file: String,
+
+    /// continue from this URL instead, e.g. a mirror or a fresh signed URL; its size and ETag or content must match
+    #[argh(option)]
+    pub new_url: Option<String>,
+
+    /// file to read the defaults of the download from instead of ~/.config/rtget/config.toml
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// profile of the config file to take the defaults of the download from
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// print informational messages; -vv adds debug and -vvv trace messages
+    #[argh(switch, short = 'v')]
+    pub verbose: u8,
+}
+
+impl ResumeArgs {
+    /// Returns the arguments of the download to continue from `url` over `connections` connections,
+    /// with the `defaults` of the config file for the other options.
+    pub fn download_args(&self, url: &str, connections: usize, defaults: &config::Defaults) -> Result<CommandLineArgs, AppError> {
+        let connections = connections.to_string();
+        let mut args = vec!["-u", url, "-o", &self.file, "-c", &connections];
+        args.extend(std::iter::repeat_n("-v", self.verbose.into()));
+        with_defaults(&args, defaults)
+    }
+}
+
+/// Arguments of `rtget install-launchd`.
+#[derive(FromArgs)]
+/// Register a macOS launchd agent running rtget in the background with the arguments after --
+pub struct InstallLaunchdArgs {
+    /// label of the agent, default is local.rtget
+    #[argh(option, default = "String::from(\"local.rtget\")")]
+    pub label: String,
+
+    /// start the download again whenever it exits, not only at login
+    #[argh(switch)]
+    pub keep_alive: bool,
+
+    /// the arguments of the download, e.g. -- -i urls.txt -o downloads
+    #[argh(positional, greedy)]
+    pub args: Vec<String>,
+}
+
+/// Arguments of `rtget service`.
+#[derive(FromArgs)]
+/// Install, uninstall, start or stop the Windows service running rtget with the arguments after -- given to install
+pub struct ServiceArgs {
+    /// install, uninstall, start or stop
+    #[argh(positional)]
+    pub action: String,
+
+    /// directory the service downloads in, recorded by install
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    #[argh(option, hidden_help)]
+    pub directory: Option<String>,
+
+    /// the arguments of the download, e.g. -- -i urls.txt -o downloads
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    #[argh(positional, greedy)]
+    pub args: Vec<String>,
+}
+
+/// Parses the arguments of a download a service manager runs, checking that it has something to download.
+///
+/// The service manager keeps the download in the background, so -b is refused.
+pub fn service_download_args(args: &[String]) -> Result<CommandLineArgs, String> {
+    let download = CommandLineArgs::from_args(&["rtget"], &args.iter().map(String::as_str).collect::<Vec<_>>())
+        .map_err(|early_exit| format!("invalid arguments of the download: {}", early_exit.output.trim()))?
+        .with_positional_urls();
+    download.check_sources()?;
+    match download.background {
+        true => Err("the service manager runs the download in the background already, without -b".to_string()),
+        false => Ok(download),
+    }
+}
+
+/// Arguments of `rtget daemon`.
+#[derive(FromArgs)]
+/// Run until stopped, downloading what clients add over an aria2-compatible JSON-RPC interface
+pub struct DaemonArgs {
+    /// address the JSON-RPC interface listens on, default is 127.0.0.1:6800; other than a loopback address it needs --rpc-certificate
+    #[argh(option, default = "String::from(\"127.0.0.1:6800\")")]
+    pub rpc_listen: String,
+
+    /// PEM file of the certificate chain the interface serves HTTPS with, together with --rpc-private-key
+    #[argh(option)]
+    pub rpc_certificate: Option<String>,
+
+    /// PEM file of the private key of --rpc-certificate
+    #[argh(option)]
+    pub rpc_private_key: Option<String>,
+
+    /// address or network clients may connect to the interface from, e.g. 192.168.1.0/24; others are disconnected
+    #[argh(option, from_str_fn(parse_ip_range))]
+    pub rpc_allow_ip: Vec<IpRange>,
+
+    /// secret every call must pass as token:<secret>, like aria2 --rpc-secret; a random one is generated and printed without it
+    #[argh(option)]
+    pub rpc_secret: Option<String>,
+
+    /// origin of a web page allowed to call the interface, e.g. http://localhost:8080; requests of other pages are refused
+    #[argh(option)]
+    pub rpc_allow_origin: Vec<String>,
+
+    /// let web pages of any origin call the interface
+    #[argh(switch)]
+    pub rpc_allow_origin_all: bool,
+
+    /// socket, or named pipe on Windows, rtget ctl connects to, default is rtget.sock in $XDG_RUNTIME_DIR
+    #[argh(option)]
+    pub socket: Option<String>,
+
+    /// TOML file of the users the daemon serves, each with their own downloads, directory and limits
+    #[argh(option)]
+    pub users: Option<String>,
+
+    /// add a download whenever a cron schedule matches, given as "<minute> <hour> <day> <month> <weekday> <url> [<mirror>...]"
+    #[argh(option, from_str_fn(parse_schedule))]
+    pub schedule: Vec<Recurring>,
+
+    /// directory files are saved in unless a download names its own, default is the current directory
+    #[argh(option, default = "String::from(\".\")")]
+    pub dir: String,
+
+    /// how many files are downloaded at the same time, default is 5
+    #[argh(option, short = 'j', default = "5")]
+    pub jobs: usize,
+
+    /// number of concurrent connections of each download unless it asks for its own, default is 1
+    #[argh(option, short = 'c', default = "1")]
+    pub connections: u8,
+
+    /// URL to POST a JSON description of each finished or failed download to, like --notify-webhook
+    #[argh(option)]
+    pub notify_webhook: Option<String>,
+
+    /// start no further download once the downloads used this many bytes, e.g. 10G
+    #[argh(option, from_str_fn(parse_size))]
+    pub quota: Option<u64>,
+
+    /// with --quota, renew the budget after every interval, like 1d or 30d, instead of once for the whole run
+    #[argh(option, from_str_fn(parse_interval))]
+    pub quota_period: Option<Duration>,
+
+    /// file to read the defaults of the downloads from instead of ~/.config/rtget/config.toml
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// profile of the config file to take the defaults of the downloads from
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// print informational messages; -vv adds debug and -vvv trace messages
+    #[argh(switch, short = 'v')]
+    pub verbose: u8,
+
+    /// write all output to this file, rotating it as --log-max-size and --log-rotate say (Unix only)
+    #[argh(option)]
+    pub log_file: Option<String>,
+
+    /// with --log-file, size past which the file is rotated, e.g. 50M, or 0 for no limit, default is 10M
+    #[argh(option, from_str_fn(parse_size), default = "10 << 20")]
+    pub log_max_size: u64,
+
+    /// with --log-file, also rotate the file after every interval, like 1h or 1d
+    #[argh(option, from_str_fn(parse_interval))]
+    pub log_rotate: Option<Duration>,
+
+    /// with --log-file, number of rotated files kept, default is 5
+    #[argh(option, default = "5")]
+    pub log_keep: usize,
+
+    /// how log messages are written: text, or json for a JSON object per message with the fields of its download, part, range and attempt
+    #[argh(option, from_str_fn(parse_log_format), default = "logfile::Format::Text")]
+    pub log_format: logfile::Format,
+}
+
+impl DaemonArgs {
+    /// Returns the arguments of a download of the daemon from `urls`, the first of them with the others as mirrors,
+    /// with the `defaults` of the config file for the other options.
+    pub fn download_args(&self, urls: &[String], connections: Option<u8>, defaults: &config::Defaults) -> Result<CommandLineArgs, AppError> {
+        let connections = connections.unwrap_or(self.connections).to_string();
+        let mut args = vec!["-u", &urls[0], "-c", &connections];
+        for mirror in &urls[1..] {
+            args.extend(["--mirror", mirror]);
+        }
+        if let Some(webhook) = &self.notify_webhook {
+            args.extend(["--notify-webhook", webhook]);
+        }
+        args.extend(std::iter::repeat_n("-v", self.verbose.into()));
+        with_defaults(&args, defaults)
+    }
+}
+
+// The arguments of a download a subcommand runs, after the defaults of the config file for the options `args` leaves out
+fn with_defaults(args: &[&str], defaults: &config::Defaults) -> Result<CommandLineArgs, AppError> {
+    let defaults = defaults.arguments(args)?;
+    let args: Vec<&str> = defaults.iter().map(String::as_str).chain(args.iter().copied()).collect();
+    CommandLineArgs::from_args(&["rtget"], &args)
+        .map_err(|early_exit| AppError::StringError(format!("the defaults of the config file do not fit the download: {}", early_exit.output.trim())))
+}
+
+/// Arguments of `rtget ctl`.
+#[derive(FromArgs)]
+/// Manage the downloads of a running rtget daemon: add <url> [<mirror>...], status [<id>], pause <id>, resume <id> or cancel <id>
+pub struct CtlArgs {
+    /// add, status, pause, resume or cancel
+    #[argh(positional)]
+    pub action: String,
+
+    /// the URL and mirrors to add, or the ID of a download
+    #[argh(positional)]
+    pub args: Vec<String>,
+
+    /// socket, or named pipe on Windows, the daemon listens on, default is the one of rtget daemon
+    #[argh(option)]
+    pub socket: Option<String>,
+
+    /// directory add saves the file in, instead of the one of the daemon
+    #[argh(option)]
+    pub dir: Option<String>,
+
+    /// name of the file add saves, instead of the one derived from its URL
+    #[argh(option, short = 'o')]
+    pub out: Option<String>,
+
+    /// number of concurrent connections of the download add starts, instead of those of the daemon
+    #[argh(option, short = 'c')]
+    pub connections: Option<u8>,
+
+    /// local time the download add queues starts at, like HH:MM or YYYY-MM-DD HH:MM
+    #[argh(option)]
+    pub start_at: Option<String>,
+
+    /// token of a user of the daemon's --users, for a login the daemon does not know
+    #[argh(option)]
+    pub token: Option<String>,
+}
+
+/// Arguments of `rtget history`.
+#[derive(FromArgs)]
+/// List the past downloads, oldest first, with when they ended, their outcome, size, duration and file
+pub struct HistoryArgs {
+    /// only downloads since this local date or time, like 2024-03-01 or "2024-03-01 18:00", or this long ago, like 12h or 7d
+    #[argh(option)]
+    pub since: Option<String>,
+
+    /// only downloads whose URL contains this text
+    #[argh(option)]
+    pub url: Option<String>,
+
+    /// only failed downloads
+    #[argh(switch)]
+    pub failed: bool,
+
+    /// print the downloads as a JSON array, with the SHA-256 of each file
+    #[argh(switch)]
+    pub json: bool,
+
+    /// history file to read instead of ~/.rtget-history
+    #[argh(option)]
+    pub file: Option<String>,
+}
+
+/// Arguments of `rtget status`.
+#[derive(FromArgs)]
+/// Show how far the interrupted downloads of files, or of those in directories, got
+pub struct StatusArgs {
+    /// output files of interrupted downloads, or directories to look for them in, default is the current directory
+    #[argh(positional)]
+    pub paths: Vec<String>,
+}
+
+/// What rtget is asked to do: a subcommand with its arguments.
+pub enum Command {
+    /// Download files, with `rtget get` or with the arguments alone, like `rtget <url>` or `rtget -u <url>`
+    Get(Box<CommandLineArgs>),
+    Resume(ResumeArgs),
+    Status(StatusArgs),
+    Check(CheckArgs),
+    Bench(BenchArgs),
+    Diagnose(DiagnoseArgs),
+    Replay(ReplayArgs),
+    History(HistoryArgs),
+    Daemon(Box<DaemonArgs>),
+    Ctl(CtlArgs),
+    InstallLaunchd(InstallLaunchdArgs),
+    Service(ServiceArgs),
+}
+
+// Short names of the options of rtget get and the long names they stand for
+const SHORT_NAMES: [(char, &str); 15] = [
+    ('u', "url"),
+    ('i', "input-file"),
+    ('r', "recursive"),
+    ('p', "page-requisites"),
+    ('l', "level"),
+    ('A', "accept"),
+    ('R', "reject"),
+    ('I', "include-directories"),
+    ('X', "exclude-directories"),
+    ('j', "jobs"),
+    ('o', "output"),
+    ('c', "connections"),
+    ('b', "background"),
+    ('v', "verbose"),
+    ('q', "quiet"),
+];
+
+/// Returns whether the option with the long `name` is among `args`, by its long or short name.
+pub fn is_given(args: &[&str], name: &str) -> bool {
+    let short = SHORT_NAMES.iter().find(|(_, long)| *long == name).map(|(short, _)| format!("-{}", short));
+    expand_verbosity(args).iter().any(|arg| arg.strip_prefix("--") == Some(name) || Some(*arg) == short.as_deref())
+}
+
+// The arguments with -vv and -vvv replaced by as many -v, which argh does not take grouped, up to `--`
+fn expand_verbosity<'a>(args: &[&'a str]) -> Vec<&'a str> {
+    let end = args.iter().position(|arg| *arg == "--").unwrap_or(args.len());
+    let grouped = |arg: &str| arg.len() > 2 && arg.strip_prefix('-').is_some_and(|flags| flags.chars().all(|flag| flag == 'v'));
+    let expanded = args[..end].iter().flat_map(|arg| match grouped(arg) {
+        true => std::iter::repeat_n("-v", arg.len() - 1),
+        false => std::iter::repeat_n(*arg, 1),
+    });
+    expanded.chain(args[end..].iter().copied()).collect()
+}
+
+// Names of the subcommands, which the first argument is matched against
+const SUBCOMMANDS: [&str; 12] = ["get", "resume", "status", "check", "bench", "diagnose", "replay", "history", "daemon", "ctl", "install-launchd", "service"];
+
+impl CommandLineArgs {
+    // The URLs given without -u join those of -u
+    fn with_positional_urls(mut self) -> CommandLineArgs {
+        self.url.append(&mut self.urls);
+        self
+    }
+}
+
+/// Parses the command line into the subcommand it asks for, exiting on `--help` or errors like `argh::from_env`.
+///
+/// A download also takes the defaults of its options from the config file.
+pub fn command_from_env() -> Command {
+    let args: Vec<String> = std::env::args().collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let defaults = match args.get(1) {
+        Some(name) if *name != "get" && SUBCOMMANDS.contains(name) => Vec::new(),
+        _ => config::defaults(args.get(1..).unwrap_or_default()).unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            std::process::exit(error::EXIT_USAGE);
+        }),
+    };
+    let defaults: Vec<&str> = defaults.iter().map(String::as_str).collect();
+    parse_command(&args, &defaults).unwrap_or_else(|(command, early_exit)| {
+        if early_exit.status.is_ok() {
+            println!("{}", early_exit.output);
+            std::process::exit(0);
+        }
+        eprintln!("{}\nRun {} --help for more information.", early_exit.output, command);
+        std::process::exit(error::EXIT_USAGE);
+    })
+}
+
+/// Parses `args`, the program name first, into the subcommand they ask for.
+///
+/// Without the name of a subcommand first they are the arguments of `rtget get`, which come after
+/// the `defaults` of its options. Errors come with the command they are about, like `rtget check`.
+pub fn parse_command(args: &[&str], defaults: &[&str]) -> Result<Command, (String, EarlyExit)> {
+    let program = args.first().copied().unwrap_or("rtget");
+    let name = args.get(1).copied().filter(|name| SUBCOMMANDS.contains(name));
+    let command = match name {
+        Some(name) => format!("{} {}", program, name),
+        None => program.to_string(),
+    };
+    let rest = expand_verbosity(args.get(1 + usize::from(name.is_some())..).unwrap_or_default());
+    let rest = rest.as_slice();
+    let parsed = match name {
+        Some("resume") => ResumeArgs::from_args(&[&command], rest).map(Command::Resume),
+        Some("status") => StatusArgs::from_args(&[&command], rest).map(Command::Status),
+        Some("check") => CheckArgs::from_args(&[&command], rest).map(Command::Check),
+        Some("bench") => BenchArgs::from_args(&[&command], rest).map(Command::Bench),
+        Some("diagnose") => DiagnoseArgs::from_args(&[&command], rest).map(Command::Diagnose),
+        Some("replay") => ReplayArgs::from_args(&[&command], rest).map(Command::Replay),
+        Some("history") => HistoryArgs::from_args(&[&command], rest).map(Command::History),
+        Some("daemon") => DaemonArgs::from_args(&[&command], rest).map(|args| Command::Daemon(Box::new(args))),
+        Some("ctl") => CtlArgs::from_args(&[&command], rest).map(Command::Ctl),
+        Some("install-launchd") => InstallLaunchdArgs::from_args(&[&command], rest).map(Command::InstallLaunchd),
+        Some("service") => ServiceArgs::from_args(&[&command], rest).map(Command::Service),
+        _ => {
+            let args: Vec<&str> = defaults.iter().chain(rest).copied().collect();
+            CommandLineArgs::from_args(&[&command], &args).map(|args| Command::Get(Box::new(args.with_positional_urls())))
+        }
+    };
+    parsed.map_err(|early_exit| (command, early_exit))
 }
 
 /*
@@ -35,17 +1216,274 @@ The following tests verify the command line arguments parsing functionality.
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_server;
 
     #[test]
     fn test_args_parsing() {
         let args = CommandLineArgs::from_args(&["test"], &["--url", "http://example.com", "--background"]).unwrap();
-        assert_eq!(args.url, "http://example.com");
+        assert_eq!(args.url, ["http://example.com"]);
         assert!(args.background);
     }
 
+    #[test]
+    fn test_args_pinned_pubkey() {
+        let args = CommandLineArgs::from_args(&["test"], &["--url", "https://example.com", "--pinned-pubkey", "sha256//AAAA"]).unwrap();
+        assert_eq!(args.pinned_pubkey.as_deref(), Some("sha256//AAAA"));
+    }
+
+    #[test]
+    fn test_replay_args() {
+        let args = ReplayArgs::from_args(&["rtget replay"], &["failure.log"]).unwrap();
+        assert_eq!(args.log, "failure.log");
+    }
+
+    #[test]
+    fn test_check_args() {
+        let args = CheckArgs::from_args(&["rtget check"], &["https://example.com/a.iso", "-c", "8"]).unwrap();
+        assert_eq!(args.url, "https://example.com/a.iso");
+        assert_eq!(args.connections, 8);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let Ok(Command::Get(args)) = parse_command(&["rtget", "http://a/x.iso", "-u", "http://a/y.iso", "-c", "4"], &[]) else {
+            panic!("a URL alone is a download");
+        };
+        assert_eq!(args.url, ["http://a/y.iso", "http://a/x.iso"]);
+        let Ok(Command::Get(args)) = parse_command(&["rtget", "get", "http://a/x.iso"], &[]) else {
+            panic!("get is a download");
+        };
+        assert_eq!(args.url, ["http://a/x.iso"]);
+        let Ok(Command::Get(args)) = parse_command(&["rtget", "http://a/x.iso", "-c", "2"], &["--limit-rate", "1M", "--dir", "isos"]) else {
+            panic!("the defaults of the config file are options of the download");
+        };
+        assert_eq!((args.connections, args.limit_rate, args.dir.as_deref()), (Connections::Fixed(2), Some(1 << 20), Some("isos")));
+        assert!(is_given(&["-c", "2"], "connections") && is_given(&["--dir", "isos"], "dir") && !is_given(&["-o", "dir"], "dir"));
+        assert!(matches!(parse_command(&["rtget", "history", "--json"], &[]), Ok(Command::History(args)) if args.json));
+        assert!(matches!(parse_command(&["rtget", "status"], &[]), Ok(Command::Status(args)) if args.paths.is_empty()));
+        let Err((command, _)) = parse_command(&["rtget", "check"], &[]) else {
+            panic!("check needs a URL");
+        };
+        assert_eq!(command, "rtget check");
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let Ok(Command::Get(args)) = parse_command(&["rtget", "http://a/x.iso", "-vv", "-v"], &[]) else {
+            panic!("-vv is a valid option");
+        };
+        assert_eq!(args.verbose, 3);
+        assert!(matches!(parse_command(&["rtget", "daemon", "-vvv"], &[]), Ok(Command::Daemon(args)) if args.verbose == 3));
+        assert_eq!(expand_verbosity(&["-vv", "--", "-vv"]), ["-v", "-v", "--", "-vv"]);
+        assert!(is_given(&["-vv"], "verbose"));
+        assert!(parse_command(&["rtget", "http://a/x.iso", "-vx"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_resume_args() {
+        let args = ResumeArgs::from_args(&["rtget resume"], &["a.iso", "--new-url", "https://mirror.example.com/a.iso"]).unwrap();
+        assert_eq!(args.new_url.as_deref(), Some("https://mirror.example.com/a.iso"));
+        let download = args.download_args("https://mirror.example.com/a.iso", 4, &config::Defaults::default()).unwrap();
+        assert_eq!(download.url, ["https://mirror.example.com/a.iso"]);
+        assert_eq!(download.output.as_deref(), Some("a.iso"));
+        assert_eq!(download.connections, Connections::Fixed(4));
+        assert_eq!(download.verbose, 0);
+    }
+
+    #[test]
+    fn test_connections_for_small_files() {
+        assert_eq!(Connections::Fixed(16).initial_for(10 * 1024, DEFAULT_MIN_SPLIT_SIZE), 1);
+        assert_eq!(Connections::Fixed(16).initial_for(10 * 1024, 4096), 3);
+        assert_eq!(Connections::Fixed(4).initial_for(100 << 20, DEFAULT_MIN_SPLIT_SIZE), 4);
+        assert_eq!(Connections::Auto.initial_for(1024, DEFAULT_MIN_SPLIT_SIZE), 1);
+        assert_eq!(max_ranges(0, DEFAULT_MIN_SPLIT_SIZE), 1);
+        assert_eq!(max_ranges(3 << 20, 0), 3 << 20);
+    }
+
+    #[test]
+    fn test_parse_connections() {
+        assert_eq!(parse_connections("8"), Ok(Connections::Fixed(8)));
+        assert_eq!(parse_connections("Auto"), Ok(Connections::Auto));
+        assert!(parse_connections("many").is_err());
+        assert_eq!(Connections::Fixed(0).initial(), 1);
+    }
+
+    #[test]
+    fn test_parse_file_allocation() {
+        assert_eq!(parse_file_allocation("FALLOC"), Ok(FileAllocation::Falloc));
+        assert_eq!(parse_file_allocation("none"), Ok(FileAllocation::None));
+        assert!(parse_file_allocation("prealloc").is_err());
+    }
+
+    #[test]
+    fn test_parse_io_backend() {
+        assert_eq!(parse_io_backend("uring"), Ok(IoBackend::Uring));
+        assert_eq!(parse_io_backend("STD"), Ok(IoBackend::Std));
+        assert_eq!(parse_io_backend("mmap"), Ok(IoBackend::Mmap));
+        assert_eq!(parse_progress_format("JSON"), Ok(progress::Format::Json));
+        assert!(parse_progress_format("xml").is_err());
+        assert_eq!(parse_log_format("Json"), Ok(logfile::Format::Json));
+        assert!(parse_log_format("xml").is_err());
+        assert_eq!(parse_progress_style("Detailed"), Ok(progress::Style::Detailed));
+        assert_eq!(parse_progress_chars("█▓░"), Ok("█▓░".to_string()));
+        assert!(parse_progress_chars("#").is_err());
+        assert!(parse_progress_chars("=表").is_err());
+        assert!(parse_io_backend("aio").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1500"), Ok(1500));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_interval("1D"), Ok(Duration::from_secs(86_400)));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("5w").is_err());
+        let args = CommandLineArgs::from_args(&["rtget"], &["-u", "http://a/x.iso", "--keep-previous", "3"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_parse_connection_counts() {
+        assert_eq!(parse_connection_counts("1, 4,16"), Ok(vec![1, 4, 16]));
+        assert!(parse_connection_counts("0").is_err());
+        assert!(parse_connection_counts("4,,8").is_err());
+    }
+
     #[test]
     fn test_args_error() {
-        let args = CommandLineArgs::from_args(&["test"], &[]);
-        assert!(args.is_err(), "Expected an error when no arguments are passed");
+        // Without -u the URLs may come from -i, so only the check finds nothing to download
+        let args = CommandLineArgs::from_args(&["test"], &[]).unwrap();
+        assert!(args.check_sources().is_err(), "Expected an error when no arguments are passed");
+    }
+
+    #[test]
+    fn test_input_file_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["-i", "urls.txt", "-j", "4"]).unwrap();
+        assert_eq!((args.input_file.as_deref(), args.jobs), (Some("urls.txt"), 4));
+        assert!(args.check_sources().is_ok());
+        let args = CommandLineArgs::from_args(&["test"], &["-i", "urls.txt", "--checksum", "md5=00000000000000000000000000000000"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_url_range_args() {
+        // A URL with a range is a batch of its own
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/part[1-3].bin", "--continue"]).unwrap();
+        assert!(args.is_batch());
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_total_connections_args() {
+        // Several URLs are a batch of their own
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/1.bin", "-u", "http://a/2.bin", "--total-connections", "8"]).unwrap();
+        assert!(args.is_batch() && args.check_sources().is_ok());
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/1.bin", "--total-connections", "0"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_recursive_args() {
+        // -r walks the website of one URL
+        let args = CommandLineArgs::from_args(&["test"], &["-r", "-l", "2", "-u", "http://a/"]).unwrap();
+        assert!(args.is_batch() && args.check_sources().is_ok() && args.level == 2);
+        let args = CommandLineArgs::from_args(&["test"], &["-r", "-u", "http://a/", "-u", "http://b/"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_dry_run_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/part[1-3].bin", "--dry-run"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_no_clobber_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/1.bin", "--no-clobber", "--continue"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_report_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/1.bin", "--report", "report.json"]).unwrap();
+        assert!(args.check_sources().is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_page_requisites_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["-p", "-u", "http://a/docs/"]).unwrap();
+        assert!(args.is_batch() && args.check_sources().is_ok());
+        let args = CommandLineArgs::from_args(&["test"], &["-p", "-i", "urls.txt"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_extract_links_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["--extract-links", "a[href$=.iso]", "-u", "http://a/pub/"]).unwrap();
+        assert!(args.is_batch() && args.check_sources().is_ok());
+        let args = CommandLineArgs::from_args(&["test"], &["--extract-links", "table a", "-u", "http://a/pub/"]).unwrap();
+        assert!(args.check_sources().is_err());
+        let args = CommandLineArgs::from_args(&["test"], &["--extract-links", "$.assets[*].url", "-r", "-u", "http://a/"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_force_args() {
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/1.bin", "--force", "--no-clobber"]).unwrap();
+        assert!(args.check_sources().is_err());
+    }
+
+    #[test]
+    fn test_service_args() {
+        let args = ServiceArgs::from_args(&["rtget service"], &["install", "--", "-i", "urls.txt", "-o", "downloads"]).unwrap();
+        assert_eq!(args.action, "install");
+        assert_eq!(args.args, ["-i", "urls.txt", "-o", "downloads"]);
+        assert!(service_download_args(&args.args).is_ok());
+        assert!(service_download_args(&["-u".to_string(), "http://a/x".to_string(), "-b".to_string()]).is_err());
+        assert!(service_download_args(&["-o".to_string(), "downloads".to_string()]).is_err());
+        assert!(service_download_args(&["--unknown".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_daemon_args() {
+        let args = DaemonArgs::from_args(&["rtget daemon"], &["--rpc-secret", "s3cret", "-c", "4", "--schedule", "@daily http://a/x.iso", "--notify-webhook", "http://hooks/rtget"]).unwrap();
+        assert_eq!(args.schedule[0].urls, ["http://a/x.iso"]);
+        assert!(DaemonArgs::from_args(&["rtget daemon"], &["--schedule", "0 2 * * http://a/x.iso"]).is_err());
+        assert_eq!((args.rpc_listen.as_str(), args.rpc_secret.as_deref(), args.dir.as_str(), args.jobs), ("127.0.0.1:6800", Some("s3cret"), ".", 5));
+        let download = args.download_args(&["http://a/x.iso".to_string(), "http://b/x.iso".to_string()], None, &config::Defaults::default()).unwrap();
+        assert_eq!((download.url, download.mirror), (vec!["http://a/x.iso".to_string()], vec!["http://b/x.iso".to_string()]));
+        assert_eq!(download.connections, Connections::Fixed(4));
+        assert_eq!(download.notify_webhook.as_deref(), Some("http://hooks/rtget"));
+        assert_eq!(args.download_args(&["http://a/x.iso".to_string()], Some(2), &config::Defaults::default()).unwrap().connections, Connections::Fixed(2));
+    }
+
+    #[test]
+    fn test_subcommand_downloads_take_config_defaults() {
+        let path = test_server::temp_dir("subcommand_defaults").join("config.toml");
+        std::fs::write(&path, "connections = 8\nlimit-rate = \"2M\"\n\n[profile.slow]\nlimit-rate = \"500K\"\n").unwrap();
+        let defaults = config::Defaults::load(path.to_str(), None).unwrap();
+
+        // The options the subcommand sets itself replace those of the file
+        let resume = ResumeArgs::from_args(&["rtget resume"], &["a.iso", "--config", path.to_str().unwrap()]).unwrap();
+        let download = resume.download_args("http://a/a.iso", 4, &defaults).unwrap();
+        assert_eq!((download.connections, download.limit_rate), (Connections::Fixed(4), Some(2 << 20)));
+
+        let daemon = DaemonArgs::from_args(&["rtget daemon"], &["--config", path.to_str().unwrap(), "--profile", "slow"]).unwrap();
+        let defaults = config::Defaults::load(daemon.config.as_deref(), daemon.profile.as_deref()).unwrap();
+        let download = daemon.download_args(&["http://a/x.iso".to_string()], None, &defaults).unwrap();
+        assert_eq!((download.connections, download.limit_rate), (Connections::Fixed(1), Some(500 << 10)));
+
+        std::fs::write(&path, "no-such-option = true\n").unwrap();
+        let defaults = config::Defaults::load(path.to_str(), None).unwrap();
+        assert!(resume.download_args("http://a/a.iso", 4, &defaults).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::args::{CommandLineArgs, Connections};
+use crate::checksum::{Algorithm, DigestTracker, ExpectedDigest};
+use crate::downloader::RemoteFile;
+use crate::error::AppError;
+use crate::sequence;
+
+/// One download of a batch
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    /// The URL as given in the input, or as a pattern expanded into
+    pub url: String,
+    /// Where the file is saved when the output or a manifest names it; otherwise it keeps its own name
+    pub output: Option<PathBuf>,
+    /// The hash the file must match, from a manifest
+    pub checksum: Option<ExpectedDigest>,
+}
+
+/// The result of one download of a batch
+pub struct Outcome {
+    /// The URL as given in the input
+    pub url: String,
+    /// Bytes downloaded, or why the download failed
+    pub result: Result<u64, AppError>,
+    /// Where the file was saved
+    pub output: Option<PathBuf>,
+    /// How long the download took
+    pub duration: Duration,
+    /// Where the file was served from, once its server answered
+    pub effective: Option<Effective>,
+}
+
+/// Where a file is served from: its URL after redirects, the IP address connected to and the protocol
+#[derive(Clone, Debug, PartialEq)]
+pub struct Effective {
+    pub url: String,
+    pub ip: Option<IpAddr>,
+    pub protocol: String,
+}
+
+impl Effective {
+    /// Returns where the file the probe learned about in `remote` is served from.
+    pub fn of(remote: &RemoteFile) -> Effective {
+        Effective { url: remote.url.to_string(), ip: remote.address.map(|address| address.ip()), protocol: remote.protocol() }
+    }
+
+    /// Returns the line of --print-effective-url: the URL, the IP address or `-` when unknown, and the protocol, separated by tabs.
+    pub fn line(&self) -> String {
+        let ip = self.ip.map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        format!("{}\t{}\t{}", self.url, ip, self.protocol)
+    }
+
+    // Adds the fields of the download in the report to `entry`, null when the server never answered
+    fn add_to(effective: Option<&Effective>, mut entry: Value) -> Value {
+        if let Some(entry) = entry.as_object_mut() {
+            entry.insert("effective_url".to_string(), json!(effective.map(|effective| &effective.url)));
+            entry.insert("ip".to_string(), json!(effective.and_then(|effective| effective.ip).map(|ip| ip.to_string())));
+            entry.insert("protocol".to_string(), json!(effective.map(|effective| &effective.protocol)));
+        }
+        entry
+    }
+}
+
+impl Outcome {
+    /// The outcome of the download of `url` that took `duration`, returned the bytes downloaded and the output, and was served from `effective`.
+    pub fn new(url: String, result: Result<(u64, PathBuf), AppError>, duration: Duration, effective: Option<Effective>) -> Outcome {
+        let (result, output) = match result {
+            Ok((downloaded, output)) => (Ok(downloaded), Some(output)),
+            Err(error) => (Err(error), None),
+        };
+        Outcome { url, result, output, duration, effective }
+    }
+}
+
+/// Reads the URLs to download from the input file at `path`, or from standard input for `-`.
+pub fn read_urls(path: &str) -> Result<Vec<String>, AppError> {
+    let text = match path {
+        "-" => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        }
+        path => std::fs::read_to_string(path).map_err(|e| AppError::IoError(format!("{}: {}", path, e)))?,
+    };
+    Ok(parse_urls(&text))
+}
+
+/// Returns the URLs of an input file, one per line; blank lines and lines starting with `#` are skipped.
+pub fn parse_urls(text: &str) -> Vec<String> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(str::to_string).collect()
+}
+
+/// Expands the URL patterns among `urls` into the downloads of a batch.
+///
+/// When `output` is a template like `part#1.bin`, every URL of a pattern is saved under the template
+/// filled with its values; the other files keep their own names.
+pub fn entries(urls: Vec<String>, output: Option<&str>) -> Result<Vec<Entry>, AppError> {
+    let template = output.filter(|output| sequence::is_template(output));
+    let mut entries = Vec::new();
+    for url in urls {
+        match sequence::expand(&url).map_err(AppError::UrlValidationError)? {
+            Some(expansions) => entries.extend(expansions.into_iter().map(|expansion| Entry {
+                output: template.map(|template| sequence::fill(template, &expansion.values).into()),
+                url: expansion.url,
+                checksum: None,
+            })),
+            None => entries.push(Entry { url, output: None, checksum: None }),
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns how many files of a batch run at once, and the connections each one uses.
+///
+/// Every file may open as many connections as -c allows, `auto_max` with `-c auto`, so
+/// --total-connections lets only as many files run as fit. A file is given fewer connections only
+/// when -c alone exceeds the limit.
+pub fn limits(args: &CommandLineArgs, auto_max: usize) -> (usize, Connections) {
+    let jobs = args.jobs.max(1);
+    let Some(total) = args.total_connections else {
+        return (jobs, args.connections);
+    };
+    let per_file = match args.connections {
+        Connections::Fixed(connections) => connections.max(1) as usize,
+        Connections::Auto => auto_max,
+    };
+    match per_file <= total {
+        true => (jobs.min(total / per_file), args.connections),
+        false => (1, Connections::Fixed(u8::try_from(total).expect("fewer than the connections of -c"))),
+    }
+}
+
+/// What a batch does with a download whose output another URL of the batch is saved to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collision {
+    /// Save it with the first number no other download has before the extension, e.g. `file-1.iso`
+    Number,
+    /// Save it with a hash of its URL before the extension, e.g. `file-3fa2c1d0.iso`
+    Hash,
+    /// Start no download of the batch
+    Fail,
+}
+
+impl Collision {
+    /// Parses `number`, `hash` or `fail`.
+    pub fn parse(value: &str) -> Result<Collision, String> {
+        match value {
+            "number" => Ok(Collision::Number),
+            "hash" => Ok(Collision::Hash),
+            "fail" => Ok(Collision::Fail),
+            _ => Err(format!("invalid --on-collision {}, expected number, hash or fail", value)),
+        }
+    }
+}
+
+/// The outputs the downloads of a batch are saved to, so that no two URLs write the same file
+pub struct Outputs {
+    collision: Collision,
+    // The URL each output is claimed by
+    claimed: HashMap<PathBuf, String>,
+}
+
+impl Outputs {
+    /// Starts a batch handling collisions as `collision` says.
+    pub fn new(collision: Collision) -> Outputs {
+        Outputs { collision, claimed: HashMap::new() }
+    }
+
+    /// Claims `path` for the download of `url` and returns where it is saved.
+    ///
+    /// That is `path` unless another URL claimed it first; a URL listed again shares its output.
+    /// Outputs are claimed in the order of the input, so the same batch gets the same names every
+    /// time, whatever order its downloads end in.
+    pub fn claim(&mut self, url: &str, path: &Path) -> Result<PathBuf, String> {
+        let claimed = match self.claimed.get(path) {
+            None => path.to_path_buf(),
+            Some(first) if first == url => return Ok(path.to_path_buf()),
+            Some(first) => match self.collision {
+                Collision::Number => (1..).map(|number| suffixed(path, &number.to_string())).find(|numbered| !self.claimed.contains_key(numbered)).expect("some number is free"),
+                Collision::Hash => {
+                    let hash: String = Sha256::digest(url.as_bytes())[..4].iter().map(|byte| format!("{:02x}", byte)).collect();
+                    suffixed(path, &hash)
+                }
+                Collision::Fail => return Err(format!("{} and {} would both be saved as {}; pass --on-collision number or hash to keep both", first, url, path.display())),
+            },
+        };
+        self.claimed.insert(claimed.clone(), url.to_string());
+        Ok(claimed)
+    }
+}
+
+// `path` with `-<suffix>` before its extension
+// Existing files are numbered as `file.iso.1` when a download starts, so the names of a batch stay apart from those
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!("{}-{}.{}", stem, suffix, extension.to_string_lossy())),
+        None => path.with_file_name(format!("{}-{}", stem, suffix)),
+    }
+}
+
+/// Returns whether a batch starts no further download after one finished with `result`.
+///
+/// With --halt-on-error the first failure stops it, otherwise, as with --keep-going, only an interruption does.
+pub fn halts_after<T>(args: &CommandLineArgs, result: &Result<T, AppError>) -> bool {
+    match result {
+        Ok(_) => false,
+        Err(AppError::Interrupted) => true,
+        Err(_) => args.halt_on_error,
+    }
+}
+
+/// Summarizes the downloads of a batch, one line per URL in the order of the input.
+///
+/// `skipped` URLs were not started because the batch was interrupted or stopped early.
+pub fn summary(outcomes: &[Outcome], skipped: usize) -> String {
+    let succeeded = outcomes.iter().filter(|outcome| outcome.result.is_ok()).count();
+    let mut output = String::new();
+    let _ = writeln!(output, "{} of {} downloads succeeded", succeeded, outcomes.len() + skipped);
+    for outcome in outcomes {
+        let _ = match &outcome.result {
+            Ok(bytes) => writeln!(output, "  OK      {} ({} bytes)", outcome.url, bytes),
+            Err(e) => writeln!(output, "  FAILED  {}: {}", outcome.url, e),
+        };
+    }
+    if skipped > 0 {
+        let _ = writeln!(output, "  {} not started", skipped);
+    }
+    output
+}
+
+/// Renders the JSON report of --report: the counts of the batch and one object per URL, `not_started` last.
+///
+/// Each download has its status, the bytes downloaded, its duration in seconds and average speed in
+/// bytes per second, and the output path and SHA-256 of the saved file, or the error it failed with.
+/// Downloads that started also have their URL after redirects, the IP address connected to and the
+/// protocol, null when the server never answered.
+pub fn report(outcomes: &[Outcome], not_started: &[String]) -> String {
+    let downloads = outcomes.iter().map(|outcome| Effective::add_to(outcome.effective.as_ref(), match &outcome.result {
+        Ok(downloaded) => {
+            let seconds = outcome.duration.as_secs_f64();
+            json!({
+                "url": outcome.url,
+                "status": "ok",
+                "bytes": downloaded,
+                "duration": seconds,
+                "speed": (seconds > 0.0).then(|| (*downloaded as f64 / seconds).round()),
+                "output": outcome.output.as_deref().map(Path::to_string_lossy),
+                "sha256": outcome.output.as_deref().and_then(sha256),
+            })
+        }
+        Err(error) => json!({ "url": outcome.url, "status": "failed", "duration": outcome.duration.as_secs_f64(), "error": error.to_string() }),
+    }));
+    let not_started_downloads = not_started.iter().map(|url| json!({ "url": url, "status": "not started" }));
+    let succeeded = outcomes.iter().filter(|outcome| outcome.result.is_ok()).count();
+    json!({
+        "succeeded": succeeded,
+        "failed": outcomes.len() - succeeded,
+        "not_started": not_started.len(),
+        "downloads": downloads.chain(not_started_downloads).collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+/// Returns the SHA-256 of the file at `path` in hex, unless it cannot be read.
+pub fn sha256(path: &Path) -> Option<String> {
+    let size = std::fs::metadata(path).ok().filter(|metadata| metadata.is_file())?.len();
+    let digests = DigestTracker::new([Algorithm::Sha256]).finish(path, size).ok()?;
+    let digest = digests.with_suffix(Algorithm::Sha256, &[])?;
+    Some(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    #[test]
+    fn test_outputs() {
+        let mut outputs = Outputs::new(Collision::Number);
+        assert_eq!(outputs.claim("http://a/x.iso", Path::new("x.iso")), Ok(PathBuf::from("x.iso")));
+        assert_eq!(outputs.claim("http://a/x.iso", Path::new("x.iso")), Ok(PathBuf::from("x.iso")));
+        assert_eq!(outputs.claim("http://b/x.iso", Path::new("x.iso")), Ok(PathBuf::from("x-1.iso")));
+        // A name taken by a later URL is numbered too, and numbers are not given twice
+        assert_eq!(outputs.claim("http://c/x-1.iso", Path::new("x-1.iso")), Ok(PathBuf::from("x-1-1.iso")));
+        assert_eq!(outputs.claim("http://c/x.iso", Path::new("x.iso")), Ok(PathBuf::from("x-2.iso")));
+        assert_eq!(outputs.claim("http://c/README", Path::new("README")), Ok(PathBuf::from("README")));
+        assert_eq!(outputs.claim("http://d/README", Path::new("README")), Ok(PathBuf::from("README-1")));
+
+        let mut outputs = Outputs::new(Collision::Hash);
+        outputs.claim("http://a/x.iso", Path::new("isos/x.iso")).unwrap();
+        let hashed = outputs.claim("http://b/x.iso", Path::new("isos/x.iso")).unwrap();
+        let expected: String = Sha256::digest(b"http://b/x.iso")[..4].iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(hashed, PathBuf::from(format!("isos/x-{}.iso", expected)));
+
+        let mut outputs = Outputs::new(Collision::Fail);
+        outputs.claim("http://a/x.iso", Path::new("x.iso")).unwrap();
+        assert!(outputs.claim("http://b/x.iso", Path::new("x.iso")).unwrap_err().contains("http://a/x.iso and http://b/x.iso"));
+        assert_eq!(Collision::parse("hash"), Ok(Collision::Hash));
+        assert!(Collision::parse("rename").is_err());
+    }
+
+    #[test]
+    fn test_parse_urls() {
+        let urls = parse_urls("# nightly builds\nhttp://a/1.bin\n\n  http://a/2.bin  \r\n#http://a/3.bin\n");
+        assert_eq!(urls, ["http://a/1.bin", "http://a/2.bin"]);
+    }
+
+    #[test]
+    fn test_entries() {
+        let urls = vec!["http://a/[08-10].bin".to_string(), "http://a/other.bin".to_string()];
+        let entries = entries(urls.clone(), Some("out/#1.bin")).unwrap();
+        let outputs: Vec<_> = entries.iter().map(|entry| entry.output.as_ref().map(|path| path.to_str().unwrap())).collect();
+        assert_eq!(outputs, [Some("out/08.bin"), Some("out/09.bin"), Some("out/10.bin"), None]);
+        assert_eq!(entries[2].url, "http://a/10.bin");
+
+        // A directory is not a template, so every file keeps its own name
+        assert!(super::entries(urls, Some("out")).unwrap().iter().all(|entry| entry.output.is_none()));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = |flags: &[&str]| limits(&CommandLineArgs::from_args(&["rtget"], flags).unwrap(), 16);
+        assert_eq!(limits(&["-j", "4", "-c", "8"]), (4, Connections::Fixed(8)));
+        assert_eq!(limits(&["-j", "4", "-c", "8", "--total-connections", "20"]), (2, Connections::Fixed(8)));
+        assert_eq!(limits(&["-j", "4", "-c", "auto", "--total-connections", "32"]), (2, Connections::Auto));
+        assert_eq!(limits(&["-j", "4", "-c", "8", "--total-connections", "5"]), (1, Connections::Fixed(5)));
+    }
+
+    #[test]
+    fn test_halts_after() {
+        let args = |flags: &[&str]| CommandLineArgs::from_args(&["rtget"], &[&["-u", "http://a/[1-3].bin"], flags].concat()).unwrap();
+        let failed: Result<(), AppError> = Err(AppError::HttpStatus(404, "404 Not Found".to_string()));
+        for keep_going in [args(&[]), args(&["--keep-going"])] {
+            assert!(keep_going.check_sources().is_ok());
+            assert!(!halts_after(&keep_going, &Ok(())) && !halts_after(&keep_going, &failed));
+            assert!(halts_after(&keep_going, &Err::<(), _>(AppError::Interrupted)));
+        }
+        let halt = args(&["--halt-on-error"]);
+        assert!(halt.check_sources().is_ok());
+        assert!(!halts_after(&halt, &Ok(())) && halts_after(&halt, &failed));
+        assert!(args(&["--halt-on-error", "--keep-going"]).check_sources().is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let outcomes = [
+            Outcome::new("http://a/1.bin".to_string(), Ok((10, PathBuf::from("1.bin"))), Duration::ZERO, None),
+            Outcome::new("http://a/2.bin".to_string(), Err(AppError::Interrupted), Duration::ZERO, None),
+        ];
+        assert_eq!(
+            summary(&outcomes, 1),
+            "1 of 3 downloads succeeded\n  OK      http://a/1.bin (10 bytes)\n  FAILED  http://a/2.bin: The download was interrupted\n  1 not started\n"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let output = crate::test_server::temp_dir("report").join("1.bin");
+        std::fs::write(&output, b"").unwrap();
+        let effective = Effective { url: "http://b/1.bin".to_string(), ip: Some([127, 0, 0, 1].into()), protocol: "HTTP/1.1".to_string() };
+        let outcomes = [
+            Outcome::new("http://c/1.bin".to_string(), Ok((10, output.clone())), Duration::from_secs(2), Some(effective)),
+            Outcome::new("http://a/2.bin".to_string(), Err(AppError::Interrupted), Duration::from_millis(500), None),
+        ];
+        let report: serde_json::Value = serde_json::from_str(&report(&outcomes, &["http://a/3.bin".to_string()])).unwrap();
+        assert_eq!((report["succeeded"].as_u64(), report["failed"].as_u64(), report["not_started"].as_u64()), (Some(1), Some(1), Some(1)));
+        let downloads = report["downloads"].as_array().unwrap();
+        assert_eq!(downloads[0]["speed"].as_f64(), Some(5.0));
+        assert_eq!(downloads[0]["output"].as_str(), output.to_str());
+        assert_eq!(downloads[0]["sha256"].as_str(), Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+        assert_eq!(downloads[0]["effective_url"].as_str(), Some("http://b/1.bin"));
+        assert_eq!(downloads[0]["ip"].as_str(), Some("127.0.0.1"));
+        assert_eq!(downloads[0]["protocol"].as_str(), Some("HTTP/1.1"));
+        assert_eq!(downloads[1]["error"].as_str(), Some("The download was interrupted"));
+        assert!(downloads[1]["effective_url"].is_null());
+        assert_eq!(outcomes[1].effective, None);
+        assert_eq!(downloads[2]["status"].as_str(), Some("not started"));
+    }
+}
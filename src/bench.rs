@@ -0,0 +1,105 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+use indicatif::{HumanBytes, ProgressBar};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use url::Url;
+use crate::downloader::{ClientOptions, Downloader, FileDownloader};
+use crate::error::AppError;
+
+/// Downloads `url` over each of `connections` in turn and reports the throughput of every configuration.
+///
+/// Each run splits the file, or its first `budget` bytes, into one range per connection and discards
+/// what arrives. A run stops when its ranges are done or after `duration`, whichever comes first, so
+/// a large file does not have to be downloaded in full.
+pub async fn report(url: &Url, connections: &[usize], duration: Duration, budget: Option<u64>, options: &ClientOptions) -> Result<String, AppError> {
+    let downloader = FileDownloader::with_options(options)?;
+    let remote = downloader.probe(url.as_str()).await?;
+    // Without ranges every configuration would download over a single connection
+    let size = match remote.size {
+        Some(size) if remote.accepts_ranges && size > 0 => size,
+        _ => return Err(AppError::RangeNotSupported),
+    };
+    let size = budget.map_or(size, |budget| usize::try_from(budget).unwrap_or(usize::MAX).clamp(1, size));
+
+    let mut output = String::new();
+    let _ = writeln!(output, "{:<12} {:>12} {:>8}  Throughput", "Connections", "Bytes", "Seconds");
+    let mut fastest: Option<(usize, f64)> = None;
+    for &count in connections {
+        let (bytes, elapsed, failure) = measure(&downloader, &remote.url, count, size, duration).await;
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let _ = write!(output, "{:<12} {:>12} {:>8.2}  {}/s", count, bytes, elapsed.as_secs_f64(), HumanBytes(rate as u64));
+        match failure {
+            Some(e) => {
+                let _ = writeln!(output, " (failed: {})", e);
+            }
+            None => {
+                let _ = writeln!(output);
+                if fastest.is_none_or(|(_, best)| rate > best) {
+                    fastest = Some((count, rate));
+                }
+            }
+        }
+    }
+    match fastest {
+        Some((count, rate)) => {
+            let _ = writeln!(output, "Fastest:      -c {} ({}/s)", count, HumanBytes(rate as u64));
+        }
+        None => {
+            let _ = writeln!(output, "Fastest:      none, every configuration failed");
+        }
+    }
+    Ok(output)
+}
+
+// Download the first `size` bytes of `url` over `connections` connections for at most `duration`
+// Returns the bytes received, the time taken and the error that stopped the run early, if any
+async fn measure(downloader: &FileDownloader, url: &Url, connections: usize, size: usize, duration: Duration) -> (u64, Duration, Option<AppError>) {
+    let progress = ProgressBar::hidden();
+    let started = Instant::now();
+    let mut running = JoinSet::new();
+    for (start, end) in FileDownloader::calculate_byte_ranges(connections, size) {
+        let (downloader, url, progress) = (downloader.connection(), url.to_string(), progress.clone());
+        running.spawn(async move { downloader.download_chunk(&url, start, end, &mut tokio::io::sink(), &progress).await });
+    }
+    let mut failure = None;
+    // Running out of time ends the run like finishing it; the remaining ranges are dropped with the set
+    while let Ok(Some(finished)) = tokio::time::timeout_at(started + duration, running.join_next()).await {
+        match finished {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => failure = Some(e),
+            Err(e) => failure = Some(AppError::StringError(e.to_string())),
+        }
+        if failure.is_some() {
+            break;
+        }
+    }
+    (progress.position(), started.elapsed(), failure)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Quirks};
+
+    #[tokio::test]
+    async fn test_report_every_configuration() {
+        let url = Url::parse(&test_server::serve(vec![0; 100_000])).unwrap();
+        let report = report(&url, &[1, 4], Duration::from_secs(10), None, &ClientOptions::default()).await.unwrap();
+        let rows: Vec<_> = report.lines().collect();
+        assert!(rows[1].starts_with("1                  100000 "), "{}", report);
+        assert!(rows[2].starts_with("4                  100000 "), "{}", report);
+        assert!(rows[3].starts_with("Fastest:      -c "));
+
+        // A byte budget only downloads the start of the file
+        let report = super::report(&url, &[2], Duration::from_secs(10), Some(1000), &ClientOptions::default()).await.unwrap();
+        assert!(report.lines().nth(1).unwrap().starts_with("2                    1000 "), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn test_report_without_ranges() {
+        let url = Url::parse(&test_server::serve_with(vec![0; 1000], Quirks { ignore_range: true, ..Quirks::default() })).unwrap();
+        assert!(matches!(report(&url, &[1], Duration::from_secs(1), None, &ClientOptions::default()).await, Err(AppError::RangeNotSupported)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use reqwest::header::{HeaderMap, HeaderName, CONTENT_TYPE, ETAG, LAST_MODIFIED};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// A cached copy of a download and the validators needed to revalidate it
+#[derive(Debug, PartialEq)]
+pub struct CacheEntry {
+    /// Path of the cached content
+    pub path: PathBuf,
+    /// `ETag` of the cached content, sent back as `If-None-Match`
+    pub etag: Option<String>,
+    /// `Last-Modified` of the cached content, sent back as `If-Modified-Since`
+    pub last_modified: Option<String>,
+    /// `Content-Type` of the cached content
+    pub content_type: Option<String>,
+}
+
+/// A directory of downloaded files keyed by URL
+///
+/// Each URL is stored as two files named after the SHA-256 of the URL: the content and a
+/// `.meta` file holding the URL and its validators as `name: value` lines.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Creates a cache rooted at `dir`, which is created on the first store.
+    pub fn new(dir: impl Into<PathBuf>) -> Cache {
+        Cache { dir: dir.into() }
+    }
+
+    /// Returns the cached copy of `url`, if there is one with at least one validator.
+    pub fn lookup(&self, url: &Url) -> Option<CacheEntry> {
+        let (content_path, meta_path) = self.paths(url);
+        let meta = std::fs::read_to_string(meta_path).ok()?;
+        let field = |name: &str| {
+            meta.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                .map(str::to_string)
+        };
+        // Guard against a stale or foreign file under the same name
+        if field("url").as_deref() != Some(url.as_str()) || !content_path.is_file() {
+            return None;
+        }
+        let entry = CacheEntry {
+            path: content_path,
+            etag: field("etag"),
+            last_modified: field("last-modified"),
+            content_type: field("content-type"),
+        };
+        (entry.etag.is_some() || entry.last_modified.is_some()).then_some(entry)
+    }
+
+    /// Stores a copy of `file` as the content of `url` along with the validators found in `headers`.
+    ///
+    /// Responses without `ETag` or `Last-Modified` cannot be revalidated and are not stored.
+    /// Returns whether the file was stored.
+    pub fn store(&self, url: &Url, headers: &HeaderMap, file: &Path) -> io::Result<bool> {
+        let header = |name: HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+        if header(ETAG).is_none() && header(LAST_MODIFIED).is_none() {
+            return Ok(false);
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        let (content_path, meta_path) = self.paths(url);
+
+        // Write to a temporary name first so a failed copy never leaves a truncated entry behind
+        let temporary = content_path.with_extension("tmp");
+        std::fs::copy(file, &temporary)?;
+        std::fs::rename(&temporary, &content_path)?;
+
+        let mut meta = format!("url: {}\n", url);
+        for (name, value) in [("etag", header(ETAG)), ("last-modified", header(LAST_MODIFIED)), ("content-type", header(CONTENT_TYPE))] {
+            if let Some(value) = value {
+                meta.push_str(&format!("{}: {}\n", name, value));
+            }
+        }
+        std::fs::write(meta_path, meta)?;
+        Ok(true)
+    }
+
+    // Paths of the content and metadata files of `url`
+    fn paths(&self, url: &Url) -> (PathBuf, PathBuf) {
+        let key: String = Sha256::digest(url.as_str().as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect();
+        (self.dir.join(&key), self.dir.join(format!("{}.meta", key)))
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_store_and_lookup() {
+        let dir = test_server::temp_dir("cache");
+        let cache = Cache::new(dir.join("cache"));
+        let url = Url::parse("https://example.com/artifact.tar.gz").unwrap();
+        let download = dir.join("artifact.tar.gz");
+        std::fs::write(&download, b"artifact").unwrap();
+
+        // Nothing to revalidate with, nothing stored
+        assert!(!cache.store(&url, &HeaderMap::new(), &download).unwrap());
+        assert_eq!(cache.lookup(&url), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+        assert!(cache.store(&url, &headers, &download).unwrap());
+        let entry = cache.lookup(&url).unwrap();
+        assert_eq!(entry.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(entry.last_modified, None);
+        assert_eq!(std::fs::read(entry.path).unwrap(), b"artifact");
+
+        // Other URLs are not served from the entry
+        assert_eq!(cache.lookup(&Url::parse("https://example.com/other").unwrap()), None);
+    }
+}
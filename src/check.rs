@@ -0,0 +1,98 @@
+use std::fmt::Write as _;
+use reqwest::header::{HeaderMap, HeaderName};
+use url::Url;
+use crate::args;
+use crate::downloader::{describe_session, ClientOptions, Downloader, FileDownloader, RemoteFile};
+use crate::error::AppError;
+
+/// Probes `url` the way a download would and reports what was learned, without downloading anything.
+///
+/// `connections` and `min_split_size` are only used to show the chunk plan a download with `-c` and
+/// `--min-split-size` would use.
+pub async fn report(url: &Url, connections: usize, min_split_size: u64, options: &ClientOptions) -> Result<String, AppError> {
+    let downloader = FileDownloader::with_options(options)?;
+    let remote = downloader.probe(url.as_str()).await?;
+
+    let mut output = String::new();
+    let _ = writeln!(output, "URL:            {}", url);
+    if remote.url != *url {
+        let _ = writeln!(output, "Redirected to:  {}", remote.url);
+    }
+    let _ = writeln!(output, "Resolved:       {}", resolve(&remote.url).await);
+    if remote.url.scheme() == "https" {
+        let tls = describe_session(&remote.url, options).await.unwrap_or_else(|e| format!("handshake failed: {}", e));
+        let _ = writeln!(output, "TLS:            {}", tls);
+    }
+    let _ = writeln!(output, "Ranges:         {}", if remote.accepts_ranges { "supported" } else { "not supported" });
+    let _ = writeln!(
+        output,
+        "Content-Length: {}",
+        remote.size.map_or_else(|| "unknown".to_string(), |size| size.to_string())
+    );
+    let _ = writeln!(output, "Content-Type:   {}", header(&remote.headers, reqwest::header::CONTENT_TYPE));
+    let _ = writeln!(output, "ETag:           {}", header(&remote.headers, reqwest::header::ETAG));
+    let _ = writeln!(output, "Plan:           {}", plan(&remote, connections, min_split_size));
+    Ok(output)
+}
+
+// Resolve the host of `url` to the addresses a download would connect to
+async fn resolve(url: &Url) -> String {
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return "no host".to_string();
+    };
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addresses) => addresses.map(|address| address.ip().to_string()).collect::<Vec<_>>().join(", "),
+        Err(e) => format!("failed: {}", e),
+    }
+}
+
+// Value of a header for display, `none` when missing
+fn header(headers: &HeaderMap, name: HeaderName) -> &str {
+    headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("none")
+}
+
+// Describe how a download would split the file, mirroring the decisions of `run_in_foreground`
+fn plan(remote: &RemoteFile, connections: usize, min_split_size: u64) -> String {
+    match remote.size {
+        Some(0) => "single stream (empty file)".to_string(),
+        Some(size) if remote.accepts_ranges => {
+            let connections = connections.min(args::max_ranges(size as u64, min_split_size));
+            let ranges = FileDownloader::calculate_byte_ranges(connections, size);
+            let ranges: Vec<_> = ranges.iter().map(|(start, end)| format!("{}-{}", start, end)).collect();
+            format!("{} range(s): bytes {}", ranges.len(), ranges.join(", "))
+        }
+        Some(_) => "single stream (the server does not support byte ranges)".to_string(),
+        None => "single stream (the server did not report a content length)".to_string(),
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Quirks};
+
+    #[tokio::test]
+    async fn test_report_ranged_file() {
+        let url = Url::parse(&test_server::serve(vec![0; 1000])).unwrap();
+        let report = report(&url, 4, 100, &ClientOptions::default()).await.unwrap();
+        assert!(report.contains("Resolved:       127.0.0.1\n"));
+        assert!(report.contains("Ranges:         supported\n"));
+        assert!(report.contains("Content-Length: 1000\n"));
+        assert!(report.contains("Plan:           4 range(s): bytes 0-249, 250-499, 500-749, 750-999\n"));
+
+        // A file below the minimum split size is not split into more ranges than it can fill
+        let small = super::report(&url, 16, 400, &ClientOptions::default()).await.unwrap();
+        assert!(small.contains("Plan:           3 range(s): bytes 0-333, 334-667, 668-999\n"));
+        let tiny = super::report(&url, 16, 1, &ClientOptions::default()).await.unwrap();
+        assert!(tiny.contains("Plan:           16 range(s): bytes 0-62, "));
+    }
+
+    #[tokio::test]
+    async fn test_report_without_ranges() {
+        let url = Url::parse(&test_server::serve_with(vec![0; 1000], Quirks { ignore_range: true, ..Quirks::default() })).unwrap();
+        let report = report(&url, 4, 100, &ClientOptions::default()).await.unwrap();
+        assert!(report.contains("Ranges:         not supported\n"));
+        assert!(report.contains("Plan:           single stream (the server does not support byte ranges)\n"));
+    }
+}
@@ -0,0 +1,494 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use indicatif::ProgressBar;
+use tokio::io::AsyncWrite;
+use url::Url;
+use crate::downloader::{Downloader, FileDownloader, RequestSpec};
+
+// How often the bytes written ahead of the hashed part of a segmented download are hashed
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+// Bytes read back from the output at once
+const READ_BLOCK_SIZE: usize = 256 * 1024;
+
+// Largest checksum file fetched by --auto-checksum
+const MAX_SUMS_SIZE: u64 = 1024 * 1024;
+
+// Suffixes of a checksum file published for one file, strongest hash first
+const SIDECAR_SUFFIXES: &[(&str, Algorithm)] = &[
+    ("sha512", Algorithm::Sha512),
+    ("sha256", Algorithm::Sha256),
+    ("sha1", Algorithm::Sha1),
+    ("md5", Algorithm::Md5),
+];
+
+// Checksum files listing every file of a directory, strongest hash first
+const SUMS_FILES: &[(&str, Algorithm)] = &[
+    ("SHA512SUMS", Algorithm::Sha512),
+    ("SHA256SUMS", Algorithm::Sha256),
+    ("SHA1SUMS", Algorithm::Sha1),
+    ("MD5SUMS", Algorithm::Md5),
+    ("B3SUMS", Algorithm::Blake3),
+];
+
+/// Hash algorithms a download can be verified with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    /// Name of the algorithm as used by `--checksum`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    // Length of a digest in bytes
+    fn digest_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 | Algorithm::Blake3 => 32,
+            Algorithm::Sha512 => 64,
+        }
+    }
+}
+
+/// An expected hash of the whole file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpectedDigest {
+    pub algorithm: Algorithm,
+    pub value: Vec<u8>,
+}
+
+/// Parses a checksum given as `<algorithm>=<hex digest>`, e.g. `sha256=9f86d0...`.
+pub fn parse_checksum(value: &str) -> Result<ExpectedDigest, String> {
+    let (name, hex) = value.trim().split_once('=').ok_or_else(|| format!("invalid checksum {}, expected <algorithm>=<hex digest>", value))?;
+    let algorithm = match name.trim().to_ascii_lowercase().as_str() {
+        "md5" => Algorithm::Md5,
+        "sha1" => Algorithm::Sha1,
+        "sha256" => Algorithm::Sha256,
+        "sha512" => Algorithm::Sha512,
+        "blake3" => Algorithm::Blake3,
+        _ => return Err(format!("unknown checksum algorithm {}, expected md5, sha1, sha256, sha512 or blake3", name)),
+    };
+    let hex = hex.trim();
+    let value = decode_hex(hex).filter(|value| value.len() == algorithm.digest_len()).ok_or_else(|| {
+        format!("invalid {} digest {}, expected {} hex digits", algorithm.as_str(), hex, algorithm.digest_len() * 2)
+    })?;
+    Ok(ExpectedDigest { algorithm, value })
+}
+
+/// Looks for a checksum published next to `url`, as `<url>.sha256` and the like or in a
+/// `SHA256SUMS` style file of its directory.
+///
+/// Returns the checksum of the file and where it was found, or `None` if no candidate has one.
+pub async fn discover(downloader: &FileDownloader, url: &Url) -> Option<(ExpectedDigest, Url)> {
+    let file_name = url.path_segments()?.next_back().filter(|name| !name.is_empty())?.to_string();
+    for (candidate, algorithm, sidecar) in candidates(url) {
+        let mut body = Vec::new();
+        if let Err(e) = downloader.download_whole(candidate.as_str(), &RequestSpec::default(), &mut body, &ProgressBar::hidden(), Some(MAX_SUMS_SIZE)).await {
+            tracing::debug!("No checksum at {}: {}", candidate, e);
+            continue;
+        }
+        match parse_sums(&String::from_utf8_lossy(&body), algorithm, &file_name, sidecar) {
+            Some(expected) => return Some((expected, candidate)),
+            None => tracing::debug!("{} has no {} checksum of {}", candidate, algorithm.as_str(), file_name),
+        }
+    }
+    None
+}
+
+// The checksum files that may be published next to `url`, their hash and whether they are for that file alone
+fn candidates(url: &Url) -> Vec<(Url, Algorithm, bool)> {
+    let mut candidates = Vec::new();
+    for &(suffix, algorithm) in SIDECAR_SUFFIXES {
+        let mut sidecar = url.clone();
+        sidecar.set_query(None);
+        sidecar.set_fragment(None);
+        sidecar.set_path(&format!("{}.{}", url.path(), suffix));
+        candidates.push((sidecar, algorithm, true));
+    }
+    for &(name, algorithm) in SUMS_FILES {
+        if let Ok(sums) = url.join(name) {
+            candidates.push((sums, algorithm, false));
+        }
+    }
+    candidates
+}
+
+/// Finds the checksum of `file_name` in the text of a checksum file.
+///
+/// Lines look like `<hex>  <name>` as written by `sha256sum`, or `SHA256 (<name>) = <hex>` in the
+/// BSD style; names are compared without their directory. A `sidecar` published for this file
+/// alone may also name it differently, or hold nothing but the hex digest, as long as it has only
+/// one entry. Anything else, like the armor of a signed file, is skipped.
+pub fn parse_sums(text: &str, algorithm: Algorithm, file_name: &str, sidecar: bool) -> Option<ExpectedDigest> {
+    let mut entries = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (hex, name) = match line.split_once(") = ") {
+            // BSD style: SHA256 (name) = hex
+            Some((head, hex)) => match head.split_once(" (") {
+                Some((_, name)) => (hex, name),
+                None => continue,
+            },
+            // GNU style: hex  name, or hex *name for binary mode
+            None => match line.split_once(char::is_whitespace) {
+                Some((hex, name)) => (hex, name.trim_start().trim_start_matches('*')),
+                None => (line, ""),
+            },
+        };
+        let Some(value) = decode_hex(hex).filter(|value| value.len() == algorithm.digest_len()) else {
+            continue;
+        };
+        let base_name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+        if base_name == file_name {
+            return Some(ExpectedDigest { algorithm, value });
+        }
+        entries.push(value);
+    }
+    match entries.pop() {
+        Some(value) if sidecar && entries.is_empty() => Some(ExpectedDigest { algorithm, value }),
+        _ => None,
+    }
+}
+
+// The running hash of one algorithm
+#[derive(Clone)]
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(bytes),
+            Hasher::Sha1(hasher) => hasher.update(bytes),
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Sha512(hasher) => hasher.update(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// The digests of a finished file
+pub struct Digests(Vec<(Algorithm, Hasher)>);
+
+impl Digests {
+    /// Whether the file has the expected digest.
+    pub fn matches(&self, expected: &ExpectedDigest) -> bool {
+        self.with_suffix(expected.algorithm, &[]).is_some_and(|value| value == expected.value)
+    }
+
+    /// Returns the `algorithm` digest of the file followed by `suffix`, if it was computed.
+    ///
+    /// Signatures hash data of their own after the signed file.
+    pub fn with_suffix(&self, algorithm: Algorithm, suffix: &[u8]) -> Option<Vec<u8>> {
+        let (_, hasher) = self.0.iter().find(|(known, _)| *known == algorithm)?;
+        let mut hasher = hasher.clone();
+        hasher.update(suffix);
+        Some(hasher.finalize())
+    }
+}
+
+/// Hashes a file while it is being downloaded
+///
+/// The supported hashes cannot be computed per range and combined later, so the tracker follows
+/// the part of the file that is complete from its first byte on. Bytes written right where that
+/// part ends are hashed as they pass through, which is all of them for in-order output. Bytes that
+/// ranges further on write ahead of it are read back while the download still runs, when they are
+/// most likely still cached, so finishing the digest only has to read what is left.
+///
+/// Clones share the same hashes. The default tracker hashes nothing.
+#[derive(Clone, Default)]
+pub struct DigestTracker {
+    state: Option<Arc<Mutex<TrackerState>>>,
+}
+
+struct TrackerState {
+    hashers: Vec<(Algorithm, Hasher)>,
+    // Bytes hashed from the start of the file on
+    hashed: u64,
+}
+
+impl DigestTracker {
+    /// Creates a tracker computing a digest with each of `algorithms`.
+    pub fn new(algorithms: impl IntoIterator<Item = Algorithm>) -> DigestTracker {
+        let mut hashers: Vec<(Algorithm, Hasher)> = Vec::new();
+        for algorithm in algorithms {
+            if !hashers.iter().any(|(known, _)| *known == algorithm) {
+                hashers.push((algorithm, Hasher::new(algorithm)));
+            }
+        }
+        if hashers.is_empty() {
+            return DigestTracker::default();
+        }
+        DigestTracker { state: Some(Arc::new(Mutex::new(TrackerState { hashers, hashed: 0 }))) }
+    }
+
+    /// Whether the tracker computes any digest.
+    pub fn is_active(&self) -> bool {
+        self.state.is_some()
+    }
+
+    // Lock the hashes, ignoring a task that panicked while holding them
+    fn lock(&self) -> Option<std::sync::MutexGuard<'_, TrackerState>> {
+        self.state.as_ref().map(|state| state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    /// Hashes `bytes` just written at `offset`, as far as they continue the hashed part of the file.
+    ///
+    /// Bytes further on are left to be read back from the file once the part before them is complete.
+    pub fn observe(&self, offset: u64, bytes: &[u8]) {
+        let Some(mut state) = self.lock() else {
+            return;
+        };
+        let end = offset + bytes.len() as u64;
+        if offset > state.hashed || end <= state.hashed {
+            return;
+        }
+        let new = &bytes[(state.hashed - offset) as usize..];
+        for (_, hasher) in &mut state.hashers {
+            hasher.update(new);
+        }
+        state.hashed = end;
+    }
+
+    // Bytes hashed from the start of the file on
+    fn hashed(&self) -> u64 {
+        self.lock().map_or(0, |state| state.hashed)
+    }
+
+    // Read the file at `path` from the end of the hashed part up to `end` and hash it
+    fn catch_up(&self, path: &Path, end: u64) -> io::Result<()> {
+        let mut offset = self.hashed();
+        if !self.is_active() || offset >= end {
+            return Ok(());
+        }
+        let mut file = std::fs::File::open(path)?;
+        let mut buffer = vec![0; READ_BLOCK_SIZE];
+        while offset < end {
+            // Bytes hashed as they were written in the meantime need not be read again
+            offset = offset.max(self.hashed());
+            if offset >= end {
+                break;
+            }
+            let length = buffer.len().min((end - offset) as usize);
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer[..length])?;
+            self.observe(offset, &buffer[..length]);
+            offset += length as u64;
+        }
+        Ok(())
+    }
+
+    /// Keeps hashing the bytes of the file at `path` that are complete up to `written_prefix()`.
+    ///
+    /// Runs until it is aborted, which is done once the download stops.
+    pub async fn follow(self, path: PathBuf, written_prefix: impl Fn() -> u64) {
+        loop {
+            tokio::time::sleep(FOLLOW_INTERVAL).await;
+            let end = written_prefix();
+            if end <= self.hashed() {
+                continue;
+            }
+            let (tracker, output) = (self.clone(), path.clone());
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || tracker.catch_up(&output, end)).await {
+                tracing::debug!("Could not hash {} while downloading: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Completes the digests of the file at `path`, which is `size` bytes long.
+    ///
+    /// Only the bytes that were not hashed during the download are read.
+    pub fn finish(&self, path: &Path, size: u64) -> io::Result<Digests> {
+        self.catch_up(path, size)?;
+        let Some(mut state) = self.lock() else {
+            return Ok(Digests(Vec::new()));
+        };
+        Ok(Digests(std::mem::take(&mut state.hashers)))
+    }
+}
+
+/// Passes writes on to a sequential writer and hashes what it accepted
+pub struct HashingWriter<W> {
+    inner: W,
+    digest: DigestTracker,
+    // Position of the next byte in the file
+    offset: u64,
+}
+
+impl<W> HashingWriter<W> {
+    /// Wraps `inner`, whose next byte lands at `offset` in the file.
+    pub fn new(inner: W, digest: DigestTracker, offset: u64) -> HashingWriter<W> {
+        HashingWriter { inner, digest, offset }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.digest.observe(this.offset, &buf[..written]);
+        this.offset += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Decode a string of hex digit pairs, `None` if it is not one
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok()).collect()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    #[test]
+    fn test_parse_checksum() {
+        let expected = parse_checksum("SHA256=2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824").unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Sha256);
+        assert_eq!(expected.value[..2], [0x2c, 0xf2]);
+        assert!(parse_checksum("md5=5d41402abc4b2a76b9719d911017c592").is_ok());
+        assert!(parse_checksum("sha256=2cf24d").is_err(), "Digests of the wrong length must be rejected");
+        assert!(parse_checksum("sha256=zz").is_err());
+        assert!(parse_checksum("crc32=3610a686").is_err());
+        assert!(parse_checksum("2cf24dba").is_err());
+    }
+
+    #[test]
+    fn test_parse_sums() {
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let other = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+        let sums = format!("# release files\n{}  world.iso\n{} *./images/hello.iso\n", other, hello);
+        let expected = parse_sums(&sums, Algorithm::Sha256, "hello.iso", false).unwrap();
+        assert_eq!(expected, parse_checksum(&format!("sha256={}", hello)).unwrap());
+        assert_eq!(parse_sums(&sums, Algorithm::Sha256, "missing.iso", false), None);
+
+        let bsd = format!("SHA256 (world.iso) = {}\nSHA256 (hello.iso) = {}\n", other, hello);
+        assert_eq!(parse_sums(&bsd, Algorithm::Sha256, "hello.iso", false), Some(expected.clone()));
+
+        // A file published for one file alone may only hold the digest, or name it differently
+        assert_eq!(parse_sums(&format!("{}\n", hello), Algorithm::Sha256, "hello.iso", true), Some(expected.clone()));
+        assert_eq!(parse_sums(&format!("{}  hello-1.0.iso\n", hello), Algorithm::Sha256, "hello.iso", true), Some(expected));
+        assert_eq!(parse_sums(&sums, Algorithm::Sha256, "missing.iso", true), None, "two entries, neither for the file");
+        assert_eq!(parse_sums(hello, Algorithm::Sha512, "hello.iso", true), None, "digests of another length do not count");
+        assert_eq!(parse_sums("<html>Not Found</html>", Algorithm::Sha256, "hello.iso", true), None);
+    }
+
+    #[test]
+    fn test_checksum_candidates() {
+        let url = Url::parse("https://example.org/releases/hello.iso?mirror=1").unwrap();
+        let candidates: Vec<String> = candidates(&url).into_iter().map(|(url, _, _)| url.to_string()).collect();
+        assert_eq!(candidates[0], "https://example.org/releases/hello.iso.sha512");
+        assert_eq!(candidates[1], "https://example.org/releases/hello.iso.sha256");
+        assert!(candidates.contains(&"https://example.org/releases/SHA256SUMS".to_string()));
+    }
+
+    #[test]
+    fn test_verify_every_algorithm() {
+        let path = test_server::temp_dir("checksum").join("hello");
+        std::fs::write(&path, b"hello").unwrap();
+
+        for checksum in [
+            "md5=5d41402abc4b2a76b9719d911017c592",
+            "sha1=aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d",
+            "sha256=2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            "sha512=9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043",
+            "blake3=ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f",
+        ] {
+            assert!(verify(&path, &parse_checksum(checksum).unwrap()), "{}", checksum);
+        }
+
+        std::fs::write(&path, b"hellO").unwrap();
+        assert!(!verify(&path, &parse_checksum("blake3=ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f").unwrap()));
+    }
+
+    // Hash a whole file that was written without a tracker
+    fn verify(path: &Path, expected: &ExpectedDigest) -> bool {
+        let size = std::fs::metadata(path).unwrap().len();
+        DigestTracker::new([expected.algorithm]).finish(path, size).unwrap().matches(expected)
+    }
+
+    #[test]
+    fn test_tracker_reads_back_bytes_written_ahead() {
+        let path = test_server::temp_dir("checksum-tracker").join("out");
+        let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let expected = ExpectedDigest { algorithm: Algorithm::Sha256, value: Sha256::digest(&content).to_vec() };
+
+        // The second half arrives first and can only be hashed once the first half is complete
+        let tracker = DigestTracker::new([Algorithm::Sha256, Algorithm::Sha256]);
+        tracker.observe(500_000, &content[500_000..600_000]);
+        assert_eq!(tracker.hashed(), 0);
+        tracker.observe(0, &content[..300_000]);
+        tracker.observe(200_000, &content[200_000..400_000]);
+        assert_eq!(tracker.hashed(), 400_000, "overlapping writes are hashed once");
+        tracker.catch_up(&path, 600_000).unwrap();
+        assert_eq!(tracker.hashed(), 600_000);
+        tracker.observe(600_000, &content[600_000..700_000]);
+        assert!(tracker.finish(&path, content.len() as u64).unwrap().matches(&expected));
+
+        // Without a digest to compute, nothing is read
+        let idle = DigestTracker::new([]);
+        assert!(!idle.is_active());
+        assert!(!idle.finish(Path::new("/nonexistent"), 10).unwrap().matches(&expected));
+    }
+}
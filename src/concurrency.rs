@@ -1,13 +1,59 @@
-use tokio::task;
-use std::sync::Arc;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::task::{ready, Context, Poll};
+use indicatif::ProgressBar;
+use tokio::io::{AsyncWrite, DuplexStream};
+use tokio::task::JoinSet;
+use tracing::Instrument;
+use crate::checksum::DigestTracker;
 use crate::downloader::{Downloader, FileDownloader};
+use crate::error::AppError;
+use crate::filesystem::RangeOutput;
+use crate::replay::{self, EventKind};
+
+// How often --connections auto measures the throughput
+const TUNE_INTERVAL: Duration = Duration::from_secs(2);
+
+// Factor by which another connection has to raise the throughput to be kept
+const TUNE_MIN_GAIN: f64 = 1.1;
+
+// Longest backoff between two attempts of a range, unless the first wait is longer
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+// Failed ranges in a row after which a source is tried after all the others
+const MAX_SOURCE_FAILURES: u32 = 3;
+
+/// Where a download task writes the bytes of its range
+pub enum ChunkSink {
+    /// The preallocated output file, written in place from the start of the range
+    Output(RangeOutput),
+    /// A bounded in-memory pipe drained in order by the output writer
+    Pipe(DuplexStream),
+}
 
 /// Download the task struct
-#[derive(Clone)]
 pub struct DownloadTask {
     url: String,
     start: usize,
     end: usize,
+    sink: ChunkSink,
+    progress: ProgressBar,
+    // The connection of this task, sharing the client of the download
+    downloader: FileDownloader,
+    // Where the range can be downloaded from, and which of them the task tries first
+    sources: SourcePool,
+    preferred: usize,
+    // Hands out the ranges of a task writing the output, instead of its fixed `start` and `end`
+    scheduler: Option<SegmentScheduler>,
+    // Set to stop a scheduled task from taking another range once its current one is done
+    retired: Arc<AtomicBool>,
+    // How often a failed range is tried again
+    retries: RetryPolicy,
 }
 
 /// Download a file concurrently
@@ -17,23 +63,482 @@ pub struct DownloadTask {
 /// * `url` - The URL of the file to download
 /// * `start` - The start byte of the file to download
 /// * `end` - The end byte of the file to download
+/// * `sink` - Where the downloaded bytes are written to
+/// * `progress` - The progress bar advanced as bytes arrive
+/// * `downloader` - The downloader whose client and connection pool the task shares
 /// 
 impl DownloadTask {
     // Creates a new download task.
-    pub fn new(url: String, start: usize, end: usize) -> Self {
-        DownloadTask { url, start, end }
+    pub fn new(url: String, start: usize, end: usize, sink: ChunkSink, progress: ProgressBar, downloader: &FileDownloader) -> Self {
+        let sources = SourcePool::new(vec![url.clone()]);
+        DownloadTask { url, start, end, sink, progress, downloader: downloader.connection(), sources, preferred: 0, scheduler: None, retired: Arc::default(), retries: RetryPolicy::default() }
+    }
+
+    // Creates a task that keeps taking ranges from `scheduler` and writes them into `output`, until none is left.
+    pub fn scheduled(url: String, output: RangeOutput, scheduler: SegmentScheduler, progress: ProgressBar, downloader: &FileDownloader) -> Self {
+        DownloadTask { scheduler: Some(scheduler), ..DownloadTask::new(url, 0, 0, ChunkSink::Output(output), progress, downloader) }
+    }
+
+    // Download from the source `preferred` of `sources`, and take a range that fails there up on the other ones, e.g. when a mirror is down
+    // Only ranges written to the output can move; a pipe may already have passed bytes on
+    pub fn with_sources(mut self, sources: SourcePool, preferred: usize) -> Self {
+        self.url = sources.url(preferred);
+        self.sources = sources;
+        self.preferred = preferred;
+        self
+    }
+
+    // Try a range that failed again as `retries` allows, picking up after the bytes already written
+    pub fn with_retries(mut self, retries: RetryPolicy) -> Self {
+        self.retries = retries;
+        self
     }
 
     // Execute the download task
-    async fn execute(url: String, start: usize, end: usize) -> Result<(), Box<dyn std::error::Error>> {
-        let downloader = FileDownloader::new();
-        match downloader.download_chunk(&url, start, end).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Box::new(e)),
+    async fn execute(self) -> Result<(), AppError> {
+        if let (Some(scheduler), ChunkSink::Output(output)) = (&self.scheduler, &self.sink) {
+            return self.download_scheduled(scheduler, output).await;
+        }
+        let (start, end) = (self.start, self.end);
+        replay::record(EventKind::ChunkStart, format!("bytes {}-{}", start, end));
+        let result = self.download().await;
+        match &result {
+            Ok(()) => replay::record(EventKind::ChunkDone, format!("bytes {}-{}", start, end)),
+            Err(e) => replay::record(EventKind::ChunkFailed, format!("bytes {}-{}: {}", start, end, e)),
+        }
+        result
+    }
+
+    // Download the range into the sink, trying what is left of it again after a transient failure
+    async fn download(mut self) -> Result<(), AppError> {
+        let initial = self.progress.position();
+        let mut attempt = 1;
+        loop {
+            let start = resume_point(self.start, &self.progress, initial);
+            let span = range_span(start as u64, self.end as u64, attempt);
+            let result = self.download_rest(initial).instrument(span.clone()).await;
+            let start = resume_point(self.start, &self.progress, initial);
+            match result {
+                Err(e) if start <= self.end && self.retries.allows(attempt, &e) => {
+                    self.retries.wait(attempt, start as u64, self.end as u64, &e).instrument(span).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Download the rest of the range into the sink, from the other sources if the one of the task fails
+    async fn download_rest(&mut self, initial: u64) -> Result<(), AppError> {
+        match &mut self.sink {
+            ChunkSink::Output(output) => {
+                let mut result = Ok(());
+                let mut failed: Option<String> = None;
+                for (source, url) in self.sources.order(self.preferred) {
+                    // Every source picks up after the bytes the failed attempts wrote
+                    let start = resume_point(self.start, &self.progress, initial);
+                    if let (Err(e), Some(failed)) = (&result, &failed) {
+                        tracing::warn!("bytes {}-{}: {} failed ({}), retrying from {}", start, self.end, failed, e, url);
+                        replay::record(EventKind::Fallback, format!("bytes {}-{}: retrying from {}", start, self.end, url));
+                    }
+                    let mut writer = output.writer(start as u64).await?;
+                    result = self.downloader.download_chunk(&url, start, self.end, &mut writer, &self.progress).await;
+                    self.sources.report(source, result.is_ok());
+                    if result.is_ok() {
+                        break;
+                    }
+                    failed = Some(url);
+                }
+                result
+            }
+            // Dropping the pipe at the end signals end of range to the reader
+            ChunkSink::Pipe(pipe) => {
+                let start = resume_point(self.start, &self.progress, initial);
+                self.downloader.download_chunk(&self.url, start, self.end, pipe, &self.progress).await
+            }
         }
     }
 }
 
+// The span of attempt number `attempt` at bytes `start` to `end`, whose fields the messages logged meanwhile carry
+// Spans are at error level, so messages carry their fields at every verbosity
+fn range_span(start: u64, end: u64, attempt: u32) -> tracing::Span {
+    tracing::error_span!("range", bytes = %format!("{}-{}", start, end), attempt)
+}
+
+// First byte of the range from `start` that is not in its sink yet
+// The progress bar counts bytes once they reached the sink, from `initial` on
+fn resume_point(start: usize, progress: &ProgressBar, initial: u64) -> usize {
+    start + progress.position().saturating_sub(initial) as usize
+}
+
+impl DownloadTask {
+    // Download ranges from the scheduler into the output until every range is taken
+    // A range whose end was handed to another task stops early, which is not a failure
+    async fn download_scheduled(&self, scheduler: &SegmentScheduler, output: &RangeOutput) -> Result<(), AppError> {
+        let mut claimed = scheduler.claim(&self.progress);
+        while let Some(index) = claimed {
+            let mut attempt = 1;
+            let span = |attempt| scheduler.remaining(index).map_or_else(tracing::Span::none, |(start, end)| range_span(start, end, attempt));
+            let mut result = self.download_span(scheduler, output, index).instrument(span(attempt)).await;
+            while let Err(e) = &result {
+                let Some((start, end)) = scheduler.remaining(index).filter(|_| self.retries.allows(attempt, e)) else {
+                    break;
+                };
+                self.retries.wait(attempt, start, end, e).instrument(range_span(start, end, attempt)).await;
+                attempt += 1;
+                result = self.download_span(scheduler, output, index).instrument(span(attempt)).await;
+            }
+            if let Err(e) = result {
+                scheduler.release(index);
+                return Err(e);
+            }
+            if self.retired.load(Ordering::Relaxed) {
+                tracing::info!("Retiring a connection that did not make the download faster");
+                break;
+            }
+            claimed = scheduler.claim(&self.progress);
+        }
+        Ok(())
+    }
+
+    // Download what is left of a claimed range, moving it to the other sources if the one of the task fails
+    async fn download_span(&self, scheduler: &SegmentScheduler, output: &RangeOutput, index: usize) -> Result<(), AppError> {
+        let mut result = Ok(());
+        let mut failed: Option<String> = None;
+        for (source, url) in self.sources.order(self.preferred) {
+            let Some((start, end)) = scheduler.remaining(index) else {
+                break;
+            };
+            if let (Err(e), Some(failed)) = (&result, &failed) {
+                tracing::warn!("bytes {}-{}: {} failed ({}), retrying from {}", start, end, failed, e, url);
+                replay::record(EventKind::Fallback, format!("bytes {}-{}: retrying from {}", start, end, url));
+            }
+            replay::record(EventKind::ChunkStart, format!("bytes {}-{}", start, end));
+            let mut writer = SpanWriter { inner: output.writer(start).await?, scheduler: scheduler.clone(), index };
+            result = self.downloader.download_chunk(&url, start as usize, end as usize, &mut writer, &self.progress).await;
+            if scheduler.remaining(index).is_none() {
+                self.sources.report(source, true);
+                replay::record(EventKind::ChunkDone, format!("bytes {}-{}", start, scheduler.end(index)));
+                return Ok(());
+            }
+            if result.is_ok() {
+                result = Err(AppError::StringError(format!("the server sent less than the requested bytes {}-{}", start, end)));
+            }
+            if let Err(e) = &result {
+                self.sources.report(source, false);
+                replay::record(EventKind::ChunkFailed, format!("bytes {}-{}: {}", start, end, e));
+            }
+            failed = Some(url);
+        }
+        result
+    }
+}
+
+/// The URLs the same file is downloaded from, and how well each one did
+///
+/// Every connection starts out on a source of its own, round robin. A range that fails on one
+/// source is taken up right away by the next, the ones that failed the fewest ranges in a row first,
+/// so a mirror going down in the middle of a range does not hold up the download. Clones share
+/// the same sources.
+#[derive(Clone)]
+pub struct SourcePool {
+    sources: Arc<Mutex<Vec<Source>>>,
+}
+
+struct Source {
+    url: String,
+    // Ranges that failed in a row
+    failures: u32,
+}
+
+impl SourcePool {
+    /// Creates a pool of `urls`, which must not be empty; the first one is the origin.
+    pub fn new(urls: Vec<String>) -> SourcePool {
+        let sources = urls.into_iter().map(|url| Source { url, failures: 0 }).collect();
+        SourcePool { sources: Arc::new(Mutex::new(sources)) }
+    }
+
+    /// Number of sources.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    // Lock the sources, ignoring a task that panicked while holding them
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Source>> {
+        self.sources.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The URL of source `index`
+    fn url(&self, index: usize) -> String {
+        self.lock()[index].url.clone()
+    }
+
+    // The sources to try a range from in turn: `preferred` first, then the others with the fewest failures in a row
+    // Sources that failed too often in a row, the preferred one included, come last
+    fn order(&self, preferred: usize) -> Vec<(usize, String)> {
+        let sources = self.lock();
+        let mut order: Vec<usize> = (0..sources.len()).collect();
+        order.sort_by_key(|&index| (sources[index].failures >= MAX_SOURCE_FAILURES, index != preferred, sources[index].failures, index));
+        order.into_iter().map(|index| (index, sources[index].url.clone())).collect()
+    }
+
+    // Count a range that was downloaded from source `index`, or that failed there
+    fn report(&self, index: usize, succeeded: bool) {
+        let mut sources = self.lock();
+        let alone = sources.len() == 1;
+        let source = &mut sources[index];
+        source.failures = if succeeded { 0 } else { source.failures + 1 };
+        if source.failures == MAX_SOURCE_FAILURES && !alone {
+            tracing::warn!("{} failed {} ranges in a row, trying it last from now on", source.url, MAX_SOURCE_FAILURES);
+        }
+    }
+}
+
+/// How often a failed range is tried again, and how long to wait in between
+///
+/// Every attempt picks up after the bytes the failed ones wrote. The wait doubles after every failed
+/// attempt, up to a minute, and a random part of up to half of it is left out, so connections that
+/// failed together do not all come back at once. Errors that another attempt cannot fix, like a
+/// missing file or a server that ignores ranges, are not retried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    tries: u32,
+    wait: Duration,
+}
+
+impl RetryPolicy {
+    /// Tries a range up to `tries` times in all, waiting `wait` before the first retry.
+    pub fn new(tries: u32, wait: Duration) -> Self {
+        RetryPolicy { tries: tries.max(1), wait }
+    }
+
+    // Whether a range whose attempt number `attempt` failed with `error` is tried again
+    fn allows(&self, attempt: u32, error: &AppError) -> bool {
+        attempt < self.tries && error.is_transient()
+    }
+
+    // How long to wait after attempt number `attempt` failed
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.wait.saturating_mul(1 << (attempt - 1).min(16)).min(MAX_RETRY_WAIT.max(self.wait));
+        let jitter = RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64;
+        backoff.mul_f64(1.0 - jitter / 2.0)
+    }
+
+    // Report the failed attempt of bytes `start` to `end` and wait before the next one
+    async fn wait(&self, attempt: u32, start: u64, end: u64, error: &AppError) {
+        let backoff = self.backoff(attempt);
+        tracing::warn!("bytes {}-{}: attempt {} of {} failed ({}), retrying in {:.1}s", start, end, attempt, self.tries, error, backoff.as_secs_f64());
+        replay::record(EventKind::Retry, format!("bytes {}-{}: attempt {} of {} in {} ms", start, end, attempt + 1, self.tries, backoff.as_millis()));
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+impl Default for RetryPolicy {
+    // A single attempt
+    fn default() -> Self {
+        RetryPolicy::new(1, Duration::ZERO)
+    }
+}
+
+/// The byte ranges of a download written into the output, shared by its tasks
+///
+/// Every task claims a range nobody works on yet. Once there is none left, a task takes over the
+/// second half of the remaining bytes of the range that has the most left, aria2-style, so a fast
+/// connection helps out a slow one instead of sitting idle. Ranges are never split into pieces
+/// smaller than the minimum split size.
+#[derive(Clone)]
+pub struct SegmentScheduler {
+    spans: Arc<Mutex<Vec<Span>>>,
+    min_split: u64,
+    // Hashes the bytes as they are written
+    digest: DigestTracker,
+}
+
+// One byte range of the output and how far it got
+struct Span {
+    start: u64,
+    // Last byte, inclusive; moves down when another task takes over the rest
+    end: u64,
+    // Bytes written from `start` on
+    written: u64,
+    // Bytes after the written ones a write is under way for, which are not taken over
+    reserved: u64,
+    // The bar of the task working on the range, `None` while nobody does
+    owner: Option<ProgressBar>,
+}
+
+impl Span {
+    // Bytes of the range not written yet
+    fn left(&self) -> u64 {
+        (self.end + 1 - self.start).saturating_sub(self.written)
+    }
+
+    // Bytes of the range another task may take over
+    fn unreserved(&self) -> u64 {
+        self.left().saturating_sub(self.reserved)
+    }
+}
+
+impl SegmentScheduler {
+    /// Creates a scheduler for `ranges` of `(start, end, written)`, with `written` bytes already in the output.
+    ///
+    /// A range is only split when both halves get at least `min_split` bytes.
+    pub fn new(ranges: &[(u64, u64, u64)], min_split: u64) -> SegmentScheduler {
+        let spans = ranges.iter().map(|&(start, end, written)| Span { start, end, written, reserved: 0, owner: None }).collect();
+        SegmentScheduler { spans: Arc::new(Mutex::new(spans)), min_split: min_split.max(1), digest: DigestTracker::default() }
+    }
+
+    /// Passes the bytes written into the ranges on to `digest`.
+    pub fn with_digest(mut self, digest: DigestTracker) -> SegmentScheduler {
+        self.digest = digest;
+        self
+    }
+
+    /// Returns the ranges as `(start, end, written)`, in the order they were created.
+    pub fn ranges(&self) -> Vec<(u64, u64, u64)> {
+        self.lock().iter().map(|span| (span.start, span.end, span.written.min(span.end + 1 - span.start))).collect()
+    }
+
+    /// Bytes written into all ranges together.
+    pub fn written(&self) -> u64 {
+        self.ranges().iter().map(|&(_, _, written)| written).sum()
+    }
+
+    /// Bytes written from the start of the file on without a gap.
+    pub fn written_prefix(&self) -> u64 {
+        let mut ranges = self.ranges();
+        ranges.sort_unstable_by_key(|&(start, _, _)| start);
+        let mut prefix = 0;
+        for (start, end, written) in ranges {
+            if start != prefix {
+                break;
+            }
+            prefix = start + written;
+            if prefix <= end {
+                break;
+            }
+        }
+        prefix
+    }
+
+    /// Number of ranges that still have bytes left.
+    pub fn unfinished(&self) -> usize {
+        self.lock().iter().filter(|span| span.left() > 0).count()
+    }
+
+    // Lock the ranges, ignoring a task that panicked while holding them
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Span>> {
+        self.spans.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Take a range for the task advancing `bar`: an unclaimed one, or else the second half of the busiest one
+    // The bars of both tasks are resized so they keep showing the bytes each one is responsible for
+    fn claim(&self, bar: &ProgressBar) -> Option<usize> {
+        let mut spans = self.lock();
+        if let Some(index) = spans.iter().position(|span| span.owner.is_none() && span.left() > 0) {
+            let span = &mut spans[index];
+            bar.inc_length(span.end + 1 - span.start);
+            bar.inc(span.written);
+            span.owner = Some(bar.clone());
+            span.reserved = 0;
+            return Some(index);
+        }
+
+        let (victim, left) = spans
+            .iter()
+            .enumerate()
+            .filter(|(_, span)| span.owner.is_some())
+            .map(|(index, span)| (index, span.unreserved()))
+            .max_by_key(|&(_, left)| left)?;
+        let stolen = left / 2;
+        if stolen < self.min_split || left - stolen < self.min_split {
+            return None;
+        }
+        let span = &mut spans[victim];
+        let split = span.end + 1 - stolen;
+        let end = std::mem::replace(&mut span.end, split - 1);
+        if let Some(owner) = &span.owner {
+            owner.set_length(owner.length().unwrap_or_default().saturating_sub(stolen));
+        }
+        tracing::info!("bytes {}-{}: taking over from a slower connection", split, end);
+        replay::record(EventKind::Plan, format!("split bytes {}-{} off a slower range", split, end));
+        bar.inc_length(stolen);
+        spans.push(Span { start: split, end, written: 0, reserved: 0, owner: Some(bar.clone()) });
+        Some(spans.len() - 1)
+    }
+
+    // Hand a range back after its task failed, so it is not split any more
+    fn release(&self, index: usize) {
+        let mut spans = self.lock();
+        spans[index].owner = None;
+        spans[index].reserved = 0;
+    }
+
+    // The first and last byte of the range that are still missing, `None` once it is complete
+    fn remaining(&self, index: usize) -> Option<(u64, u64)> {
+        let spans = self.lock();
+        let span = &spans[index];
+        (span.left() > 0).then_some((span.start + span.written, span.end))
+    }
+
+    // The last byte of the range
+    fn end(&self, index: usize) -> u64 {
+        self.lock()[index].end
+    }
+
+    // Reserve the next bytes of the range for a write of `length` bytes, and return how many fit before its end
+    // The end moves where another task took over, but never into the bytes reserved
+    fn reserve(&self, index: usize, length: usize) -> usize {
+        let mut spans = self.lock();
+        let span = &mut spans[index];
+        span.reserved = span.left().min(length as u64);
+        span.reserved as usize
+    }
+
+    // Count the bytes just written into the range and hash them
+    fn advance(&self, index: usize, written: &[u8]) {
+        let offset = {
+            let mut spans = self.lock();
+            let span = &mut spans[index];
+            span.written += written.len() as u64;
+            span.reserved = 0;
+            span.start + span.written - written.len() as u64
+        };
+        self.digest.observe(offset, written);
+    }
+}
+
+// Writes one range of the scheduler and counts what reached the output
+// Stops with an error at the end of the range, which moves when another task takes over the rest
+// Each write reserves its bytes first, so the range never overlaps the one taken over
+struct SpanWriter {
+    inner: Box<dyn AsyncWrite + Unpin + Send>,
+    scheduler: SegmentScheduler,
+    index: usize,
+}
+
+impl AsyncWrite for SpanWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowance = this.scheduler.reserve(this.index, buf.len());
+        if allowance == 0 && !buf.is_empty() {
+            return Poll::Ready(Err(io::Error::other("the rest of the range was taken over")));
+        }
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowance]))?;
+        this.scheduler.advance(this.index, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
 /// Download multiple download tasks concurrently
 ///
 /// # Arguments
@@ -42,6 +547,7 @@ impl DownloadTask {
 ///
 pub struct ConcurrentDownloader {
     tasks: Vec<DownloadTask>,
+    tuner: Option<ConnectionTuner>,
 }
 
 /// Execute all download tasks concurrently
@@ -53,30 +559,211 @@ pub struct ConcurrentDownloader {
 impl ConcurrentDownloader {
     /// Creates a new `ConcurrentDownloader` with specified tasks.
     pub fn new(tasks: Vec<DownloadTask>) -> Self {
-        ConcurrentDownloader { tasks }
+        ConcurrentDownloader { tasks, tuner: None }
     }
 
-    /// Execute all download tasks concurrently.
-    pub async fn execute_all(&self) {
-        let mut handles = vec![];
+    /// Lets `tuner` add connections while the tasks run.
+    pub fn with_tuner(mut self, tuner: ConnectionTuner) -> Self {
+        self.tuner = Some(tuner);
+        self
+    }
 
-        for task in &self.tasks {
-            let task = Arc::new(task.clone()); // Wrap the task in Arc
-            let url = task.url.clone();
-            let start = task.start;
-            let end = task.end;
+    /// Execute all download tasks concurrently.
+    ///
+    /// Every task runs to its end, even after another one failed; the outcome holds the first error.
+    pub async fn execute_all(self) -> DownloadOutcome {
+        self.execute_until(std::future::pending()).await
+    }
 
+    /// Execute all download tasks concurrently until they finish or `cancel` resolves.
+    ///
+    /// Cancelled tasks are stopped before this returns, so the bytes reported per range are on disk.
+    pub async fn execute_until(self, cancel: impl Future<Output = ()>) -> DownloadOutcome {
+        let scheduler = self.tasks.iter().find_map(|task| task.scheduler.clone());
+        let mut tuner = self.tuner;
+        let mut ranges = Vec::new();
+        let mut bars = Vec::new();
+        let mut running = JoinSet::new();
+        // Every connection logs in a span of its own, numbered from 1 in the order they started
+        let mut parts = 0;
+        for task in self.tasks {
+            ranges.push(RangeOutcome { start: task.start, end: task.end, completed: 0 });
+            bars.push((task.progress.clone(), task.progress.position()));
             // Spawn an asynchronous task for each download task
-            let handle = task::spawn(async move {
-                DownloadTask::execute(url, start, end).await.unwrap();
-            });
+            parts += 1;
+            running.spawn(task.execute().instrument(tracing::error_span!("part", index = parts)));
+        }
+
+        tokio::pin!(cancel);
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + TUNE_INTERVAL, TUNE_INTERVAL);
+        let mut failure = None;
+        let termination = loop {
+            tokio::select! {
+                finished = running.join_next() => match finished {
+                    Some(result) => {
+                        let result = result.map_err(|e| AppError::StringError(e.to_string())).and_then(|r| r);
+                        if let (Err(error), None) = (result, &failure) {
+                            failure = Some(error);
+                        }
+                    }
+                    None => break failure.map_or(Termination::Completed, Termination::Failed),
+                },
+                _ = &mut cancel => {
+                    running.shutdown().await;
+                    break Termination::Cancelled;
+                }
+                _ = ticks.tick(), if tuner.is_some() && failure.is_none() => {
+                    if let Some(task) = tuner.as_mut().and_then(ConnectionTuner::tune) {
+                        parts += 1;
+                        running.spawn(task.execute().instrument(tracing::error_span!("part", index = parts)));
+                    }
+                }
+            }
+        };
 
-            handles.push(handle);
+        // Scheduled tasks share their ranges, which the scheduler keeps track of
+        if let Some(scheduler) = scheduler {
+            let ranges = scheduler
+                .ranges()
+                .into_iter()
+                .map(|(start, end, written)| RangeOutcome { start: start as usize, end: end as usize, completed: written })
+                .collect();
+            return DownloadOutcome { ranges, termination };
         }
+        // A range restarted from a fallback may end up behind where it began
+        for (range, (bar, initial)) in ranges.iter_mut().zip(bars) {
+            range.completed = bar.position().saturating_sub(initial);
+        }
+        DownloadOutcome { ranges, termination }
+    }
+}
+
+/// Adds connections to a scheduled download while each one makes it faster, for `--connections auto`
+///
+/// Every few seconds the tuner measures the bytes written by all connections. As long as the last
+/// connection it added raised the throughput by at least a tenth, it adds another one. Once one does
+/// not, that connection is retired after its current range and the number of connections settles.
+pub struct ConnectionTuner {
+    scheduler: SegmentScheduler,
+    // Creates the task of one more connection, given its index
+    connect: Box<dyn FnMut(usize) -> DownloadTask + Send>,
+    connections: usize,
+    max: usize,
+    // Bytes written at the last measurement
+    written: u64,
+    // Throughput before the last connection was added, in bytes per second
+    rate: Option<f64>,
+    // Stops the last connection added if it does not pay off
+    trial: Option<Arc<AtomicBool>>,
+    settled: bool,
+}
+
+impl ConnectionTuner {
+    /// Creates a tuner for a download that started `connections` connections and may use up to `max`.
+    ///
+    /// `connect` creates the task of another connection, given its index.
+    pub fn new(scheduler: SegmentScheduler, connections: usize, max: usize, connect: impl FnMut(usize) -> DownloadTask + Send + 'static) -> Self {
+        let written = scheduler.written();
+        ConnectionTuner { scheduler, connect: Box::new(connect), connections, max, written, rate: None, trial: None, settled: false }
+    }
+
+    // Measure the throughput since the last tick and return the task of another connection, if one should be added
+    fn tune(&mut self) -> Option<DownloadTask> {
+        if self.settled {
+            return None;
+        }
+        let written = self.scheduler.written();
+        let rate = written.saturating_sub(self.written) as f64 / TUNE_INTERVAL.as_secs_f64();
+        self.written = written;
+        tracing::info!("{} connections: {:.0} bytes/s", self.connections, rate);
+
+        if let Some(previous) = self.rate {
+            if rate < previous * TUNE_MIN_GAIN {
+                if let Some(trial) = self.trial.take() {
+                    trial.store(true, Ordering::Relaxed);
+                    self.connections -= 1;
+                }
+                return self.settle();
+            }
+        }
+        if self.connections >= self.max || self.scheduler.unfinished() == 0 {
+            return self.settle();
+        }
+        self.rate = Some(rate);
+        let task = (self.connect)(self.connections);
+        self.connections += 1;
+        self.trial = Some(task.retired.clone());
+        tracing::info!("Trying {} connections", self.connections);
+        Some(task)
+    }
+
+    // Stop adding connections
+    fn settle(&mut self) -> Option<DownloadTask> {
+        self.settled = true;
+        tracing::info!("Settled on {} connections", self.connections);
+        None
+    }
+}
+
+/// Why a set of download tasks stopped
+#[derive(Debug)]
+pub enum Termination {
+    /// Every range was downloaded
+    Completed,
+    /// The caller cancelled the download
+    Cancelled,
+    /// A range failed with this error, the first one reported
+    Failed(AppError),
+}
 
-        // Await all spawned tasks to complete
-        for handle in handles {
-            handle.await.unwrap();
+/// How far the task of one byte range got
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeOutcome {
+    /// The first byte of the range
+    pub start: usize,
+    /// The last byte of the range
+    pub end: usize,
+    /// The bytes of the range in the output, counted from `start`
+    pub completed: u64,
+}
+
+impl RangeOutcome {
+    /// The bytes of the range that are still missing, as a new `(start, end)` range.
+    pub fn remaining(&self) -> Option<(usize, usize)> {
+        let start = self.start + self.completed as usize;
+        (start <= self.end).then_some((start, self.end))
+    }
+}
+
+/// What a set of download tasks achieved, whether or not they finished
+///
+/// A cancelled or failed download keeps the progress of every range, so the missing bytes can be
+/// fetched later with new tasks instead of starting over.
+#[derive(Debug)]
+pub struct DownloadOutcome {
+    /// The ranges in the order of their tasks
+    pub ranges: Vec<RangeOutcome>,
+    /// Why the tasks stopped
+    pub termination: Termination,
+}
+
+impl DownloadOutcome {
+    /// The bytes downloaded by all ranges together.
+    pub fn bytes_completed(&self) -> u64 {
+        self.ranges.iter().map(|range| range.completed).sum()
+    }
+
+    /// The byte ranges still to download, ready to be handed to new tasks.
+    pub fn remaining(&self) -> Vec<(usize, usize)> {
+        self.ranges.iter().filter_map(RangeOutcome::remaining).collect()
+    }
+
+    /// Turns the outcome into a plain result, for callers that have no use for partial progress.
+    pub fn into_result(self) -> Result<(), AppError> {
+        match self.termination {
+            Termination::Completed => Ok(()),
+            Termination::Cancelled => Err(AppError::Interrupted),
+            Termination::Failed(error) => Err(error),
         }
     }
 }
@@ -85,46 +772,282 @@ impl ConcurrentDownloader {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_server;
+    use crate::checksum::{Algorithm, ExpectedDigest};
+    use crate::downloader::ClientOptions;
+    use sha2::Digest;
     use tokio::runtime::Runtime;
 
-    // Mock version of DownloadTask for testing
-    struct MockDownloadTask {
-        url: String,
-        start: usize,
-        end: usize,
-    }
-
-    impl MockDownloadTask {
-        fn new(url: String, start: usize, end: usize) -> Self {
-            MockDownloadTask { url, start, end }
-        }
-
-        async fn execute(&self) {
-            // Simulate a download task (e.g., a simple async delay)
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        }
+    // Downloader with the default settings
+    fn downloader() -> FileDownloader {
+        FileDownloader::with_options(&ClientOptions::default()).unwrap()
     }
 
     #[test]
     fn test_execute_all_tasks() {
         let runtime = Runtime::new().unwrap(); // Create a Tokio runtime for the async test
+        let body: Vec<u8> = (0..=255).collect();
+        let url = test_server::serve(body.clone());
+        let dir = test_server::temp_dir("execute_all");
+        let output = dir.join("out");
+        std::fs::write(&output, [0u8; 256]).unwrap();
 
         runtime.block_on(async {
-            let tasks = vec![
-                DownloadTask::new("https://example.com".to_string(), 0, 65536),
-                DownloadTask::new("https://example.com".to_string(), 0, 65536),
-                DownloadTask::new("https://example.com".to_string(), 0, 65536),
-                DownloadTask::new("https://example.com".to_string(), 0, 65536),
-                DownloadTask::new("https://example.com".to_string(), 0, 65536),
-                DownloadTask::new("https://example.com".to_string(), 0, 65536),
-            ];
+            let tasks: Vec<_> = (0..4)
+                .map(|i| DownloadTask::new(url.clone(), i * 64, i * 64 + 63, ChunkSink::Output(RangeOutput::File(output.clone())), ProgressBar::hidden(), &downloader()))
+                .collect();
 
             let downloader = ConcurrentDownloader::new(tasks);
-            downloader.execute_all().await; // This runs the tasks
+            downloader.execute_all().await.into_result().unwrap(); // This runs the tasks
+        });
+
+        // Every range landed at its own offset
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+    }
+
+    #[test]
+    fn test_fallback_url() {
+        let runtime = Runtime::new().unwrap();
+        let body: Vec<u8> = (0..=255).collect();
+        let url = test_server::serve(body.clone());
+        let dir = test_server::temp_dir("fallback");
+        let output = dir.join("out");
+        std::fs::write(&output, [0u8; 32]).unwrap();
+
+        runtime.block_on(async {
+            // Nothing listens on the discard port of localhost
+            let sources = SourcePool::new(vec![url, "http://127.0.0.1:9/file.bin".to_string()]);
+            let task = DownloadTask::new(String::new(), 16, 31, ChunkSink::Output(RangeOutput::File(output.clone())), ProgressBar::hidden(), &downloader())
+                .with_sources(sources, 1);
+            ConcurrentDownloader::new(vec![task]).execute_all().await.into_result().unwrap();
+        });
+
+        assert_eq!(std::fs::read(&output).unwrap()[16..], body[16..32]);
+    }
+
+    #[test]
+    fn test_source_order() {
+        let sources = SourcePool::new(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let urls = |preferred| sources.order(preferred).into_iter().map(|(_, url)| url).collect::<Vec<_>>();
+        assert_eq!(urls(1), ["b", "a", "c"]);
+
+        // The sources that failed the fewest ranges in a row are tried first
+        sources.report(0, false);
+        assert_eq!(urls(2), ["c", "b", "a"]);
+
+        // A source that keeps failing is tried last, even by its own connections
+        for _ in 0..MAX_SOURCE_FAILURES {
+            sources.report(1, false);
+        }
+        assert_eq!(urls(1), ["c", "a", "b"]);
+        sources.report(1, true);
+        assert_eq!(urls(1), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_partial_failure_keeps_progress() {
+        let runtime = Runtime::new().unwrap();
+        let body: Vec<u8> = (0..=255).collect();
+        let url = test_server::serve(body.clone());
+        let dir = test_server::temp_dir("partial_failure");
+        let output = dir.join("out");
+        std::fs::write(&output, [0u8; 256]).unwrap();
+
+        let outcome = runtime.block_on(async {
+            let good = DownloadTask::new(url, 0, 127, ChunkSink::Output(RangeOutput::File(output.clone())), ProgressBar::hidden(), &downloader());
+            let dead = DownloadTask::new("http://127.0.0.1:9/file.bin".to_string(), 128, 255, ChunkSink::Output(RangeOutput::File(output.clone())), ProgressBar::hidden(), &downloader());
+            ConcurrentDownloader::new(vec![good, dead]).execute_all().await
+        });
+
+        assert!(matches!(outcome.termination, Termination::Failed(_)));
+        assert_eq!(outcome.bytes_completed(), 128);
+        assert_eq!(outcome.remaining(), vec![(128, 255)]);
+        assert_eq!(std::fs::read(&output).unwrap()[..128], body[..128]);
+    }
+
+    #[test]
+    fn test_cancelled_tasks() {
+        let runtime = Runtime::new().unwrap();
+        let url = test_server::serve(vec![1; 4096]);
+        let output = test_server::temp_dir("cancelled").join("out");
+        std::fs::write(&output, [0u8; 4096]).unwrap();
+
+        let outcome = runtime.block_on(async {
+            let task = DownloadTask::new(url, 0, 4095, ChunkSink::Output(RangeOutput::File(output.clone())), ProgressBar::hidden(), &downloader());
+            ConcurrentDownloader::new(vec![task]).execute_until(async {}).await
+        });
+
+        assert!(matches!(outcome.termination, Termination::Cancelled));
+        let (start, end) = outcome.remaining()[0];
+        assert_eq!((start as u64, end), (outcome.bytes_completed(), 4095));
+        assert!(matches!(outcome.into_result(), Err(AppError::Interrupted)));
+    }
+
+    #[test]
+    fn test_scheduler_splits_the_busiest_range() {
+        let scheduler = SegmentScheduler::new(&[(0, 99, 0), (100, 199, 100)], 10);
+        let bar = || ProgressBar::with_draw_target(Some(0), indicatif::ProgressDrawTarget::hidden());
+        let (slow, fast) = (bar(), bar());
+        assert_eq!(scheduler.claim(&slow), Some(0));
+        scheduler.advance(0, &[0; 20]);
+        assert_eq!(scheduler.written_prefix(), 20);
+
+        // Nothing is left to claim, so the fast task takes over half of what the slow one has left
+        assert_eq!(scheduler.claim(&fast), Some(2));
+        assert_eq!(scheduler.ranges(), vec![(0, 59, 20), (100, 199, 100), (60, 99, 0)]);
+        assert_eq!(scheduler.reserve(0, 100), 40);
+        assert_eq!((slow.length(), fast.length()), (Some(60), Some(40)));
+
+        // Pieces below the minimum split size stay where they are
+        scheduler.advance(0, &[0; 35]);
+        scheduler.advance(2, &[0; 35]);
+        assert_eq!(scheduler.claim(&bar()), None);
+        assert_eq!(scheduler.unfinished(), 2);
+        assert_eq!(scheduler.written_prefix(), 55, "the gap after the first range ends the written part");
+    }
+
+    #[test]
+    fn test_reserved_bytes_are_not_taken_over() {
+        let scheduler = SegmentScheduler::new(&[(0, 99, 0)], 10);
+        let bar = || ProgressBar::with_draw_target(Some(0), indicatif::ProgressDrawTarget::hidden());
+        assert_eq!(scheduler.claim(&bar()), Some(0));
+        scheduler.advance(0, &[0; 20]);
+
+        // A write of 40 bytes is under way, so only the 40 bytes after it are split between both tasks
+        assert_eq!(scheduler.reserve(0, 40), 40);
+        assert_eq!(scheduler.claim(&bar()), Some(1));
+        assert_eq!(scheduler.ranges(), vec![(0, 79, 20), (80, 99, 0)]);
+        scheduler.advance(0, &[0; 40]);
+        assert_eq!(scheduler.reserve(0, 40), 20);
+    }
+
+    #[test]
+    fn test_scheduled_tasks() {
+        let runtime = Runtime::new().unwrap();
+        let body: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        let url = test_server::serve(body.clone());
+        let output = test_server::temp_dir("scheduled").join("out");
+        std::fs::write(&output, vec![0u8; 4096]).unwrap();
 
-            // Assertions to check if tasks were executed
-            // This might depend on whether your tasks modify some state or produce some output
+        // One range for two tasks, which have to split it between them
+        let digest = DigestTracker::new([Algorithm::Sha256]);
+        let scheduler = SegmentScheduler::new(&[(0, 4095, 0)], 16).with_digest(digest.clone());
+        let outcome = runtime.block_on(async {
+            let tasks = (0..2)
+                .map(|_| DownloadTask::scheduled(url.clone(), RangeOutput::File(output.clone()), scheduler.clone(), ProgressBar::hidden(), &downloader()))
+                .collect();
+            ConcurrentDownloader::new(tasks).execute_all().await
         });
+
+        assert!(matches!(outcome.termination, Termination::Completed));
+        assert_eq!(outcome.bytes_completed(), 4096);
+        assert_eq!(scheduler.unfinished(), 0);
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+        let expected = ExpectedDigest { algorithm: Algorithm::Sha256, value: sha2::Sha256::digest(&body).to_vec() };
+        assert!(digest.finish(&output, 4096).unwrap().matches(&expected));
+    }
+
+    #[test]
+    fn test_retries_resume_after_a_reset() {
+        let runtime = Runtime::new().unwrap();
+        let body: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        let cut = test_server::Quirks { cut_first: Some(1000), ..test_server::Quirks::default() };
+        let retries = RetryPolicy::new(3, Duration::from_millis(10));
+
+        // A scheduled range picks up where the connection was reset
+        let url = test_server::serve_with(body.clone(), cut);
+        let output = test_server::temp_dir("retries").join("out");
+        std::fs::write(&output, vec![0u8; 4096]).unwrap();
+        let scheduler = SegmentScheduler::new(&[(0, 4095, 0)], 4096);
+        let outcome = runtime.block_on(async {
+            let task = DownloadTask::scheduled(url, RangeOutput::File(output.clone()), scheduler.clone(), ProgressBar::hidden(), &downloader());
+            ConcurrentDownloader::new(vec![task.with_retries(retries)]).execute_all().await
+        });
+        assert!(matches!(outcome.termination, Termination::Completed));
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+
+        // So does a streamed range, without passing any byte on twice
+        let url = test_server::serve_with(body.clone(), cut);
+        let streamed = runtime.block_on(async {
+            let (writer, mut reader) = tokio::io::duplex(8192);
+            let task = DownloadTask::new(url, 0, 4095, ChunkSink::Pipe(writer), ProgressBar::hidden(), &downloader());
+            ConcurrentDownloader::new(vec![task.with_retries(retries)]).execute_all().await.into_result().unwrap();
+            let mut streamed = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut streamed).await.unwrap();
+            streamed
+        });
+        assert_eq!(streamed, body);
+
+        // Without retries the reset fails the download
+        let url = test_server::serve_with(body, cut);
+        let output = test_server::temp_dir("no_retries").join("out");
+        std::fs::write(&output, vec![0u8; 4096]).unwrap();
+        let outcome = runtime.block_on(async {
+            let task = DownloadTask::new(url, 0, 4095, ChunkSink::Output(RangeOutput::File(output)), ProgressBar::hidden(), &downloader());
+            ConcurrentDownloader::new(vec![task]).execute_all().await
+        });
+        assert!(matches!(outcome.termination, Termination::Failed(_)));
+    }
+
+    #[test]
+    fn test_stalled_range_is_reopened() {
+        let runtime = Runtime::new().unwrap();
+        let body: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        let url = test_server::serve_with(body.clone(), test_server::Quirks { stall_first: Some(1000), ..test_server::Quirks::default() });
+        let output = test_server::temp_dir("stalled").join("out");
+        std::fs::write(&output, vec![0u8; 4096]).unwrap();
+
+        let options = ClientOptions { stall_timeout: Some(Duration::from_millis(200)), ..ClientOptions::default() };
+        let started = std::time::Instant::now();
+        let outcome = runtime.block_on(async {
+            let task = DownloadTask::new(url, 0, 4095, ChunkSink::Output(RangeOutput::File(output.clone())), ProgressBar::hidden(), &FileDownloader::with_options(&options).unwrap())
+                .with_retries(RetryPolicy::new(2, Duration::from_millis(10)));
+            ConcurrentDownloader::new(vec![task]).execute_all().await
+        });
+
+        // The range picked up after the stall instead of waiting for the server
+        assert!(matches!(outcome.termination, Termination::Completed));
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let retries = RetryPolicy::new(10, Duration::from_secs(1));
+        for attempt in 1..10 {
+            let full = Duration::from_secs(1 << (attempt - 1)).min(MAX_RETRY_WAIT);
+            let backoff = retries.backoff(attempt);
+            assert!(backoff <= full && backoff >= full / 2, "attempt {}: {:?}", attempt, backoff);
+        }
+        assert!(retries.allows(9, &AppError::HttpStatus(503, "503 Service Unavailable".to_string())));
+        assert!(!retries.allows(10, &AppError::HttpStatus(503, "503 Service Unavailable".to_string())));
+        assert!(!retries.allows(1, &AppError::HttpStatus(404, "404 Not Found".to_string())));
+        assert!(!retries.allows(1, &AppError::RangeNotSupported));
+        assert!(!RetryPolicy::default().allows(1, &AppError::IoError("connection reset".to_string())));
+    }
+
+    #[test]
+    fn test_tuner_keeps_connections_that_pay_off() {
+        let scheduler = SegmentScheduler::new(&[(0, 1 << 30, 0)], 1);
+        let connect = {
+            let scheduler = scheduler.clone();
+            move |_| DownloadTask::scheduled("http://127.0.0.1:9/".to_string(), RangeOutput::File("out".into()), scheduler.clone(), ProgressBar::hidden(), &downloader())
+        };
+        let mut tuner = ConnectionTuner::new(scheduler.clone(), 2, 4, connect);
+
+        scheduler.advance(0, &[0; 1000]);
+        assert!(tuner.tune().is_some());
+        // Twice as fast with the third connection, so a fourth one is tried
+        scheduler.advance(0, &[0; 2000]);
+        let fourth = tuner.tune().unwrap();
+        assert_eq!(tuner.connections, 4);
+        // The fourth one did not help and is retired
+        scheduler.advance(0, &[0; 2000]);
+        assert!(tuner.tune().is_none());
+        assert!(fourth.retired.load(Ordering::Relaxed));
+        assert_eq!(tuner.connections, 3);
+        scheduler.advance(0, &[0; 9000]);
+        assert!(tuner.tune().is_none());
     }
 
     #[test]
@@ -133,9 +1056,9 @@ mod tests {
 
         runtime.block_on(async {
             let downloader = ConcurrentDownloader::new(vec![]);
-            downloader.execute_all().await; // No tasks to execute
+            downloader.execute_all().await.into_result().unwrap(); // No tasks to execute
 
             // Assertions to confirm no errors or panics occur when no tasks are present
         });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+use serde_json::{Map, Value};
+use crate::args;
+use crate::error::AppError;
+
+// Options that name what to download rather than how, which a config file cannot set
+const NOT_DEFAULTS: [&str; 7] = ["url", "input-file", "manifest", "background", "config", "profile", "help"];
+
+/// Returns where the defaults of downloads are read from: `rtget/config.toml` in `$XDG_CONFIG_HOME`,
+/// or `~/.config`, or `%APPDATA%` on Windows.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME").filter(|home| !home.is_empty()) {
+        Some(home) => PathBuf::from(home),
+        None if cfg!(windows) => PathBuf::from(std::env::var_os("APPDATA")?),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("rtget").join("config.toml"))
+}
+
+/// Returns the options the config file adds to the arguments of a download, `args` without the program name.
+///
+/// The file is the one of `--config` in `args`, which must exist, or the default one if there is one.
+/// With `--profile` the settings of that profile replace those at the top of the file. Options given
+/// in `args` replace those of the file, whatever name they are given by.
+pub fn defaults(args: &[&str]) -> Result<Vec<String>, AppError> {
+    let value_of = |option: &str| match args.iter().position(|arg| *arg == option) {
+        Some(index) => match args.get(index + 1) {
+            Some(value) => Ok(Some(*value)),
+            None => Err(AppError::StringError(format!("{} needs a value", option))),
+        },
+        None => Ok(None),
+    };
+    Defaults::load(value_of("--config")?, value_of("--profile")?)?.arguments(args)
+}
+
+/// The settings of a config file, which give the defaults of the options of downloads.
+///
+/// Subcommands that run downloads of their own, like `rtget resume` and `rtget daemon`, load them
+/// once and add them to the arguments of each download.
+#[derive(Debug, Default)]
+pub struct Defaults {
+    path: PathBuf,
+    settings: Map<String, Value>,
+}
+
+impl Defaults {
+    /// Reads the file `config`, which must exist, or the default one if there is one, with the
+    /// settings of `profile` in place of those at the top of the file.
+    pub fn load(config: Option<&str>, profile_name: Option<&str>) -> Result<Defaults, AppError> {
+        let path = match config {
+            Some(path) => PathBuf::from(path),
+            None => match default_path().filter(|path| path.is_file()) {
+                Some(path) => path,
+                None if profile_name.is_some() => return Err(AppError::StringError("--profile needs a config file, and there is none".to_string())),
+                None => return Ok(Defaults::default()),
+            },
+        };
+        let invalid = |message: String| AppError::StringError(format!("invalid config file {}: {}", path.display(), message));
+        let mut settings = load(&path).map_err(invalid)?;
+        if let Some(name) = profile_name {
+            settings = profile(&settings, name).map_err(invalid)?;
+        }
+        Ok(Defaults { path, settings })
+    }
+
+    /// Returns the options the settings add to `given`, the arguments of a download, leaving out those given already.
+    pub fn arguments(&self, given: &[&str]) -> Result<Vec<String>, AppError> {
+        arguments(&self.settings, given).map_err(|message| AppError::StringError(format!("invalid config file {}: {}", self.path.display(), message)))
+    }
+}
+
+/// Reads the config file at `path`.
+pub fn load(path: &Path) -> Result<Map<String, Value>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse(&text)
+}
+
+/// Returns the settings of the profile `name`: those at the top of the file, replaced by those of its
+/// `[profile.<name>]` table.
+///
+/// A profile with `inherits = "<other>"` first takes the settings of that other profile, so profiles
+/// can share a base. Settings are replaced as a whole, lists included, and `false` turns off a switch.
+pub fn profile(settings: &Map<String, Value>, name: &str) -> Result<Map<String, Value>, String> {
+    let mut tables = Vec::new();
+    let mut next = Some(name);
+    while let Some(name) = next {
+        if tables.iter().any(|(inherited, _)| *inherited == name) {
+            return Err(format!("profile {} inherits from itself", name));
+        }
+        let table = settings
+            .get("profile")
+            .and_then(|profiles| profiles.get(name))
+            .and_then(Value::as_object)
+            .ok_or_else(|| format!("there is no [profile.{}]", name))?;
+        next = match table.get("inherits") {
+            Some(Value::String(inherited)) => Some(inherited.as_str()),
+            Some(_) => return Err(format!("inherits of profile {} must be the name of a profile", name)),
+            None => None,
+        };
+        tables.push((name, table));
+    }
+    let mut merged = Map::new();
+    for table in std::iter::once(settings).chain(tables.iter().rev().map(|(_, table)| *table)) {
+        for (key, value) in table {
+            if key != "inherits" && !value.is_object() {
+                merged.insert(key.replace('_', "-"), value.clone());
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Turns the top-level settings of a config file into options, leaving out those `given` already.
+///
+/// Keys are the long names of the options, with `-` or `_`: `true` gives a switch, `false` leaves it
+/// off, a list repeats the option, and strings and numbers are its value. Tables are left out.
+pub fn arguments(settings: &Map<String, Value>, given: &[&str]) -> Result<Vec<String>, String> {
+    let mut arguments = Vec::new();
+    for (key, value) in settings {
+        let name = key.replace('_', "-");
+        if value.is_object() {
+            continue;
+        }
+        if NOT_DEFAULTS.contains(&name.as_str()) {
+            return Err(format!("{} is given on the command line, not in the config file", name));
+        }
+        if args::is_given(given, &name) {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => arguments.push(format!("--{}", name)),
+                Value::Bool(false) => {}
+                Value::String(value) => arguments.extend([format!("--{}", name), value.clone()]),
+                Value::Number(value) => arguments.extend([format!("--{}", name), value.to_string()]),
+                _ => return Err(format!("{} must be a string, a number, true or false, or a list of them", key)),
+            }
+        }
+    }
+    Ok(arguments)
+}
+
+/// Parses a config file, with the line of the first error.
+pub fn parse(text: &str) -> Result<Map<String, Value>, String> {
+    toml::from_str(text).map_err(|error: toml::de::Error| {
+        let before = error.span().and_then(|span| text.get(..span.start)).unwrap_or_default();
+        format!("line {}: {}", before.matches('\n').count() + 1, error.message())
+    })
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const CONFIG: &str = r#"
+# defaults of every download
+connections = 8
+limit-rate = "2M"   # per download
+proxy = 'http://proxy.example.com:3128'
+header = [
+    "X-Team: storage",
+    "Accept-Language: en",
+]
+verify_boundaries = true
+no-hsts = false
+
+[profile.work]
+proxy = "http://proxy.work.example.com:8080"
+user = "me:secret"
+verify-boundaries = false
+
+[profile.work-slow]
+inherits = "work"
+limit_rate = "500K"
+header = ["X-Team: storage"]
+
+[profile.loop]
+inherits = "loop"
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = parse(CONFIG).unwrap();
+        assert_eq!(config["connections"], json!(8));
+        assert_eq!(config["limit-rate"], json!("2M"));
+        assert_eq!(config["header"], json!(["X-Team: storage", "Accept-Language: en"]));
+        assert_eq!(config["profile"]["work"]["proxy"], json!("http://proxy.work.example.com:8080"));
+        assert_eq!(parse("a = \"tab\\there\"\nb = -1.5").unwrap(), *json!({"a": "tab\there", "b": -1.5}).as_object().unwrap());
+        assert_eq!(parse("a = 1\na = 2").unwrap_err(), "line 2: duplicate key `a` in document root");
+        assert_eq!(parse("a = \"open\nb = 1").unwrap_err(), "line 1: invalid basic string");
+        assert!(parse("a = 1 2").is_err());
+        assert!(parse("a = 1\n[a]").is_err());
+    }
+
+    #[test]
+    fn test_arguments() {
+        let config = parse(CONFIG).unwrap();
+        let arguments = arguments(&config, &["-c", "4", "http://a/x.iso"]).unwrap();
+        assert_eq!(
+            arguments,
+            ["--header", "X-Team: storage", "--header", "Accept-Language: en", "--limit-rate", "2M", "--proxy", "http://proxy.example.com:3128", "--verify-boundaries"]
+        );
+        assert!(super::arguments(&parse("url = \"http://a/x.iso\"").unwrap(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        let config = parse(CONFIG).unwrap();
+        // A profile replaces the settings at the top, and those of the profile it inherits from
+        let slow = profile(&config, "work-slow").unwrap();
+        assert_eq!(
+            arguments(&slow, &[]).unwrap(),
+            ["--connections", "8", "--header", "X-Team: storage", "--limit-rate", "500K", "--proxy", "http://proxy.work.example.com:8080", "--user", "me:secret"]
+        );
+        assert_eq!(profile(&config, "home").unwrap_err(), "there is no [profile.home]");
+        assert_eq!(profile(&config, "loop").unwrap_err(), "profile loop inherits from itself");
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt::Write as _;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+
+// Number of trailing bytes of each range covered by its checksum
+const TAIL_SIZE: u64 = 64 * 1024;
+
+// How often the control file is rewritten while the download runs
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One byte range of the download and how far it got
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// First byte of the range
+    pub start: u64,
+    /// Last byte of the range, inclusive
+    pub end: u64,
+    /// Bytes of the range already written to the output
+    pub written: u64,
+    /// Checksum of the last bytes written, used to detect torn writes before appending
+    pub tail_checksum: Option<String>,
+}
+
+impl Segment {
+    // Number of bytes in the range
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// The persistent state of a segmented download, stored next to the output as `<output>.rtget`
+///
+/// It is rewritten while the download runs and removed once it is complete, so an
+/// interrupted download can pick up every range where it left off.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlFile {
+    /// URL the download was started from
+    pub url: String,
+    /// ETag of the remote file, if the server sent one
+    pub etag: Option<String>,
+    /// Total size of the remote file
+    pub total_size: u64,
+    /// The byte ranges, in order
+    pub segments: Vec<Segment>,
+}
+
+impl ControlFile {
+    /// Creates the state of a fresh download split into `ranges`.
+    pub fn new(url: &str, etag: Option<&str>, total_size: u64, ranges: &[(u64, u64)]) -> ControlFile {
+        ControlFile {
+            url: url.to_string(),
+            etag: etag.map(str::to_string),
+            total_size,
+            segments: ranges.iter().map(|&(start, end)| Segment { start, end, written: 0, tail_checksum: None }).collect(),
+        }
+    }
+
+    /// Loads a control file, returning `None` if it is missing or malformed.
+    pub fn load(path: &Path) -> Option<ControlFile> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        if lines.next()? != "# rtget control file v1" {
+            return None;
+        }
+        let mut control = ControlFile { url: String::new(), etag: None, total_size: 0, segments: Vec::new() };
+        for line in lines {
+            let mut fields = line.split('\t');
+            match fields.next()? {
+                "url" => control.url = fields.next()?.to_string(),
+                "etag" => control.etag = fields.next().map(str::to_string),
+                "size" => control.total_size = fields.next()?.parse().ok()?,
+                "segment" => {
+                    let start = fields.next()?.parse().ok()?;
+                    let end = fields.next()?.parse().ok()?;
+                    let written = fields.next()?.parse().ok()?;
+                    let tail_checksum = fields.next().filter(|checksum| *checksum != "-").map(str::to_string);
+                    control.segments.push(Segment { start, end, written, tail_checksum });
+                }
+                _ => return None,
+            }
+        }
+        Some(control)
+    }
+
+    /// Writes the control file atomically, so a crash never leaves a half-written state behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = String::from("# rtget control file v1\n");
+        let _ = writeln!(contents, "url\t{}", self.url);
+        if let Some(etag) = &self.etag {
+            let _ = writeln!(contents, "etag\t{}", etag);
+        }
+        let _ = writeln!(contents, "size\t{}", self.total_size);
+        for segment in &self.segments {
+            let checksum = segment.tail_checksum.as_deref().unwrap_or("-");
+            let _ = writeln!(contents, "segment\t{}\t{}\t{}\t{}", segment.start, segment.end, segment.written, checksum);
+        }
+        let temporary = path.with_extension("rtget.tmp");
+        std::fs::write(&temporary, contents)?;
+        std::fs::rename(temporary, path)
+    }
+
+    /// Whether this state belongs to the download of `url` as it is on the server now.
+    ///
+    /// An ETag is only compared when both sides have one.
+    pub fn matches(&self, url: &str, total_size: u64, etag: Option<&str>) -> bool {
+        self.url == url && self.same_content(total_size, etag) && !self.segments.is_empty()
+    }
+
+    /// Whether a file of `total_size` bytes with `etag` is the file this state was recorded for.
+    ///
+    /// An ETag is only compared when both sides have one.
+    pub fn same_content(&self, total_size: u64, etag: Option<&str>) -> bool {
+        let same_etag = match (self.etag.as_deref(), etag) {
+            (Some(saved), Some(current)) => saved == current,
+            _ => true,
+        };
+        self.total_size == total_size && same_etag
+    }
+
+    /// Returns the byte range of the file covered by the last recorded tail checksum, with that checksum.
+    ///
+    /// Fetching this range from another server and comparing its `checksum` tells whether it serves the same content.
+    pub fn tail_piece(&self) -> Option<(u64, u64, &str)> {
+        self.segments.iter().find_map(|segment| {
+            let checksum = segment.tail_checksum.as_deref()?;
+            let length = segment.written.min(TAIL_SIZE);
+            let end = segment.start + segment.written - 1;
+            Some((end + 1 - length, end, checksum))
+        })
+    }
+
+    /// Checks the written start of every range of `output` against its recorded tail checksum.
+    ///
+    /// Ranges whose tail does not match were torn by a crash, or the output was changed since, and start over.
+    pub fn verify(&mut self, output: &Path) {
+        for (index, segment) in self.segments.iter_mut().enumerate() {
+            let intact = segment.written <= segment.len()
+                && tail_checksum(output, segment.start, segment.written).ok().flatten() == segment.tail_checksum;
+            if !intact {
+                tracing::warn!("Range {} does not match the control file, downloading it again", index + 1);
+                segment.written = 0;
+                segment.tail_checksum = None;
+            }
+        }
+    }
+
+    /// Records the ranges as they are in `output` now, given as `(start, end, written)`, with the checksum of their tails.
+    ///
+    /// The ranges replace the recorded ones, which lets them change while the download runs.
+    pub fn refresh(&mut self, output: &Path, ranges: &[(u64, u64, u64)]) -> io::Result<()> {
+        self.segments = ranges
+            .iter()
+            .map(|&(start, end, written)| {
+                let written = written.min(end - start + 1);
+                Ok(Segment { start, end, written, tail_checksum: tail_checksum(output, start, written)? })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(())
+    }
+}
+
+/// Keeps `path` up to date with the progress of the ranges until the task is aborted.
+///
+/// `ranges` returns the current ranges as `(start, end, written)`, counting only bytes already in
+/// `output`. Failing to save is only logged; the download itself goes on.
+pub async fn save_periodically(mut control: ControlFile, ranges: impl Fn() -> Vec<(u64, u64, u64)>, output: PathBuf, path: PathBuf) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = control.refresh(&output, &ranges()).and_then(|()| control.save(&path)) {
+            tracing::warn!("Could not save the control file {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Checksum of the last TAIL_SIZE bytes of the first `written` bytes of the range at `start`, `None` if nothing was written
+fn tail_checksum(path: &Path, start: u64, written: u64) -> io::Result<Option<String>> {
+    if written == 0 {
+        return Ok(None);
+    }
+    let tail = written.min(TAIL_SIZE);
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(start + written - tail))?;
+    let mut buffer = vec![0; tail as usize];
+    file.read_exact(&mut buffer)?;
+    Ok(Some(checksum(&buffer)))
+}
+
+/// Checksum of a range tail as recorded in the control file.
+pub fn checksum(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)[..16].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = test_server::temp_dir("control_save");
+        let path = dir.join("out.rtget");
+        let mut control = ControlFile::new("https://example.com/a.iso", Some("\"v1\""), 200, &[(0, 99), (100, 199)]);
+        control.segments[1].written = 42;
+        control.segments[1].tail_checksum = Some("00ff".to_string());
+        control.save(&path).unwrap();
+
+        let loaded = ControlFile::load(&path).unwrap();
+        assert_eq!(loaded, control);
+        assert!(loaded.matches("https://example.com/a.iso", 200, Some("\"v1\"")));
+        assert!(loaded.matches("https://example.com/a.iso", 200, None));
+        assert!(!loaded.matches("https://example.com/a.iso", 201, Some("\"v1\"")));
+        assert!(!loaded.matches("https://example.com/a.iso", 200, Some("\"v2\"")));
+    }
+
+    #[test]
+    fn test_verify_detects_torn_writes() {
+        let dir = test_server::temp_dir("control_verify");
+        let output = dir.join("out");
+        let mut control = ControlFile::new("https://example.com/a.iso", None, 200, &[(0, 99), (100, 199)]);
+        let mut contents = vec![0u8; 200];
+        contents[..30].fill(1);
+        contents[100..130].fill(2);
+        std::fs::write(&output, &contents).unwrap();
+        control.refresh(&output, &[(0, 99, 30), (100, 199, 30)]).unwrap();
+        assert_eq!(control.segments[0].written, 30);
+
+        // More bytes reached the first range after the save; the second range got garbage over its tail
+        contents[30..50].fill(1);
+        contents[100..130].fill(0);
+        std::fs::write(&output, &contents).unwrap();
+        control.verify(&output);
+
+        assert_eq!(control.segments[0].written, 30);
+        assert_eq!(control.segments[1].written, 0);
+
+        // An output that went away starts over as well
+        control.refresh(&output, &[(0, 99, 30), (100, 199, 30)]).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        control.verify(&output);
+        assert!(control.segments.iter().all(|segment| segment.written == 0));
+    }
+
+    #[test]
+    fn test_tail_piece() {
+        let dir = test_server::temp_dir("control_tail_piece");
+        let output = dir.join("out");
+        let mut control = ControlFile::new("https://example.com/a.iso", None, 200, &[(0, 99), (100, 199)]);
+        assert_eq!(control.tail_piece(), None);
+
+        let mut contents = vec![0u8; 200];
+        contents[100..103].copy_from_slice(b"abc");
+        std::fs::write(&output, &contents).unwrap();
+        control.refresh(&output, &[(0, 99, 0), (100, 199, 3)]).unwrap();
+        let expected = checksum(b"abc");
+        assert_eq!(control.tail_piece(), Some((100, 102, expected.as_str())));
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use url::Url;
+use crate::batch::Entry;
+use crate::filter::Filters;
+use crate::robots::Robots;
+
+/// Most bytes of a downloaded page read to look for links
+pub const MAX_PAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+// Extensions of URLs that may be pages, crawled for their links even when the filters reject their name
+const PAGE_EXTENSIONS: [&str; 8] = ["html", "htm", "shtml", "xhtml", "php", "asp", "aspx", "jsp"];
+
+/// A link found in a page
+#[derive(Debug, PartialEq)]
+pub struct Link {
+    pub url: Url,
+    /// Whether the page needs it to be displayed, like an image, a stylesheet or a script, rather than linking to it
+    pub requisite: bool,
+}
+
+/// Walks a website from a start page, following the links of every downloaded page.
+///
+/// Only links to the host of the start page are followed, and only up to `level` links away from
+/// it. Every URL is queued once, to be saved under `<host>/<path>` like the website lays it out.
+/// Links the filters reject are not queued, except pages rejected by name alone: those are still
+/// downloaded to follow their links, and deleted afterwards. Neither are links the robots.txt of the
+/// website disallows.
+///
+/// With page requisites, the images, stylesheets and scripts of every page are queued too, those of
+/// the deepest pages and those on other hosts included, so every page downloaded can be displayed.
+pub struct Crawler {
+    host: Option<String>,
+    /// Links away from the start page of the deepest pages queued
+    level: usize,
+    /// Whether the requisites of every page are queued, wherever they are
+    requisites: bool,
+    filters: Filters,
+    robots: Robots,
+    /// Links away from the start page of every URL queued so far
+    depths: HashMap<Url, usize>,
+    /// Pages queued only for their links, deleted once followed
+    transit: HashSet<Url>,
+    /// The page each URL was first found in
+    referrers: HashMap<Url, Url>,
+}
+
+impl Crawler {
+    /// Creates a crawler starting at `start`, following links at most `level` deep, or without limit for 0.
+    pub fn new(start: &Url, level: usize, filters: Filters) -> Crawler {
+        Crawler {
+            host: start.host_str().map(str::to_string),
+            level: if level == 0 { usize::MAX } else { level },
+            requisites: false,
+            filters,
+            robots: Robots::default(),
+            depths: HashMap::new(),
+            transit: HashSet::new(),
+            referrers: HashMap::new(),
+        }
+    }
+
+    /// Creates a crawler queueing only the requisites of the page `start`, to save it for offline reading.
+    pub fn page_requisites(start: &Url, filters: Filters) -> Crawler {
+        Crawler { level: 0, requisites: true, ..Crawler::new(start, 1, filters) }
+    }
+
+    /// Queues the requisites of every page too, as needed to display it.
+    pub fn with_requisites(mut self) -> Crawler {
+        self.requisites = true;
+        self
+    }
+
+    /// Sets the robots.txt rules of the website.
+    pub fn with_robots(mut self, robots: Robots) -> Crawler {
+        self.robots = robots;
+        self
+    }
+
+    /// Returns how long to wait between two downloads, as asked by the robots.txt of the website.
+    pub fn crawl_delay(&self) -> Option<std::time::Duration> {
+        self.robots.crawl_delay
+    }
+
+    /// Queues the start page, returning its download; when the filters reject it, it is kept only for its links.
+    pub fn start(&mut self, start: &Url) -> Entry {
+        let start = without_fragment(start);
+        if !self.filters.accepts(&start) {
+            self.transit.insert(start.clone());
+        }
+        self.depths.insert(start.clone(), 0);
+        entry(&start)
+    }
+
+    /// Looks for links in the page of `url` downloaded to `path`, and returns the downloads of those not queued yet.
+    ///
+    /// Files that are not HTML and pages at the deepest level have no links to follow.
+    pub fn follow(&mut self, url: &str, path: &Path) -> Vec<Entry> {
+        let entries = match self.follows(url) {
+            true => read_page(path).map(|html| self.follow_page(url, &html)).unwrap_or_default(),
+            false => Vec::new(),
+        };
+        if Url::parse(url).is_ok_and(|url| self.transit.remove(&without_fragment(&url))) {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Could not delete {}, downloaded only for its links: {}", path.display(), e);
+            }
+        }
+        entries
+    }
+
+    /// Returns whether the links of the page of `url` are followed, i.e. it was queued and is not at the deepest level.
+    ///
+    /// With page requisites, pages at the deepest level are followed for their requisites only.
+    pub fn follows(&self, url: &str) -> bool {
+        let depth = Url::parse(url).ok().and_then(|url| self.depths.get(&without_fragment(&url)).copied());
+        depth.is_some_and(|depth| depth < self.level || (self.requisites && depth == self.level))
+    }
+
+    /// Returns the page that linked to `url` first, if it was not the start page.
+    pub fn referrer(&self, url: &str) -> Option<&Url> {
+        Url::parse(url).ok().and_then(|url| self.referrers.get(&without_fragment(&url)))
+    }
+
+    /// Queues the links of the HTML page `html` of `url` that pass the filters, and returns their downloads.
+    pub fn follow_page(&mut self, url: &str, html: &str) -> Vec<Entry> {
+        let Ok(page) = Url::parse(url).map(|url| without_fragment(&url)) else {
+            return Vec::new();
+        };
+        let Some(depth) = self.depths.get(&page).copied().filter(|_| self.follows(url)) else {
+            return Vec::new();
+        };
+        let mut entries = Vec::new();
+        for link in extract_links(html, &page) {
+            let url = without_fragment(&link.url);
+            let own_host = url.host_str() == self.host.as_deref();
+            // Only requisites are queued from the deepest pages, and from other hosts
+            let wanted = (link.requisite && self.requisites) || (own_host && depth < self.level);
+            if !matches!(url.scheme(), "http" | "https") || !wanted || self.depths.contains_key(&url) {
+                continue;
+            }
+            if local_path(&url).is_none() {
+                tracing::warn!("Skipping {}, it would be saved outside of the output directory", url);
+                continue;
+            }
+            if own_host && !self.robots.allows(&url) {
+                tracing::info!("Skipping {}, disallowed by robots.txt", url);
+                continue;
+            }
+            if !self.filters.accepts(&url) {
+                if link.requisite || !self.filters.accepts_directory_of(&url) || !may_be_page(&url) {
+                    tracing::info!("Skipping {}, rejected by the filters", url);
+                    continue;
+                }
+                self.transit.insert(url.clone());
+            }
+            self.depths.insert(url.clone(), depth + 1);
+            self.referrers.insert(url.clone(), page.clone());
+            entries.push(entry(&url));
+        }
+        entries
+    }
+}
+
+/// Returns whether the URL may be an HTML page, by the extension of its file name or the lack of one.
+pub fn may_be_page(url: &Url) -> bool {
+    let name = url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((_, extension)) => PAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()),
+        None => true,
+    }
+}
+
+// The download of a URL of the website, saved where the website lays it out
+fn entry(url: &Url) -> Entry {
+    Entry { url: url.to_string(), output: local_path(url), checksum: None }
+}
+
+// The URL without its #fragment, which names a place in the same page
+fn without_fragment(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url
+}
+
+/// Returns where a file of a website is saved: `<host>/<path>`, with `index.html` for directories.
+///
+/// A query string stays part of the file name, so `list?page=2` and `list?page=3` do not overwrite each other,
+/// with its `/`, `\\` and NUL percent-encoded. There is no path for a URL that would lead out of the host's directory.
+pub fn local_path(url: &Url) -> Option<PathBuf> {
+    let mut path = PathBuf::from(url.host_str().unwrap_or_default());
+    let segments: Vec<&str> = url.path_segments().map(|segments| segments.collect()).unwrap_or_default();
+    let (directories, file_name) = match segments.split_last() {
+        Some((file_name, directories)) => (directories, *file_name),
+        None => (&[][..], ""),
+    };
+    path.extend(directories.iter().filter(|directory| !directory.is_empty()));
+    let mut file_name = match file_name {
+        "" => "index.html".to_string(),
+        file_name => file_name.to_string(),
+    };
+    if let Some(query) = url.query() {
+        file_name.push('?');
+        file_name.push_str(&query.replace('/', "%2F").replace('\\', "%5C").replace('\0', "%00"));
+    }
+    let path = path.join(file_name);
+    path.components().all(|component| matches!(component, Component::Normal(_))).then_some(path)
+}
+
+// Read the downloaded file at `path` if it is an HTML page
+fn read_page(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    std::fs::File::open(path).ok()?.take(MAX_PAGE_SIZE).read_to_end(&mut bytes).ok()?;
+    let named_html = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm"));
+    (named_html || looks_like_html(&bytes)).then(|| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Returns whether `bytes` start like an HTML page.
+pub fn looks_like_html(bytes: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).trim_start().to_ascii_lowercase();
+    start.starts_with("<!doctype html") || start.contains("<html")
+}
+
+/// Returns the links of the HTML page `html`, resolved against its URL `base` or its `<base href>`.
+///
+/// Anchors, areas, frames and `<link>` elements link to other pages; images, scripts, stylesheets,
+/// icons and media sources are requisites of the page.
+pub fn extract_links(html: &str, base: &Url) -> Vec<Link> {
+    let mut base = base.clone();
+    let mut links = Vec::new();
+    for (name, attributes) in tags(html) {
+        let attribute = |wanted: &str| attributes.iter().find(|(name, _)| name == wanted).map(|(_, value)| value.as_str());
+        let (target, requisite) = match name.as_str() {
+            "base" => {
+                if let Some(href) = attribute("href").and_then(|href| base.join(href).ok()) {
+                    base = href;
+                }
+                continue;
+            }
+            "a" | "area" => (attribute("href"), false),
+            "frame" | "iframe" => (attribute("src"), false),
+            "link" => {
+                let rel = attribute("rel").unwrap_or_default().to_ascii_lowercase();
+                (attribute("href"), rel.split_whitespace().any(|rel| matches!(rel, "stylesheet" | "icon" | "preload")))
+            }
+            "img" | "script" | "source" | "video" | "audio" | "embed" | "track" => (attribute("src"), true),
+            _ => continue,
+        };
+        if let Some(url) = target.map(str::trim).filter(|target| !target.is_empty()).and_then(|target| base.join(target).ok()) {
+            links.push(Link { url, requisite });
+        }
+    }
+    links
+}
+
+/// Returns the start tags of `html` with their lowercase names and attributes, skipping comments and the text of scripts.
+pub fn tags(html: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let mut tags = Vec::new();
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let name_length = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        if name_length == 0 {
+            continue;
+        }
+        let name = rest[..name_length].to_ascii_lowercase();
+        rest = &rest[name_length..];
+        let mut attributes = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+            if rest.is_empty() || rest.starts_with('>') {
+                break;
+            }
+            let attribute_length = rest.find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/')).unwrap_or(rest.len()).max(1);
+            let attribute = rest[..attribute_length].to_ascii_lowercase();
+            rest = rest[attribute_length..].trim_start();
+            let value = match rest.strip_prefix('=') {
+                Some(value) => {
+                    let value = value.trim_start();
+                    let (text, remainder) = match value.chars().next() {
+                        Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                            Some(end) => (&value[1..end + 1], &value[end + 2..]),
+                            None => (&value[1..], ""),
+                        },
+                        _ => {
+                            let end = value.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(value.len());
+                            (&value[..end], &value[end..])
+                        }
+                    };
+                    rest = remainder;
+                    text.replace("&amp;", "&")
+                }
+                None => String::new(),
+            };
+            attributes.push((attribute, value));
+        }
+        // The text of scripts and styles may hold anything, including what looks like tags
+        if name == "script" || name == "style" {
+            let closing = format!("</{}", name);
+            rest = rest.to_ascii_lowercase().find(&closing).map_or("", |end| &rest[end..]);
+        }
+        tags.push((name, attributes));
+    }
+    tags
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    #[test]
+    fn test_extract_links() {
+        let base = Url::parse("http://example.com/docs/index.html").unwrap();
+        let html = r#"<!DOCTYPE html><html><head>
+            <link rel="stylesheet" href="style.css"><link rel=next href='page2.html'>
+            <script src="/app.js">if (a < b) { document.write('<a href="nope.html">') }</script>
+            </head><body><!-- <a href="commented.html"> -->
+            <a href="guide/start.html#install">Start</a> <A HREF=../about.html>About</A>
+            <img src="logo.png" alt=""><a href="list?a=1&amp;b=2">List</a> <a>none</a>
+            </body></html>"#;
+        let links: Vec<(String, bool)> = extract_links(html, &base).into_iter().map(|link| (link.url.to_string(), link.requisite)).collect();
+        assert_eq!(
+            links,
+            [
+                ("http://example.com/docs/style.css".to_string(), true),
+                ("http://example.com/docs/page2.html".to_string(), false),
+                ("http://example.com/app.js".to_string(), true),
+                ("http://example.com/docs/guide/start.html#install".to_string(), false),
+                ("http://example.com/about.html".to_string(), false),
+                ("http://example.com/docs/logo.png".to_string(), true),
+                ("http://example.com/docs/list?a=1&b=2".to_string(), false),
+            ]
+        );
+
+        // A <base> changes what the links are relative to
+        let links = extract_links(r#"<base href="http://cdn.example.com/v2/"><img src="a.png">"#, &base);
+        assert_eq!(links[0].url.as_str(), "http://cdn.example.com/v2/a.png");
+    }
+
+    #[test]
+    fn test_local_path() {
+        let path = |url: &str| local_path(&Url::parse(url).unwrap()).unwrap();
+        assert_eq!(path("http://example.com/"), Path::new("example.com/index.html"));
+        assert_eq!(path("http://example.com/docs/"), Path::new("example.com/docs/index.html"));
+        assert_eq!(path("http://example.com:8080/docs/a.png"), Path::new("example.com/docs/a.png"));
+        assert_eq!(path("http://example.com/list?page=2"), Path::new("example.com/list?page=2"));
+        // A query cannot lead out of the directory of the host
+        assert_eq!(path("http://example.com/dir/list?x=/../../../../tmp/pwned"), Path::new("example.com/dir/list?x=%2F..%2F..%2F..%2F..%2Ftmp%2Fpwned"));
+        assert_eq!(path("http://example.com/list?x=..\\..\\pwned"), Path::new("example.com/list?x=..%5C..%5Cpwned"));
+        assert_eq!(path("http://example.com/%2e%2e/%2E%2E/pwned"), Path::new("example.com/pwned"));
+    }
+
+    #[test]
+    fn test_follow() {
+        let dir = test_server::temp_dir("crawler_follow");
+        let start = Url::parse("http://example.com/").unwrap();
+        let mut crawler = Crawler::new(&start, 2, Filters::default());
+        assert_eq!(crawler.start(&start).output.as_deref(), Some(Path::new("example.com/index.html")));
+
+        let page = dir.join("index.html");
+        std::fs::write(&page, r#"<a href="/a.html">a</a><a href="/a.html#top">again</a><a href="http://other.org/">away</a><a href="mailto:x@example.com">mail</a>"#).unwrap();
+        let urls: Vec<String> = crawler.follow("http://example.com/", &page).into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, ["http://example.com/a.html"]);
+
+        // The second level is followed, the third is not
+        std::fs::write(&page, r#"<a href="/b.html">b</a><a href="/">home</a>"#).unwrap();
+        let urls: Vec<String> = crawler.follow("http://example.com/a.html", &page).into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, ["http://example.com/b.html"]);
+        std::fs::write(&page, r#"<a href="/c.html">c</a>"#).unwrap();
+        assert!(crawler.follow("http://example.com/b.html", &page).is_empty());
+
+        // Files other than pages have no links
+        let image = dir.join("logo.png");
+        std::fs::write(&image, "\u{89}PNG <a href=\"/d.html\">").unwrap();
+        assert!(crawler.follow("http://example.com/a.html", &image).is_empty());
+    }
+
+    #[test]
+    fn test_follow_filtered() {
+        let dir = test_server::temp_dir("crawler_follow_filtered");
+        let start = Url::parse("http://example.com/index.html").unwrap();
+        let args: crate::args::CommandLineArgs = argh::FromArgs::from_args(&["rtget"], &["-A", "*.iso,index.html", "-X", "/old"]).unwrap();
+        let mut crawler = Crawler::new(&start, 0, Filters::from_args(&args));
+        crawler.start(&start);
+        let page = dir.join("index.html");
+        std::fs::write(&page, r#"<a href="a.iso">iso</a><a href="a.txt">txt</a><a href="old/b.iso">old</a><a href="pub/">pub</a><img src="logo.png">"#).unwrap();
+        let urls: Vec<String> = crawler.follow("http://example.com/index.html", &page).into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, ["http://example.com/a.iso", "http://example.com/pub/"]);
+        assert!(page.exists());
+
+        // A page rejected by name is deleted once its links are followed
+        std::fs::write(&page, r#"<a href="c.iso">iso</a>"#).unwrap();
+        assert_eq!(crawler.follow("http://example.com/pub/", &page).len(), 1);
+        assert!(!page.exists());
+
+        // So is a start page the filters reject
+        let start = Url::parse("http://example.com/").unwrap();
+        let mut crawler = Crawler::new(&start, 0, Filters::from_args(&args));
+        crawler.start(&start);
+        std::fs::write(&page, r#"<a href="a.iso">iso</a>"#).unwrap();
+        assert_eq!(crawler.follow("http://example.com/", &page).len(), 1);
+        assert!(!page.exists());
+    }
+
+    #[test]
+    fn test_follow_robots() {
+        let dir = test_server::temp_dir("crawler_follow_robots");
+        let start = Url::parse("http://example.com/").unwrap();
+        let mut crawler = Crawler::new(&start, 0, Filters::default()).with_robots(Robots::parse("User-agent: *\nDisallow: /private/\n"));
+        crawler.start(&start);
+        let page = dir.join("index.html");
+        std::fs::write(&page, r#"<a href="/private/a.html">a</a><a href="/public/b.html">b</a>"#).unwrap();
+        let urls: Vec<String> = crawler.follow("http://example.com/", &page).into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, ["http://example.com/public/b.html"]);
+    }
+
+    #[test]
+    fn test_follow_requisites() {
+        let dir = test_server::temp_dir("crawler_follow_requisites");
+        let start = Url::parse("http://example.com/docs/").unwrap();
+        let page = dir.join("index.html");
+        let html = r#"<link rel="stylesheet" href="style.css"><img src="http://cdn.example.net/logo.png"><a href="guide.html">guide</a>"#;
+        std::fs::write(&page, html).unwrap();
+
+        // The page alone takes its requisites, wherever they are, but no other page
+        let mut crawler = Crawler::page_requisites(&start, Filters::default());
+        crawler.start(&start);
+        let urls: Vec<String> = crawler.follow("http://example.com/docs/", &page).into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, ["http://example.com/docs/style.css", "http://cdn.example.net/logo.png"]);
+
+        // With -r the deepest pages get their requisites too
+        let mut crawler = Crawler::new(&start, 1, Filters::default()).with_requisites();
+        crawler.start(&start);
+        assert_eq!(crawler.follow("http://example.com/docs/", &page).len(), 3);
+        let urls: Vec<String> = crawler.follow("http://example.com/docs/guide.html", &page).into_iter().map(|entry| entry.url).collect();
+        assert!(urls.is_empty(), "the requisites were queued already: {:?}", urls);
+        std::fs::write(&page, r#"<img src="diagram.png"><a href="next.html">next</a>"#).unwrap();
+        let urls: Vec<String> = crawler.follow("http://example.com/docs/guide.html", &page).into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, ["http://example.com/docs/diagram.png"]);
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use indicatif::HumanBytes;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use crate::args::CtlArgs;
+use crate::daemon::Queue;
+use crate::error::AppError;
+use crate::rpc::{self, Caller};
+use crate::server::Access;
+use crate::users::Identity;
+
+// Most downloads `rtget ctl status` lists of the waiting and of the stopped ones
+const MAX_LISTED: u64 = 1000;
+
+/// Returns where the daemon listens for `rtget ctl` unless --socket says otherwise.
+///
+/// On Unix this is `rtget.sock` in `$XDG_RUNTIME_DIR`, or a socket of the user in the temporary
+/// directory; on Windows the named pipe `\\.\pipe\rtget`.
+pub fn default_socket() -> PathBuf {
+    #[cfg(unix)]
+    {
+        match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir).join("rtget.sock"),
+            // SAFETY: getuid cannot fail
+            None => std::env::temp_dir().join(format!("rtget-{}.sock", unsafe { libc::getuid() })),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(r"\\.\pipe\rtget")
+    }
+}
+
+/// The control socket of a daemon, see [`bind`].
+#[cfg(unix)]
+pub type Listener = tokio::net::UnixListener;
+
+/// The control pipe of a daemon, see [`bind`].
+#[cfg(windows)]
+pub struct Listener {
+    path: PathBuf,
+    // The instance of the pipe the next client connects to
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+/// Listens for `rtget ctl` on the socket `path`, which only the user may connect to, or every user
+/// when `shared`, for a daemon serving the users of --users.
+///
+/// A socket left behind by a daemon that no longer runs is replaced; one a daemon still listens on is an error.
+#[cfg(unix)]
+pub fn bind(path: &Path, shared: bool) -> Result<Listener, AppError> {
+    use std::os::unix::fs::PermissionsExt;
+    let listener = match Listener::bind(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && std::os::unix::net::UnixStream::connect(path).is_err() => {
+            std::fs::remove_file(path)?;
+            Listener::bind(path)
+        }
+        listener => listener,
+    };
+    let listener = listener.map_err(|e| match e.kind() {
+        std::io::ErrorKind::AddrInUse => AppError::StringError(format!("a daemon already listens on {}", path.display())),
+        _ => AppError::StringError(format!("could not listen on {}: {}", path.display(), e)),
+    })?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(if shared { 0o666 } else { 0o600 }))?;
+    Ok(listener)
+}
+
+/// Listens for `rtget ctl` on the named pipe `path`, which must not have another daemon.
+#[cfg(windows)]
+pub fn bind(path: &Path, _shared: bool) -> Result<Listener, AppError> {
+    let next = tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)
+        .map_err(|e| AppError::StringError(format!("could not listen on {}: {}", path.display(), e)))?;
+    Ok(Listener { path: path.to_path_buf(), next })
+}
+
+/// Removes the socket `path` once the daemon stops listening on it.
+pub fn unbind(path: &Path) {
+    #[cfg(unix)]
+    let _ = std::fs::remove_file(path);
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Answers the clients of `listener` with the JSON-RPC interface of the daemon, one call per line.
+///
+/// The login of a client tells who it is, see [`crate::users::identify_uid`], so the user running the
+/// daemon and those of --users need no token; anyone else passes one as over the network.
+#[cfg(unix)]
+pub async fn serve(listener: Listener, queue: Arc<Queue>, access: Arc<Access>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let identity = stream.peer_cred().ok().and_then(|cred| crate::users::identify_uid(&access.users, cred.uid()));
+        tokio::spawn(connection(stream, queue.clone(), access.clone(), identity));
+    }
+}
+
+/// Answers the clients of `listener` with the JSON-RPC interface of the daemon, one call per line.
+///
+/// Only the user running the daemon may open the pipe, so calls need no token.
+#[cfg(windows)]
+pub async fn serve(mut listener: Listener, queue: Arc<Queue>, access: Arc<Access>) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    loop {
+        if listener.next.connect().await.is_err() {
+            continue;
+        }
+        let Ok(next) = ServerOptions::new().create(&listener.path) else {
+            continue;
+        };
+        let connected = std::mem::replace(&mut listener.next, next);
+        tokio::spawn(connection(connected, queue.clone(), access.clone(), Some(Identity::Owner)));
+    }
+}
+
+// Answer every line a client sends until it disconnects, as `identity` when its login tells who it is
+async fn connection<S: AsyncRead + AsyncWrite>(stream: S, queue: Arc<Queue>, access: Arc<Access>, identity: Option<Identity>) {
+    let caller = match identity {
+        Some(identity) => Caller::Known(identity),
+        None => Caller::Network(&access),
+    };
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(answer) = rpc::handle(&queue, &caller, &line) {
+            if writer.write_all(format!("{}\n", answer).as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Runs the action of `rtget ctl` against the daemon and returns what to print.
+pub async fn run(args: &CtlArgs) -> Result<String, AppError> {
+    let socket = args.socket.as_ref().map_or_else(default_socket, PathBuf::from);
+    let call = |method: &'static str, params: Value| call(&socket, method, with_token(args.token.as_deref(), params));
+    let argument = |what: &str| match args.args.as_slice() {
+        [argument] => Ok(argument.as_str()),
+        _ => Err(AppError::StringError(format!("rtget ctl {} needs {}", args.action, what))),
+    };
+    match args.action.as_str() {
+        "add" => {
+            if args.args.is_empty() {
+                return Err(AppError::StringError("rtget ctl add needs a URL, optionally followed by its mirrors".to_string()));
+            }
+            let mut options = serde_json::Map::new();
+            if let Some(dir) = &args.dir {
+                // The daemon may run in another directory, so a relative one is made absolute here
+                options.insert("dir".to_string(), json!(std::path::absolute(dir)?.display().to_string()));
+            }
+            if let Some(out) = &args.out {
+                options.insert("out".to_string(), json!(out));
+            }
+            if let Some(connections) = args.connections {
+                options.insert("split".to_string(), json!(connections.to_string()));
+            }
+            if let Some(start_at) = &args.start_at {
+                options.insert("start-at".to_string(), json!(start_at));
+            }
+            let gid = call("aria2.addUri", json!([args.args, options])).await?;
+            Ok(format!("{}\n", gid.as_str().unwrap_or_default()))
+        }
+        "status" => {
+            let statuses = match args.args.as_slice() {
+                [] => {
+                    let token = args.token.as_deref();
+                    let calls = json!([[
+                        {"methodName": "aria2.tellActive", "params": with_token(token, json!([]))},
+                        {"methodName": "aria2.tellWaiting", "params": with_token(token, json!([0, MAX_LISTED]))},
+                        {"methodName": "aria2.tellStopped", "params": with_token(token, json!([0, MAX_LISTED]))},
+                    ]]);
+                    // system.multicall itself takes no token
+                    let results = self::call(&socket, "system.multicall", calls).await?;
+                    let mut statuses: Vec<Value> = results.as_array().into_iter().flatten().filter_map(|result| result.get(0)?.as_array().cloned()).flatten().collect();
+                    statuses.sort_by(|a, b| a["gid"].as_str().cmp(&b["gid"].as_str()));
+                    statuses
+                }
+                [id] => vec![call("aria2.tellStatus", json!([gid(id)])).await?],
+                _ => return Err(AppError::StringError("rtget ctl status takes at most one ID".to_string())),
+            };
+            Ok(render_statuses(&statuses))
+        }
+        "pause" | "resume" | "cancel" => {
+            let (method, done) = match args.action.as_str() {
+                "pause" => ("aria2.pause", "Paused"),
+                "resume" => ("aria2.unpause", "Resumed"),
+                _ => ("aria2.remove", "Cancelled"),
+            };
+            let gid = gid(argument("the ID of a download")?);
+            call(method, json!([gid])).await?;
+            Ok(format!("{} {}\n", done, gid))
+        }
+        action => Err(AppError::StringError(format!("unknown action {}, expected add, status, pause, resume or cancel", action))),
+    }
+}
+
+// The GID an ID stands for: IDs may leave out the leading zeros, e.g. 3 for 0000000000000003
+fn gid(id: &str) -> String {
+    match id.len() < 16 && !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => format!("{:0>16}", id),
+        false => id.to_string(),
+    }
+}
+
+// The parameters of a call with the token of --token first, if any
+fn with_token(token: Option<&str>, params: Value) -> Value {
+    match (token, params) {
+        (Some(token), Value::Array(params)) => Value::Array(std::iter::once(json!(format!("token:{}", token))).chain(params).collect()),
+        (_, params) => params,
+    }
+}
+
+// Send one JSON-RPC call to the daemon listening on `socket` and return its result
+async fn call(socket: &Path, method: &str, params: Value) -> Result<Value, AppError> {
+    let not_running = |e: std::io::Error| AppError::StringError(format!("could not reach the daemon on {}: {}; start one with rtget daemon", socket.display(), e));
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(socket).await.map_err(not_running)?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(socket).map_err(not_running)?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    writer.write_all(format!("{}\n", request).as_bytes()).await?;
+    let mut answer = String::new();
+    BufReader::new(reader).read_line(&mut answer).await?;
+    let answer: Value = serde_json::from_str(&answer).map_err(|e| AppError::StringError(format!("invalid answer from the daemon: {}", e)))?;
+    match answer.get("error") {
+        Some(error) => Err(AppError::StringError(error["message"].as_str().unwrap_or("the daemon refused the call").to_string())),
+        None => Ok(answer["result"].clone()),
+    }
+}
+
+// One line per download with its ID, state, progress, speed and file, as described by aria2.tellStatus
+fn render_statuses(statuses: &[Value]) -> String {
+    if statuses.is_empty() {
+        return "No downloads\n".to_string();
+    }
+    let number = |status: &Value, key: &str| status[key].as_str().and_then(|value| value.parse::<u64>().ok()).unwrap_or_default();
+    let mut output = String::new();
+    let _ = writeln!(output, "{:<16}  {:<8} {:>5} {:>12}  File", "ID", "Status", "Done", "Speed");
+    for status in statuses {
+        let (completed, total) = (number(status, "completedLength"), number(status, "totalLength"));
+        let done = match total {
+            0 => "-".to_string(),
+            total => format!("{}%", completed * 100 / total),
+        };
+        let speed = match status["status"].as_str() {
+            Some("active") => format!("{}/s", HumanBytes(number(status, "downloadSpeed"))),
+            _ => "-".to_string(),
+        };
+        let mut file = match status["files"][0]["path"].as_str().filter(|path| !path.is_empty()) {
+            Some(path) => path.to_string(),
+            None => status["files"][0]["uris"][0]["uri"].as_str().unwrap_or_default().to_string(),
+        };
+        if let Some(start_at) = status["startAt"].as_str() {
+            file.push_str(&format!(" (starts at {})", start_at));
+        }
+        let _ = write!(output, "{:<16}  {:<8} {:>5} {:>12}  {}", status["gid"].as_str().unwrap_or_default(), status["status"].as_str().unwrap_or_default(), done, speed, file);
+        match status["errorMessage"].as_str() {
+            Some(error) => {
+                let _ = writeln!(output, " ({})", error);
+            }
+            None => {
+                let _ = writeln!(output);
+            }
+        }
+    }
+    output
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+    use crate::test_server;
+
+    fn ctl(args: &[&str], socket: &Path) -> CtlArgs {
+        let mut args = args.to_vec();
+        let socket = socket.display().to_string();
+        args.extend(["--socket", &socket]);
+        CtlArgs::from_args(&["rtget ctl"], &args).unwrap()
+    }
+
+    #[test]
+    fn test_gid() {
+        assert_eq!(gid("3"), "0000000000000003");
+        assert_eq!(gid("00000000000000a1"), "00000000000000a1");
+        assert_eq!(gid("not-a-gid"), "not-a-gid");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run() {
+        let socket = test_server::temp_dir("ctl").join("rtget.sock");
+        let queue = Arc::new(Queue::new(Path::new("downloads"), 1));
+        let access = Arc::new(Access { secret: "s3cret".to_string(), allowed_origins: Vec::new(), allow_origin_all: false, allowed_ips: Vec::new(), users: Arc::default() });
+        tokio::spawn(serve(bind(&socket, false).unwrap(), queue.clone(), access));
+        assert!(bind(&socket, false).is_err());
+
+        assert_eq!(run(&ctl(&["add", "http://a/1.iso", "http://b/1.iso", "-o", "one.iso"], &socket)).await.unwrap(), "0000000000000001\n");
+        run(&ctl(&["add", "http://a/2.iso"], &socket)).await.unwrap();
+        assert_eq!(run(&ctl(&["pause", "2"], &socket)).await.unwrap(), "Paused 0000000000000002\n");
+        let status = run(&ctl(&["status"], &socket)).await.unwrap();
+        let lines: Vec<&str> = status.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("0000000000000001  waiting      -            -  downloads/one.iso"), "{}", lines[1]);
+        assert!(lines[2].starts_with("0000000000000002  paused"), "{}", lines[2]);
+        assert_eq!(run(&ctl(&["cancel", "1"], &socket)).await.unwrap(), "Cancelled 0000000000000001\n");
+        assert!(run(&ctl(&["status", "1"], &socket)).await.unwrap().contains("removed"));
+        assert!(run(&ctl(&["pause", "9"], &socket)).await.is_err());
+        assert!(run(&ctl(&["frobnicate"], &socket)).await.is_err());
+        // The login of the client tells it runs the daemon, so a token is not needed but does no harm
+        assert!(run(&ctl(&["status", "--token", "wrong"], &socket)).await.unwrap().contains("paused"));
+        unbind(&socket);
+        assert!(run(&ctl(&["status"], &socket)).await.is_err());
+    }
+}
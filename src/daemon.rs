@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, watch, Notify};
+use crate::error::AppError;
+use crate::progress::{self, Bars};
+use crate::scheduler;
+
+// Events kept for subscribers that fall behind
+const EVENT_CAPACITY: usize = 256;
+
+/// The state of a download of the daemon, named like aria2 names them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Waiting,
+    Active,
+    Paused,
+    Complete,
+    Error,
+    Removed,
+}
+
+impl Status {
+    /// Returns the name of the state, e.g. `active`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Waiting => "waiting",
+            Status::Active => "active",
+            Status::Paused => "paused",
+            Status::Complete => "complete",
+            Status::Error => "error",
+            Status::Removed => "removed",
+        }
+    }
+
+    /// Returns whether the download ended, and stays in this state.
+    pub fn is_stopped(&self) -> bool {
+        matches!(self, Status::Complete | Status::Error | Status::Removed)
+    }
+}
+
+/// A change of the state of a download, e.g. `onDownloadComplete`, sent to the subscribers of the queue
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// The aria2 name of the notification
+    pub method: &'static str,
+    pub gid: String,
+}
+
+/// What a download of the daemon fetches, and where it saves it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Request {
+    /// The URL of the file, then its mirrors
+    pub urls: Vec<String>,
+    /// Directory the file is saved in, instead of the one of the daemon
+    pub dir: Option<PathBuf>,
+    /// Name of the file, instead of the one derived from its URL
+    pub out: Option<String>,
+    /// Connections of the download, instead of those of the daemon
+    pub connections: Option<u8>,
+    /// When the download may start, see [`crate::scheduler`]
+    pub start_at: Option<SystemTime>,
+    /// The user of --users the download is for, if any, see [`crate::users`]
+    pub owner: Option<String>,
+    /// Whether the file may be saved outside the directory of the daemon, as its owner may ask
+    pub trusted: bool,
+}
+
+/// A download the daemon should start now.
+pub struct Start {
+    pub gid: String,
+    pub request: Request,
+    /// Where the file is saved, the directory of the request or of the daemon
+    pub dir: PathBuf,
+    /// Turns true when the download should stop, see [`crate::interrupt::stoppable`]
+    pub stop: watch::Receiver<bool>,
+    /// The bars of the download, see [`progress::watched`]
+    pub bars: Bars,
+}
+
+// A download of the queue
+struct Job {
+    gid: String,
+    request: Request,
+    status: Status,
+    // The state to take once the running download stopped: paused or removed
+    stopping: Option<Status>,
+    stop: Option<watch::Sender<bool>>,
+    bars: Bars,
+    output: Option<PathBuf>,
+    error: Option<String>,
+}
+
+// The downloads of the daemon in the order they were added
+struct State {
+    jobs: Vec<Job>,
+    last_gid: u64,
+}
+
+/// The downloads of a daemon, started --jobs at a time in the order they were added.
+///
+/// Every download has a GID, 16 hexadecimal digits like aria2 uses. Stopped downloads stay listed
+/// with their outcome.
+pub struct Queue {
+    state: Mutex<State>,
+    dir: PathBuf,
+    max_active: usize,
+    // Most downloads of each user with a limit running at once
+    user_jobs: HashMap<String, usize>,
+    // Wakes up the loop starting the downloads
+    changed: Notify,
+    // Wakes up the scheduler when a deferred download was added
+    deferred: Notify,
+    events: broadcast::Sender<Event>,
+}
+
+impl Queue {
+    /// Creates an empty queue saving files in `dir` and running `max_active` downloads at a time.
+    pub fn new(dir: &Path, max_active: usize) -> Queue {
+        Queue {
+            state: Mutex::new(State { jobs: Vec::new(), last_gid: 0 }),
+            dir: dir.to_path_buf(),
+            max_active: max_active.max(1),
+            user_jobs: HashMap::new(),
+            changed: Notify::new(),
+            deferred: Notify::new(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Limits the downloads of each user of `user_jobs` running at once, on top of those of the daemon.
+    pub fn with_user_jobs(self, user_jobs: HashMap<String, usize>) -> Queue {
+        Queue { user_jobs, ..self }
+    }
+
+    /// Returns a receiver of every change of state from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Resolves once a download was added, or its state changed.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Resolves once a download that starts later was added.
+    pub async fn deferred(&self) {
+        self.deferred.notified().await;
+    }
+
+    /// Wakes up the loop starting the downloads, e.g. when deferred downloads may start.
+    pub fn wake(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Returns when the next deferred download may start, if one waits.
+    pub fn next_deferred(&self) -> Option<SystemTime> {
+        let now = SystemTime::now();
+        self.state().jobs.iter().filter(|job| job.status == Status::Waiting).filter_map(|job| job.request.start_at).filter(|at| *at > now).min()
+    }
+
+    /// Queues a download and returns its GID.
+    ///
+    /// A download with a start time waits for it, see [`crate::scheduler::run`]. The directory it
+    /// asks for is taken from the one of the daemon when relative, and must stay inside it unless the
+    /// request is trusted. The downloads of a user go to their own directory inside it, and stay there.
+    pub fn add(&self, request: Request) -> Result<String, String> {
+        if request.urls.is_empty() {
+            return Err("a download needs at least one URL".to_string());
+        }
+        if request.out.as_deref().is_some_and(|out| out.is_empty() || Path::new(out).is_absolute() || out.split(['/', '\\']).any(|part| part == "..")) {
+            return Err("out must name a file inside the directory of the download".to_string());
+        }
+        let base = match &request.owner {
+            Some(owner) => self.dir.join(owner),
+            None => self.dir.clone(),
+        };
+        let dir = match &request.dir {
+            Some(dir) if request.trusted => Some(dir.clone()),
+            Some(dir) => Some(inside_dir(&base, dir)?),
+            None => request.owner.is_some().then_some(base),
+        };
+        let request = Request { dir, ..request };
+        let mut state = self.state();
+        state.last_gid += 1;
+        let gid = format!("{:016x}", state.last_gid);
+        let deferred = request.start_at.is_some();
+        state.jobs.push(Job { gid: gid.clone(), request, status: Status::Waiting, stopping: None, stop: None, bars: Bars::default(), output: None, error: None });
+        drop(state);
+        if deferred {
+            self.deferred.notify_one();
+        }
+        self.changed.notify_one();
+        Ok(gid)
+    }
+
+    /// Takes the next waiting download to start, if fewer than --jobs run and its start time came.
+    pub fn next_start(&self) -> Option<Start> {
+        let mut state = self.state();
+        if state.jobs.iter().filter(|job| job.status == Status::Active).count() >= self.max_active {
+            return None;
+        }
+        let now = SystemTime::now();
+        // A user with a limit waits for one of their downloads to end, and the downloads of others go first meanwhile
+        let mut active: HashMap<&str, usize> = HashMap::new();
+        for owner in state.jobs.iter().filter(|job| job.status == Status::Active).filter_map(|job| job.request.owner.as_deref()) {
+            *active.entry(owner).or_default() += 1;
+        }
+        let full: Vec<String> = active.into_iter().filter(|(owner, count)| self.user_jobs.get(*owner).is_some_and(|limit| count >= limit)).map(|(owner, _)| owner.to_string()).collect();
+        let job = state.jobs.iter_mut().find(|job| {
+            job.status == Status::Waiting && job.request.start_at.is_none_or(|at| at <= now) && job.request.owner.as_ref().is_none_or(|owner| !full.contains(owner))
+        })?;
+        let (stop, stopped) = watch::channel(false);
+        job.status = Status::Active;
+        job.stop = Some(stop);
+        let start = Start {
+            gid: job.gid.clone(),
+            request: job.request.clone(),
+            dir: job.request.dir.clone().unwrap_or_else(|| self.dir.clone()),
+            stop: stopped,
+            bars: job.bars.clone(),
+        };
+        drop(state);
+        self.notify("aria2.onDownloadStart", &start.gid);
+        Some(start)
+    }
+
+    /// Records how the download `gid` ended: the path and size of the file, or the error.
+    ///
+    /// A download stopped to be paused or removed takes that state instead.
+    pub fn finish(&self, gid: &str, result: Result<(u64, PathBuf), AppError>) {
+        let mut state = self.state();
+        let Some(job) = state.jobs.iter_mut().find(|job| job.gid == gid) else {
+            return;
+        };
+        job.stop = None;
+        let stopping = job.stopping.take();
+        let (status, method) = match result {
+            Ok((_, output)) => {
+                job.output = Some(output);
+                (Status::Complete, "aria2.onDownloadComplete")
+            }
+            Err(AppError::Interrupted) if stopping == Some(Status::Paused) => (Status::Paused, "aria2.onDownloadPause"),
+            Err(_) if stopping == Some(Status::Removed) => (Status::Removed, "aria2.onDownloadStop"),
+            Err(error) => {
+                job.error = Some(error.to_string());
+                (Status::Error, "aria2.onDownloadError")
+            }
+        };
+        job.status = status;
+        drop(state);
+        self.notify(method, gid);
+    }
+
+    /// Pauses the download `gid`: a waiting one stays queued until it is unpaused, a running one stops and keeps its parts.
+    pub fn pause(&self, gid: &str) -> Result<(), String> {
+        self.update(gid, |job| match job.status {
+            Status::Waiting => Ok(Some((Status::Paused, "aria2.onDownloadPause"))),
+            Status::Active => {
+                job.stopping = Some(Status::Paused);
+                Ok(None)
+            }
+            status => Err(format!("the download {} is {} and cannot be paused", job.gid, status.as_str())),
+        })
+    }
+
+    /// Queues the paused download `gid` again, to continue where it stopped.
+    pub fn unpause(&self, gid: &str) -> Result<(), String> {
+        self.update(gid, |job| match job.status {
+            Status::Paused => Ok(Some((Status::Waiting, ""))),
+            status => Err(format!("the download {} is {}, not paused", job.gid, status.as_str())),
+        })
+    }
+
+    /// Removes the download `gid` from the queue, stopping it if it runs.
+    ///
+    /// What it saved so far stays on disk.
+    pub fn remove(&self, gid: &str) -> Result<(), String> {
+        self.update(gid, |job| match job.status {
+            Status::Waiting | Status::Paused => Ok(Some((Status::Removed, "aria2.onDownloadStop"))),
+            Status::Active => {
+                job.stopping = Some(Status::Removed);
+                Ok(None)
+            }
+            status => Err(format!("the download {} is {} already", job.gid, status.as_str())),
+        })
+    }
+
+    /// Drops the stopped download `gid` from the list, like aria2.removeDownloadResult.
+    pub fn forget(&self, gid: &str) -> Result<(), String> {
+        let mut state = self.state();
+        let index = state.jobs.iter().position(|job| job.gid == gid).ok_or_else(|| format!("no download has the GID {}", gid))?;
+        match state.jobs[index].status {
+            status if status.is_stopped() => {
+                state.jobs.remove(index);
+                Ok(())
+            }
+            status => Err(format!("the download {} is {}, not stopped", gid, status.as_str())),
+        }
+    }
+
+    /// Moves the waiting or paused download `gid` `offset` places later in the queue, or earlier for a
+    /// negative offset, so it starts after or before the others.
+    pub fn move_by(&self, gid: &str, offset: isize) -> Result<(), String> {
+        let mut state = self.state();
+        let index = state.jobs.iter().position(|job| job.gid == gid).ok_or_else(|| format!("no download has the GID {}", gid))?;
+        match state.jobs[index].status {
+            Status::Waiting | Status::Paused => {
+                let job = state.jobs.remove(index);
+                let position = index.saturating_add_signed(offset).min(state.jobs.len());
+                state.jobs.insert(position, job);
+                Ok(())
+            }
+            status => Err(format!("the download {} is {} and keeps its place", gid, status.as_str())),
+        }
+    }
+
+    /// Returns the bytes downloaded, the size and the bytes per second of every part of the download `gid`.
+    pub fn parts(&self, gid: &str) -> Vec<(u64, Option<u64>, u64)> {
+        let state = self.state();
+        let Some(job) = state.jobs.iter().find(|job| job.gid == gid) else {
+            return Vec::new();
+        };
+        let bars = job.bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        bars.iter().map(|bar| (bar.position(), bar.length(), if bar.is_finished() { 0 } else { bar.per_sec() as u64 })).collect()
+    }
+
+    /// Stops the running downloads, e.g. before the daemon exits, keeping their parts.
+    pub fn stop_all(&self) {
+        for job in self.state().jobs.iter_mut().filter(|job| job.status == Status::Active) {
+            job.stopping = Some(Status::Paused);
+            if let Some(stop) = &job.stop {
+                let _ = stop.send(true);
+            }
+        }
+    }
+
+    /// Returns the GIDs of the downloads in state `status`, in the order they were added.
+    pub fn gids(&self, status: impl Fn(Status) -> bool) -> Vec<String> {
+        self.gids_of(None, status)
+    }
+
+    /// Returns the GIDs of the downloads of `user` in state `status`, or of everyone without a user.
+    pub fn gids_of(&self, user: Option<&str>, status: impl Fn(Status) -> bool) -> Vec<String> {
+        self.state().jobs.iter().filter(|job| status(job.status) && (user.is_none() || job.request.owner.as_deref() == user)).map(|job| job.gid.clone()).collect()
+    }
+
+    /// Returns whether `user`, or everyone without a user, may see the download `gid`.
+    ///
+    /// A download that does not exist is visible, and reported missing when asked for.
+    pub fn visible(&self, gid: &str, user: Option<&str>) -> bool {
+        user.is_none() || self.state().jobs.iter().find(|job| job.gid == gid).is_none_or(|job| job.request.owner.as_deref() == user)
+    }
+
+    /// Returns the state of the download `gid` as aria2.tellStatus describes it.
+    ///
+    /// Sizes and speeds are decimal strings, as in aria2.
+    pub fn status(&self, gid: &str) -> Option<Value> {
+        let state = self.state();
+        let job = state.jobs.iter().find(|job| job.gid == gid)?;
+        let (completed, total, speed) = progress::totals(&job.bars);
+        let dir = job.request.dir.clone().unwrap_or_else(|| self.dir.clone());
+        let path = job.output.clone().or_else(|| job.request.out.as_ref().map(|out| dir.join(out)));
+        let total = match (job.status, total) {
+            (Status::Complete, _) => completed.max(total.unwrap_or_default()),
+            (_, total) => total.unwrap_or_default(),
+        };
+        let uris: Vec<Value> = job.request.urls.iter().map(|url| json!({"uri": url, "status": "used"})).collect();
+        let mut status = json!({
+            "gid": job.gid,
+            "status": job.status.as_str(),
+            "totalLength": total.to_string(),
+            "completedLength": completed.to_string(),
+            "uploadLength": "0",
+            "downloadSpeed": if job.status == Status::Active { speed } else { 0 }.to_string(),
+            "uploadSpeed": "0",
+            "connections": job.bars.lock().map(|bars| bars.iter().filter(|bar| !bar.is_finished()).count()).unwrap_or_default().to_string(),
+            "dir": dir.display().to_string(),
+            "files": [{
+                "index": "1",
+                "path": path.map(|path| path.display().to_string()).unwrap_or_default(),
+                "length": total.to_string(),
+                "completedLength": completed.to_string(),
+                "selected": "true",
+                "uris": uris,
+            }],
+        });
+        if let Some(start_at) = job.request.start_at.filter(|_| job.status == Status::Waiting) {
+            status["startAt"] = json!(scheduler::describe(start_at));
+        }
+        if let Some(error) = &job.error {
+            status["errorCode"] = json!("1");
+            status["errorMessage"] = json!(error);
+        }
+        Some(status)
+    }
+
+    /// Returns the overall speed and the number of downloads in each state, as aria2.getGlobalStat does.
+    pub fn global_stat(&self, user: Option<&str>) -> Value {
+        let state = self.state();
+        let jobs: Vec<&Job> = state.jobs.iter().filter(|job| user.is_none() || job.request.owner.as_deref() == user).collect();
+        let count = |wanted: fn(&Status) -> bool| jobs.iter().filter(|job| wanted(&job.status)).count().to_string();
+        let speed: u64 = jobs.iter().filter(|job| job.status == Status::Active).map(|job| progress::totals(&job.bars).2).sum();
+        json!({
+            "downloadSpeed": speed.to_string(),
+            "uploadSpeed": "0",
+            "numActive": count(|status| *status == Status::Active),
+            "numWaiting": count(|status| matches!(status, Status::Waiting | Status::Paused)),
+            "numStopped": count(Status::is_stopped),
+            "numStoppedTotal": count(Status::is_stopped),
+        })
+    }
+
+    // Apply `change` to the download `gid`, which returns the state it takes right away and the event to send
+    // A running download is asked to stop instead, and takes its state once it did
+    fn update(&self, gid: &str, change: impl FnOnce(&mut Job) -> Result<Option<(Status, &'static str)>, String>) -> Result<(), String> {
+        let mut state = self.state();
+        let job = state.jobs.iter_mut().find(|job| job.gid == gid).ok_or_else(|| format!("no download has the GID {}", gid))?;
+        match change(job)? {
+            Some((status, method)) => {
+                job.status = status;
+                drop(state);
+                if !method.is_empty() {
+                    self.notify(method, gid);
+                }
+                self.changed.notify_one();
+            }
+            None => {
+                if let Some(stop) = &job.stop {
+                    let _ = stop.send(true);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Tell the subscribers and the loop starting downloads that the download `gid` changed state
+    fn notify(&self, method: &'static str, gid: &str) {
+        let _ = self.events.send(Event { method, gid: gid.to_string() });
+        self.changed.notify_one();
+    }
+
+    // Lock the state, ignoring a thread that panicked while holding it
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// The directory `dir` of a download names inside `base`
+// Parent components are refused, and links are followed as far as the directory exists, so neither leads outside
+fn inside_dir(base: &Path, dir: &Path) -> Result<PathBuf, String> {
+    let outside = || format!("dir must be inside {}, the directory of the daemon", base.display());
+    if dir.components().any(|component| component == Component::ParentDir) {
+        return Err(outside());
+    }
+    let resolved_base = resolved(base).map_err(|e| e.to_string())?;
+    let resolved = resolved(&base.join(dir)).map_err(|e| e.to_string())?;
+    let inside = resolved.strip_prefix(&resolved_base).map_err(|_| outside())?;
+    Ok(base.join(inside))
+}
+
+// The absolute path of `path`, with the links of the part that exists followed
+fn resolved(path: &Path) -> std::io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let mut existing = path.as_path();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return Ok(canonical.join(path.strip_prefix(existing).expect("an ancestor of the path")));
+        }
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return Ok(path),
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(url: &str) -> Request {
+        Request { urls: vec![url.to_string()], ..Request::default() }
+    }
+
+    #[test]
+    fn test_queue() {
+        let queue = Queue::new(Path::new("downloads"), 1);
+        let mut events = queue.subscribe();
+        let first = queue.add(request("http://a/1.iso")).unwrap();
+        let second = queue.add(request("http://a/2.iso")).unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("0000000000000001", "0000000000000002"));
+        assert!(queue.add(Request { out: Some("../x".to_string()), ..request("http://a/3.iso") }).is_err());
+
+        // One download at a time
+        let start = queue.next_start().unwrap();
+        assert_eq!((start.gid.as_str(), start.dir.as_path()), (first.as_str(), Path::new("downloads")));
+        assert!(queue.next_start().is_none());
+        assert_eq!(events.try_recv().unwrap(), Event { method: "aria2.onDownloadStart", gid: first.clone() });
+
+        // Pausing a running download stops it, and it is paused once it stopped
+        queue.pause(&first).unwrap();
+        assert!(*start.stop.borrow());
+        assert_eq!(queue.status(&first).unwrap()["status"], "active");
+        queue.finish(&first, Err(AppError::Interrupted));
+        assert_eq!(queue.status(&first).unwrap()["status"], "paused");
+        assert_eq!(queue.gids(|status| status == Status::Paused), std::slice::from_ref(&first));
+
+        let start = queue.next_start().unwrap();
+        assert_eq!(start.gid, second);
+        queue.finish(&second, Ok((8, PathBuf::from("downloads/2.iso"))));
+        let status = queue.status(&second).unwrap();
+        assert_eq!((status["status"].as_str(), status["files"][0]["path"].as_str()), (Some("complete"), Some("downloads/2.iso")));
+        assert!(queue.pause(&second).is_err());
+
+        queue.unpause(&first).unwrap();
+        assert_eq!(queue.next_start().unwrap().gid, first);
+        queue.finish(&first, Err(AppError::HttpStatus(404, "404 Not Found".to_string())));
+        let status = queue.status(&first).unwrap();
+        assert_eq!(status["status"], "error");
+        assert_eq!(status["errorMessage"], "Could not connect to the server: 404 Not Found");
+        assert_eq!(queue.global_stat(None)["numStopped"], "2");
+        assert!(queue.remove("0000000000000009").is_err());
+
+        // A deferred download waits for its time
+        let later = SystemTime::now() + std::time::Duration::from_secs(3600);
+        let deferred = queue.add(Request { start_at: Some(later), ..request("http://a/4.iso") }).unwrap();
+        assert!(queue.next_start().is_none());
+        assert_eq!(queue.next_deferred(), Some(later));
+        assert!(queue.status(&deferred).unwrap()["startAt"].is_string());
+        queue.remove(&deferred).unwrap();
+        assert_eq!(queue.next_deferred(), None);
+
+        queue.forget(&first).unwrap();
+        assert!(queue.status(&first).is_none());
+        assert_eq!(queue.global_stat(None)["numStopped"], "2");
+
+        // Waiting downloads move ahead of others, running ones keep their place
+        let third = queue.add(request("http://a/5.iso")).unwrap();
+        let fourth = queue.add(request("http://a/6.iso")).unwrap();
+        queue.move_by(&fourth, -1).unwrap();
+        assert_eq!(queue.gids(|status| status == Status::Waiting), [fourth.clone(), third.clone()]);
+        queue.move_by(&fourth, 10).unwrap();
+        assert_eq!(queue.gids(|status| status == Status::Waiting), [third.clone(), fourth.clone()]);
+        assert!(queue.move_by(&second, 1).is_err());
+        assert_eq!(queue.next_start().unwrap().gid, third);
+        assert!(queue.parts(&third).is_empty());
+        queue.remove(&fourth).unwrap();
+    }
+
+    #[test]
+    fn test_dir() {
+        let dir = crate::test_server::temp_dir("daemon-dir");
+        let queue = Queue::new(&dir, 1);
+        let gid = queue.add(Request { dir: Some(PathBuf::from("isos/new")), ..request("http://a/1.iso") }).unwrap();
+        assert_eq!(queue.status(&gid).unwrap()["dir"].as_str(), dir.join("isos/new").to_str());
+        assert!(queue.add(Request { dir: Some(dir.join("isos")), ..request("http://a/2.iso") }).is_ok());
+        for outside in ["../elsewhere", "isos/../../elsewhere", "/tmp"] {
+            assert!(queue.add(Request { dir: Some(PathBuf::from(outside)), ..request("http://a/3.iso") }).is_err(), "{} was accepted", outside);
+        }
+        // A link inside the directory does not lead outside of it
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/tmp", dir.join("link")).unwrap();
+            assert!(queue.add(Request { dir: Some(PathBuf::from("link/sub")), ..request("http://a/4.iso") }).is_err());
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_users() {
+        let queue = Queue::new(Path::new("downloads"), 3).with_user_jobs(HashMap::from([("alice".to_string(), 1)]));
+        let of = |owner: &str, url: &str| Request { owner: Some(owner.to_string()), ..request(url) };
+        let first = queue.add(of("alice", "http://a/1.iso")).unwrap();
+        let second = queue.add(of("alice", "http://a/2.iso")).unwrap();
+        let bob = queue.add(of("bob", "http://a/3.iso")).unwrap();
+        assert_eq!(queue.status(&first).unwrap()["dir"], "downloads/alice");
+
+        // Alice runs one download at a time, and Bob's starts meanwhile
+        assert_eq!(queue.next_start().unwrap().gid, first);
+        assert_eq!(queue.next_start().unwrap().gid, bob);
+        assert!(queue.next_start().is_none());
+        queue.finish(&first, Ok((8, PathBuf::from("downloads/alice/1.iso"))));
+        assert_eq!(queue.next_start().unwrap().gid, second);
+
+        assert_eq!(queue.gids_of(Some("bob"), |_| true), std::slice::from_ref(&bob));
+        assert!(queue.visible(&bob, Some("bob")) && !queue.visible(&bob, Some("alice")) && queue.visible(&bob, None));
+        assert_eq!(queue.global_stat(Some("alice"))["numActive"], "1");
+        assert_eq!(queue.global_stat(None)["numActive"], "2");
+        assert!(queue.add(Request { dir: Some(PathBuf::from("/srv")), ..of("bob", "http://a/4.iso") }).is_err());
+        assert!(queue.add(Request { dir: Some(PathBuf::from("/srv")), trusted: true, ..request("http://a/5.iso") }).is_ok());
+    }
+}
@@ -1,94 +1,320 @@
-#[cfg(target_os = "linux")]
-mod linux {
-    /// Daemonize the process on Linux
-    pub fn daemonize() {
+use crate::args::ServiceArgs;
+use crate::error::AppError;
 
+#[cfg(unix)]
+mod unix {
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    /// Starts rtget again with the same arguments except -b, detached from the terminal, and returns its process id.
+    ///
+    /// Re-running the program rather than forking keeps the threads of the runtime out of the
+    /// child. It runs in a session of its own, like daemon(3) leaves it, so closing the terminal
+    /// does not stop it, and appends its messages to `log` in the current directory.
+    pub fn daemonize(log: &str) -> io::Result<u32> {
+        let arguments = std::env::args_os().skip(1).filter(|argument| argument != "-b" && argument != "--background");
+        let log = std::fs::OpenOptions::new().create(true).append(true).open(log)?;
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(arguments).env(super::BACKGROUND_ENV, "1").stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log);
+        // SAFETY: setsid is async-signal-safe, as code between fork and exec must be
+        unsafe {
+            command.pre_exec(|| match libc::setsid() {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+        Ok(command.spawn()?.id())
     }
 }
 
 #[cfg(target_os = "windows")]
 pub(crate) mod windows {
-    #[macro_use]
-    use windows_service::{
-        define_windows_service,
-        service::{
-            ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType
-        },
-        service_control_handler::{self, ServiceControlHandlerResult},
-        service_dispatcher,
+    use std::ffi::OsString;
+    use std::io::{self, BufRead, BufReader, Read};
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::process::CommandExt;
+    use std::path::PathBuf;
+    use std::process::{Command, ExitStatus, Stdio};
+    use std::sync::{mpsc, OnceLock};
+    use std::time::{Duration, Instant};
+    use windows_service::define_windows_service;
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+        ServiceType,
     };
-    use std::{ffi::OsString, sync::mpsc, time::Duration};
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::service_dispatcher;
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_sys::Win32::System::EventLog::{DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, REPORT_EVENT_TYPE};
+    use crate::error::AppError;
+
+    // Name of the service, and of the source of its events in the Application log
+    const SERVICE_NAME: &str = "rtget";
+
+    // A process without a console, out of the Ctrl-C group of the terminal that started it
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+    // How long the download may take to stop before the service reports it stopped anyway
+    const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+    // The directory and arguments of the download the service runs, set before the dispatcher starts
+    static DOWNLOAD: OnceLock<(PathBuf, Vec<String>)> = OnceLock::new();
 
     // Define the Windows service entry point
     define_windows_service!(ffi_service_main, service_main);
 
+    /// Starts rtget again with the same arguments except -b as a detached process, and returns its process id.
+    pub fn daemonize(log: &str) -> io::Result<u32> {
+        let arguments = std::env::args_os().skip(1).filter(|argument| argument != "-b" && argument != "--background");
+        let log = std::fs::OpenOptions::new().create(true).append(true).open(log)?;
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(arguments).env(super::BACKGROUND_ENV, "1").stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log);
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+        Ok(command.spawn()?.id())
+    }
+
+    /// Registers the service, starting with Windows to run rtget with `arguments` in `directory`.
+    pub fn install(directory: PathBuf, arguments: &[String]) -> Result<(), AppError> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE).map_err(service_error)?;
+        // The service manager starts the program as `rtget service run`, which hands it to the dispatcher
+        let launch_arguments = [OsString::from("service"), "run".into(), "--directory".into(), directory.into(), "--".into()]
+            .into_iter()
+            .chain(arguments.iter().map(OsString::from))
+            .collect();
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "rtget downloader".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments,
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG).map_err(service_error)?;
+        service.set_description("Downloads files in the background with rtget").map_err(service_error)
+    }
+
+    /// Stops the service if it runs, and removes it.
+    pub fn uninstall() -> Result<(), AppError> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT).map_err(service_error)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE).map_err(service_error)?;
+        // The service is only removed once it stopped, so wait for it like `sc stop` users do
+        if service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped {
+            service.stop().map_err(service_error)?;
+            let started = Instant::now();
+            while service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped && started.elapsed() < STOP_TIMEOUT {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+        }
+        service.delete().map_err(service_error)
+    }
+
+    /// Asks the service manager to start the service.
+    pub fn start() -> Result<(), AppError> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT).map_err(service_error)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::START).map_err(service_error)?;
+        service.start::<&str>(&[]).map_err(service_error)
+    }
+
+    /// Asks the service manager to stop the service.
+    pub fn stop() -> Result<(), AppError> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT).map_err(service_error)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::STOP).map_err(service_error)?;
+        service.stop().map(|_| ()).map_err(service_error)
+    }
+
+    /// Runs as the service, downloading with `arguments` in `directory` until the download ends or the service is stopped.
+    ///
+    /// Only the service manager can start this; it blocks until the service stops.
+    pub fn run(directory: PathBuf, arguments: Vec<String>) -> Result<(), AppError> {
+        let _ = DOWNLOAD.set((directory, arguments));
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(service_error)
+    }
+
     // Main logic for the service
-    fn service_main(arguments: Vec<OsString>) {
-        if let Err(e) = run_service(arguments) {
-            // Log the error or handle it as required
+    // Errors cannot reach a console, so they go to the event log
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            report_event(EVENTLOG_ERROR_TYPE, &format!("The rtget service failed: {}", e));
         }
     }
 
-    fn run_service(arguments: Vec<OsString>) -> windows_service::Result<()> {
+    // Run the download as a child process, relaying its messages to the event log and its end to the service manager
+    fn run_service() -> Result<(), AppError> {
+        let (directory, arguments) = DOWNLOAD.get().cloned().unwrap_or_default();
         let (shutdown_tx, shutdown_rx) = mpsc::channel();
-
         let event_handler = move |control_event| -> ServiceControlHandlerResult {
             match control_event {
-                ServiceControl::Stop => {
-                    // Handle stop event
-                    shutdown_tx.send(()).unwrap();
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    let _ = shutdown_tx.send(());
                     ServiceControlHandlerResult::NoError
                 }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
                 _ => ServiceControlHandlerResult::NotImplemented,
             }
         };
+        let status_handle = service_control_handler::register(SERVICE_NAME, event_handler).map_err(service_error)?;
 
-        // Register the service control handler
-        let status_handle = service_control_handler::register("rtget", event_handler)?;
+        let mut child = match Command::new(std::env::current_exe()?)
+            .args(&arguments)
+            .current_dir(&directory)
+            .env(super::BACKGROUND_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                set_status(status_handle, ServiceState::Stopped, ServiceExitCode::ServiceSpecific(1), 0)?;
+                return Err(e.into());
+            }
+        };
+        set_status(status_handle, ServiceState::Running, ServiceExitCode::NO_ERROR, 0)?;
+        report_event(EVENTLOG_INFORMATION_TYPE, &format!("Downloading with rtget {} in {}", arguments.join(" "), directory.display()));
+        let stdout = child.stdout.take().map(|stdout| relay(stdout, EVENTLOG_INFORMATION_TYPE));
+        let stderr = child.stderr.take().map(|stderr| relay(stderr, EVENTLOG_ERROR_TYPE));
 
-        // Set the service status to running
-        status_handle.set_service_status(ServiceStatus {
-            service_type: ServiceType::OWN_PROCESS,
-            current_state: ServiceState::Running,
-            controls_accepted: ServiceControlAccept::STOP,
-            exit_code: ServiceExitCode::Win32(0),
-            checkpoint: 0,
-            wait_hint: Duration::default(),
-            process_id: None,
-        })?;
-
-        // Main service loop
-        shutdown_rx.recv().unwrap();
-
-        // Service shutdown
-        status_handle.set_service_status(ServiceStatus {
-            service_type: ServiceType::OWN_PROCESS,
-            current_state: ServiceState::Stopped,
-            controls_accepted: ServiceControlAccept::empty(),
-            exit_code: ServiceExitCode::Win32(0),
-            checkpoint: 0,
-            wait_hint: Duration::default(),
-            process_id: None,
-        })?;
+        // The download ends by itself, or is stopped by the service manager
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if shutdown_rx.recv_timeout(Duration::from_millis(500)).is_ok() {
+                break None;
+            }
+        };
+        let status: ExitStatus = match status {
+            Some(status) => status,
+            None => {
+                set_status(status_handle, ServiceState::StopPending, ServiceExitCode::NO_ERROR, 1)?;
+                // Parts already written stay on disk with the state of the download, for the next start to resume
+                let _ = child.kill();
+                child.wait()?
+            }
+        };
+        for thread in stdout.into_iter().chain(stderr) {
+            let _ = thread.join();
+        }
+        let exit_code = match status.code() {
+            Some(0) | None => ServiceExitCode::NO_ERROR,
+            Some(code) => {
+                report_event(EVENTLOG_ERROR_TYPE, &format!("The download exited with status {}", code));
+                ServiceExitCode::ServiceSpecific(code as u32)
+            }
+        };
+        set_status(status_handle, ServiceState::Stopped, exit_code, 0)
+    }
 
-        Ok(())
+    // Report the state of the service; `checkpoint` counts the steps of a pending state
+    fn set_status(handle: ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode, checkpoint: u32) -> Result<(), AppError> {
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let wait_hint = if state == ServiceState::StopPending { STOP_TIMEOUT } else { Duration::default() };
+        handle
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted,
+                exit_code,
+                checkpoint,
+                wait_hint,
+                process_id: None,
+            })
+            .map_err(service_error)
     }
 
-    /// Function to daemonize the process on Windows.
-    pub fn daemonize() {
-        // Run the service dispatcher
-        // This will block until the service is stopped
-        if let Err(_e) = service_dispatcher::start("rtget", ffi_service_main) {
+    // Write every line of `output` to the event log as an event of `kind`, on a thread of its own
+    fn relay<R: Read + Send + 'static>(output: R, kind: REPORT_EVENT_TYPE) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                if !line.trim().is_empty() {
+                    report_event(kind, line.trim_end());
+                }
+            }
+        })
+    }
 
+    // Write a message to the Application event log
+    fn report_event(kind: REPORT_EVENT_TYPE, message: &str) {
+        let wide = |text: &str| std::ffi::OsStr::new(text).encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+        let (source, message) = (wide(SERVICE_NAME), wide(message));
+        // SAFETY: both strings are NUL terminated and outlive the calls, and the handle is released once
+        unsafe {
+            let log = RegisterEventSourceW(std::ptr::null(), source.as_ptr());
+            if log != 0 {
+                let strings = [message.as_ptr()];
+                ReportEventW(log, kind, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null());
+                DeregisterEventSource(log);
+            }
         }
     }
+
+    // Describe an error of the service manager
+    fn service_error(error: windows_service::Error) -> AppError {
+        AppError::StringError(format!("service manager: {}", error))
+    }
+}
+
+/// File the messages of a download continuing in the background are appended to
+pub const LOG_FILE: &str = "rtget-log";
+
+/// Variable set in the environment of a download continuing in the background, or run by the service
+pub const BACKGROUND_ENV: &str = "RTGET_BACKGROUND";
+
+/// Tells whether this process is a download continuing in the background, with nobody to answer it.
+pub fn in_background() -> bool {
+    std::env::var_os(BACKGROUND_ENV).is_some()
 }
 
 /// Cross-platform daemonization function.
-pub fn daemonize() {
-    #[cfg(target_os = "linux")]
-    linux::daemonize();
+///
+/// The download continues in a detached process appending its messages to `log`, and this one may exit.
+pub fn daemonize(log: &str) -> Result<(), AppError> {
+    #[cfg(unix)]
+    {
+        let pid = unix::daemonize(log)?;
+        println!("Continuing in background, pid {}.\nOutput will be written to {}.", pid, log);
+        Ok(())
+    }
 
     #[cfg(target_os = "windows")]
-    windows::daemonize();
-}
\ No newline at end of file
+    {
+        let pid = windows::daemonize(log)?;
+        println!("Continuing in background, pid {}.\nOutput will be written to {}.", pid, log);
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, target_os = "windows")))]
+    Err(AppError::StringError("--background is not supported on this platform".to_string()))
+}
+
+/// Runs an action of `rtget service`: install, uninstall, start or stop the Windows service, or run as it.
+pub fn service(args: &ServiceArgs) -> Result<(), AppError> {
+    #[cfg(target_os = "windows")]
+    {
+        match args.action.as_str() {
+            "install" => {
+                crate::args::service_download_args(&args.args)?;
+                windows::install(std::env::current_dir()?, &args.args)?;
+                println!("Installed the rtget service; it starts with Windows, or now with: rtget service start");
+                Ok(())
+            }
+            "uninstall" => windows::uninstall(),
+            "start" => windows::start(),
+            "stop" => windows::stop(),
+            "run" => windows::run(args.directory.clone().unwrap_or_default().into(), args.args.clone()),
+            action => Err(AppError::StringError(format!("unknown service action {}, expected install, uninstall, start or stop", action))),
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    Err(AppError::StringError(format!("rtget service {} manages a Windows service; elsewhere use -b or install-launchd", args.action)))
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use reqwest::header::ETAG;
+use crate::downloader::RemoteFile;
+use crate::filesystem;
+use crate::mirrors;
+
+// The files saved so far with --dedupe-content, by the content key of their remote file
+static SAVED: Mutex<Option<HashMap<String, PathBuf>>> = Mutex::new(None);
+
+/// Returns a key naming the content of the remote file, the same for every URL serving the same bytes.
+///
+/// The key is the `Digest` the server announces or, without one, its strong ETag with the size of
+/// the file. ETags are only compared between URLs of the same host, which is what issued them.
+pub fn content_key(remote: &RemoteFile) -> Option<String> {
+    if let Some(digest) = mirrors::parse_digest(&remote.headers) {
+        let hex: String = digest.value.iter().map(|byte| format!("{:02x}", byte)).collect();
+        return Some(format!("{}={}", digest.algorithm.as_str(), hex));
+    }
+    let etag = remote.headers.get(ETAG).and_then(|value| value.to_str().ok()).filter(|etag| !etag.starts_with("W/"))?;
+    Some(format!("{} {} {}", remote.url.host_str()?, remote.size?, etag))
+}
+
+/// Returns where a file with the content `key` was saved earlier in this run, if it still exists.
+pub fn saved(key: &str) -> Option<PathBuf> {
+    let saved = SAVED.lock().unwrap_or_else(|e| e.into_inner());
+    saved.as_ref()?.get(key).filter(|path| path.is_file()).cloned()
+}
+
+/// Remembers that the file with the content `key` was saved at `path`.
+pub fn remember(key: String, path: &Path) {
+    let mut saved = SAVED.lock().unwrap_or_else(|e| e.into_inner());
+    saved.get_or_insert_with(HashMap::new).insert(key, path.to_path_buf());
+}
+
+/// Saves `output` as a hard link to `source`, or as a copy where they cannot share a file, replacing any file there.
+///
+/// Returns the size of the file.
+pub fn link_or_copy(source: &Path, output: &Path) -> io::Result<u64> {
+    let size = std::fs::metadata(source)?.len();
+    if output == source {
+        return Ok(size);
+    }
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Like a download, the file only appears under its name once it is complete
+    let part_path = filesystem::part_path(output);
+    if part_path.exists() {
+        std::fs::remove_file(&part_path)?;
+    }
+    if let Err(e) = std::fs::hard_link(source, &part_path) {
+        tracing::debug!("Could not link {} to {} ({}), copying it", part_path.display(), source.display(), e);
+        std::fs::copy(source, &part_path)?;
+    }
+    std::fs::rename(&part_path, output)?;
+    Ok(size)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use url::Url;
+
+    #[test]
+    fn test_content_key() {
+        let remote = |url: &str, etag: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ETAG, HeaderValue::from_static(etag));
+            RemoteFile { size: Some(8), url: Url::parse(url).unwrap(), headers, accepts_ranges: true, address: None, version: None }
+        };
+        let key = content_key(&remote("http://a/latest.tar.gz", "\"v1\""));
+        assert_eq!(key, content_key(&remote("http://a/1.0.tar.gz", "\"v1\"")));
+        assert_ne!(key, content_key(&remote("http://b/1.0.tar.gz", "\"v1\"")));
+        assert_eq!(content_key(&remote("http://a/latest.tar.gz", "W/\"v1\"")), None);
+
+        let mut digested = remote("http://b/1.0.tar.gz", "W/\"v1\"");
+        digested.headers.insert("digest", HeaderValue::from_static("sha-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="));
+        assert_eq!(
+            content_key(&digested).as_deref(),
+            Some("sha256=e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn test_link_or_copy() {
+        let dir = test_server::temp_dir("dedupe");
+        let source = dir.join("a.bin");
+        std::fs::write(&source, b"artifact").unwrap();
+        std::fs::write(dir.join("b.bin"), b"old").unwrap();
+        assert_eq!(link_or_copy(&source, &dir.join("b.bin")).unwrap(), 8);
+        assert_eq!(link_or_copy(&source, &dir.join("copies/c.bin")).unwrap(), 8);
+        assert_eq!(std::fs::read(dir.join("b.bin")).unwrap(), b"artifact");
+        assert_eq!(std::fs::read(dir.join("copies/c.bin")).unwrap(), b"artifact");
+        assert_eq!(link_or_copy(&source, &source).unwrap(), 8);
+    }
+}
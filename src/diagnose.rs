@@ -0,0 +1,229 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use url::Url;
+use crate::downloader::{describe_session, ClientOptions, Downloader, FileDownloader};
+
+// Time allowed for each diagnostic step
+const STAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Proxy variables honoured by the HTTP client, the direct DNS and TCP checks bypass them
+const PROXY_VARIABLES: &[&str] = &["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"];
+
+/// The stages of reaching a server, in the order they are attempted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Dns,
+    Tcp,
+    Tls,
+    Http,
+}
+
+impl Stage {
+    // Name shown in the report
+    fn as_str(self) -> &'static str {
+        match self {
+            Stage::Dns => "DNS",
+            Stage::Tcp => "TCP",
+            Stage::Tls => "TLS",
+            Stage::Http => "HTTP",
+        }
+    }
+}
+
+/// The outcome of each stage up to the first failure, plus a hint about that failure
+pub struct Diagnosis {
+    url: Url,
+    proxy: Option<String>,
+    stages: Vec<(Stage, Result<String, String>)>,
+    hint: Option<String>,
+}
+
+impl Diagnosis {
+    /// Returns the first stage that failed, if any.
+    pub fn failed_stage(&self) -> Option<Stage> {
+        self.stages.iter().find(|(_, outcome)| outcome.is_err()).map(|(stage, _)| *stage)
+    }
+
+    /// Renders the diagnosis as a human readable report.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let _ = writeln!(output, "Diagnosing {}", self.url);
+        if let Some(proxy) = &self.proxy {
+            let _ = writeln!(output, "  note    proxy     {} (the DNS and TCP checks connect directly)", proxy);
+        }
+        for (stage, outcome) in &self.stages {
+            let (status, detail) = match outcome {
+                Ok(detail) => ("ok", detail),
+                Err(error) => ("failed", error),
+            };
+            let _ = writeln!(output, "  {:<7} {:<9} {}", status, stage.as_str(), detail);
+        }
+        if let Some(hint) = &self.hint {
+            let _ = writeln!(output, "Hint: {}", hint);
+        }
+        output
+    }
+
+    // Record the failing stage and its hint, ending the diagnosis
+    fn fail(mut self, stage: Stage, error: String, hint: Option<String>) -> Diagnosis {
+        self.stages.push((stage, Err(error)));
+        self.hint = hint;
+        self
+    }
+}
+
+/// Checks each stage of reaching `url` in turn (DNS, TCP, TLS, HTTP) and stops at the first failure.
+pub async fn diagnose(url: &Url, options: &ClientOptions) -> Diagnosis {
+    let proxy = PROXY_VARIABLES
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()).map(|v| format!("{}={}", name, v)));
+    let behind_proxy = proxy.is_some();
+    let mut diagnosis = Diagnosis { url: url.clone(), proxy, stages: Vec::new(), hint: None };
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        diagnosis.stages.push((Stage::Dns, Err("the URL has no host".to_string())));
+        return diagnosis;
+    };
+
+    // DNS
+    let addresses: Vec<SocketAddr> = match timeout(STAGE_TIMEOUT, tokio::net::lookup_host((host, port))).await {
+        Ok(Ok(addresses)) => addresses.collect(),
+        Ok(Err(e)) => return diagnosis.fail(Stage::Dns, e.to_string(), dns_hint(behind_proxy)),
+        Err(_) => return diagnosis.fail(Stage::Dns, "timed out".to_string(), dns_hint(behind_proxy)),
+    };
+    let listed = addresses.iter().map(|a| a.ip().to_string()).collect::<Vec<_>>().join(", ");
+    diagnosis.stages.push((Stage::Dns, Ok(listed)));
+
+    // TCP, trying every address like the client would
+    let mut failures = Vec::new();
+    let mut connected = None;
+    for address in &addresses {
+        match timeout(STAGE_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(_)) => {
+                connected = Some(*address);
+                break;
+            }
+            Ok(Err(e)) => failures.push((*address, e.kind(), e.to_string())),
+            Err(_) => failures.push((*address, io::ErrorKind::TimedOut, "timed out".to_string())),
+        }
+    }
+    match connected {
+        Some(address) => diagnosis.stages.push((Stage::Tcp, Ok(format!("connected to {}", address)))),
+        None => {
+            let errors = failures.iter().map(|(address, _, error)| format!("{}: {}", address, error)).collect::<Vec<_>>().join("; ");
+            let hint = tcp_hint(&failures, port, behind_proxy);
+            return diagnosis.fail(Stage::Tcp, errors, hint);
+        }
+    }
+
+    // TLS
+    if url.scheme() == "https" {
+        match timeout(STAGE_TIMEOUT, describe_session(url, options)).await {
+            Ok(Ok(session)) => diagnosis.stages.push((Stage::Tls, Ok(session))),
+            Ok(Err(e)) => return diagnosis.fail(Stage::Tls, e.to_string(), tls_hint(url, options)),
+            Err(_) => return diagnosis.fail(Stage::Tls, "handshake timed out".to_string(), tls_hint(url, options)),
+        }
+    }
+
+    // HTTP
+    let probed = match FileDownloader::with_options(options) {
+        Ok(downloader) => timeout(STAGE_TIMEOUT, downloader.probe(url.as_str())).await.map_err(|_| "timed out".to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match probed {
+        Ok(Ok(remote)) => {
+            let size = remote.size.map_or_else(|| "unknown size".to_string(), |size| format!("{} bytes", size));
+            diagnosis.stages.push((Stage::Http, Ok(format!("file found, {}", size))));
+            diagnosis
+        }
+        Ok(Err(e)) => {
+            let hint = e.status().and_then(http_hint);
+            diagnosis.fail(Stage::Http, e.to_string(), hint)
+        }
+        Err(error) => diagnosis.fail(Stage::Http, error, None),
+    }
+}
+
+// Explain a failed name lookup
+fn dns_hint(proxy: bool) -> Option<String> {
+    Some(if proxy {
+        "The host name does not resolve locally; with a proxy configured only the proxy needs to resolve it, so check the proxy itself".to_string()
+    } else {
+        "Check the spelling of the host name; if this network only allows traffic through a proxy, set HTTPS_PROXY".to_string()
+    })
+}
+
+// Explain a failed TCP connection from the errors of every address tried
+fn tcp_hint(failures: &[(SocketAddr, io::ErrorKind, String)], port: u16, proxy: bool) -> Option<String> {
+    if !failures.is_empty() && failures.iter().all(|(address, _, _)| address.is_ipv6()) {
+        return Some("The host only resolved to IPv6 addresses and none are reachable; this network may lack IPv6 connectivity".to_string());
+    }
+    if failures.iter().any(|(_, kind, _)| *kind == io::ErrorKind::ConnectionRefused) {
+        return Some(format!("Nothing is listening on port {}; check the port in the URL", port));
+    }
+    if proxy {
+        return Some("Direct connections are blocked, which is expected when a proxy is required; the download itself goes through the proxy".to_string());
+    }
+    Some("The connection attempt went unanswered; a firewall may be dropping it or a proxy may be required (set HTTPS_PROXY)".to_string())
+}
+
+// Explain a failed TLS handshake
+fn tls_hint(url: &Url, options: &ClientOptions) -> Option<String> {
+    if !options.pinned_pubkeys.is_empty() {
+        return Some("A public key pin is configured; check that --pinned-pubkey still matches the server key".to_string());
+    }
+    if options.tls_min_version.is_some() || options.tls_max_version.is_some() || !options.cipher_suites.is_empty() {
+        return Some("The server may not support the configured TLS versions or cipher suites".to_string());
+    }
+    if matches!(url.host(), Some(url::Host::Ipv4(_)) | Some(url::Host::Ipv6(_))) {
+        return Some("No SNI is sent to an IP address and certificates rarely cover one; use the host name instead".to_string());
+    }
+    Some("The certificate may be expired, self-signed or issued for another name (SNI), or a TLS-intercepting proxy is in the way".to_string())
+}
+
+// Explain an HTTP error status
+fn http_hint(status: u16) -> Option<String> {
+    match status {
+        401 | 403 => Some("The server refused access; the link may have expired or require authentication".to_string()),
+        404 | 410 => Some("The file does not exist at this URL; check the path".to_string()),
+        407 => Some("The proxy requires authentication; add credentials to the proxy URL".to_string()),
+        429 => Some("The server is rate limiting requests; try again later or with fewer connections".to_string()),
+        500..=599 => Some("The server reported an internal problem; try again later".to_string()),
+        _ => None,
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_diagnose_reachable_server() {
+        let url = Url::parse(&test_server::serve(vec![0; 10])).unwrap();
+        let diagnosis = diagnose(&url, &ClientOptions::default()).await;
+        assert_eq!(diagnosis.failed_stage(), None);
+        assert!(diagnosis.render().contains("ok      HTTP      file found, 10 bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_closed_port() {
+        // Bind and release a port so nothing is listening on it
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/file.bin", port)).unwrap();
+        let diagnosis = diagnose(&url, &ClientOptions::default()).await;
+        assert_eq!(diagnosis.failed_stage(), Some(Stage::Tcp));
+        assert!(diagnosis.render().contains(&format!("Hint: Nothing is listening on port {}", port)));
+    }
+
+    #[test]
+    fn test_http_hint() {
+        assert!(http_hint(403).unwrap().contains("refused access"));
+        assert!(http_hint(302).is_none());
+    }
+}
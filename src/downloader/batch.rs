@@ -0,0 +1,134 @@
+use indicatif::ProgressBar;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+// Longest time received bytes are held back before they are written, however few they are
+const MAX_HOLD: Duration = Duration::from_millis(500);
+
+/// Collects the pieces of a response body into larger writes of the sink
+///
+/// Connections deliver the body in pieces of a few KiB, and writing each one on its own costs a
+/// seek and a write per piece. The batch writes once it has `size` bytes, ending each write on a
+/// multiple of `size` in the file, so a range starting in the middle of a block fills that block
+/// up first and the writes after it are aligned. Bytes only count on the progress bar once they are
+/// written, so the bar never runs ahead of the sink and a retry resumes at the right byte. On slow
+/// connections a batch is written after half a second, however small. A size of 0 writes every
+/// piece as it arrives.
+pub struct WriteBatch<'a, W> {
+    sink: &'a mut W,
+    progress: &'a ProgressBar,
+    size: usize,
+    buffer: Vec<u8>,
+    // Position in the file of the first byte in the buffer
+    offset: u64,
+    // When the buffer was last written
+    written_at: Instant,
+}
+
+impl<'a, W: AsyncWrite + Unpin> WriteBatch<'a, W> {
+    /// Batches the writes into `sink` of the bytes from `offset` in the file on, `size` bytes at a time.
+    pub fn new(sink: &'a mut W, progress: &'a ProgressBar, size: usize, offset: u64) -> WriteBatch<'a, W> {
+        WriteBatch { sink, progress, size, buffer: Vec::with_capacity(size), offset, written_at: Instant::now() }
+    }
+
+    /// Adds the next `bytes` of the body, writing the batches they complete.
+    pub async fn push(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        if self.size == 0 {
+            self.buffer.extend_from_slice(bytes);
+            return self.write_out().await;
+        }
+        while !bytes.is_empty() {
+            // Bytes missing up to the next multiple of the batch size
+            let end = self.offset + self.buffer.len() as u64;
+            let room = self.size - (end % self.size as u64) as usize;
+            let (batch, rest) = bytes.split_at(room.min(bytes.len()));
+            self.buffer.extend_from_slice(batch);
+            bytes = rest;
+            if batch.len() == room {
+                self.write_out().await?;
+            }
+        }
+        if self.written_at.elapsed() >= MAX_HOLD {
+            self.write_out().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes what is left in the batch and flushes the sink.
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.write_out().await?;
+        self.sink.flush().await
+    }
+
+    // Write the buffered bytes and count them on the progress bar
+    async fn write_out(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.sink.write_all(&self.buffer).await?;
+            self.progress.inc(self.buffer.len() as u64);
+            self.offset += self.buffer.len() as u64;
+            self.buffer.clear();
+        }
+        self.written_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Records the length of every write
+    #[derive(Default)]
+    struct Writes(Vec<usize>);
+
+    impl AsyncWrite for Writes {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().0.push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_batched_and_aligned() {
+        let (mut sink, progress) = (Writes::default(), ProgressBar::hidden());
+        // A range starting 100 bytes before a block boundary fills up that block first
+        let mut batch = WriteBatch::new(&mut sink, &progress, 1000, 900);
+        for _ in 0..25 {
+            batch.push(&[0; 100]).await.unwrap();
+        }
+        assert_eq!(progress.position(), 2100, "only written bytes are counted");
+        batch.finish().await.unwrap();
+        assert_eq!(sink.0, [100, 1000, 1000, 400]);
+        assert_eq!(progress.position(), 2500);
+
+        // Without batching every piece is written as it arrives
+        let mut sink = Writes::default();
+        let mut batch = WriteBatch::new(&mut sink, &progress, 0, 0);
+        batch.push(&[0; 10]).await.unwrap();
+        batch.push(&[0; 20]).await.unwrap();
+        batch.finish().await.unwrap();
+        assert_eq!(sink.0, [10, 20]);
+    }
+
+    #[tokio::test]
+    async fn test_slow_bytes_are_not_held_back() {
+        let (mut sink, progress) = (Writes::default(), ProgressBar::hidden());
+        let mut batch = WriteBatch::new(&mut sink, &progress, 1000, 0);
+        batch.push(&[0; 10]).await.unwrap();
+        tokio::time::sleep(MAX_HOLD).await;
+        batch.push(&[0; 10]).await.unwrap();
+        assert_eq!(progress.position(), 20);
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+// Resolved addresses per host and when they were resolved
+type Entries = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+/// A DNS cache shared by every client of a download
+///
+/// CDNs often hand out a different edge on every lookup; resolving a host once and reusing the
+/// answer keeps all chunks on the same servers. Clones share the same entries.
+#[derive(Clone, Default)]
+pub struct DnsCache {
+    // How long an answer is reused, `None` keeps it for the lifetime of the process
+    ttl: Option<Duration>,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl DnsCache {
+    /// Creates a cache whose answers expire after `ttl`.
+    ///
+    /// A zero TTL disables caching, `None` never expires answers.
+    pub fn new(ttl: Option<Duration>) -> DnsCache {
+        DnsCache { ttl, entries: Arc::default() }
+    }
+
+    // Cached addresses of `host`, if they have not expired
+    fn get(&self, host: &str) -> Option<Vec<SocketAddr>> {
+        let entries = self.entries.lock().ok()?;
+        let (resolved_at, addresses) = entries.get(host)?;
+        self.ttl.is_none_or(|ttl| resolved_at.elapsed() < ttl).then(|| addresses.clone())
+    }
+
+    // Remember the addresses of `host`
+    fn insert(&self, host: &str, addresses: Vec<SocketAddr>) {
+        if self.ttl == Some(Duration::ZERO) {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(host.to_string(), (Instant::now(), addresses));
+        }
+    }
+}
+
+impl Resolve for DnsCache {
+    fn resolve(&self, name: Name) -> Resolving {
+        let cache = self.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses = match cache.get(host) {
+                Some(addresses) => addresses,
+                None => {
+                    // The port is filled in by the client
+                    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+                    tracing::info!("Resolved {} to {:?}", host, addresses.iter().map(SocketAddr::ip).collect::<Vec<_>>());
+                    cache.insert(host, addresses.clone());
+                    addresses
+                }
+            };
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_answers_are_cached() {
+        let cache = DnsCache::new(None);
+        let addresses: Vec<_> = cache.resolve(Name::from_str("localhost").unwrap()).await.unwrap().collect();
+        assert!(!addresses.is_empty());
+        assert_eq!(cache.get("localhost"), Some(addresses));
+
+        // Clones share the entries
+        assert!(cache.clone().get("localhost").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_disables_caching() {
+        let cache = DnsCache::new(Some(Duration::ZERO));
+        let _ = cache.resolve(Name::from_str("localhost").unwrap()).await.unwrap();
+        assert_eq!(cache.get("localhost"), None);
+    }
+}
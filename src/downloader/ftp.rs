@@ -1,37 +1,221 @@
-use reqwest;
+use indicatif::ProgressBar;
 use reqwest::Client;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use url::Url;
 use crate::error::AppError;
+use super::http::RequestContext;
+use super::RemoteFile;
 
-pub async fn download(client: &Client, url: &str, start: usize, end: usize) -> Result<(), String> {
+pub async fn download<W>(client: &Client, url: &str, context: &RequestContext, start: usize, end: usize, sink: &mut W, progress: &ProgressBar) -> Result<(), AppError>
+where
+    W: AsyncWrite + Unpin,
+{
     // Perform FTP request
-    match client.get(url).header("Range", format!("bytes={}-{}", start, end)).send().await {
-        Ok(response) => {
-            if response.status().is_success() {
-                let mut stream = response.bytes_stream();
-                Ok(())
-            } else {
-                Err(AppError::CouldNotConnect(response.status().to_string())).unwrap()}
+    let mut response = client.get(url).header("Range", format!("bytes={}-{}", start, end)).send().await?;
+    if !response.status().is_success() {
+        return Err(AppError::from(response.status()));
+    }
+    let mut batch = context.batch(sink, progress, start as u64);
+    while let Some(chunk) = context.receive(&mut response).await? {
+        batch.push(&chunk).await?;
+    }
+    batch.finish().await?;
+    Ok(())
+}
+
+pub async fn download_whole<W>(client: &Client, url: &str, context: &RequestContext, sink: &mut W, progress: &ProgressBar, max_size: Option<u64>) -> Result<(), AppError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut response = client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(AppError::from(response.status()));
+    }
+    let mut written = 0u64;
+    let mut batch = context.batch(sink, progress, 0);
+    while let Some(chunk) = context.receive(&mut response).await? {
+        written += chunk.len() as u64;
+        if let Some(max) = max_size.filter(|&max| written > max) {
+            return Err(AppError::FileTooLarge(max));
         }
-        Err(e) => Err(AppError::CouldNotConnect(e.to_string())).unwrap(),
+        batch.push(&chunk).await?;
     }
+    batch.finish().await?;
+    Ok(())
 }
 
-pub async fn get_total_file_size(client: &Client, url: &str) -> Result<usize, String> {
-    match client.head(url).send().await {
-        Ok(response) => {
-            if response.status().is_success() {
-                if let Some(content_length) = response.headers().get(reqwest::header::CONTENT_LENGTH) {
-                    if let Ok(content_length_str) = content_length.to_str() {
-                        if let Ok(size) = content_length_str.parse::<usize>() {
-                            return Ok(size);
-                        }
-                    }
+pub async fn probe(client: &Client, url: &str) -> Result<RemoteFile, AppError> {
+    let response = client.head(url).send().await?;
+    if response.status().is_success() {
+        if let Some(content_length) = response.headers().get(reqwest::header::CONTENT_LENGTH) {
+            if let Ok(content_length_str) = content_length.to_str() {
+                if let Ok(size) = content_length_str.parse::<usize>() {
+                    let headers = response.headers().clone();
+                    return Ok(RemoteFile { size: Some(size), url: response.url().clone(), headers, accepts_ranges: true, address: response.remote_addr(), version: None });
                 }
-                Err("Failed to parse content length".to_string())
-            } else {
-                Err(AppError::CouldNotConnect(response.status().to_string())).unwrap()
             }
         }
-        Err(e) => Err(AppError::CouldNotConnect(e.to_string())).unwrap(),
+        Err(AppError::StringError("Failed to parse content length".to_string()))
+    } else {
+        Err(AppError::from(response.status()))
+    }
+}
+
+// Lists the names of the files in the directory of `directory`, an ftp:// URL ending in a slash
+// Logs in with the credentials of the URL, or anonymously, and reads an NLST over a passive data connection
+pub async fn list(directory: &Url) -> Result<Vec<String>, AppError> {
+    let host = directory.host_str().ok_or_else(|| AppError::UrlValidationError(format!("{} has no host", directory)))?;
+    let port = directory.port_or_known_default().unwrap_or(21);
+    let (reader, mut writer) = TcpStream::connect((host, port)).await?.into_split();
+    let mut control = BufReader::new(reader);
+    expect(&mut control, &[220]).await?;
+
+    let user = match directory.username() {
+        "" => "anonymous".to_string(),
+        user => decode(user),
+    };
+    let password = directory.password().map_or_else(|| "rtget@".to_string(), decode);
+    if command(&mut writer, &mut control, &format!("USER {}", user), &[230, 331]).await? == 331 {
+        command(&mut writer, &mut control, &format!("PASS {}", password), &[230]).await?;
+    }
+    let (_, passive) = send(&mut writer, &mut control, "PASV", &[227]).await?;
+    let data_port = passive_port(&passive).ok_or_else(|| AppError::CouldNotConnect(format!("unexpected PASV reply {}", passive)))?;
+    // The data connection goes to the host of the control connection, whatever address a server behind NAT advertises
+    let mut data = TcpStream::connect((host, data_port)).await?;
+    let path = decode(directory.path());
+    command(&mut writer, &mut control, &format!("NLST {}", path), &[125, 150]).await?;
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).await?;
+    expect(&mut control, &[226, 250]).await?;
+    let _ = writer.write_all(b"QUIT\r\n").await;
+
+    // Some servers list the names with the path of the directory in front
+    Ok(listing.lines().map(|line| line.trim().rsplit('/').next().unwrap_or_default().to_string()).filter(|name| !name.is_empty()).collect())
+}
+
+// Send a command and read the code of its reply, which must be one of `expected`
+async fn command<W>(writer: &mut W, control: &mut BufReader<tokio::net::tcp::OwnedReadHalf>, line: &str, expected: &[u16]) -> Result<u16, AppError>
+where
+    W: AsyncWrite + Unpin,
+{
+    send(writer, control, line, expected).await.map(|(code, _)| code)
+}
+
+// Send a command and read its reply, whose code must be one of `expected`
+async fn send<W>(writer: &mut W, control: &mut BufReader<tokio::net::tcp::OwnedReadHalf>, line: &str, expected: &[u16]) -> Result<(u16, String), AppError>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    expect(control, expected).await
+}
+
+// Read a reply, spanning several lines like `230-Welcome` up to `230 Done`, whose code must be one of `expected`
+async fn expect(control: &mut BufReader<tokio::net::tcp::OwnedReadHalf>, expected: &[u16]) -> Result<(u16, String), AppError> {
+    let mut line = String::new();
+    control.read_line(&mut line).await?;
+    let code: u16 = line.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| AppError::CouldNotConnect(format!("unexpected FTP reply {:?}", line.trim_end())))?;
+    let mut reply = line.clone();
+    if line.as_bytes().get(3) == Some(&b'-') {
+        let last = format!("{} ", code);
+        loop {
+            line.clear();
+            if control.read_line(&mut line).await? == 0 || line.starts_with(&last) {
+                break;
+            }
+        }
+        reply = line.clone();
+    }
+    match expected.contains(&code) {
+        true => Ok((code, reply.trim_end().to_string())),
+        false => Err(AppError::FtpReply(code, reply.trim_end().to_string())),
     }
-}
\ No newline at end of file
+}
+
+// The data port of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply
+fn passive_port(reply: &str) -> Option<u16> {
+    let numbers = &reply[reply.find('(')? + 1..reply.rfind(')')?];
+    let numbers: Vec<u16> = numbers.split(',').map(|number| number.trim().parse().ok()).collect::<Option<_>>()?;
+    match numbers[..] {
+        [_, _, _, _, high, low] if high < 256 && low < 256 => Some(high * 256 + low),
+        _ => None,
+    }
+}
+
+// Percent-decode a part of the URL for the FTP commands
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = value.get(index + 1..index + 3).filter(|_| bytes[index] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_passive_port() {
+        assert_eq!(passive_port("227 Entering Passive Mode (192,168,1,2,19,137)."), Some(5001));
+        assert_eq!(passive_port("227 Entering Passive Mode"), None);
+        assert_eq!(passive_port("227 (1,2,3,4,300,1)"), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("/pub/my%20logs/"), "/pub/my logs/");
+        assert_eq!(decode("user%40example.com"), "user@example.com");
+        assert_eq!(decode("100%"), "100%");
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        // A server answering just the commands of a listing
+        let control = TcpListener::bind("127.0.0.1:0").unwrap();
+        let data = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (control_port, data_port) = (control.local_addr().unwrap().port(), data.local_addr().unwrap().port());
+        std::thread::spawn(move || {
+            let (stream, _) = control.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220-Welcome\r\n220 Ready\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let reply = match line.split_whitespace().next().unwrap_or_default() {
+                    "USER" => "331 Password required\r\n".to_string(),
+                    "PASS" => "230 Logged in\r\n".to_string(),
+                    "PASV" => format!("227 Entering Passive Mode (10,0,0,1,{},{})\r\n", data_port / 256, data_port % 256),
+                    "NLST" => {
+                        assert_eq!(line.trim_end(), "NLST /pub/logs/");
+                        writer.write_all(b"150 Here it comes\r\n").unwrap();
+                        let (mut listing, _) = data.accept().unwrap();
+                        listing.write_all(b"2024-01.gz\r\n/pub/logs/2024-02.gz\r\nREADME\r\n").unwrap();
+                        "226 Done\r\n".to_string()
+                    }
+                    _ => break,
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+                line.clear();
+            }
+        });
+        let directory = Url::parse(&format!("ftp://127.0.0.1:{}/pub/logs/", control_port)).unwrap();
+        assert_eq!(list(&directory).await.unwrap(), ["2024-01.gz", "2024-02.gz", "README"]);
+    }
+}
@@ -1,53 +1,501 @@
-use reqwest;
-use reqwest::Client;
+use indicatif::ProgressBar;
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant as StdInstant};
+use reqwest::header::{HeaderMap, HeaderName};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+use crate::cache::CacheEntry;
 use crate::error::AppError;
+use crate::replay::{self, EventKind};
+use super::auth::AuthProvider;
+use super::batch::WriteBatch;
+use super::hooks::RequestHook;
+use super::throttle::Throttle;
+use super::{RemoteFile, RequestSpec};
 
-// Download a file from an HTTP URL
+// Request header asking for an instance digest, not among the predefined header names
+const WANT_DIGEST: HeaderName = HeaderName::from_static("want-digest");
+
+/// What every request for one URL goes through: the presets of its host, its credentials, the request hooks
+/// and the bandwidth limits and timeouts of its connection
+pub struct RequestContext {
+    url: Url,
+    presets: HeaderMap,
+    auth: Option<Arc<dyn AuthProvider>>,
+    hooks: Vec<Arc<dyn RequestHook>>,
+    throttle: Throttle,
+    stall_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    buffer_size: usize,
+}
+
+impl RequestContext {
+    /// Combines the preset headers of the host of `url` with the credentials `auth` has for it.
+    pub fn new(url: Url, presets: HeaderMap, auth: Option<Arc<dyn AuthProvider>>) -> RequestContext {
+        RequestContext { url, presets, auth, hooks: Vec::new(), throttle: Throttle::default(), stall_timeout: None, deadline: None, buffer_size: 0 }
+    }
+
+    /// Runs every request through `hooks` as well.
+    pub fn with_hooks(mut self, hooks: Vec<Arc<dyn RequestHook>>) -> RequestContext {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Receives the response bodies within the limits of `throttle`.
+    pub fn with_throttle(mut self, throttle: Throttle) -> RequestContext {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Gives up a response body that delivers nothing for `stall_timeout`.
+    pub fn with_stall_timeout(mut self, stall_timeout: Option<Duration>) -> RequestContext {
+        self.stall_timeout = stall_timeout;
+        self
+    }
+
+    /// Gives up every request that is not done by `deadline`.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> RequestContext {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Collects the bodies into writes of `buffer_size` bytes, or writes every piece as it arrives for 0.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> RequestContext {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Batches the writes into `sink` of a body starting at `offset` in the file.
+    pub fn batch<'a, W: AsyncWrite + Unpin>(&self, sink: &'a mut W, progress: &'a ProgressBar, offset: u64) -> WriteBatch<'a, W> {
+        WriteBatch::new(sink, progress, self.buffer_size, offset)
+    }
+
+    /// Waits for the next piece of the body of `response` and until the bandwidth limits let it through.
+    ///
+    /// Fails once nothing arrived for the stall timeout or the deadline passed; dropping the response
+    /// then closes its connection.
+    pub async fn receive(&self, response: &mut Response) -> Result<Option<impl Deref<Target = [u8]>>, AppError> {
+        let chunk = self.in_time(self.stall_timeout, response.chunk()).await??;
+        if let Some(chunk) = &chunk {
+            self.throttle.consume(chunk.len()).await;
+        }
+        Ok(chunk)
+    }
+
+    // Wait for `future` until the deadline, or for `stall_timeout` if that ends first
+    async fn in_time<T>(&self, stall_timeout: Option<Duration>, future: impl Future<Output = T>) -> Result<T, AppError> {
+        let stalled_at = stall_timeout.map(|timeout| Instant::now() + timeout);
+        let limit = match (stalled_at, self.deadline) {
+            (Some(stalled_at), Some(deadline)) => stalled_at.min(deadline),
+            (Some(limit), None) | (None, Some(limit)) => limit,
+            (None, None) => return Ok(future.await),
+        };
+        tokio::time::timeout_at(limit, future).await.map_err(|_| match stall_timeout {
+            Some(timeout) if stalled_at == Some(limit) => AppError::Stalled(timeout.as_secs()),
+            _ => AppError::TimedOut,
+        })
+    }
+
+    // Headers of the next attempt, asking the provider for its current credentials
+    fn current(&self) -> HeaderMap {
+        let mut headers = self.presets.clone();
+        if let Some(auth) = &self.auth {
+            headers.extend(auth.headers(&self.url));
+        }
+        headers
+    }
+}
+
+// Send the request built by `build`, resending it once if the server rejects the credentials and the provider refreshed them
+// Nothing is written before the response arrives, so every request can be retried this way
+async fn send(client: &Client, context: &RequestContext, build: impl Fn(HeaderMap) -> RequestBuilder) -> Result<Response, AppError> {
+    let response = execute(client, context, build(context.current())).await?;
+    let status = response.status();
+    let retry = matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+        && context.auth.as_ref().is_some_and(|auth| auth.challenge(&context.url, status, response.headers()));
+    if !retry {
+        return Ok(response);
+    }
+    replay::record(EventKind::Fallback, format!("{} answered {}, retrying with refreshed credentials", context.url, status));
+    execute(client, context, build(context.current())).await
+}
+
+// Send a single request through the hooks of the context
+async fn execute(client: &Client, context: &RequestContext, builder: RequestBuilder) -> Result<Response, AppError> {
+    let mut request = builder.build()?;
+    let method = request.method().clone();
+    for hook in &context.hooks {
+        let url = request.url().clone();
+        hook.before_request(&method, &url, request.headers_mut())?;
+    }
+    let sent = StdInstant::now();
+    let response = context.in_time(None, client.execute(request)).await??;
+    for hook in &context.hooks {
+        hook.after_response(&method, response.url(), response.status(), response.headers(), sent.elapsed());
+    }
+    Ok(response)
+}
+
+// Download a byte range of a file from an HTTP URL into `sink`
+// `context` adds the preset headers, credentials and hooks of the host to every request
 // Returns an error message if the download failed
-pub async fn download(client: &Client, url: &str, start: usize, end: usize) -> Result<(), String> {
+pub async fn download<W>(client: &Client, url: &str, context: &RequestContext, start: usize, end: usize, sink: &mut W, progress: &ProgressBar) -> Result<(), AppError>
+where
+    W: AsyncWrite + Unpin,
+{
     // Perform HTTP request
-    match client.get(url).header("Range", format!("bytes={}-{}", start, end)).send().await {
-        // If the request was successful, return the response body as a stream
-        Ok(response) => {
-            // If the request was successful, return the response body as a stream
-            if response.status().is_success() {
-                let mut stream = response.bytes_stream();
-                Ok(())
-            } else {
-                // If the request was not successful, return an error message
-                Err(AppError::CouldNotConnect(response.status().to_string())).unwrap()
-            }
+    let range = format!("bytes={}-{}", start, end);
+    let mut response = send(client, context, |headers| client.get(url).headers(headers).header(reqwest::header::RANGE, &range)).await?;
+
+    // 416 means the remote file is shorter than expected; report its actual size if the server tells us
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        let total = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(content_range_total);
+        return Err(AppError::RangeNotSatisfiable(total));
+    }
+
+    // Anything but 206 means the server did not honour the range and would send the wrong bytes
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        if response.status().is_success() {
+            return Err(AppError::RangeNotSupported);
         }
         // If the request was not successful, return an error message
-        Err(e) => Err(AppError::CouldNotConnect(e.to_string())).unwrap(),
+        return Err(AppError::from(response.status()));
     }
 
+    // Stream the response body into the sink
+    // A server sending more than the requested range would overrun the neighbouring chunk
+    let expected = (end - start + 1) as u64;
+    let mut written = 0u64;
+    let mut batch = context.batch(sink, progress, start as u64);
+    while let Some(chunk) = context.receive(&mut response).await? {
+        written += chunk.len() as u64;
+        if written > expected {
+            return Err(AppError::StringError(format!("the server sent more than the {} requested bytes", expected)));
+        }
+        batch.push(&chunk).await?;
+    }
+    batch.finish().await?;
+    Ok(())
 }
 
-// Get the total file size from the HTTP response headers
-// Returns the total file size in bytes as an usize or an error message if the size could not be parsed
-pub async fn get_total_file_size(client: &Client, url: &str) -> Result<usize, String> {
+// Download the whole file from an HTTP URL into `sink` without a Range header
+// Used for servers that do not support byte ranges and for requests other than a plain GET
+// The transfer is aborted as soon as more than `max_size` bytes arrive, whatever the server announced
+pub async fn download_whole<W>(client: &Client, url: &str, context: &RequestContext, request: &RequestSpec, sink: &mut W, progress: &ProgressBar, max_size: Option<u64>) -> Result<(), AppError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut response = send(client, context, |headers| {
+        // A Content-Type of the user, given with --header or in the presets, is kept as it is
+        let content_type = request.body.as_deref().and_then(body_type).filter(|_| !headers.contains_key(reqwest::header::CONTENT_TYPE));
+        let builder = client.request(request.method.clone(), url).headers(headers);
+        let builder = match content_type {
+            Some(content_type) => builder.header(reqwest::header::CONTENT_TYPE, content_type),
+            None => builder,
+        };
+        match &request.body {
+            Some(body) => builder.body(body.clone()),
+            None => builder,
+        }
+    })
+    .await?;
+    if !response.status().is_success() {
+        return Err(AppError::from(response.status()));
+    }
+    let mut written = 0u64;
+    let mut batch = context.batch(sink, progress, 0);
+    while let Some(chunk) = context.receive(&mut response).await? {
+        written += chunk.len() as u64;
+        if let Some(max) = max_size.filter(|&max| written > max) {
+            return Err(AppError::FileTooLarge(max));
+        }
+        batch.push(&chunk).await?;
+    }
+    batch.finish().await?;
+    Ok(())
+}
+
+// The Content-Type of a request body: JSON, a form of `name=value` pairs like curl sends, or none the body can be told apart by
+fn body_type(body: &[u8]) -> Option<&'static str> {
+    if serde_json::from_slice::<serde_json::Value>(body).is_ok_and(|value| value.is_object() || value.is_array()) {
+        return Some("application/json");
+    }
+    let form = std::str::from_utf8(body).is_ok_and(|text| {
+        text.split('&').all(|pair| pair.split_once('=').is_some_and(|(name, _)| !name.is_empty()) && !pair.contains(char::is_whitespace))
+    });
+    form.then_some("application/x-www-form-urlencoded")
+}
+
+// Probe the file with a HEAD request
+// Many CDNs answer HEAD with 403/405, in which case a one byte ranged GET is used instead
+// Returns the total file size in bytes (if known), the final URL, the response headers and whether ranges are supported
+pub async fn probe(client: &Client, url: &str, context: &RequestContext) -> Result<RemoteFile, AppError> {
     // Perform HTTP request
-    match client.head(url).send().await {
-        // If the request was successful,
-        // parse the content length header and return the size in bytes
-        Ok(response) => {
-            // If the request was successful,
-            // parse the content length header and return the size in bytes
-            if response.status().is_success() {
-                // Get the content length header value as a string
-                response
-                    .headers()
-                    .get(reqwest::header::CONTENT_LENGTH)
-                    .and_then(|v| v.to_str().ok())
-                    .and_then(|s| s.parse().ok())
-                    .ok_or("Could not parse content length". to_string())
-            } else {
-                // If the request was not successful, return an error message
-                Err(AppError::CouldNotConnect(response.status().to_string())).unwrap()
-            }
+    // Ask for an instance digest so the download can be verified (RFC 3230)
+    let response = send(client, context, |headers| client.head(url).headers(headers).header(WANT_DIGEST, "sha-512;q=1, sha-256;q=0.9")).await?;
+    replay::record(EventKind::Probe, format!("HEAD {} -> {}", url, response.status()));
+
+    // If the request was successful,
+    // parse the content length header and return the size in bytes
+    if response.status().is_success() {
+        if let Some(size) = header_number(response.headers(), reqwest::header::CONTENT_LENGTH) {
+            let accepts_ranges = match response.headers().get(reqwest::header::ACCEPT_RANGES).and_then(|v| v.to_str().ok()) {
+                Some(units) => units.trim().eq_ignore_ascii_case("bytes"),
+                // Plenty of servers support ranges without advertising them, so ask
+                None => ranged_probe(client, url, context).await?.accepts_ranges,
+            };
+            return Ok(RemoteFile {
+                size: Some(size),
+                url: response.url().clone(),
+                headers: response.headers().clone(),
+                accepts_ranges,
+                address: response.remote_addr(),
+                version: Some(response.version()),
+            });
         }
+    }
+    tracing::info!("HEAD request answered with {}, falling back to a ranged GET", response.status());
+    ranged_probe(client, url, context).await
+}
+
+// Ask for the first byte only and read the total size from the Content-Range header
+// A 200 answer means the server ignores ranges and its content length is the full size
+// Chunked responses without any length leave the size unknown
+async fn ranged_probe(client: &Client, url: &str, context: &RequestContext) -> Result<RemoteFile, AppError> {
+    let response = send(client, context, |headers| {
+        client
+            .get(url)
+            .headers(headers)
+            .header(reqwest::header::RANGE, "bytes=0-0")
+            .header(WANT_DIGEST, "sha-512;q=1, sha-256;q=0.9")
+    })
+    .await?;
+    replay::record(EventKind::Probe, format!("GET {} bytes=0-0 -> {}", url, response.status()));
+    let size = match response.status() {
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(content_range_total),
+        status if status.is_success() => header_number(response.headers(), reqwest::header::CONTENT_LENGTH),
         // If the request was not successful, return an error message
-        Err(e) => Err(AppError::CouldNotConnect(e.to_string())).unwrap(),
+        status => return Err(AppError::from(status)),
+    };
+    let accepts_ranges = response.status() == StatusCode::PARTIAL_CONTENT;
+    // The body is dropped unread, which closes the connection
+    Ok(RemoteFile { size, url: response.url().clone(), headers: response.headers().clone(), accepts_ranges, address: response.remote_addr(), version: Some(response.version()) })
+}
+
+// Send a conditional GET with the validators of the cached copy
+// Returns true on 304 Not Modified; any other answer is dropped unread and the file is downloaded normally
+pub async fn revalidate(client: &Client, url: &str, context: &RequestContext, cached: &CacheEntry) -> Result<bool, AppError> {
+    let response = send(client, context, |headers| {
+        let mut request = client.get(url).headers(headers);
+        if let Some(etag) = &cached.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    })
+    .await?;
+    replay::record(EventKind::Probe, format!("conditional GET {} -> {}", url, response.status()));
+    Ok(response.status() == StatusCode::NOT_MODIFIED)
+}
+
+// Parse a numeric header value
+fn header_number(headers: &HeaderMap, name: HeaderName) -> Option<usize> {
+    headers.get(name).and_then(|v| v.to_str().ok()).and_then(|s| s.trim().parse().ok())
+}
+
+// Extract the complete length from a `bytes 0-0/12345` or `bytes */12345` Content-Range value
+fn content_range_total(value: &str) -> Option<usize> {
+    value.trim().strip_prefix("bytes")?.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Quirks};
+    use crate::downloader::auth::BearerToken;
+    use crate::downloader::hooks::HostFilter;
+    use reqwest::Method;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Request context without presets, credentials or hooks
+    fn plain(url: &str) -> RequestContext {
+        RequestContext::new(Url::parse(url).unwrap(), HeaderMap::new(), None)
+    }
+
+    #[test]
+    fn test_body_type() {
+        assert_eq!(body_type(br#"{"format": "csv"}"#), Some("application/json"));
+        assert_eq!(body_type(b" [1, 2]\n"), Some("application/json"));
+        assert_eq!(body_type(b"format=csv&from=2024-01-01"), Some("application/x-www-form-urlencoded"));
+        assert_eq!(body_type(b"42"), None);
+        assert_eq!(body_type(b"plain text = not a form"), None);
+        assert_eq!(body_type(b"<export/>"), None);
+        assert_eq!(body_type(b"\xff\xfe"), None);
+    }
+
+    #[test]
+    fn test_content_range_total() {
+        assert_eq!(content_range_total("bytes 0-0/12345"), Some(12345));
+        assert_eq!(content_range_total("bytes */777"), Some(777));
+        assert_eq!(content_range_total("bytes 0-0/*"), None);
+    }
+
+    #[tokio::test]
+    async fn test_download_past_the_end() {
+        let url = test_server::serve(vec![0; 100]);
+        let result = download(&Client::new(), &url, &plain(&url), 200, 299, &mut Vec::new(), &ProgressBar::hidden()).await;
+        assert!(matches!(result, Err(AppError::RangeNotSatisfiable(Some(100)))));
+    }
+
+    #[tokio::test]
+    async fn test_stall_and_deadline() {
+        let stalling = Quirks { stall_first: Some(1000), ..Quirks::default() };
+
+        // The deadline ends a transfer that is still making progress, keeping what arrived
+        let url = test_server::serve_with(vec![1; 4096], stalling);
+        let context = plain(&url).with_deadline(Some(Instant::now() + Duration::from_millis(300)));
+        let (mut sink, progress) = (Vec::new(), ProgressBar::hidden());
+        let result = download(&Client::new(), &url, &context, 0, 4095, &mut sink, &progress).await;
+        assert!(matches!(result, Err(AppError::TimedOut)));
+        assert_eq!((sink.len(), progress.position()), (1000, 1000));
+
+        // A stall that ends first is reported as such, and can be retried
+        let url = test_server::serve_with(vec![1; 4096], stalling);
+        let context = plain(&url).with_stall_timeout(Some(Duration::from_millis(100))).with_deadline(Some(Instant::now() + Duration::from_secs(10)));
+        let result = download(&Client::new(), &url, &context, 0, 4095, &mut Vec::new(), &ProgressBar::hidden()).await;
+        assert!(matches!(result, Err(AppError::Stalled(0))));
+
+        // A request that does not get through in time is given up as well
+        let context = plain(&url).with_deadline(Some(Instant::now()));
+        assert!(matches!(probe(&Client::new(), &url, &context).await, Err(AppError::TimedOut)));
+    }
+
+    #[tokio::test]
+    async fn test_probe_falls_back_when_head_is_rejected() {
+        let url = test_server::serve_with(vec![1; 1000], Quirks { reject_head: true, ..Quirks::default() });
+        let remote = probe(&Client::new(), &url, &plain(&url)).await.unwrap();
+        assert_eq!(remote.size, Some(1000));
+        assert!(remote.accepts_ranges);
+    }
+
+    #[tokio::test]
+    async fn test_probe_detects_ignored_ranges() {
+        let url = test_server::serve_with(vec![1; 1000], Quirks { ignore_range: true, ..Quirks::default() });
+        let remote = probe(&Client::new(), &url, &plain(&url)).await.unwrap();
+        assert_eq!(remote.size, Some(1000));
+        assert!(!remote.accepts_ranges);
+
+        // Ranged requests are refused, the whole file still comes through
+        let mut sink = Vec::new();
+        assert!(matches!(download(&Client::new(), &url, &plain(&url), 0, 99, &mut sink, &ProgressBar::hidden()).await, Err(AppError::RangeNotSupported)));
+        download_whole(&Client::new(), &url, &plain(&url), &RequestSpec::default(), &mut sink, &ProgressBar::hidden(), None).await.unwrap();
+        assert_eq!(sink.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_probe_without_content_length() {
+        let url = test_server::serve_with(vec![3; 5000], Quirks { no_content_length: true, ..Quirks::default() });
+        let remote = probe(&Client::new(), &url, &plain(&url)).await.unwrap();
+        assert_eq!(remote.size, None);
+        assert!(!remote.accepts_ranges);
+
+        // The body is read until the server closes the connection
+        let mut sink = Vec::new();
+        download_whole(&Client::new(), &url, &plain(&url), &RequestSpec::default(), &mut sink, &ProgressBar::hidden(), None).await.unwrap();
+        assert_eq!(sink, vec![3; 5000]);
+    }
+
+    #[tokio::test]
+    async fn test_download_whole_enforces_max_size() {
+        // Without a Content-Length only the bytes actually received can be checked
+        let url = test_server::serve_with(vec![4; 5000], Quirks { no_content_length: true, ..Quirks::default() });
+        let mut sink = Vec::new();
+        let result = download_whole(&Client::new(), &url, &plain(&url), &RequestSpec::default(), &mut sink, &ProgressBar::hidden(), Some(4096)).await;
+        assert!(matches!(result, Err(AppError::FileTooLarge(4096))));
+        assert!(sink.len() <= 4096);
+    }
+
+    #[tokio::test]
+    async fn test_revalidate() {
+        let url = test_server::serve_with(vec![5; 100], Quirks { etag: Some("\"v2\""), ..Quirks::default() });
+        let cached = |etag: &str| CacheEntry { path: "cached".into(), etag: Some(etag.to_string()), last_modified: None, content_type: None };
+        assert!(revalidate(&Client::new(), &url, &plain(&url), &cached("\"v2\"")).await.unwrap());
+        assert!(!revalidate(&Client::new(), &url, &plain(&url), &cached("\"v1\"")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_download_whole_with_post_body() {
+        let url = test_server::serve(vec![0; 10]);
+        let request = RequestSpec { method: reqwest::Method::POST, body: Some(br#"{"export":"all"}"#.to_vec()) };
+        let mut sink = Vec::new();
+        download_whole(&Client::new(), &url, &plain(&url), &request, &mut sink, &ProgressBar::hidden(), None).await.unwrap();
+        // The test server echoes POST bodies
+        assert_eq!(sink, br#"{"export":"all"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_credentials_are_refreshed() {
+        let url = test_server::serve_with(vec![7; 100], Quirks { authorization: Some("Bearer new"), ..Quirks::default() });
+        let dir = test_server::temp_dir("http_auth");
+        let file = dir.join("token");
+        std::fs::write(&file, "old").unwrap();
+        let auth: Arc<dyn AuthProvider> = Arc::new(BearerToken::from_file("127.0.0.1", &file).unwrap());
+        let context = RequestContext::new(Url::parse(&url).unwrap(), HeaderMap::new(), Some(auth));
+
+        // The token in the file is still the rejected one
+        assert!(matches!(probe(&Client::new(), &url, &context).await, Err(AppError::HttpStatus(401, _))));
+
+        // The token is refreshed behind the download's back
+        std::fs::write(&file, "new").unwrap();
+        let mut sink = Vec::new();
+        download(&Client::new(), &url, &context, 0, 9, &mut sink, &ProgressBar::hidden()).await.unwrap();
+        assert_eq!(sink, vec![7; 10]);
+    }
+
+    // Signs every request and records the statuses it sees
+    #[derive(Default)]
+    struct Signer {
+        statuses: Mutex<Vec<StatusCode>>,
+    }
+
+    impl RequestHook for Signer {
+        fn before_request(&self, _method: &Method, _url: &Url, headers: &mut HeaderMap) -> Result<(), AppError> {
+            headers.insert(reqwest::header::AUTHORIZATION, "Signed abc".parse().unwrap());
+            Ok(())
+        }
+
+        fn after_response(&self, _method: &Method, _url: &Url, status: StatusCode, _headers: &HeaderMap, _elapsed: Duration) {
+            self.statuses.lock().unwrap().push(status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_hooks() {
+        let url = test_server::serve_with(vec![7; 100], Quirks { authorization: Some("Signed abc"), ..Quirks::default() });
+        let signer = Arc::new(Signer::default());
+        let context = plain(&url).with_hooks(vec![signer.clone()]);
+        let mut sink = Vec::new();
+        download(&Client::new(), &url, &context, 0, 9, &mut sink, &ProgressBar::hidden()).await.unwrap();
+        assert_eq!(*signer.statuses.lock().unwrap(), vec![StatusCode::PARTIAL_CONTENT]);
+
+        // A veto cancels the request before it is sent
+        let context = plain(&url).with_hooks(vec![signer.clone(), Arc::new(HostFilter::new(&["127.0.0.1".to_string()]))]);
+        assert!(matches!(probe(&Client::new(), &url, &context).await, Err(AppError::RequestRefused(_))));
+        assert_eq!(signer.statuses.lock().unwrap().len(), 1);
     }
 }
\ No newline at end of file
@@ -1,53 +1,417 @@
 mod http;
 mod ftp;
+mod tls;
+mod dns;
+mod presets;
+mod auth;
+mod hooks;
+mod throttle;
+mod batch;
+mod shared;
 
-use reqwest::{Client, Url};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use indicatif::ProgressBar;
+use reqwest::header::HeaderMap;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Method, Url, Version};
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+use crate::args::CommandLineArgs;
+use crate::cache::CacheEntry;
 use crate::error::AppError;
 
+pub use auth::AuthProvider;
+pub use hooks::{redacted_url, RequestHook, DEBUG_HTTP_TARGET};
+pub use shared::multiplexed;
+pub use throttle::{limited, RateLimiter};
+pub use tls::describe_session;
+use http::RequestContext;
+
+// Idle connections kept per host, enough for every connection of a download
+const POOL_MAX_IDLE_PER_HOST: usize = 100;
+
+// Redirects followed for one request, as many as reqwest follows by default
+const MAX_REDIRECTS: usize = 10;
+
+// Settings applied to every client created by a FileDownloader
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    // SHA-256 digests of the accepted server public keys, empty disables pinning
+    pub pinned_pubkeys: Vec<[u8; 32]>,
+    // Lowest TLS version that may be negotiated
+    pub tls_min_version: Option<tls::TlsVersion>,
+    // Highest TLS version that may be negotiated
+    pub tls_max_version: Option<tls::TlsVersion>,
+    // Allowlist of cipher suite names, empty keeps the defaults
+    pub cipher_suites: Vec<String>,
+    // Resolved addresses shared by the clients of all chunks
+    pub dns_cache: dns::DnsCache,
+    // User agents and extra headers sent to each host
+    pub header_presets: presets::HeaderPresets,
+    // Credentials of the requests, if any
+    pub auth: Option<Arc<dyn AuthProvider>>,
+    // Hooks every request goes through, in order
+    pub hooks: Vec<Arc<dyn RequestHook>>,
+    // Bandwidth limit shared by the clients of all chunks, if any
+    pub rate_limit: Option<throttle::RateLimiter>,
+    // Bandwidth limit of each client in bytes per second, if any
+    pub rate_limit_per_connection: Option<u64>,
+    // How long a response body may deliver nothing before its connection is given up, if at all
+    pub stall_timeout: Option<Duration>,
+    // How long connecting to a server may take, if limited
+    pub connect_timeout: Option<Duration>,
+    // How long a single read from a connection may take, if limited
+    pub read_timeout: Option<Duration>,
+    // When every request has to be done, if at all
+    pub deadline: Option<Instant>,
+    // Bytes of a response body collected into one write of the output, 0 writes every piece as it arrives
+    pub buffer_size: usize,
+    // Proxy of every request, instead of those of the environment, if any
+    pub proxy: Option<reqwest::Proxy>,
+    // TLS sessions of the connections, reported once per host, shared by the clients of all chunks
+    pub sessions: tls::SessionLog,
+}
+
+impl ClientOptions {
+    // Build the client options from the command line arguments
+    // Returns an error if one of the options is malformed
+    pub fn from_args(args: &CommandLineArgs) -> Result<Self, AppError> {
+        let pinned_pubkeys = match &args.pinned_pubkey {
+            Some(spec) => tls::parse_pins(spec)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            pinned_pubkeys,
+            tls_min_version: args.tls_min_version.as_deref().map(tls::parse_version).transpose()?,
+            tls_max_version: args.tls_max_version.as_deref().map(tls::parse_version).transpose()?,
+            cipher_suites: args.ciphers.as_deref().map(tls::parse_ciphers).transpose()?.unwrap_or_default(),
+            dns_cache: dns::DnsCache::new(args.dns_cache_ttl.map(Duration::from_secs)),
+            header_presets: match &args.headers_file {
+                Some(path) => presets::HeaderPresets::load(Path::new(path))?,
+                // The default file is optional
+                None => match presets::HeaderPresets::default_path().filter(|path| path.is_file()) {
+                    Some(path) => presets::HeaderPresets::load(&path)?,
+                    None => presets::HeaderPresets::default(),
+                },
+            }
+            .with_overrides(&args.header)
+            .map_err(|e| AppError::InvalidHeaderPresets(format!("--header {}", e)))?,
+            auth: auth_provider(args)?,
+            hooks: request_hooks(args),
+            // A limit of 0 means no limit
+            rate_limit: args.limit_rate.filter(|&rate| rate > 0).map(throttle::RateLimiter::new),
+            rate_limit_per_connection: args.limit_rate_per_conn.filter(|&rate| rate > 0),
+            // A timeout of 0 waits forever
+            stall_timeout: Some(args.stall_timeout).filter(|&seconds| seconds > 0).map(Duration::from_secs),
+            connect_timeout: args.connect_timeout.map(Duration::from_secs),
+            read_timeout: args.read_timeout.map(Duration::from_secs),
+            // --max-time counts from the start of the download
+            deadline: args.max_time.map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+            buffer_size: usize::try_from(args.buffer_size).unwrap_or(usize::MAX),
+            proxy: args
+                .proxy
+                .as_deref()
+                .map(|proxy| reqwest::Proxy::all(proxy).map_err(|e| AppError::StringError(format!("invalid --proxy {}: {}", proxy, e))))
+                .transpose()?,
+            sessions: tls::SessionLog::default(),
+        })
+    }
+}
+
+// Build the request hooks asked for on the command line
+fn request_hooks(args: &CommandLineArgs) -> Vec<Arc<dyn RequestHook>> {
+    let mut hooks: Vec<Arc<dyn RequestHook>> = Vec::new();
+    if !args.deny_host.is_empty() {
+        hooks.push(Arc::new(hooks::HostFilter::new(&args.deny_host)));
+    }
+    // Last, to log the headers the other hooks leave
+    if args.debug_http {
+        hooks.push(Arc::new(hooks::DebugHttp));
+    }
+    hooks
+}
+
+// Build the credentials given by --user, --bearer-token or --netrc, in that order of precedence
+// Credentials from the command line are only sent to the host of the URL being downloaded
+fn auth_provider(args: &CommandLineArgs) -> Result<Option<Arc<dyn AuthProvider>>, AppError> {
+    let host = args.url.first().and_then(|url| Url::parse(url).ok()).and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+    if let Some(user) = &args.user {
+        let (user, password) = user
+            .split_once(':')
+            .ok_or_else(|| AppError::InvalidCredentials("--user expects user:password".to_string()))?;
+        return Ok(Some(Arc::new(auth::BasicAuth::new(&host, user, password))));
+    }
+    if let Some(token) = &args.bearer_token {
+        let token = match token.strip_prefix('@') {
+            Some(file) => auth::BearerToken::from_file(&host, Path::new(file))?,
+            None => auth::BearerToken::new(&host, token),
+        };
+        return Ok(Some(Arc::new(token)));
+    }
+    if args.netrc {
+        let path = auth::Netrc::default_path().ok_or_else(|| AppError::InvalidCredentials("no home directory for ~/.netrc".to_string()))?;
+        return Ok(Some(Arc::new(auth::Netrc::load(&path)?)));
+    }
+    Ok(None)
+}
+
+// The request used to fetch a whole file, a plain GET unless --method or --data say otherwise
+#[derive(Clone)]
+pub struct RequestSpec {
+    // HTTP method of the request
+    pub method: Method,
+    // Request body, labelled with the Content-Type it looks like unless the user gave one
+    pub body: Option<Vec<u8>>,
+}
+
+impl Default for RequestSpec {
+    fn default() -> Self {
+        Self { method: Method::GET, body: None }
+    }
+}
+
+impl RequestSpec {
+    // Build the request from the command line arguments
+    // `--data` defaults the method to POST; `@file` reads the body from a file
+    // Returns an error if the method is malformed or the body file cannot be read
+    pub fn from_args(args: &CommandLineArgs) -> Result<Self, AppError> {
+        let body = match args.data.as_deref() {
+            Some(data) => Some(match data.strip_prefix('@') {
+                Some(path) => std::fs::read(path)?,
+                None => data.as_bytes().to_vec(),
+            }),
+            None => None,
+        };
+        let method = match args.method.as_deref() {
+            Some(method) => Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| AppError::StringError(format!("invalid HTTP method: {}", method)))?,
+            None if body.is_some() => Method::POST,
+            None => Method::GET,
+        };
+        Ok(Self { method, body })
+    }
+
+    // Whether this is a body-less GET, the only request that can be probed and split into ranges
+    pub fn is_plain_get(&self) -> bool {
+        self.method == Method::GET && self.body.is_none()
+    }
+}
+
+// What the probe request learned about a remote file
+pub struct RemoteFile {
+    // Total size of the file in bytes, unknown for chunked or streaming responses
+    pub size: Option<usize>,
+    // Final URL after following redirects
+    pub url: Url,
+    // Headers of the probe response
+    pub headers: HeaderMap,
+    // Whether the server honours byte range requests
+    pub accepts_ranges: bool,
+    // Address of the server that answered the probe, when the connection reports it
+    pub address: Option<SocketAddr>,
+    // HTTP version the probe was answered with
+    pub version: Option<Version>,
+}
+
+impl RemoteFile {
+    /// Returns the protocol the file is served with, like `HTTP/1.1` or `HTTP/2`, or the scheme of its URL in capitals.
+    pub fn protocol(&self) -> String {
+        match self.version {
+            Some(Version::HTTP_09) => "HTTP/0.9".to_string(),
+            Some(Version::HTTP_10) => "HTTP/1.0".to_string(),
+            Some(Version::HTTP_11) => "HTTP/1.1".to_string(),
+            Some(Version::HTTP_2) => "HTTP/2".to_string(),
+            Some(Version::HTTP_3) => "HTTP/3".to_string(),
+            _ => self.url.scheme().to_ascii_uppercase(),
+        }
+    }
+}
+
 // Downloader trait to manage downloading files from different protocols
 pub trait Downloader {
-    fn new() -> Self;
-    async fn download_chunk(&self, url: &str, start: usize, end: usize) -> Result<(), AppError>;
-    async fn get_total_file_size(&self, url: &str) -> Result<usize, AppError>;
+    fn with_options(options: &ClientOptions) -> Result<Self, AppError> where Self: Sized;
+    async fn download_chunk<W>(&self, url: &str, start: usize, end: usize, sink: &mut W, progress: &ProgressBar) -> Result<(), AppError>
+    where
+        W: AsyncWrite + Unpin;
+    async fn download_whole<W>(&self, url: &str, request: &RequestSpec, sink: &mut W, progress: &ProgressBar, max_size: Option<u64>) -> Result<(), AppError>
+    where
+        W: AsyncWrite + Unpin;
+    async fn probe(&self, url: &str) -> Result<RemoteFile, AppError>;
+    async fn revalidate(&self, url: &str, cached: &CacheEntry) -> Result<bool, AppError>;
     fn calculate_byte_ranges(connections: usize,total_file_size: usize) -> Vec<(usize, usize)>;
 }
 
 // FileDownloader struct to manage downloading files from different protocols
+// Connections made from one downloader share its client, and with it the pooled connections and TLS sessions
 pub struct FileDownloader {
     client: Client,
+    options: ClientOptions,
+    // The bandwidth limits of this connection
+    throttle: throttle::Throttle,
+    // Whether the client is the one of a batch, shared with its other files
+    shared: bool,
+}
+
+impl FileDownloader {
+    /// Returns a downloader for another connection of the same download.
+    ///
+    /// It shares the client, its connection pool and the global bandwidth limit, and has a
+    /// per-connection limit of its own.
+    pub fn connection(&self) -> FileDownloader {
+        FileDownloader { client: self.client.clone(), options: self.options.clone(), throttle: self.throttle.for_connection(), shared: self.shared }
+    }
+
+    /// Returns the client the files of a batch share, see [`multiplexed`].
+    ///
+    /// Unlike the client of a single download it speaks HTTP/2 to servers offering it, so the
+    /// requests of many small files to one host go over a single connection.
+    pub fn shared_client(options: &ClientOptions) -> Result<Client, AppError> {
+        client(options, true)
+    }
+
+    /// Returns the downloader of one file: over the client of the batch within [`multiplexed`],
+    /// otherwise over a client of its own.
+    pub fn for_file(options: &ClientOptions) -> Result<FileDownloader, AppError> {
+        match shared::client() {
+            Some(client) => Ok(FileDownloader { client, options: options.clone(), throttle: throttle::Throttle::new(options.rate_limit_per_connection, options.rate_limit.clone()), shared: true }),
+            None => FileDownloader::with_options(options),
+        }
+    }
+
+    /// Returns a downloader for the ranges of a file split over several connections.
+    ///
+    /// The client of a batch would multiplex them over one HTTP/2 connection, so they get a client
+    /// of their own then; otherwise this is another connection of the same client.
+    pub fn for_ranges(&self) -> Result<FileDownloader, AppError> {
+        match self.shared {
+            true => FileDownloader::with_options(&self.options),
+            false => Ok(self.connection()),
+        }
+    }
+
+    /// Lists the names of the files in `directory`, a URL ending in a slash.
+    ///
+    /// Only FTP servers list their directories.
+    pub async fn list_directory(&self, directory: &Url) -> Result<Vec<String>, AppError> {
+        match directory.scheme() {
+            "ftp" => ftp::list(directory).await,
+            _ => Err(AppError::UnsupportedProtocol),
+        }
+    }
+
+    // Presets, credentials, hooks, bandwidth limits and timeouts of the requests for `url`
+    fn context_for(&self, url: &Url) -> RequestContext {
+        let options = &self.options;
+        RequestContext::new(url.clone(), options.header_presets.headers_for(url.host_str().unwrap_or_default()), options.auth.clone())
+            .with_hooks(options.hooks.clone())
+            .with_throttle(self.throttle.clone())
+            .with_stall_timeout(options.stall_timeout)
+            .with_deadline(options.deadline)
+            .with_buffer_size(options.buffer_size)
+    }
+}
+
+// Build the HTTP client of `options`, speaking HTTP/2 where the server offers it with `http2`
+// Otherwise HTTP/2 would multiplex all ranges over a single connection, so every range gets an HTTP/1.1 connection of its own
+// Connections stay in the pool for the next range or retry to the same host
+fn client(options: &ClientOptions, http2: bool) -> Result<Client, AppError> {
+    let mut tls = tls::client_config(options)?;
+    let mut builder = Client::builder().dns_resolver(Arc::new(options.dns_cache.clone())).pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST);
+    builder = match http2 {
+        true => {
+            tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            builder
+        }
+        false => {
+            // The preconfigured TLS would offer HTTP/2 otherwise, and a server picking it would multiplex the ranges anyway
+            tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+            builder.http1_only()
+        }
+    };
+    builder = builder.use_preconfigured_tls(tls);
+    // Redirects are limited as by default, and each hop goes through the hooks, so a denied host is not reached that way
+    let hooks = options.hooks.clone();
+    builder = builder.redirect(Policy::custom(move |attempt| match hooks.iter().try_for_each(|hook| hook.before_redirect(attempt.url())) {
+        Err(error) => attempt.error(error),
+        Ok(()) if attempt.previous().len() >= MAX_REDIRECTS => attempt.error("too many redirects"),
+        Ok(()) => attempt.follow(),
+    }));
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = options.read_timeout {
+        builder = builder.read_timeout(timeout);
+    }
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    Ok(builder.build()?)
 }
 
 // Implement Downloader for FileDownloader
 impl Downloader for FileDownloader {
-    // Create a new FileDownloader struct
-    // Returns a new FileDownloader struct
-    fn new() -> Self {
-        Self {
-            client: Client::new(),
-        }
+    // Create a new FileDownloader struct configured with `options`
+    // Returns an error if the HTTP client could not be built
+    fn with_options(options: &ClientOptions) -> Result<Self, AppError> {
+        let client = client(options, false)?;
+        let throttle = throttle::Throttle::new(options.rate_limit_per_connection, options.rate_limit.clone());
+        Ok(Self { client, options: options.clone(), throttle, shared: false })
     }
 
-    // Download a chunk of a file from a URL
+    // Download a chunk of a file from a URL into `sink`
     // `start` and `end` are the start and end byte positions of the chunk to download
     // Returns an error if the URL is not valid or the protocol is not supported
-    async fn download_chunk(&self, url: &str, start: usize, end: usize) -> Result<(), AppError> {
+    async fn download_chunk<W>(&self, url: &str, start: usize, end: usize, sink: &mut W, progress: &ProgressBar) -> Result<(), AppError>
+    where
+        W: AsyncWrite + Unpin,
+    {
         let parsed_url = Url::parse(url).map_err(|e| AppError::UrlParseError(e.to_string()))?;
         // Check if the URL is valid and the protocol is supported
         match parsed_url.scheme() {
-            "http" | "https" => Ok(http::download(&self.client, url, start, end).await?),
-            "ftp" | "sftp" => Ok(ftp::download(&self.client, url, start, end).await?),
+            "http" | "https" => http::download(&self.client, url, &self.context_for(&parsed_url), start, end, sink, progress).await,
+            "ftp" | "sftp" => ftp::download(&self.client, url, &self.context_for(&parsed_url), start, end, sink, progress).await,
             _ => Err(AppError::UnsupportedProtocol),
         }
     }
 
-    // Get the total size of a file from a URL
+    // Download a whole file from a URL into `sink` over a single connection
+    // `request` is the method and body to send, `max_size` aborts the transfer once more bytes than allowed arrive
     // Returns an error if the URL is not valid or the protocol is not supported
-    async fn get_total_file_size(&self, url: &str) -> Result<usize, AppError> {
+    async fn download_whole<W>(&self, url: &str, request: &RequestSpec, sink: &mut W, progress: &ProgressBar, max_size: Option<u64>) -> Result<(), AppError>
+    where
+        W: AsyncWrite + Unpin,
+    {
         let parsed_url = Url::parse(url).map_err(|e| AppError::UrlParseError(e.to_string()))?;
         // Check if the URL is valid and the protocol is supported
         match parsed_url.scheme() {
-            "http" | "https" => Ok(http::get_total_file_size(&self.client, url).await?),
-            "ftp" | "sftp" => Ok(ftp::get_total_file_size(&self.client, url).await?),
+            "http" | "https" => http::download_whole(&self.client, url, &self.context_for(&parsed_url), request, sink, progress, max_size).await,
+            "ftp" | "sftp" if request.is_plain_get() => ftp::download_whole(&self.client, url, &self.context_for(&parsed_url), sink, progress, max_size).await,
+            _ => Err(AppError::UnsupportedProtocol),
+        }
+    }
+
+    // Probe a URL for the total size of the file and its response headers
+    // Returns an error if the URL is not valid or the protocol is not supported
+    async fn probe(&self, url: &str) -> Result<RemoteFile, AppError> {
+        let parsed_url = Url::parse(url).map_err(|e| AppError::UrlParseError(e.to_string()))?;
+        // Check if the URL is valid and the protocol is supported
+        match parsed_url.scheme() {
+            "http" | "https" => http::probe(&self.client, url, &self.context_for(&parsed_url)).await,
+            "ftp" | "sftp" => ftp::probe(&self.client, url).await,
+            _ => Err(AppError::UnsupportedProtocol),
+        }
+    }
+
+    // Ask the server whether a cached copy of the file is still current
+    // Returns true if the cached copy can be reused, protocols without validators never reuse it
+    async fn revalidate(&self, url: &str, cached: &CacheEntry) -> Result<bool, AppError> {
+        let parsed_url = Url::parse(url).map_err(|e| AppError::UrlParseError(e.to_string()))?;
+        match parsed_url.scheme() {
+            "http" | "https" => http::revalidate(&self.client, url, &self.context_for(&parsed_url), cached).await,
+            "ftp" | "sftp" => Ok(false),
             _ => Err(AppError::UnsupportedProtocol),
         }
     }
@@ -55,11 +419,14 @@ impl Downloader for FileDownloader {
     // Calculate byte ranges for a file
     // `connections` is the number of concurrent connections to use
     // `total_file_size` is the total size of the file to download
-    // Returns a vector of byte ranges
+    // Returns a vector of byte ranges, fewer than `connections` when the file has fewer bytes than that
     fn calculate_byte_ranges(connections: usize,total_file_size: usize) -> Vec<(usize, usize)>{
-        let chunk_size = (total_file_size + connections - 1) / connections;
+        let connections = connections.clamp(1, total_file_size.max(1));
+        let chunk_size = total_file_size.div_ceil(connections);
         // Calculate byte ranges for the file
+        // Rounding the chunk size up may leave the last connections without any bytes
         let byte_ranges: Vec<_> = (0..connections)
+            .take_while(|i| i * chunk_size < total_file_size)
             .map(|i| {
                 // Calculate start and end byte positions for the chunk
                 let start = i * chunk_size;
@@ -70,3 +437,38 @@ impl Downloader for FileDownloader {
         byte_ranges
     }
 }
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::hooks::HostFilter;
+    use crate::test_server;
+
+    #[tokio::test]
+    async fn test_client_alpn_offer() {
+        let (url, offers) = test_server::serve_tls_hellos();
+        // The listener has no certificate, so the requests fail once it has read the hello
+        let _ = client(&ClientOptions::default(), false).unwrap().get(&url).send().await;
+        let _ = client(&ClientOptions::default(), true).unwrap().get(&url).send().await;
+        assert_eq!(*offers.lock().unwrap(), vec![vec![b"http/1.1".to_vec()], vec![b"h2".to_vec(), b"http/1.1".to_vec()]]);
+    }
+
+    #[tokio::test]
+    async fn test_denied_host_is_not_reached_by_redirect() {
+        // The redirect leads from 127.0.0.1 to the same kind of server on localhost
+        let target = test_server::serve(b"secret".to_vec()).replace("127.0.0.1", "localhost");
+        let url = test_server::serve_redirect(target);
+        let fetch = |hosts: &[&str]| {
+            let options = ClientOptions { hooks: vec![Arc::new(HostFilter::new(&hosts.iter().map(|host| host.to_string()).collect::<Vec<_>>()))], ..ClientOptions::default() };
+            let url = url.clone();
+            async move {
+                let mut body = Vec::new();
+                let downloader = FileDownloader::with_options(&options).unwrap();
+                downloader.download_whole(&url, &RequestSpec::default(), &mut body, &ProgressBar::hidden(), None).await.map(|_| body)
+            }
+        };
+        assert_eq!(fetch(&["example.com"]).await.unwrap(), b"secret");
+        assert!(matches!(fetch(&["localhost"]).await, Err(AppError::RequestRefused(_))));
+    }
+}
@@ -0,0 +1,160 @@
+use std::hash::{BuildHasher, RandomState};
+use std::path::{Path, PathBuf};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use crate::error::AppError;
+
+// Name of the header presets file in the home directory
+const PRESETS_FILE_NAME: &str = ".rtget-headers";
+
+/// User agents and extra request headers per host, applied to probes and chunk requests alike
+///
+/// The file holds `Name: value` lines. Before the first `[host]` section they apply to every
+/// host, except `User-Agent` lines which form a rotation list: each host gets one agent of the
+/// list for the whole run. Lines in a `[host]` section apply to that host and its subdomains and
+/// override the global ones. Blank lines and lines starting with `#` are ignored.
+#[derive(Clone, Default)]
+pub struct HeaderPresets {
+    user_agents: Vec<HeaderValue>,
+    global: HeaderMap,
+    // Host sections, least specific first so more specific ones override them
+    hosts: Vec<(String, HeaderMap)>,
+    // Headers of the command line, which override all others
+    overrides: HeaderMap,
+    // Seeded per process, so the agent of a host is stable within a run and rotates across runs
+    rotation: RandomState,
+}
+
+impl HeaderPresets {
+    /// Default location of the presets file, `~/.rtget-headers`.
+    ///
+    /// Returns `None` when no home directory is known.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(PRESETS_FILE_NAME))
+    }
+
+    /// Loads the presets from `path`.
+    ///
+    /// Returns an error if the file cannot be read or a line is malformed.
+    pub fn load(path: &Path) -> Result<HeaderPresets, AppError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| AppError::InvalidHeaderPresets(format!("{}: {}", path.display(), e)))?;
+        HeaderPresets::parse(&contents).map_err(|e| AppError::InvalidHeaderPresets(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses the contents of a presets file.
+    pub fn parse(contents: &str) -> Result<HeaderPresets, String> {
+        let mut presets = HeaderPresets::default();
+        let mut section: Option<usize> = None;
+        for (number, line) in contents.lines().enumerate().map(|(index, line)| (index + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(host) = line.strip_prefix('[') {
+                let host = host.strip_suffix(']').ok_or_else(|| format!("line {}: unterminated section", number))?;
+                presets.hosts.push((host.trim().trim_start_matches('.').to_ascii_lowercase(), HeaderMap::new()));
+                section = Some(presets.hosts.len() - 1);
+                continue;
+            }
+            let (name, value) = line.split_once(':').ok_or_else(|| format!("line {}: expected `Name: value`", number))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| format!("line {}: invalid header name", number))?;
+            let value = HeaderValue::from_str(value.trim()).map_err(|_| format!("line {}: invalid header value", number))?;
+            match section {
+                Some(index) => {
+                    presets.hosts[index].1.append(name, value);
+                }
+                None if name == USER_AGENT => presets.user_agents.push(value),
+                None => {
+                    presets.global.append(name, value);
+                }
+            }
+        }
+        presets.hosts.sort_by_key(|(host, _)| host.len());
+        Ok(presets)
+    }
+
+    /// Adds `Name: value` headers sent to every host, replacing those of the file with the same name.
+    pub fn with_overrides(mut self, headers: &[String]) -> Result<HeaderPresets, String> {
+        for header in headers {
+            let (name, value) = header.split_once(':').ok_or_else(|| format!("{}: expected `Name: value`", header))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| format!("{}: invalid header name", header))?;
+            let value = HeaderValue::from_str(value.trim()).map_err(|_| format!("{}: invalid header value", header))?;
+            self.overrides.append(name, value);
+        }
+        Ok(self)
+    }
+
+    /// Returns the headers to send with every request to `host`.
+    pub fn headers_for(&self, host: &str) -> HeaderMap {
+        let host = host.to_ascii_lowercase();
+        let mut headers = HeaderMap::new();
+        if !self.user_agents.is_empty() {
+            let index = self.rotation.hash_one(&host) as usize % self.user_agents.len();
+            headers.insert(USER_AGENT, self.user_agents[index].clone());
+        }
+        headers.extend(self.global.clone());
+        for (domain, section) in &self.hosts {
+            if host == *domain || host.ends_with(&format!(".{}", domain)) {
+                headers.extend(section.clone());
+            }
+        }
+        headers.extend(self.overrides.clone());
+        headers
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRESETS: &str = "\
+# rotated per host
+User-Agent: agent-a
+User-Agent: agent-b
+Accept-Language: en
+
+[example.com]
+Referer: https://example.com/
+
+[cdn.example.com]
+User-Agent: cdn-agent
+Accept-Language: de
+";
+
+    #[test]
+    fn test_headers_for() {
+        let presets = HeaderPresets::parse(PRESETS).unwrap();
+
+        let other = presets.headers_for("other.org");
+        let agent = other.get(USER_AGENT).unwrap().clone();
+        assert!(agent == "agent-a" || agent == "agent-b");
+        assert_eq!(other["accept-language"], "en");
+        assert_eq!(other.get("referer"), None);
+        // Every request to the same host carries the same agent
+        assert_eq!(presets.headers_for("OTHER.org")[USER_AGENT], agent);
+
+        let cdn = presets.headers_for("eu.cdn.example.com");
+        assert_eq!(cdn[USER_AGENT], "cdn-agent");
+        assert_eq!(cdn["accept-language"], "de");
+        assert_eq!(cdn["referer"], "https://example.com/");
+        assert_eq!(presets.headers_for("notexample.com").get("referer"), None);
+
+        // Headers of the command line win over every section
+        let presets = presets.with_overrides(&["User-Agent: cli".to_string(), "X-Team: storage".to_string()]).unwrap();
+        let cdn = presets.headers_for("eu.cdn.example.com");
+        assert_eq!(cdn[USER_AGENT], "cli");
+        assert_eq!(cdn["x-team"], "storage");
+        assert_eq!(cdn["accept-language"], "de");
+        assert!(HeaderPresets::default().with_overrides(&["no colon".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(HeaderPresets::parse("[example.com\n").is_err());
+        assert!(HeaderPresets::parse("no colon here\n").is_err());
+        assert!(HeaderPresets::parse("Bad Name: x\n").is_err());
+        assert!(HeaderPresets::default().headers_for("example.com").is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use std::future::Future;
+use reqwest::Client;
+
+tokio::task_local! {
+    // The client the downloads of the current batch share
+    static SHARED_CLIENT: Client;
+}
+
+/// Runs the downloads of `future` over `client`, which the other downloads of the batch share.
+///
+/// The files of a batch then reuse the connections of those before them instead of opening their
+/// own, and a server speaking HTTP/2 serves the requests of all of them over a single connection.
+pub async fn multiplexed<F: Future>(client: Client, future: F) -> F::Output {
+    SHARED_CLIENT.scope(client, future).await
+}
+
+/// Returns the client of the batch the current task downloads for, if any.
+pub fn client() -> Option<Client> {
+    SHARED_CLIENT.try_with(Client::clone).ok()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use crate::downloader::{ClientOptions, Downloader, FileDownloader};
+
+    // Serves a file of 10 bytes over connections kept alive, counting them
+    async fn counting_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    let mut head = false;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if !line.is_empty() {
+                            head |= line.starts_with("HEAD");
+                            continue;
+                        }
+                        let body = if head { "" } else { "0123456789" };
+                        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 10\r\nAccept-Ranges: bytes\r\n\r\n{}", body);
+                        if writer.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                        head = false;
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed() {
+        let (url, connections) = counting_server().await;
+        let options = ClientOptions::default();
+        let client = FileDownloader::shared_client(&options).unwrap();
+        for _ in 0..3 {
+            let remote = multiplexed(client.clone(), async { FileDownloader::for_file(&options).unwrap().probe(&url).await }).await.unwrap();
+            assert_eq!(remote.size, Some(10));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1, "the files of the batch share a connection");
+
+        // Outside a batch every file has a client of its own
+        assert!(super::client().is_none());
+        FileDownloader::for_file(&options).unwrap().probe(&url).await.unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+}
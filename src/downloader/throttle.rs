@@ -0,0 +1,168 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// A bandwidth limit in bytes per second
+///
+/// Bytes are let through in the order they are asked for: the waiting transfers queue up and each
+/// one sleeps for its own share of the time in turn, so a connection that keeps asking cannot
+/// starve the others. Clones share the same limit.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    // When the bytes let through so far have used up the limit
+    busy_until: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    /// Creates a limit of `bytes_per_second`, which must not be zero.
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter { bytes_per_second: bytes_per_second.max(1), busy_until: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    // Book the time `bytes` more take at this limit, and return when they fit into it
+    // Bookings follow each other in the order they are made, and the lock of tokio is fair, so waiters are served first come, first served
+    async fn reserve(&self, bytes: usize) -> Instant {
+        let mut busy_until = self.busy_until.lock().await;
+        // Time nobody used is not saved up for a later burst
+        let start = (*busy_until).max(Instant::now());
+        *busy_until = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        *busy_until
+    }
+}
+
+tokio::task_local! {
+    // The limit the downloads of the current task share with the other downloads of their user
+    static USER_LIMIT: RateLimiter;
+}
+
+/// Runs the downloads of `future` within `limit`, which other downloads of the same user share.
+pub async fn limited<F: Future>(limit: RateLimiter, future: F) -> F::Output {
+    USER_LIMIT.scope(limit, future).await
+}
+
+/// The limits the transfers of one connection go through
+///
+/// Each connection has a limit of its own, and every connection of a download shares the global
+/// one, and the one of its user in a daemon. Without any limit received bytes are passed on right away.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    connection: Option<RateLimiter>,
+    shared: Option<RateLimiter>,
+    user: Option<RateLimiter>,
+}
+
+impl Throttle {
+    /// Limits a connection to `per_connection` bytes per second and to its turn of `shared`, and of
+    /// the limit of its user when run within [`limited`].
+    pub fn new(per_connection: Option<u64>, shared: Option<RateLimiter>) -> Throttle {
+        let user = USER_LIMIT.try_with(RateLimiter::clone).ok();
+        Throttle { connection: per_connection.map(RateLimiter::new), shared, user }
+    }
+
+    /// Returns the limits of another connection: a fresh per-connection limit and the same shared ones.
+    pub fn for_connection(&self) -> Throttle {
+        Throttle { connection: self.connection.as_ref().map(|limit| RateLimiter::new(limit.bytes_per_second)), shared: self.shared.clone(), user: self.user.clone() }
+    }
+
+    /// Waits until `bytes` just received may be passed on.
+    ///
+    /// The bytes are booked on every limit at once, so stacked limits overlap rather than add up:
+    /// the slowest of them alone sets the pace.
+    pub async fn consume(&self, bytes: usize) {
+        let mut deadline = None;
+        for limit in [&self.connection, &self.shared, &self.user].into_iter().flatten() {
+            deadline = deadline.max(Some(limit.reserve(bytes).await));
+        }
+        if let Some(deadline) = deadline {
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    #[tokio::test]
+    async fn test_limit_is_enforced() {
+        let limiter = Throttle::new(None, Some(RateLimiter::new(100_000)));
+        let started = Instant::now();
+        for _ in 0..4 {
+            limiter.consume(5_000).await;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(190), "20 KB at 100 KB/s take 200 ms");
+
+        // Unlimited transfers do not wait
+        let started = Instant::now();
+        Throttle::default().consume(1 << 30).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_shared_limit_is_fair() {
+        let shared = RateLimiter::new(100_000);
+        let order = Arc::new(StdMutex::new(Vec::new()));
+        // A greedy connection asks again as soon as it is let through
+        let greedy = {
+            let (throttle, order) = (Throttle::new(None, Some(shared.clone())), order.clone());
+            tokio::spawn(async move {
+                for _ in 0..5 {
+                    throttle.consume(2_000).await;
+                    order.lock().unwrap().push("greedy");
+                }
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        // A connection arriving later is served after the turn of the greedy one already queued
+        Throttle::new(None, Some(shared)).consume(2_000).await;
+        order.lock().unwrap().push("other");
+        greedy.await.unwrap();
+
+        let order = order.lock().unwrap();
+        let other = order.iter().position(|&name| name == "other").unwrap();
+        assert!(other <= 2, "the other connection waited behind {:?}", &order[..other]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let throttle = Throttle::new(Some(50_000), Some(RateLimiter::new(1_000_000)));
+        let started = Instant::now();
+        throttle.consume(5_000).await;
+        throttle.consume(5_000).await;
+        assert!(started.elapsed() >= Duration::from_millis(190), "the lower limit of the connection applies");
+
+        // Another connection gets a limit of its own
+        let started = Instant::now();
+        throttle.for_connection().consume(5_000).await;
+        assert!(started.elapsed() < Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn test_user_limit() {
+        let user = RateLimiter::new(50_000);
+        let throttle = limited(user.clone(), async { Throttle::new(None, None) }).await;
+        let started = Instant::now();
+        throttle.consume(5_000).await;
+        throttle.for_connection().consume(5_000).await;
+        assert!(started.elapsed() >= Duration::from_millis(190), "the connections share the limit of their user");
+        assert!(Throttle::new(None, None).user.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stacked_limits_overlap() {
+        // A connection limited to 100 KB/s on its own and by the shared limit moves 100 KB/s, not half of it
+        let throttle = Throttle::new(Some(100_000), Some(RateLimiter::new(100_000)));
+        let started = Instant::now();
+        for _ in 0..4 {
+            throttle.consume(5_000).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "20 KB at 100 KB/s take 200 ms");
+        assert!(elapsed < Duration::from_millis(300), "20 KB took {:?}", elapsed);
+    }
+}
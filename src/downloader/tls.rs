@@ -0,0 +1,368 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue, Tls13ClientSessionValue, WebPkiServerVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, NamedGroup, RootCertStore, SignatureScheme, SupportedProtocolVersion};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use url::Url;
+use crate::error::AppError;
+use super::ClientOptions;
+
+// Prefix used by curl-style public key pins
+const PIN_PREFIX: &str = "sha256//";
+
+/// Parses a list of curl-style public key pins.
+///
+/// `spec` is one or more `sha256//<base64>` entries separated by `;`.
+/// Returns the raw SHA-256 digests of the pinned SubjectPublicKeyInfo structures.
+pub fn parse_pins(spec: &str) -> Result<Vec<[u8; 32]>, AppError> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| {
+            let encoded = pin.strip_prefix(PIN_PREFIX).ok_or_else(|| AppError::InvalidPinnedKey(pin.to_string()))?;
+            let digest = BASE64.decode(encoded).map_err(|_| AppError::InvalidPinnedKey(pin.to_string()))?;
+            digest.try_into().map_err(|_| AppError::InvalidPinnedKey(pin.to_string()))
+        })
+        .collect()
+}
+
+/// TLS protocol versions that can be used as policy bounds
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    // The rustls protocol version matching this policy value
+    fn protocol(self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/// Parses a TLS version given as `1.2` or `1.3`.
+pub fn parse_version(version: &str) -> Result<TlsVersion, AppError> {
+    match version.trim().trim_start_matches("TLSv").trim_start_matches("tls") {
+        "1.2" => Ok(TlsVersion::Tls12),
+        "1.3" => Ok(TlsVersion::Tls13),
+        _ => Err(AppError::InvalidTlsPolicy(format!("unsupported TLS version '{}', expected 1.2 or 1.3", version))),
+    }
+}
+
+/// Parses a comma or colon separated cipher suite allowlist.
+///
+/// Names use the IANA spelling, e.g. `TLS13_AES_256_GCM_SHA384` or `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+/// Returns an error naming the first suite that is not supported.
+pub fn parse_ciphers(list: &str) -> Result<Vec<String>, AppError> {
+    let available = rustls::crypto::ring::default_provider().cipher_suites;
+    list.split([',', ':'])
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            available
+                .iter()
+                .map(|suite| format!("{:?}", suite.suite()))
+                .find(|known| known.eq_ignore_ascii_case(name))
+                .ok_or_else(|| AppError::InvalidTlsPolicy(format!("unknown cipher suite '{}'", name)))
+        })
+        .collect()
+}
+
+// Hash the SubjectPublicKeyInfo of a DER encoded certificate
+fn spki_sha256(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.as_ref())
+        .map_err(|_| rustls::Error::General("could not parse the server certificate".to_string()))?;
+    Ok(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).into())
+}
+
+// Certificate verifier that runs the regular WebPKI checks and then
+// requires the server key to match one of the pinned keys
+#[derive(Debug)]
+struct PinnedKeyVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        // Fail closed if the presented key is not one of the pinned ones
+        let digest = spki_sha256(end_entity)?;
+        if self.pins.contains(&digest) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server public key {}{} does not match any pinned key",
+                PIN_PREFIX,
+                BASE64.encode(digest)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Builds the rustls configuration used by the HTTP client.
+///
+/// Returns an error if the configured pins or trust roots are unusable.
+pub fn client_config(options: &ClientOptions) -> Result<ClientConfig, AppError> {
+    let mut provider = rustls::crypto::ring::default_provider();
+    // Restrict the offered cipher suites to the allowlist, if any
+    if !options.cipher_suites.is_empty() {
+        provider.cipher_suites.retain(|suite| options.cipher_suites.contains(&format!("{:?}", suite.suite())));
+    }
+    let provider = Arc::new(provider);
+    let roots = Arc::new(RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() });
+
+    // Keep only the protocol versions inside the configured bounds
+    let versions: Vec<_> = [TlsVersion::Tls12, TlsVersion::Tls13]
+        .into_iter()
+        .filter(|version| options.tls_min_version.is_none_or(|min| *version >= min))
+        .filter(|version| options.tls_max_version.is_none_or(|max| *version <= max))
+        .map(TlsVersion::protocol)
+        .collect();
+    if versions.is_empty() {
+        return Err(AppError::InvalidTlsPolicy("the minimum TLS version is above the maximum".to_string()));
+    }
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&versions)
+        .map_err(|e| AppError::InvalidTlsPolicy(e.to_string()))?;
+
+    let mut config = if options.pinned_pubkeys.is_empty() {
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(pinned_verifier(roots, provider, options.pinned_pubkeys.clone())?)
+            .with_no_client_auth()
+    };
+
+    // reqwest leaves ALPN alone for preconfigured TLS, so advertise HTTP/2 ourselves
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config.resumption = Resumption::store(Arc::new(options.sessions.clone()));
+    Ok(config)
+}
+
+// Server names a session log keeps resumable sessions for, like the default store of rustls
+const SESSION_CACHE_SIZE: usize = 256;
+
+/// Reports the TLS session the connections of a download negotiated with each host, once per host
+///
+/// The log is the store rustls keeps resumable sessions in, which it hands every session of the
+/// connections the download makes: a TLS 1.3 session tells its cipher suite, a TLS 1.2 one only its
+/// version. A server that makes no session resumable is not reported. Clones share the log.
+#[derive(Clone, Debug)]
+pub struct SessionLog {
+    store: Arc<ClientSessionMemoryCache>,
+    // The session reported for each host
+    described: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Default for SessionLog {
+    fn default() -> Self {
+        SessionLog { store: Arc::new(ClientSessionMemoryCache::new(SESSION_CACHE_SIZE)), described: Arc::default() }
+    }
+}
+
+impl SessionLog {
+    // Report the session negotiated with `server_name` unless one was already
+    fn describe(&self, server_name: &ServerName<'_>, session: String) {
+        let host = server_name.to_str().into_owned();
+        let mut described = self.described.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Entry::Vacant(entry) = described.entry(host) {
+            tracing::info!("{}: negotiated {}", entry.key(), session);
+            entry.insert(session);
+        }
+    }
+}
+
+impl ClientSessionStore for SessionLog {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.store.set_kx_hint(server_name, group);
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.store.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.describe(&server_name, "TLSv1_2".to_string());
+        self.store.set_tls12_session(server_name, value);
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.store.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.store.remove_tls12_session(server_name);
+    }
+
+    fn insert_tls13_ticket(&self, server_name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.describe(&server_name, format!("TLSv1_3 {:?}", value.suite().common.suite));
+        self.store.insert_tls13_ticket(server_name, value);
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        self.store.take_tls13_ticket(server_name)
+    }
+}
+
+/// Performs a standalone handshake with the host of `url` and describes the negotiated session.
+///
+/// Uses the same configuration as the download connections, so the result reflects what they negotiate.
+/// Returns the protocol version and cipher suite, e.g. `TLSv1_3 TLS13_AES_256_GCM_SHA384`.
+pub async fn describe_session(url: &Url, options: &ClientOptions) -> Result<String, AppError> {
+    let host = url.host_str().ok_or(AppError::InvalidHostname)?.to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let server_name = ServerName::try_from(host.clone()).map_err(|_| AppError::InvalidHostname)?;
+
+    let connector = TlsConnector::from(Arc::new(client_config(options)?));
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+    let stream = connector.connect(server_name, stream).await?;
+
+    let (_, session) = stream.get_ref();
+    let version = session.protocol_version().map(|v| format!("{:?}", v)).unwrap_or_default();
+    let suite = session.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())).unwrap_or_default();
+    Ok(format!("{} {}", version, suite))
+}
+
+// Wrap the WebPKI verifier so the pinned keys are checked as well
+fn pinned_verifier(
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+    pins: Vec<[u8; 32]>,
+) -> Result<Arc<PinnedKeyVerifier>, AppError> {
+    let inner = WebPkiServerVerifier::builder_with_provider(roots, provider)
+        .build()
+        .map_err(|e| AppError::StringError(e.to_string()))?;
+    Ok(Arc::new(PinnedKeyVerifier { inner, pins }))
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::test_server;
+
+    #[test]
+    fn test_parse_multiple_pins() {
+        let digest = BASE64.encode([7u8; 32]);
+        let pins = parse_pins(&format!("sha256//{};sha256//{}", digest, digest)).unwrap();
+        assert_eq!(pins, vec![[7u8; 32], [7u8; 32]]);
+    }
+
+    #[test]
+    fn test_parse_pin_rejects_unknown_hash() {
+        assert!(parse_pins("sha1//AAAA").is_err(), "Only sha256 pins are supported");
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("1.2").unwrap(), TlsVersion::Tls12);
+        assert_eq!(parse_version("TLSv1.3").unwrap(), TlsVersion::Tls13);
+        assert!(parse_version("1.0").is_err(), "TLS 1.0 is not supported");
+    }
+
+    #[test]
+    fn test_parse_ciphers() {
+        let suites = parse_ciphers("tls13_aes_256_gcm_sha384:TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256").unwrap();
+        assert_eq!(suites, vec!["TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"]);
+        assert!(parse_ciphers("RC4_MD5").is_err(), "Unknown suites must be rejected");
+    }
+
+    #[test]
+    fn test_inverted_version_bounds() {
+        let options = ClientOptions {
+            tls_min_version: Some(TlsVersion::Tls13),
+            tls_max_version: Some(TlsVersion::Tls12),
+            ..ClientOptions::default()
+        };
+        assert!(client_config(&options).is_err());
+    }
+
+    #[test]
+    fn test_parse_pin_rejects_wrong_length() {
+        assert!(parse_pins("sha256//AAAA").is_err(), "A sha256 pin must decode to 32 bytes");
+    }
+
+    #[tokio::test]
+    async fn test_session_log() {
+        let dir = test_server::temp_dir("tls-sessions");
+        let (certificate, private_key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&certificate, test_server::CERTIFICATE).unwrap();
+        std::fs::write(&private_key, test_server::PRIVATE_KEY).unwrap();
+        let acceptor = crate::server::tls_acceptor(&certificate, &private_key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.write_all(b"ok").await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+
+        // The connections store their sessions in the log, which reports the first one of the host
+        let log = SessionLog::default();
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(test_server::CERTIFICATE.as_bytes()).unwrap()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider())).with_safe_default_protocol_versions().unwrap().with_root_certificates(roots).with_no_client_auth();
+        config.resumption = Resumption::store(Arc::new(log.clone()));
+        let connector = TlsConnector::from(Arc::new(config));
+        for _ in 0..2 {
+            let mut stream = connector.connect("localhost".try_into().unwrap(), TcpStream::connect(address).await.unwrap()).await.unwrap();
+            let mut answer = Vec::new();
+            stream.read_to_end(&mut answer).await.unwrap();
+            assert_eq!(answer, b"ok");
+        }
+        let described = log.described.lock().unwrap().clone();
+        assert_eq!(described.len(), 1);
+        assert!(described["localhost"].starts_with("TLSv1_3 TLS13_"), "{:?}", described);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -6,12 +6,98 @@ pub enum AppError {
     UrlParseError(String),
     InvalidScheme,
     InvalidHostname,
+    #[allow(dead_code)]
     UrlValidationError(String),
     CouldNotConnect(String),
+    HttpStatus(u16, String),
+    FtpReply(u16, String),
     UnsupportedProtocol,
+    RangeNotSupported,
+    RangeNotSatisfiable(Option<usize>),
+    SizeKeepsChanging(u32),
+    FileTooLarge(u64),
+    InsufficientDiskSpace(String),
+    ChecksumMismatch(String),
+    CorruptOutput(String),
+    InvalidSignature(String),
+    InvalidPinnedKey(String),
+    InvalidTlsPolicy(String),
+    InvalidHeaderPresets(String),
+    InvalidCredentials(String),
+    InvalidManifest(String),
+    RequestRefused(String),
+    QuotaReached(String),
+    Interrupted,
+    Stalled(u64),
+    TimedOut,
+    IoError(String),
     StringError(String),
 }
 
+/// Exit status of a failure none of the others describe
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit status of an invalid command line, config file, URL or option value
+pub const EXIT_USAGE: i32 = 2;
+/// Exit status of a file that could not be read or written, or a full disk
+pub const EXIT_IO: i32 = 3;
+/// Exit status of a server that could not be reached, or a connection that failed or timed out
+pub const EXIT_NETWORK: i32 = 4;
+/// Exit status of a file that does not match its checksum or signature
+pub const EXIT_VERIFICATION: i32 = 5;
+/// Exit status of a server that answered with an error, or did not serve the requested bytes
+pub const EXIT_SERVER: i32 = 6;
+/// Exit status of a batch of which some downloads failed or were not started
+pub const EXIT_PARTIAL: i32 = 7;
+
+impl AppError {
+    // Whether trying the same request again may succeed, as after a reset connection or a server error
+    // Client errors are final, except for timeouts and rate limiting
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::HttpStatus(status, _) => !(400..500).contains(status) || *status == 408 || *status == 429,
+            // FTP replies of 4xx are transient failures, those of 5xx permanent ones
+            AppError::FtpReply(code, _) => (400..500).contains(code),
+            AppError::CouldNotConnect(_) | AppError::Stalled(_) | AppError::IoError(_) | AppError::StringError(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the exit status of a download that failed with this error, so scripts can tell the kinds of failures apart.
+    ///
+    /// An interrupted download exits with the status of the signal, like a shell reports it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Interrupted => crate::interrupt::exit_status(),
+            AppError::UrlParseError(_)
+            | AppError::InvalidScheme
+            | AppError::InvalidHostname
+            | AppError::UrlValidationError(_)
+            | AppError::UnsupportedProtocol
+            | AppError::InvalidPinnedKey(_)
+            | AppError::InvalidTlsPolicy(_)
+            | AppError::InvalidHeaderPresets(_)
+            | AppError::InvalidCredentials(_)
+            | AppError::InvalidManifest(_) => EXIT_USAGE,
+            AppError::IoError(_) | AppError::InsufficientDiskSpace(_) => EXIT_IO,
+            AppError::HttpStatus(..) | AppError::FtpReply(..) => EXIT_SERVER,
+            AppError::CouldNotConnect(_) | AppError::Stalled(_) | AppError::TimedOut => EXIT_NETWORK,
+            AppError::ChecksumMismatch(_) | AppError::CorruptOutput(_) | AppError::InvalidSignature(_) => EXIT_VERIFICATION,
+            AppError::RangeNotSupported | AppError::RangeNotSatisfiable(_) | AppError::SizeKeepsChanging(_) => EXIT_SERVER,
+            AppError::FileTooLarge(_) | AppError::RequestRefused(_) | AppError::StringError(_) => EXIT_FAILURE,
+            // Like a batch that leaves downloads out for its quota
+            AppError::QuotaReached(_) => EXIT_PARTIAL,
+        }
+    }
+
+    /// Returns the status code of the error response of an HTTP server.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            AppError::HttpStatus(status, _) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
 // Implement Display for AppError
 impl std::fmt::Display for AppError {
     // Implement Display for AppError
@@ -23,8 +109,29 @@ impl std::fmt::Display for AppError {
             AppError::InvalidScheme => write!(f, "Invalid URL scheme"),
             AppError::InvalidHostname => write!(f, "Hostname is either missing or invalid"),
             AppError::UrlValidationError(msg) => write!(f, "URL is not valid: {}", msg),
-            AppError::CouldNotConnect(msg) => write!(f, "Could not connect to the server: {}", msg),
+            AppError::CouldNotConnect(msg) | AppError::HttpStatus(_, msg) => write!(f, "Could not connect to the server: {}", msg),
+            AppError::FtpReply(_, reply) => write!(f, "Could not connect to the server: FTP: {}", reply),
             AppError::UnsupportedProtocol => write!(f, "Unsupported protocol"),
+            AppError::RangeNotSupported => write!(f, "The server ignored the byte range request"),
+            AppError::RangeNotSatisfiable(Some(size)) => write!(f, "The requested range lies beyond the end of the remote file ({} bytes)", size),
+            AppError::RangeNotSatisfiable(None) => write!(f, "The requested range lies beyond the end of the remote file"),
+            AppError::SizeKeepsChanging(restarts) => write!(f, "The remote file changed size again after {} restarts of the download, giving up", restarts),
+            AppError::FileTooLarge(max) => write!(f, "The remote file exceeds the maximum file size of {} bytes", max),
+            AppError::InsufficientDiskSpace(msg) => write!(f, "Not enough disk space: {}", msg),
+            AppError::ChecksumMismatch(expected) => write!(f, "The downloaded file does not match the {}", expected),
+            AppError::CorruptOutput(msg) => write!(f, "The downloaded file is corrupt: {}", msg),
+            AppError::InvalidSignature(msg) => write!(f, "Signature verification failed: {}", msg),
+            AppError::InvalidPinnedKey(pin) => write!(f, "Invalid pinned public key: {}", pin),
+            AppError::InvalidTlsPolicy(msg) => write!(f, "Invalid TLS policy: {}", msg),
+            AppError::InvalidHeaderPresets(msg) => write!(f, "Invalid header presets: {}", msg),
+            AppError::InvalidCredentials(msg) => write!(f, "Invalid credentials: {}", msg),
+            AppError::InvalidManifest(msg) => write!(f, "Invalid manifest: {}", msg),
+            AppError::RequestRefused(msg) => write!(f, "Request refused: {}", msg),
+            AppError::QuotaReached(msg) => write!(f, "The quota was reached: {}", msg),
+            AppError::Interrupted => write!(f, "The download was interrupted"),
+            AppError::Stalled(seconds) => write!(f, "No data arrived for {} seconds", seconds),
+            AppError::TimedOut => write!(f, "The download did not finish within the time allowed by --max-time"),
+            AppError::IoError(msg) => write!(f, "I/O error: {}", msg),
             // TODO: handle other errors as the need arise
             AppError::StringError(msg) => write!(f, "An error occurred: {}", msg),
         }
@@ -39,6 +146,40 @@ impl From<String> for AppError {
     }
 }
 
+// Implement From<std::io::Error> for AppError
+// This is required to allow file system errors to be propagated with `?`
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        AppError::IoError(err.to_string())
+    }
+}
+
+// Implement From<reqwest::Error> for AppError
+// The whole source chain is kept so TLS failures (e.g. a pinned key mismatch) stay visible
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        let mut msg = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            // A request hook refused to follow a redirect
+            if let Some(AppError::RequestRefused(reason)) = cause.downcast_ref::<AppError>() {
+                return AppError::RequestRefused(reason.clone());
+            }
+            msg.push_str(&format!(": {}", cause));
+            source = cause.source();
+        }
+        AppError::CouldNotConnect(msg)
+    }
+}
+
+// Implement From<reqwest::StatusCode> for AppError
+// An error response keeps its status, which tells how the download failed
+impl From<reqwest::StatusCode> for AppError {
+    fn from(status: reqwest::StatusCode) -> Self {
+        AppError::HttpStatus(status.as_u16(), status.to_string())
+    }
+}
+
 // Implement From<AppError> for AppError
 // This is required to allow the error to be converted from another AppError
 impl std::error::Error for AppError {}
@@ -71,4 +212,19 @@ mod tests {
         let error = AppError::UrlValidationError("Invalid format".to_string());
         assert_eq!(format!("{}", error), "URL is not valid: Invalid format");
     }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(AppError::from(reqwest::StatusCode::NOT_FOUND).exit_code(), EXIT_SERVER);
+        assert_eq!(AppError::FtpReply(550, "550 No such file".to_string()).exit_code(), EXIT_SERVER);
+        assert_eq!(AppError::CouldNotConnect("error sending request: connection refused".to_string()).exit_code(), EXIT_NETWORK);
+        // An error of the connection stays one, even when its message starts with digits
+        assert_eq!(AppError::CouldNotConnect("421 bytes sent before the connection was reset".to_string()).exit_code(), EXIT_NETWORK);
+        assert_eq!(AppError::Stalled(30).exit_code(), EXIT_NETWORK);
+        assert_eq!(AppError::IoError("No space left on device".to_string()).exit_code(), EXIT_IO);
+        assert_eq!(AppError::ChecksumMismatch("sha256".to_string()).exit_code(), EXIT_VERIFICATION);
+        assert_eq!(AppError::InvalidScheme.exit_code(), EXIT_USAGE);
+        assert_eq!(AppError::StringError("other".to_string()).exit_code(), EXIT_FAILURE);
+        assert_eq!(AppError::QuotaReached("1.00 KiB (2.00 KiB used)".to_string()).exit_code(), EXIT_PARTIAL);
+    }
 }
\ No newline at end of file
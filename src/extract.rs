@@ -0,0 +1,300 @@
+use serde_json::Value;
+use url::Url;
+use crate::crawler;
+
+// Attributes holding the link of a selected element, in order of preference
+const LINK_ATTRIBUTES: [&str; 3] = ["href", "src", "data-href"];
+
+/// What --extract-links selects in an index: elements of an HTML page, or strings of a JSON document.
+#[derive(Debug, PartialEq)]
+pub enum Selector {
+    /// A CSS selector of elements whose `href` or `src` links to the files, e.g. `a[href$=".iso"]`
+    Css(Vec<Compound>),
+    /// A JSONPath to the URLs of the files, e.g. `$.assets[*].browser_download_url`
+    JsonPath(Vec<Step>),
+}
+
+/// One selector of a comma-separated CSS selector list: the conditions a single element meets.
+#[derive(Debug, PartialEq, Default)]
+pub struct Compound {
+    tag: Option<String>,
+    conditions: Vec<Condition>,
+}
+
+// A condition of a compound selector
+#[derive(Debug, PartialEq)]
+enum Condition {
+    // `#id`
+    Id(String),
+    // `.class`
+    Class(String),
+    // `[name]`, or `[name=value]` with the operator `=`, `^=`, `$=`, `*=` or `~=` and the value to test
+    Attribute { name: String, test: Option<(String, String)> },
+}
+
+/// One step of a JSONPath.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// `.name` or `['name']`
+    Member(String),
+    /// `[2]`, or `[-1]` counting from the end
+    Index(i64),
+    /// `.*` or `[*]`: every member or item
+    Wildcard,
+    /// `..name`: the members of that name at any depth
+    Descendant(String),
+}
+
+impl Selector {
+    /// Parses a JSONPath, starting with `$`, or a CSS selector.
+    ///
+    /// CSS selectors combine a tag name with `#id`, `.class` and `[attribute]` conditions, the
+    /// attribute tested with `=`, `^=`, `$=`, `*=` or `~=`; combinators between elements are not
+    /// supported. JSONPaths support members, indexes, `*` and `..` descent, without filters.
+    pub fn parse(selector: &str) -> Result<Selector, String> {
+        let selector = selector.trim();
+        match selector.strip_prefix('$') {
+            Some(path) => parse_path(path).map(Selector::JsonPath).ok_or_else(|| format!("invalid JSONPath {}", selector)),
+            None => selector.split(',').map(|compound| parse_compound(compound.trim())).collect::<Option<_>>().map(Selector::Css).ok_or_else(|| {
+                format!("invalid CSS selector {}: --extract-links supports a tag with #id, .class and [attribute] conditions, not combinators", selector)
+            }),
+        }
+    }
+
+    /// Returns the links the selector picks out of the index `body`, resolved against its URL `base`, each once.
+    ///
+    /// Only http, https and ftp links are kept.
+    pub fn links(&self, body: &str, base: &Url) -> Result<Vec<Url>, String> {
+        let targets = match self {
+            Selector::Css(compounds) => css_targets(compounds, body, base),
+            Selector::JsonPath(steps) => {
+                let document: Value = serde_json::from_str(body).map_err(|e| format!("the index is not JSON: {}", e))?;
+                let mut values = vec![&document];
+                for step in steps {
+                    values = values.into_iter().flat_map(|value| select(value, step)).collect();
+                }
+                values.into_iter().filter_map(Value::as_str).filter_map(|target| base.join(target.trim()).ok()).collect()
+            }
+        };
+        let mut links: Vec<Url> = Vec::new();
+        for mut link in targets {
+            link.set_fragment(None);
+            if matches!(link.scheme(), "http" | "https" | "ftp") && !links.contains(&link) {
+                links.push(link);
+            }
+        }
+        Ok(links)
+    }
+}
+
+// The links of the elements of `html` that match any of `compounds`, resolved against `base` or the `<base href>` of the page
+fn css_targets(compounds: &[Compound], html: &str, base: &Url) -> Vec<Url> {
+    let mut base = base.clone();
+    let mut targets = Vec::new();
+    for (name, attributes) in crawler::tags(html) {
+        let attribute = |wanted: &str| attributes.iter().find(|(name, _)| name == wanted).map(|(_, value)| value.as_str());
+        if name == "base" {
+            if let Some(href) = attribute("href").and_then(|href| base.join(href).ok()) {
+                base = href;
+            }
+            continue;
+        }
+        if !compounds.iter().any(|compound| compound.matches(&name, &attributes)) {
+            continue;
+        }
+        let target = LINK_ATTRIBUTES.iter().find_map(|wanted| attribute(wanted)).map(str::trim).filter(|target| !target.is_empty());
+        if let Some(url) = target.and_then(|target| base.join(target).ok()) {
+            targets.push(url);
+        }
+    }
+    targets
+}
+
+impl Compound {
+    // Whether the element `name` with `attributes` meets every condition
+    fn matches(&self, name: &str, attributes: &[(String, String)]) -> bool {
+        let attribute = |wanted: &str| attributes.iter().find(|(name, _)| name == wanted).map(|(_, value)| value.as_str());
+        self.tag.as_ref().is_none_or(|tag| tag == name)
+            && self.conditions.iter().all(|condition| match condition {
+                Condition::Id(id) => attribute("id") == Some(id),
+                Condition::Class(class) => attribute("class").is_some_and(|classes| classes.split_whitespace().any(|name| name == class)),
+                Condition::Attribute { name, test: None } => attribute(name).is_some(),
+                Condition::Attribute { name, test: Some((op, expected)) } => attribute(name).is_some_and(|value| match op.as_str() {
+                    "=" => value == expected,
+                    "^=" => value.starts_with(expected.as_str()),
+                    "$=" => value.ends_with(expected.as_str()),
+                    "*=" => value.contains(expected.as_str()),
+                    _ => value.split_whitespace().any(|word| word == expected),
+                }),
+            })
+    }
+}
+
+// Parse a compound selector like `a.download[href$=".iso"]`, or None if it is not one
+fn parse_compound(selector: &str) -> Option<Compound> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    let name_length = |text: &str| text.find(|c: char| !is_name(c)).unwrap_or(text.len());
+    let mut compound = Compound::default();
+    let mut rest = selector;
+    let length = name_length(rest);
+    if length > 0 {
+        compound.tag = Some(rest[..length].to_ascii_lowercase());
+        rest = &rest[length..];
+    } else if let Some(universal) = rest.strip_prefix('*') {
+        rest = universal;
+    }
+    while !rest.is_empty() {
+        let (kind, tail) = rest.split_at(1);
+        if kind == "[" {
+            let end = tail.find(']')?;
+            let (spec, tail) = (&tail[..end], &tail[end + 1..]);
+            let condition = match spec.find('=') {
+                Some(equals) => {
+                    let op_start = if equals > 0 && spec[..equals].ends_with(['^', '$', '*', '~']) { equals - 1 } else { equals };
+                    let name = spec[..op_start].trim();
+                    let value = spec[equals + 1..].trim();
+                    let value = value.strip_prefix(['"', '\'']).and_then(|quoted| quoted.strip_suffix(['"', '\''])).unwrap_or(value);
+                    (name_length(name) == name.len() && !name.is_empty()).then(|| Condition::Attribute {
+                        name: name.to_ascii_lowercase(),
+                        test: Some((spec[op_start..equals + 1].to_string(), value.to_string())),
+                    })?
+                }
+                None => {
+                    let name = spec.trim();
+                    (name_length(name) == name.len() && !name.is_empty()).then(|| Condition::Attribute { name: name.to_ascii_lowercase(), test: None })?
+                }
+            };
+            compound.conditions.push(condition);
+            rest = tail;
+            continue;
+        }
+        let length = name_length(tail);
+        let name = tail[..length].to_string();
+        compound.conditions.push(match kind {
+            "#" if length > 0 => Condition::Id(name),
+            "." if length > 0 => Condition::Class(name),
+            _ => return None,
+        });
+        rest = &tail[length..];
+    }
+    (compound.tag.is_some() || !compound.conditions.is_empty() || selector == "*").then_some(compound)
+}
+
+// Parse the steps of a JSONPath after its `$`, or None if it is not one
+fn parse_path(mut path: &str) -> Option<Vec<Step>> {
+    let name_length = |text: &str| text.find(['.', '[']).unwrap_or(text.len());
+    let mut steps = Vec::new();
+    while !path.is_empty() {
+        if let Some(rest) = path.strip_prefix("..") {
+            let length = name_length(rest);
+            let name = &rest[..length];
+            steps.push(match name {
+                "" => return None,
+                "*" => Step::Descendant(String::new()),
+                _ => Step::Descendant(name.to_string()),
+            });
+            path = &rest[length..];
+        } else if let Some(rest) = path.strip_prefix('.') {
+            let length = name_length(rest);
+            steps.push(match &rest[..length] {
+                "" => return None,
+                "*" => Step::Wildcard,
+                name => Step::Member(name.to_string()),
+            });
+            path = &rest[length..];
+        } else if let Some(rest) = path.strip_prefix('[') {
+            let end = rest.find(']')?;
+            let spec = rest[..end].trim();
+            steps.push(match spec {
+                "*" => Step::Wildcard,
+                _ if spec.starts_with(['\'', '"']) => Step::Member(spec.get(1..spec.len() - 1).filter(|_| spec.len() >= 2)?.to_string()),
+                _ => Step::Index(spec.parse().ok()?),
+            });
+            path = &rest[end + 1..];
+        } else {
+            return None;
+        }
+    }
+    Some(steps)
+}
+
+// The values one step selects from `value`
+fn select<'a>(value: &'a Value, step: &Step) -> Vec<&'a Value> {
+    match (step, value) {
+        (Step::Member(name), Value::Object(members)) => members.get(name).into_iter().collect(),
+        (Step::Index(index), Value::Array(items)) => {
+            let index = if *index < 0 { items.len() as i64 + index } else { *index };
+            usize::try_from(index).ok().and_then(|index| items.get(index)).into_iter().collect()
+        }
+        (Step::Wildcard, Value::Object(members)) => members.values().collect(),
+        (Step::Wildcard, Value::Array(items)) => items.iter().collect(),
+        (Step::Descendant(name), _) => {
+            let mut found = Vec::new();
+            descend(value, name, &mut found);
+            found
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Collect the members named `name` of `value` and of everything inside it, or every value below it for an empty name
+fn descend<'a>(value: &'a Value, name: &str, found: &mut Vec<&'a Value>) {
+    let children: Vec<(Option<&String>, &Value)> = match value {
+        Value::Object(members) => members.iter().map(|(key, value)| (Some(key), value)).collect(),
+        Value::Array(items) => items.iter().map(|item| (None, item)).collect(),
+        _ => return,
+    };
+    for (key, child) in children {
+        if name.is_empty() || key.is_some_and(|key| key == name) {
+            found.push(child);
+        }
+        descend(child, name, found);
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(selector: &str, body: &str) -> Vec<String> {
+        let base = Url::parse("http://host/pub/").unwrap();
+        Selector::parse(selector).unwrap().links(body, &base).unwrap().into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Selector::parse("$.assets[*].url"), Ok(Selector::JsonPath(vec![Step::Member("assets".to_string()), Step::Wildcard, Step::Member("url".to_string())])));
+        assert_eq!(Selector::parse("$..href"), Ok(Selector::JsonPath(vec![Step::Descendant("href".to_string())])));
+        assert_eq!(Selector::parse("$['files'][-1]"), Ok(Selector::JsonPath(vec![Step::Member("files".to_string()), Step::Index(-1)])));
+        assert!(Selector::parse("$.files[").is_err());
+        assert!(Selector::parse("a[href$=\".iso\"], link.mirror").is_ok());
+        assert!(Selector::parse("table a").is_err());
+        assert!(Selector::parse("a > img").is_err());
+        assert!(Selector::parse("").is_err());
+    }
+
+    #[test]
+    fn test_css_links() {
+        let html = r#"<html><body><a href="../">Parent</a>
+            <a href="disk1.iso">disk1.iso</a> <a class="file big" href="disk2.iso">disk2.iso</a>
+            <a href="disk1.iso">again</a> <a href="notes.txt#top" id="notes">notes</a>
+            <a href="mailto:admin@host">mail</a> <img src="logo.png"></body></html>"#;
+        assert_eq!(links("a[href$='.iso']", html), ["http://host/pub/disk1.iso", "http://host/pub/disk2.iso"]);
+        assert_eq!(links("a.big, #notes", html), ["http://host/pub/disk2.iso", "http://host/pub/notes.txt"]);
+        assert_eq!(links("img", html), ["http://host/pub/logo.png"]);
+        assert_eq!(links("a", html).len(), 4);
+        assert_eq!(links("a[href^=\"http\"]", html), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_json_links() {
+        let json = r#"{"name": "v1.0", "assets": [
+            {"name": "tool.tar.gz", "browser_download_url": "https://cdn/tool.tar.gz"},
+            {"name": "tool.zip", "browser_download_url": "/files/tool.zip"}]}"#;
+        assert_eq!(links("$.assets[*].browser_download_url", json), ["https://cdn/tool.tar.gz", "http://host/files/tool.zip"]);
+        assert_eq!(links("$..browser_download_url", json), ["https://cdn/tool.tar.gz", "http://host/files/tool.zip"]);
+        assert_eq!(links("$.assets[-1].name", json), ["http://host/pub/tool.zip"]);
+        assert!(Selector::parse("$.assets").unwrap().links("<html>", &Url::parse("http://host/").unwrap()).is_err());
+    }
+}
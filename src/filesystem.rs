@@ -1,84 +1,640 @@
-use std::fs::{metadata, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::fs::metadata;
+use std::io::{self, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use url::Url;
+use crate::checksum::{DigestTracker, HashingWriter};
 
-/// A file system abstraction for writing data to a file
-pub struct FileSystem {
-    file_path: PathBuf,
-    byte_ranges: Vec<(u64, u64)>,
+// Suffix of the file a download is written to until it is complete and verified
+const PART_SUFFIX: &str = ".part";
+
+// Extensions that name the server-side script rather than the content it serves
+const UNHELPFUL_EXTENSIONS: &[&str] = &["php", "asp", "aspx", "cgi", "jsp", "pl", "do", "action"];
+
+// Safe mapping from MIME types to file extensions
+// Only unambiguous types are listed; anything else keeps the name untouched
+const MIME_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/gzip", "gz"),
+    ("application/x-gzip", "gz"),
+    ("application/x-tar", "tar"),
+    ("application/x-xz", "xz"),
+    ("application/x-bzip2", "bz2"),
+    ("application/zstd", "zst"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/json", "json"),
+    ("application/xml", "xml"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("text/html", "html"),
+    ("text/plain", "txt"),
+    ("text/csv", "csv"),
+    ("text/css", "css"),
+    ("text/xml", "xml"),
+    ("text/javascript", "js"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/svg+xml", "svg"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+];
+
+/// How the output of a segmented download is reserved before its ranges are written
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FileAllocation {
+    /// Nothing is reserved, the file grows as the ranges land
+    None,
+    /// The file is set to its final size at once, usually as a sparse file
+    #[default]
+    Trunc,
+    /// The disk space is allocated up front, so a full disk fails the download before it starts
+    Falloc,
+}
+
+/// How the ranges of a segmented download are written to the output
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IoBackend {
+    /// Every range writes through its own file handle
+    #[default]
+    Std,
+    /// The writes of all ranges are batched through one io_uring ring, on Linux only
+    Uring,
+    /// Every range copies its bytes into a shared memory map of the output
+    Mmap,
+}
+
+/// The output of a segmented download, shared by the tasks writing its ranges in place
+#[derive(Clone)]
+pub enum RangeOutput {
+    /// Every range opens the file itself
+    File(PathBuf),
+    /// Every range writes through the same io_uring ring
+    #[cfg(target_os = "linux")]
+    Ring(crate::uring::RingFile),
+    /// Every range writes into the same memory map of the file
+    Map(crate::mmap::MappedFile),
 }
 
-/// Implement Write for FileSystem
-impl Seek for FileSystem {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.file_path.seek(pos)
+impl RangeOutput {
+    /// Returns a writer of the output from `offset` on.
+    pub async fn writer(&self, offset: u64) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        match self {
+            RangeOutput::File(path) => Ok(Box::new(RangeWriter::open(path, offset).await?)),
+            #[cfg(target_os = "linux")]
+            RangeOutput::Ring(ring) => Ok(Box::new(ring.writer(offset))),
+            RangeOutput::Map(mapped) => Ok(Box::new(mapped.writer(offset))),
+        }
     }
 }
 
-/// Implement Write for FileSystem
+/// A file system abstraction for writing data to a file
+pub struct FileSystem {
+    file_path: PathBuf,
+    // Hashes what is written through the sequential writers
+    digest: DigestTracker,
+}
+
+/// Implement FileSystem
 impl FileSystem {
     // Create a new FileSystem instance
     // file_path: The path to the file to write to
-    // byte_ranges: A vector of byte ranges to write to the file
-    pub fn new(file_path: PathBuf, byte_ranges: Vec<(u64, u64)>) -> FileSystem {
-        FileSystem {
-            file_path,
-            byte_ranges,
+    pub fn new(file_path: PathBuf) -> FileSystem {
+        FileSystem { file_path, digest: DigestTracker::default() }
+    }
+
+    // Hash the output with `digest` as it is written in order, by a single stream or the streamed ranges
+    pub fn with_digest(mut self, digest: DigestTracker) -> FileSystem {
+        self.digest = digest;
+        self
+    }
+
+    // Name of the output file, for display
+    pub fn file_name(&self) -> String {
+        self.output_path().file_name().unwrap_or_default().to_string_lossy().into_owned()
+    }
+
+    // The output the file is written for: the file itself, or `<output>` while it is written to `<output>.part`
+    pub fn output_path(&self) -> PathBuf {
+        let name = self.file_path.file_name().unwrap_or_default().to_string_lossy();
+        match name.strip_suffix(PART_SUFFIX) {
+            Some(output) if !output.is_empty() => self.file_path.with_file_name(output),
+            _ => self.file_path.clone(),
         }
     }
 
-    // Write chunks to the file
-    pub fn write_chunks(&self, chunk_data: &[(u64, Vec<u8>)]) -> io::Result<()> {
-        // Iterate through the chunks and write the data to the file
-        for &(start, ref data) in chunk_data {
-            let mut file = OpenOptions::new().create(true).write(true).open(&self.file_path)?;
-            // Seek to the start of the chunk and write the data to the file
-            file.seek(SeekFrom::Start(start))?;
-            file.write_all(data)?;
+    // Path of the control file recording the progress of the ranges, `<output>.rtget`
+    pub fn control_path(&self) -> PathBuf {
+        let output = self.output_path();
+        let mut file_name = output.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".rtget");
+        output.with_file_name(file_name)
+    }
+
+    // Move the complete `<output>.part` to the output, replacing what was there
+    // The bytes and then the new name are synced to disk first, so after a crash the output is either the old file or the complete new one
+    pub fn commit(&self) -> io::Result<()> {
+        let output = self.output_path();
+        if output == self.file_path {
+            return Ok(());
         }
+        std::fs::File::open(&self.file_path)?.sync_all()?;
+        std::fs::rename(&self.file_path, &output)?;
+        // Directories can only be opened for syncing on Unix
+        #[cfg(unix)]
+        std::fs::File::open(directory_of(&output))?.sync_all()?;
         Ok(())
     }
 
-    // Check if the file exists
-    pub fn file_exists(&self) -> bool {
-        self.file_path.exists()
-    }
-
-    // Calculate byte ranges for any existing partial files
-    // Returns a vector of adjusted byte ranges
-    pub async fn calculate_byte_ranges_on_existing_files(&self, byte_ranges: &mut Vec<(u64, u64)>) -> Vec<(u64, u64)> {
-        // Iterate through byte ranges and adjust start and end values for any existing partial files
-        for (i, (start, end)) in byte_ranges.iter_mut().enumerate() {
-            let part_file_path = Path::new(self.file_path).with_file_name(format!("{}_part_{}", Path::new(self.file_path).display().to_string(), i));
-            // If the partial file exists, adjust the start and end values to the end of the partial file
-            if part_file_path.exists() {
-                let metadata = metadata(&part_file_path).unwrap();
-                let downloaded = metadata.len();
-                // If the partial file is smaller than the requested range, adjust the end value to the end of the partial file
-                if downloaded <= *end - *start {
-                    *start += downloaded;
-                } else {
-                    *start = *end + 1;
-                }
+    // Create the output file and reserve `size` bytes for it as `allocation` says, so every range can be written in place
+    // Existing content is kept, which is what a resumed download relies on, but a longer file is cut to `size`
+    pub fn allocate(&self, size: u64, allocation: FileAllocation) -> io::Result<()> {
+        let file = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(&self.file_path)?;
+        if file.metadata()?.len() > size {
+            file.set_len(size)?;
+        }
+        match allocation {
+            FileAllocation::None => Ok(()),
+            FileAllocation::Trunc => file.set_len(size),
+            FileAllocation::Falloc => fallocate(file, size),
+        }
+    }
+
+    // The output allocated to `size` bytes, for its ranges to be written in place through `backend`
+    // Without io_uring support, or when the file cannot be mapped, the ranges are written through regular file handles instead
+    pub fn range_output(&self, backend: IoBackend, size: u64) -> RangeOutput {
+        match backend {
+            IoBackend::Std => {}
+            IoBackend::Mmap => match crate::mmap::MappedFile::open(&self.file_path, size) {
+                Ok(mapped) => return RangeOutput::Map(mapped),
+                Err(e) => tracing::warn!("The output cannot be memory mapped ({}), writing the ranges with regular writes", e),
+            },
+            #[cfg(target_os = "linux")]
+            IoBackend::Uring => match crate::uring::RingFile::open(&self.file_path) {
+                Ok(ring) => return RangeOutput::Ring(ring),
+                Err(e) => tracing::warn!("io_uring is not available ({}), writing the ranges with regular writes", e),
+            },
+            #[cfg(not(target_os = "linux"))]
+            IoBackend::Uring => tracing::warn!("io_uring is only available on Linux, writing the ranges with regular writes"),
+        }
+        RangeOutput::File(self.file_path.clone())
+    }
+
+    // Create (or truncate) the output file for sequential writing
+    // Opening a FIFO for writing blocks until a consumer opens it for reading
+    pub async fn create_output(&self) -> io::Result<HashingWriter<tokio::fs::File>> {
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)
+            .await?;
+        Ok(HashingWriter::new(file, self.digest.clone(), 0))
+    }
+
+    // Open the existing output file for appending the rest of a partial download
+    pub async fn append_output(&self) -> io::Result<HashingWriter<tokio::fs::File>> {
+        let file = tokio::fs::OpenOptions::new().append(true).open(&self.file_path).await?;
+        let offset = file.metadata().await?.len();
+        Ok(HashingWriter::new(file, self.digest.clone(), offset))
+    }
+
+    // Size of an existing output that a single-connection download left behind
+    // Outputs with a control file are segmented downloads and resume from it instead
+    pub fn partial_output_size(&self) -> Option<u64> {
+        if self.control_path().exists() {
+            return None;
+        }
+        metadata(&self.file_path).ok().filter(|m| m.is_file() && m.len() > 0).map(|m| m.len())
+    }
+
+    // Bytes the output still needs on disk to hold `size` bytes
+    // The space an existing output already takes is reused, e.g. the ranges of an interrupted download
+    // Ranges are written in place, so nothing beyond the file itself is needed
+    pub fn space_needed(&self, size: u64) -> u64 {
+        let used = match metadata(&self.file_path) {
+            #[cfg(unix)]
+            Ok(m) if m.is_file() => std::os::unix::fs::MetadataExt::blocks(&m) * 512,
+            #[cfg(not(unix))]
+            Ok(m) if m.is_file() => m.len(),
+            _ => 0,
+        };
+        size.saturating_sub(used)
+    }
+
+    // Bytes free for unprivileged users on the file system of the output, if it can be found out
+    pub fn free_space(&self) -> Option<u64> {
+        free_space(directory_of(&self.file_path))
+    }
+
+    // Remove the output file, unless it is a named pipe owned by someone else
+    pub fn remove_output(&self) -> io::Result<()> {
+        if is_fifo(&self.file_path) {
+            return Ok(());
+        }
+        remove_if_exists(&self.file_path)
+    }
+
+    // Remove the control file, once the download is complete or its ranges are stale
+    pub fn remove_control(&self) -> io::Result<()> {
+        remove_if_exists(&self.control_path())
+    }
+
+    // Stream the chunk pipes into the output file strictly in order, each with the length of its range
+    // Pipes that are not being drained yet fill up and stall their tasks, which gives backpressure
+    // Returns an error if the output could not be opened or the reader went away
+    // A pipe that ends before its range does stops the stream, as its range failed, so no later bytes follow the gap
+    pub async fn stream_in_order<R>(&self, pipes: Vec<(R, u64)>) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut output = self.create_output().await?;
+        for (mut pipe, length) in pipes {
+            let copied = tokio::io::copy(&mut pipe, &mut output).await?;
+            if copied < length {
+                output.flush().await?;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("a range ended after {} of its {} bytes", copied, length)));
             }
         }
-        // Return the adjusted byte ranges
-        byte_ranges.clone()
-    }
-
-    // Resume a download
-    // Returns an error if the file could not be opened for writing
-    pub async fn resume_download(&mut self) -> io::Result<()> {
-        // Adjust byte ranges for any existing partial files
-        let remaining_ranges = calculate_byte_ranges_on_existing_files(&mut self.byte_ranges, &self.file_path.to_string_lossy()).await;
-
-        // Implement logic to fetch and write the remaining data
-        for (start, end) in remaining_ranges {
-            // Replace this with actual data fetching logic
-            let data = vec![0u8; (end - start) as usize]; // Dummy data
-            let chunk_data = vec![(start, data)];
-            self.write_chunks(&chunk_data)?;
+        output.flush().await
+    }
+}
+
+/// Writes one range of the output file in place.
+///
+/// Each write is flushed before it is reported as done, so whoever counts the written bytes, like
+/// the progress bar the control file is saved from, never counts bytes that are not in the file yet.
+pub struct RangeWriter {
+    file: tokio::fs::File,
+    // Bytes accepted by the file but not flushed yet
+    pending: usize,
+}
+
+impl RangeWriter {
+    /// Opens the existing output at `path` for writing from `offset` on.
+    pub async fn open(path: &Path, offset: u64) -> io::Result<RangeWriter> {
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(RangeWriter { file, pending: 0 })
+    }
+}
+
+impl AsyncWrite for RangeWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending == 0 {
+            this.pending = ready!(Pin::new(&mut this.file).poll_write(cx, buf))?;
         }
-        Ok(())
+        ready!(Pin::new(&mut this.file).poll_flush(cx))?;
+        Poll::Ready(Ok(std::mem::take(&mut this.pending)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+// Allocate the disk space of the first `size` bytes of the file
+// File systems without fallocate support get the missing space written with zeros instead
+fn fallocate(file: std::fs::File, size: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let offset = libc::off_t::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large"))?;
+        // SAFETY: the descriptor stays open for the duration of the call
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, offset) } {
+            0 => return Ok(()),
+            libc::EOPNOTSUPP | libc::EINVAL => {}
+            error => return Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+    zero_fill(file, size)
+}
+
+// Extend the file to `size` bytes by writing zeros after its current end
+fn zero_fill(mut file: std::fs::File, size: u64) -> io::Result<()> {
+    use std::io::{Seek, Write};
+    let mut length = file.seek(SeekFrom::End(0))?;
+    let zeros = vec![0; 1024 * 1024];
+    while length < size {
+        let block = (size - length).min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..block])?;
+        length += block as u64;
+    }
+    file.sync_all()
+}
+
+// Directory holding `path`, the current one for a bare file name
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
     }
-}
\ No newline at end of file
+}
+
+// Bytes free for unprivileged users on the file system of the directory
+fn free_space(dir: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+        // SAFETY: the path is NUL terminated and statvfs only writes into the zeroed struct
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return None;
+        }
+        #[allow(clippy::unnecessary_cast)]
+        Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        None
+    }
+}
+
+// Remove a file, treating an already missing file as success
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns where `output` is written until it is complete and verified, `<output>.part`.
+pub fn part_path(output: &Path) -> PathBuf {
+    let mut file_name = output.file_name().unwrap_or_default().to_os_string();
+    file_name.push(PART_SUFFIX);
+    output.with_file_name(file_name)
+}
+
+// Check whether the path is a named pipe (FIFO)
+pub fn is_fifo(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        metadata(path).map(|m| m.file_type().is_fifo()).unwrap_or(false)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+// Derive the output file name from the last segment of the URL path
+// Falls back to `index.html` when the URL has no file name
+pub fn default_output_path(url: &Url) -> PathBuf {
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("index.html");
+    Path::new(file_name).to_path_buf()
+}
+
+// Placeholders an --output-template may use
+const TEMPLATE_PLACEHOLDERS: [&str; 3] = ["host", "path", "filename"];
+
+/// Checks that every `{...}` of an `--output-template` is a known placeholder.
+pub fn check_output_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..].find('}').ok_or_else(|| format!("{} has a {{ without a matching }}", template))? + open;
+        let name = &rest[open + 1..close];
+        if !TEMPLATE_PLACEHOLDERS.contains(&name) {
+            return Err(format!("unknown placeholder {{{}}} in {}, expected {{host}}, {{path}} or {{filename}}", name, template));
+        }
+        rest = &rest[close + 1..];
+    }
+    Ok(())
+}
+
+/// Lays out the output of `url` after an `--output-template`.
+///
+/// `{host}` is the host of the URL, `{path}` the directories of its path and `{filename}` the name
+/// the file gets without a template. A URL without directories drops `{path}` and its separator.
+pub fn templated_output_path(template: &str, url: &Url, file_name: &Path) -> PathBuf {
+    let mut directories: Vec<&str> = url.path_segments().map(|segments| segments.collect()).unwrap_or_default();
+    directories.pop();
+    let directories = directories.into_iter().filter(|directory| !directory.is_empty()).collect::<Vec<_>>().join("/");
+    let template = match directories.is_empty() {
+        true => template.replace("{path}/", ""),
+        false => template.to_string(),
+    };
+    let rendered = template
+        .replace("{host}", url.host_str().unwrap_or_default())
+        .replace("{path}", &directories)
+        .replace("{filename}", &file_name.to_string_lossy());
+    // Collapses the separators around placeholders that came out empty
+    Path::new(&rendered).components().collect()
+}
+
+// The first free path after `path` numbered like `file.iso.1`, `file.iso.2`, for a file that must not replace it
+pub fn numbered_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_os_string();
+    (1..)
+        .map(|number| {
+            let mut numbered = file_name.clone();
+            numbered.push(format!(".{}", number));
+            path.with_file_name(numbered)
+        })
+        .find(|numbered| std::fs::symlink_metadata(numbered).is_err())
+        .expect("some number is free")
+}
+
+// Append the extension matching `content_type` when the file name has no useful one
+// Unknown or generic content types leave the path unchanged
+pub fn with_mime_extension(path: &Path, content_type: &str) -> PathBuf {
+    let useful = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| !UNHELPFUL_EXTENSIONS.contains(&ext.as_str()));
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    match MIME_EXTENSIONS.iter().find(|(known, _)| *known == mime) {
+        Some((_, extension)) if !useful => {
+            let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+            file_name.push(".");
+            file_name.push(extension);
+            path.with_file_name(file_name)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_templated_output_path() {
+        let url = Url::parse("https://data.example.com/eu/2024/sales.csv").unwrap();
+        let file_name = default_output_path(&url);
+        assert_eq!(templated_output_path("{host}/{filename}", &url, &file_name), Path::new("data.example.com/sales.csv"));
+        assert_eq!(templated_output_path("{host}/{path}/{filename}", &url, &file_name), Path::new("data.example.com/eu/2024/sales.csv"));
+        let url = Url::parse("https://example.com/sales.csv").unwrap();
+        assert_eq!(templated_output_path("{path}/{filename}", &url, &file_name), Path::new("sales.csv"));
+
+        assert!(check_output_template("{host}/{path}/x-{filename}").is_ok());
+        assert!(check_output_template("{region}/{filename}").is_err());
+        assert!(check_output_template("{host").is_err());
+    }
+
+    #[test]
+    fn test_numbered_path() {
+        let dir = crate::test_server::temp_dir("numbered");
+        let path = dir.join("file.iso");
+        assert_eq!(numbered_path(&path), dir.join("file.iso.1"));
+        std::fs::write(dir.join("file.iso.1"), b"").unwrap();
+        assert_eq!(numbered_path(&path), dir.join("file.iso.2"));
+    }
+
+    #[test]
+    fn test_with_mime_extension() {
+        assert_eq!(with_mime_extension(Path::new("download"), "application/pdf"), Path::new("download.pdf"));
+        assert_eq!(with_mime_extension(Path::new("get.php"), "image/png; charset=binary"), Path::new("get.php.png"));
+        assert_eq!(with_mime_extension(Path::new("report.pdf"), "text/html"), Path::new("report.pdf"));
+        assert_eq!(with_mime_extension(Path::new("blob"), "application/octet-stream"), Path::new("blob"));
+    }
+
+    #[test]
+    fn test_stream_in_order() {
+        let dir = test_server::temp_dir("stream_in_order");
+        let file_system = FileSystem::new(dir.join("out"));
+
+        Runtime::new().unwrap().block_on(async {
+            let (mut first, first_reader) = tokio::io::duplex(4);
+            let (mut second, second_reader) = tokio::io::duplex(4);
+
+            // The second range finishes first but must still land after the first one
+            second.write_all(b"def").await.unwrap();
+            drop(second);
+            let writer = tokio::spawn(async move {
+                first.write_all(b"abc").await.unwrap();
+            });
+
+            file_system.stream_in_order(vec![(first_reader, 3), (second_reader, 3)]).await.unwrap();
+            writer.await.unwrap();
+        });
+
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"abcdef");
+        assert!(!is_fifo(&dir.join("out")));
+    }
+
+    #[test]
+    fn test_stream_stops_at_a_failed_range() {
+        let dir = test_server::temp_dir("stream_failed_range");
+        let file_system = FileSystem::new(dir.join("out"));
+
+        let result = Runtime::new().unwrap().block_on(async {
+            let (mut first, first_reader) = tokio::io::duplex(4);
+            let (mut second, second_reader) = tokio::io::duplex(4);
+            // The first range fails after two of its bytes
+            first.write_all(b"ab").await.unwrap();
+            drop(first);
+            second.write_all(b"def").await.unwrap();
+            drop(second);
+            file_system.stream_in_order(vec![(first_reader, 3), (second_reader, 3)]).await
+        });
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn test_ranges_written_in_place() {
+        let dir = test_server::temp_dir("ranges_in_place");
+        let file_system = FileSystem::new(dir.join("out"));
+        file_system.allocate(6, FileAllocation::Trunc).unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            // Ranges land at their offsets whatever order they are written in
+            RangeWriter::open(&dir.join("out"), 3).await.unwrap().write_all(b"def").await.unwrap();
+            RangeWriter::open(&dir.join("out"), 0).await.unwrap().write_all(b"abc").await.unwrap();
+        });
+
+        // Allocating again keeps what was written
+        file_system.allocate(6, FileAllocation::Falloc).unwrap();
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"abcdef");
+
+        // A longer file left by an earlier download is cut to the size
+        file_system.allocate(4, FileAllocation::None).unwrap();
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"abcd");
+    }
+
+    #[test]
+    fn test_part_is_committed() {
+        let dir = test_server::temp_dir("commit");
+        let output = dir.join("file.bin");
+        std::fs::write(&output, b"old").unwrap();
+        let part = FileSystem::new(part_path(&output));
+        assert_eq!(part.output_path(), output);
+        assert_eq!(part.control_path(), dir.join("file.bin.rtget"));
+        assert_eq!(part.file_name(), "file.bin");
+
+        // The old output stays in place until the new one is complete
+        std::fs::write(dir.join("file.bin.part"), b"new").unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"old");
+        part.commit().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"new");
+        assert!(!dir.join("file.bin.part").exists());
+
+        // An output that is not written through a part has nothing to commit
+        FileSystem::new(output.clone()).commit().unwrap();
+        assert_eq!(FileSystem::new(dir.join("x.part.part")).output_path(), dir.join("x.part"));
+    }
+
+    #[test]
+    fn test_file_allocation() {
+        let dir = test_server::temp_dir("file_allocation");
+        for (name, allocation, expected) in [("none", FileAllocation::None, 0), ("trunc", FileAllocation::Trunc, 3000), ("falloc", FileAllocation::Falloc, 3000)] {
+            FileSystem::new(dir.join(name)).allocate(3000, allocation).unwrap();
+            assert_eq!(metadata(dir.join(name)).unwrap().len(), expected, "{}", name);
+        }
+
+        // Zero filling only appends
+        std::fs::write(dir.join("zero"), b"abc").unwrap();
+        zero_fill(std::fs::OpenOptions::new().write(true).open(dir.join("zero")).unwrap(), 5).unwrap();
+        assert_eq!(std::fs::read(dir.join("zero")).unwrap(), b"abc\0\0");
+    }
+
+    #[test]
+    fn test_partial_output_size() {
+        let dir = test_server::temp_dir("partial_output_size");
+        let file_system = FileSystem::new(dir.join("out"));
+        assert_eq!(file_system.partial_output_size(), None);
+
+        std::fs::write(dir.join("out"), b"abc").unwrap();
+        assert_eq!(file_system.partial_output_size(), Some(3));
+
+        // A segmented download resumes from its control file instead
+        std::fs::write(file_system.control_path(), b"").unwrap();
+        assert_eq!(file_system.partial_output_size(), None);
+    }
+
+    #[test]
+    fn test_space_needed() {
+        let dir = test_server::temp_dir("space_needed");
+        let file_system = FileSystem::new(dir.join("out"));
+        assert_eq!(file_system.space_needed(1 << 20), 1 << 20);
+        assert!(file_system.free_space().is_some_and(|free| free > 0));
+
+        // The blocks an existing output takes are reused
+        std::fs::write(dir.join("out"), vec![1u8; 256 * 1024]).unwrap();
+        assert!(file_system.space_needed(1 << 20) <= 768 * 1024);
+        assert_eq!(file_system.space_needed(4096), 0);
+    }
+}
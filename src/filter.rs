@@ -0,0 +1,189 @@
+use url::Url;
+use crate::args::CommandLineArgs;
+
+/// The -A, -R, --include-directories and --exclude-directories filters deciding which URLs of a batch are downloaded
+#[derive(Clone, Debug, Default)]
+pub struct Filters {
+    accept: Vec<String>,
+    reject: Vec<String>,
+    include_directories: Vec<String>,
+    exclude_directories: Vec<String>,
+}
+
+impl Filters {
+    /// Collects the filters of the command line, each option a comma-separated list that may be repeated.
+    pub fn from_args(args: &CommandLineArgs) -> Filters {
+        Filters {
+            accept: split(&args.accept),
+            reject: split(&args.reject),
+            include_directories: split(&args.include_directories).iter().map(|directory| normalize_directory(directory)).collect(),
+            exclude_directories: split(&args.exclude_directories).iter().map(|directory| normalize_directory(directory)).collect(),
+        }
+    }
+
+    /// Returns whether a file named `name` is downloaded: it matches an -A pattern, if any, and no -R pattern.
+    ///
+    /// A pattern with `*`, `?` or `[` is a wildcard pattern of the whole name, any other one a suffix,
+    /// so `-A iso` and `-A '*.iso'` accept the same files.
+    pub fn accepts_name(&self, name: &str) -> bool {
+        let matches = |pattern: &String| match pattern.contains(['*', '?', '[']) {
+            true => wildcard_match(pattern, name),
+            false => name.ends_with(pattern.as_str()),
+        };
+        (self.accept.is_empty() || self.accept.iter().any(matches)) && !self.reject.iter().any(matches)
+    }
+
+    /// Returns whether the files of the directory `directory` of a URL path, like `/pub/iso`, are downloaded.
+    ///
+    /// A listed directory covers its subdirectories too, and may hold wildcards, e.g. `/pub/*/old`.
+    pub fn accepts_directory(&self, directory: &str) -> bool {
+        let directory = normalize_directory(directory);
+        // The directory and each of its parents, e.g. /pub, /pub/iso
+        let mut ancestors: Vec<&str> = directory.match_indices('/').skip(1).map(|(index, _)| &directory[..index]).collect();
+        ancestors.push(&directory);
+        let covers = |listed: &String| ancestors.iter().any(|ancestor| wildcard_match(listed, ancestor));
+        (self.include_directories.is_empty() || self.include_directories.iter().any(covers)) && !self.exclude_directories.iter().any(covers)
+    }
+
+    /// Returns whether `url` passes every filter, by its directory and its file name.
+    pub fn accepts(&self, url: &Url) -> bool {
+        let path = url.path();
+        let (directory, name) = path.rsplit_once('/').unwrap_or(("", path));
+        self.accepts_directory(directory) && self.accepts_name(name)
+    }
+
+    /// Returns whether `url` passes the directory filters; pages rejected only by name are still crawled for their links.
+    pub fn accepts_directory_of(&self, url: &Url) -> bool {
+        self.accepts_directory(url.path().rsplit_once('/').map_or("", |(directory, _)| directory))
+    }
+}
+
+// The patterns of every occurrence of a comma-separated option
+fn split(values: &[String]) -> Vec<String> {
+    values.iter().flat_map(|value| value.split(',')).map(str::trim).filter(|value| !value.is_empty()).map(str::to_string).collect()
+}
+
+// The directory with a leading slash and without a trailing one, e.g. `pub/iso/` as `/pub/iso`
+fn normalize_directory(directory: &str) -> String {
+    format!("/{}", directory.trim_matches('/'))
+}
+
+/// Matches `text` against a shell wildcard `pattern` of `*`, `?` and `[...]` character classes.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Where the last * started and how much of the text it has taken, to try taking one more on a mismatch
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some('[') => {
+                if let Some((matched, end)) = match_class(&pattern[p..], text[t]) {
+                    if matched {
+                        p += end;
+                        t += 1;
+                        continue;
+                    }
+                } else if text[t] == '[' {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Match `c` against the character class at the start of `pattern`, like `[a-z]` or `[!0-9]`
+// Returns whether it matched and the length of the class, or None when the class is not closed
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.get(1), Some('!' | '^'));
+    let start = if negated { 2 } else { 1 };
+    let end = start + 1 + pattern.get(start + 1..)?.iter().position(|&c| c == ']')?;
+    let class = &pattern[start..end];
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            matched |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    Some((matched != negated, end + 1))
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    fn parse_filters(flags: &[&str]) -> Filters {
+        Filters::from_args(&CommandLineArgs::from_args(&["rtget"], flags).unwrap())
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.iso", "debian-12.iso"));
+        assert!(!wildcard_match("*.iso", "debian-12.iso.sig"));
+        assert!(wildcard_match("2024-??-*.gz", "2024-05-01.gz"));
+        assert!(wildcard_match("img[0-9].png", "img7.png"));
+        assert!(!wildcard_match("img[!0-9].png", "img7.png"));
+        assert!(wildcard_match("a*b*c", "axxbyybc"));
+        assert!(wildcard_match("[", "["));
+    }
+
+    #[test]
+    fn test_accepts_name() {
+        let filters = parse_filters(&["-A", "*.iso,img", "-R", "*debug*"]);
+        assert!(filters.accepts_name("debian.iso"));
+        assert!(filters.accepts_name("disk.img"));
+        assert!(!filters.accepts_name("debian.iso.sig"));
+        assert!(!filters.accepts_name("debug-symbols.iso"));
+        assert!(parse_filters(&[]).accepts_name("anything"));
+    }
+
+    #[test]
+    fn test_accepts_directory() {
+        let filters = parse_filters(&["-I", "/pub,/mirror/*/current", "-X", "pub/old/"]);
+        assert!(filters.accepts_directory("/pub"));
+        assert!(filters.accepts_directory("/pub/iso"));
+        assert!(!filters.accepts_directory("/pub/old/2019"));
+        assert!(filters.accepts_directory("/mirror/debian/current/amd64"));
+        assert!(!filters.accepts_directory("/mirror/debian/archive"));
+        assert!(!filters.accepts_directory("/public"));
+
+        let url = Url::parse("http://example.com/pub/iso/debian.iso").unwrap();
+        assert!(filters.accepts(&url));
+        assert!(!parse_filters(&["-R", "iso"]).accepts(&url));
+        assert!(parse_filters(&["-R", "iso"]).accepts_directory_of(&url));
+    }
+}
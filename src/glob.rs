@@ -0,0 +1,61 @@
+use url::Url;
+use crate::downloader::FileDownloader;
+use crate::error::AppError;
+use crate::filter;
+
+/// Splits an FTP URL whose file name has the wildcards `*` or `?` into its directory and the pattern.
+///
+/// Returns None for any other URL. Character classes like `[0-9]` are URL patterns of their own,
+/// expanded before the directory is listed.
+pub fn pattern(url: &str) -> Option<(Url, String)> {
+    let url = Url::parse(url).ok().filter(|url| url.scheme() == "ftp")?;
+    // A `?` in the file name starts the query of the URL
+    let name = url.path_segments()?.next_back()?;
+    let pattern = match url.query() {
+        Some(query) => format!("{}?{}", name, query),
+        None => name.to_string(),
+    };
+    if !pattern.contains(['*', '?']) {
+        return None;
+    }
+    let mut directory = url.join("./").ok()?;
+    directory.set_query(None);
+    Some((directory, pattern))
+}
+
+/// Expands a wildcard URL into the URLs of the files of its directory the pattern matches, sorted by name.
+///
+/// Returns None when `url` has no wildcards.
+pub async fn expand(downloader: &FileDownloader, url: &str) -> Result<Option<Vec<String>>, AppError> {
+    let Some((directory, pattern)) = pattern(url) else {
+        return Ok(None);
+    };
+    let mut names = downloader.list_directory(&directory).await?;
+    names.sort();
+    let mut urls = Vec::new();
+    for name in names.iter().filter(|name| filter::wildcard_match(&pattern, name)) {
+        let mut file = directory.clone();
+        file.path_segments_mut().map_err(|_| AppError::UrlValidationError(format!("{} cannot have files", directory)))?.pop_if_empty().push(name);
+        urls.push(file.to_string());
+    }
+    if urls.is_empty() {
+        return Err(AppError::UrlValidationError(format!("no file of {} matches {}", directory, pattern)));
+    }
+    Ok(Some(urls))
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let split = |url| pattern(url).map(|(directory, pattern)| (directory.to_string(), pattern));
+        assert_eq!(split("ftp://host/pub/logs/2024-*.gz"), Some(("ftp://host/pub/logs/".to_string(), "2024-*.gz".to_string())));
+        assert_eq!(split("ftp://host/pub/logs/2024-0?.gz"), Some(("ftp://host/pub/logs/".to_string(), "2024-0?.gz".to_string())));
+        assert_eq!(split("ftp://host/pub/logs/2024-01.gz"), None);
+        assert_eq!(split("ftp://host/pub/*/2024-01.gz"), None);
+        assert_eq!(split("http://host/pub/logs/2024-*.gz"), None);
+    }
+}
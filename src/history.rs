@@ -0,0 +1,201 @@
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use indicatif::HumanBytes;
+use serde_json::{json, Value};
+use crate::args::{self, HistoryArgs};
+use crate::batch;
+use crate::error::AppError;
+use crate::scheduler::{self, StartAt};
+
+// Name of the history file in the home directory
+const HISTORY_FILE_NAME: &str = ".rtget-history";
+
+/// A finished download, as the history records it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// When the download finished
+    pub time: SystemTime,
+    pub url: String,
+    /// Where the file was saved, unless the download failed
+    pub path: Option<PathBuf>,
+    /// Size of the saved file
+    pub size: Option<u64>,
+    /// How long the download took
+    pub duration: Duration,
+    /// SHA-256 of the saved file in hex
+    pub sha256: Option<String>,
+    /// Why the download failed
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /// Describes the download of `url` that ended now with `result` after `duration`, hashing the saved file.
+    ///
+    /// The file is hashed on a blocking thread, so a large one does not hold up the other downloads.
+    pub async fn new(url: &str, result: Result<&Path, &AppError>, duration: Duration) -> Entry {
+        let (path, error) = match result {
+            Ok(path) => (Some(path.to_path_buf()), None),
+            Err(error) => (None, Some(error.to_string())),
+        };
+        let sha256 = match path.clone() {
+            Some(path) => tokio::task::spawn_blocking(move || batch::sha256(&path)).await.ok().flatten(),
+            None => None,
+        };
+        Entry {
+            time: SystemTime::now(),
+            url: url.to_string(),
+            size: path.as_deref().and_then(|path| std::fs::metadata(path).ok()).map(|metadata| metadata.len()),
+            sha256,
+            path,
+            duration,
+            error,
+        }
+    }
+
+    /// Describes the download as JSON, with its time in seconds since the Unix epoch and its path made absolute.
+    pub fn to_json(&self) -> Value {
+        let path = self.path.as_deref().map(|path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()));
+        json!({
+            "time": self.time.duration_since(SystemTime::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default(),
+            "url": self.url,
+            "status": if self.error.is_none() { "complete" } else { "failed" },
+            "path": path.map(|path| path.display().to_string()),
+            "size": self.size,
+            "duration": self.duration.as_secs_f64(),
+            "sha256": self.sha256,
+            "error": self.error,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Entry> {
+        Some(Entry {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(value["time"].as_u64()?),
+            url: value["url"].as_str()?.to_string(),
+            path: value["path"].as_str().map(PathBuf::from),
+            size: value["size"].as_u64(),
+            duration: Duration::from_secs_f64(value["duration"].as_f64().unwrap_or_default().max(0.0)),
+            sha256: value["sha256"].as_str().map(str::to_string),
+            error: value["error"].as_str().map(str::to_string),
+        })
+    }
+}
+
+/// Returns where the history is kept: `~/.rtget-history`, one JSON object per line.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).map(|home| PathBuf::from(home).join(HISTORY_FILE_NAME))
+}
+
+/// Appends `entry` to the history at `path`.
+pub fn append(path: &Path, entry: &Entry) -> io::Result<()> {
+    let mut file = std::fs::File::options().create(true).append(true).open(path)?;
+    // One write per line, so downloads finishing together do not interleave
+    file.write_all(format!("{}\n", entry.to_json()).as_bytes())
+}
+
+/// Reads the history at `path`, oldest first.
+///
+/// A missing file is an empty history; malformed lines are skipped.
+pub fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(text.lines().filter_map(|line| serde_json::from_str(line).ok()).filter_map(|value| Entry::from_json(&value)).collect())
+}
+
+/// Answers `rtget history`: the downloads of the history that match the filters, as a table or JSON.
+pub fn query(args: &HistoryArgs) -> Result<String, AppError> {
+    let path = match &args.file {
+        Some(file) => PathBuf::from(file),
+        None => default_path().ok_or_else(|| AppError::StringError("no home directory to find the history in".to_string()))?,
+    };
+    let since = args.since.as_deref().map(|since| parse_since(since, SystemTime::now())).transpose()?;
+    let entries: Vec<Entry> = load(&path)?
+        .into_iter()
+        .filter(|entry| since.is_none_or(|since| entry.time >= since))
+        .filter(|entry| args.url.as_deref().is_none_or(|url| entry.url.contains(url)))
+        .filter(|entry| !args.failed || entry.error.is_some())
+        .collect();
+    Ok(match args.json {
+        true => format!("{}\n", Value::Array(entries.iter().map(Entry::to_json).collect())),
+        false => render(&entries),
+    })
+}
+
+// The start of the period of --since: a local date and time, or an interval back from `now` like 7d
+fn parse_since(since: &str, now: SystemTime) -> Result<SystemTime, String> {
+    if let Ok(interval) = args::parse_interval(since) {
+        return Ok(now.checked_sub(interval).unwrap_or(SystemTime::UNIX_EPOCH));
+    }
+    // A date alone means its midnight
+    let since = if since.contains(':') { since.to_string() } else { format!("{} 00:00", since) };
+    match StartAt::parse(&since) {
+        Ok(start @ StartAt::Once(_)) => Ok(start.next(SystemTime::UNIX_EPOCH)),
+        _ => Err(format!("invalid --since {}, expected a date like 2024-03-01, a time like \"2024-03-01 18:00\" or an interval like 7d", since.trim_end_matches(" 00:00"))),
+    }
+}
+
+// One line per download: when it ended, how it went, its size and duration, and where it came from and went
+fn render(entries: &[Entry]) -> String {
+    if entries.is_empty() {
+        return "No downloads recorded\n".to_string();
+    }
+    let mut output = String::new();
+    let _ = writeln!(output, "{:<19}  {:<8} {:>10} {:>8}  URL", "Time", "Status", "Size", "Seconds");
+    for entry in entries {
+        let size = entry.size.map(|size| HumanBytes(size).to_string()).unwrap_or_else(|| "-".to_string());
+        let status = if entry.error.is_none() { "complete" } else { "failed" };
+        let _ = write!(output, "{:<19}  {:<8} {:>10} {:>8.1}  {}", scheduler::describe(entry.time), status, size, entry.duration.as_secs_f64(), entry.url);
+        let _ = match (&entry.path, &entry.error) {
+            (_, Some(error)) => writeln!(output, " ({})", error),
+            (Some(path), None) => writeln!(output, " -> {}", path.display()),
+            (None, None) => writeln!(output),
+        };
+    }
+    output
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    fn query_args(file: &Path, filters: &[&str]) -> HistoryArgs {
+        use argh::FromArgs;
+        let file = file.display().to_string();
+        let mut args = vec!["--file", &file];
+        args.extend(filters);
+        HistoryArgs::from_args(&["rtget history"], &args).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let dir = test_server::temp_dir("history");
+        let (file, history) = (dir.join("iso"), dir.join("history"));
+        std::fs::write(&file, "hello").unwrap();
+        let mut old = Entry::new("http://a/old.iso", Ok(&file), Duration::from_secs(3)).await;
+        old.time -= Duration::from_secs(10 * 86_400);
+        append(&history, &old).unwrap();
+        append(&history, &Entry::new("http://a/new.iso", Ok(&file), Duration::from_millis(1500)).await).unwrap();
+        append(&history, &Entry::new("http://b/gone.iso", Err(&AppError::HttpStatus(404, "404 Not Found".to_string())), Duration::ZERO).await).unwrap();
+
+        let entries = load(&history).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[1].size, entries[1].sha256.as_deref()), (Some(5), Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")));
+        assert!(entries[1].path.as_deref().is_some_and(Path::is_absolute));
+
+        let table = query(&query_args(&history, &["--since", "7d"])).unwrap();
+        assert_eq!(table.lines().count(), 3);
+        assert!(table.contains("http://a/new.iso -> ") && table.contains("http://b/gone.iso (Could not connect to the server: 404 Not Found)"), "{}", table);
+        let json: Value = serde_json::from_str(&query(&query_args(&history, &["--json", "--url", "a/", "--since", "2000-01-01"])).unwrap()).unwrap();
+        assert_eq!(json.as_array().map(Vec::len), Some(2));
+        assert_eq!(json[0]["status"], "complete");
+        assert_eq!(query(&query_args(&history, &["--failed", "--url", "a/"])).unwrap(), "No downloads recorded\n");
+        assert!(query(&query_args(&history, &["--since", "last week"])).is_err());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,125 @@
+use std::process::Command;
+use crate::error::AppError;
+use crate::history::Entry;
+
+/// Runs the --exec or --exec-on-error `command` of a finished download through the shell.
+///
+/// `{}` and `{path}` are replaced by the path of the saved file, `{url}` by the URL, `{sha256}` by
+/// the SHA-256 of the file and `{error}` by why the download failed, each quoted for the shell.
+/// The same values are passed in the environment as `RTGET_PATH`, `RTGET_URL`, `RTGET_SHA256` and `RTGET_ERROR`.
+pub async fn run(command: &str, entry: &Entry) -> Result<(), AppError> {
+    let path = entry.path.as_deref().map(|path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()).display().to_string());
+    let values = [
+        ("path", path.unwrap_or_default()),
+        ("url", entry.url.clone()),
+        ("sha256", entry.sha256.clone().unwrap_or_default()),
+        ("error", entry.error.clone().unwrap_or_default()),
+    ];
+    let mut shell = shell(&expand(command, &values));
+    for (name, value) in &values {
+        shell.env(format!("RTGET_{}", name.to_uppercase()), value);
+    }
+    let status = tokio::task::spawn_blocking(move || shell.status()).await.map_err(|e| AppError::StringError(e.to_string()))??;
+    match status.success() {
+        true => Ok(()),
+        false => Err(AppError::StringError(format!("the command {} failed with {}", command, status))),
+    }
+}
+
+// The command with its placeholders replaced by the quoted values, `{}` standing for the first one
+// The command is read once from start to end, so a value is never searched for placeholders itself
+fn expand(command: &str, values: &[(&str, String)]) -> String {
+    let mut expanded = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name = rest.find('}').map(|end| &rest[1..end]);
+        let value = name.and_then(|name| match name {
+            "" => values.first(),
+            name => values.iter().find(|(value_name, _)| *value_name == name),
+        });
+        match (name, value) {
+            (Some(name), Some((_, value))) => {
+                expanded.push_str(&quote(value));
+                rest = &rest[name.len() + 2..];
+            }
+            _ => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// A value the shell passes on as one argument, whatever it contains
+#[cfg(not(windows))]
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(windows)]
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_expand() {
+        let values = [("path", "/srv/it's.iso".to_string()), ("url", "http://a/x.iso".to_string()), ("sha256", String::new()), ("error", String::new())];
+        assert_eq!(expand("tar xf {} && echo {url} {sha256}", &values), r"tar xf '/srv/it'\''s.iso' && echo 'http://a/x.iso' ''");
+        assert_eq!(expand("import {path}", &values), r"import '/srv/it'\''s.iso'");
+        assert_eq!(expand("awk '{print}' {unknown} {", &values), "awk '{print}' {unknown} {");
+    }
+
+    // A value holding placeholders and shell syntax stays one quoted argument
+    #[cfg(not(windows))]
+    #[test]
+    fn test_expand_values_once() {
+        let values = [
+            ("path", "{error}".to_string()),
+            ("url", "http://evil/x?{error}".to_string()),
+            ("sha256", String::new()),
+            ("error", "FTP: 550 x';touch /tmp/pwned;'".to_string()),
+        ];
+        assert_eq!(expand("echo {url} {error} {}", &values), r"echo 'http://evil/x?{error}' 'FTP: 550 x'\'';touch /tmp/pwned;'\''' '{error}'");
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_run() {
+        use std::path::Path;
+        use std::time::Duration;
+        use crate::test_server;
+        let dir = test_server::temp_dir("hook");
+        let file = dir.join("x y.iso");
+        std::fs::write(&file, "hello").unwrap();
+        let entry = Entry::new("http://a/x.iso", Ok(Path::new(&file)), Duration::from_secs(1)).await;
+        let copy = dir.join("copy");
+        run(&format!("cp {{}} {} && test \"$RTGET_URL\" = {{url}}", copy.display()), &entry).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "hello");
+        assert!(run("exit 3", &entry).await.is_err());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+// Name of the HSTS database in the home directory
+const HSTS_FILE_NAME: &str = ".rtget-hsts";
+
+/// A known HSTS host
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HstsEntry {
+    // Whether the policy also covers subdomains of the host
+    include_subdomains: bool,
+    // Unix timestamp after which the policy no longer applies
+    expires: u64,
+}
+
+/// Persistent store of hosts that sent a Strict-Transport-Security header
+pub struct HstsStore {
+    path: PathBuf,
+    entries: HashMap<String, HstsEntry>,
+}
+
+impl HstsStore {
+    /// Default location of the HSTS database, `~/.rtget-hsts`.
+    ///
+    /// Returns `None` when no home directory is known.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(HSTS_FILE_NAME))
+    }
+
+    /// Loads the HSTS database from `path`.
+    ///
+    /// A missing or unreadable file yields an empty store; malformed lines are skipped.
+    pub fn load(path: PathBuf) -> HstsStore {
+        let entries = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let host = fields.next()?.to_string();
+                let include_subdomains = fields.next()? == "1";
+                let expires = fields.next()?.parse().ok()?;
+                Some((host, HstsEntry { include_subdomains, expires }))
+            })
+            .collect();
+        HstsStore { path, entries }
+    }
+
+    /// Writes the unexpired entries back to the database file.
+    pub fn save(&self) -> io::Result<()> {
+        let now = now();
+        let mut contents = String::from("# rtget HSTS database: host, include subdomains, expiry (unix time)\n");
+        for (host, entry) in self.entries.iter().filter(|(_, entry)| entry.expires > now) {
+            contents.push_str(&format!("{}\t{}\t{}\n", host, entry.include_subdomains as u8, entry.expires));
+        }
+        std::fs::write(&self.path, contents)
+    }
+
+    /// Returns the `https://` form of `url` if its host is a known HSTS host.
+    ///
+    /// Returns `None` when no upgrade is needed.
+    pub fn upgrade(&self, url: &Url) -> Option<Url> {
+        if url.scheme() != "http" {
+            return None;
+        }
+        let host = url.host_str()?.to_ascii_lowercase();
+        let now = now();
+
+        // Check the host itself and every parent domain that includes subdomains
+        let known = self.entries.get(&host).is_some_and(|entry| entry.expires > now)
+            || host.match_indices('.').any(|(index, _)| {
+                self.entries
+                    .get(&host[index + 1..])
+                    .is_some_and(|entry| entry.include_subdomains && entry.expires > now)
+            });
+        if !known {
+            return None;
+        }
+
+        let mut upgraded = url.clone();
+        upgraded.set_scheme("https").ok()?;
+        // An explicit port 80 would otherwise be kept for the TLS connection
+        if url.port() == Some(80) {
+            upgraded.set_port(None).ok()?;
+        }
+        Some(upgraded)
+    }
+
+    /// Records the Strict-Transport-Security header received from `host` over HTTPS.
+    ///
+    /// A `max-age=0` directive removes the host. IP addresses are never recorded.
+    pub fn record(&mut self, host: &str, header: &str) {
+        if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+            return;
+        }
+        let Some((max_age, include_subdomains)) = parse_header(header) else {
+            return;
+        };
+        let host = host.to_ascii_lowercase();
+        if max_age == 0 {
+            self.entries.remove(&host);
+        } else {
+            let expires = now().saturating_add(max_age);
+            self.entries.insert(host, HstsEntry { include_subdomains, expires });
+        }
+    }
+}
+
+// Parse the max-age and includeSubDomains directives of a Strict-Transport-Security header
+fn parse_header(header: &str) -> Option<(u64, bool)> {
+    let mut max_age = None;
+    let mut include_subdomains = false;
+    for directive in header.split(';').map(str::trim) {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        if name.trim().eq_ignore_ascii_case("max-age") {
+            max_age = value.trim().trim_matches('"').parse().ok();
+        } else if name.trim().eq_ignore_ascii_case("includesubdomains") {
+            include_subdomains = true;
+        }
+    }
+    max_age.map(|age| (age, include_subdomains))
+}
+
+// Current unix time in seconds
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(parse_header("max-age=31536000; includeSubDomains"), Some((31536000, true)));
+        assert_eq!(parse_header("max-age=\"60\""), Some((60, false)));
+        assert_eq!(parse_header("includeSubDomains"), None);
+    }
+
+    #[test]
+    fn test_upgrade_known_host_and_subdomains() {
+        let mut store = HstsStore::load(test_server::temp_dir("hsts_upgrade").join("hsts"));
+        store.record("example.com", "max-age=600; includeSubDomains");
+
+        let upgraded = store.upgrade(&Url::parse("http://example.com:80/file").unwrap()).unwrap();
+        assert_eq!(upgraded.as_str(), "https://example.com/file");
+        assert!(store.upgrade(&Url::parse("http://cdn.example.com/file").unwrap()).is_some());
+        assert!(store.upgrade(&Url::parse("http://example.org/file").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_max_age_zero_removes_host() {
+        let mut store = HstsStore::load(test_server::temp_dir("hsts_remove").join("hsts"));
+        store.record("example.com", "max-age=600");
+        store.record("example.com", "max-age=0");
+        assert!(store.upgrade(&Url::parse("http://example.com/").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = test_server::temp_dir("hsts_persist").join("hsts");
+        let mut store = HstsStore::load(path.clone());
+        store.record("example.com", "max-age=600");
+        store.save().unwrap();
+
+        let reloaded = HstsStore::load(path);
+        assert!(reloaded.upgrade(&Url::parse("http://example.com/").unwrap()).is_some());
+    }
+}
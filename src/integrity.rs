@@ -0,0 +1,76 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::downloader::{Downloader, FileDownloader};
+use crate::error::AppError;
+
+// Bytes compared on either side of a range boundary
+const WINDOW: u64 = 32;
+
+// Most boundaries one spot check fetches again
+const MAX_SPOT_CHECKS: usize = 8;
+
+/// Checks that the finished output at `path` is exactly `size` bytes long.
+pub fn check_size(path: &Path, size: u64) -> Result<(), AppError> {
+    let length = std::fs::metadata(path)?.len();
+    if length != size {
+        return Err(AppError::CorruptOutput(format!("{} is {} bytes long instead of {}", path.display(), length, size)));
+    }
+    Ok(())
+}
+
+/// Fetches the bytes around some of the range `boundaries` of `url` again and compares them with the output.
+///
+/// Two ranges meet at every boundary, written by different connections, so an off-by-one or a torn
+/// write shows up there first. At most eight boundaries, spread over the file, are checked, each
+/// with a single small request. Returns every boundary whose bytes differ.
+pub async fn spot_check(downloader: &FileDownloader, url: &str, path: &Path, boundaries: &[u64], size: u64) -> Result<(), AppError> {
+    let boundaries: Vec<u64> = boundaries.iter().copied().filter(|&boundary| boundary > 0 && boundary < size).collect();
+    let step = boundaries.len().div_ceil(MAX_SPOT_CHECKS).max(1);
+    let mut file = std::fs::File::open(path)?;
+    let mut corrupt = Vec::new();
+    for &boundary in boundaries.iter().step_by(step) {
+        let (start, end) = (boundary.saturating_sub(WINDOW), (boundary + WINDOW).min(size) - 1);
+        let mut remote = Vec::new();
+        downloader.download_chunk(url, start as usize, end as usize, &mut remote, &indicatif::ProgressBar::hidden()).await?;
+        let mut local = vec![0; (end - start + 1) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut local)?;
+        if local != remote {
+            corrupt.push(format!("{}-{}", start, end));
+        }
+    }
+    match corrupt.is_empty() {
+        true => Ok(()),
+        false => Err(AppError::CorruptOutput(format!("bytes {} differ from the server", corrupt.join(", ")))),
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::ClientOptions;
+    use crate::test_server;
+
+    #[tokio::test]
+    async fn test_spot_check() {
+        let body: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let url = test_server::serve(body.clone());
+        let dir = test_server::temp_dir("spot_check");
+        let output = dir.join("out");
+        std::fs::write(&output, &body).unwrap();
+        let downloader = FileDownloader::with_options(&ClientOptions::default()).unwrap();
+
+        check_size(&output, 1000).unwrap();
+        spot_check(&downloader, &url, &output, &[250, 500, 750], 1000).await.unwrap();
+
+        // A byte next to a boundary written wrong is found, and so is a file of the wrong size
+        let mut corrupt = body.clone();
+        corrupt[499] ^= 0xff;
+        std::fs::write(&output, &corrupt).unwrap();
+        let error = spot_check(&downloader, &url, &output, &[250, 500, 750], 1000).await.unwrap_err();
+        assert_eq!(error.to_string(), "The downloaded file is corrupt: bytes 468-531 differ from the server");
+        std::fs::write(&output, &body[..999]).unwrap();
+        assert!(matches!(check_size(&output, 1000), Err(AppError::CorruptOutput(_))));
+    }
+}
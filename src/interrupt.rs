@@ -0,0 +1,88 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::OnceLock;
+use tokio::sync::watch;
+
+// Exit status of SIGINT and SIGTERM, as a shell reports them
+const INTERRUPT_STATUS: i32 = 130;
+#[cfg(unix)]
+const TERMINATE_STATUS: i32 = 143;
+
+// Bumped on every Ctrl-C or SIGTERM that arrives while a download waits for one
+static INTERRUPTS: OnceLock<watch::Sender<u64>> = OnceLock::new();
+
+// Exit status of the last signal that arrived
+static STATUS: AtomicI32 = AtomicI32::new(INTERRUPT_STATUS);
+
+tokio::task_local! {
+    // Stops the download of the current task alone, as a daemon pausing one of its downloads does
+    static STOP: watch::Receiver<bool>;
+}
+
+/// Runs the download `future`, which stops like after Ctrl-C once `stop` turns true.
+pub async fn stoppable<F: Future>(stop: watch::Receiver<bool>, future: F) -> F::Output {
+    STOP.scope(stop, future).await
+}
+
+/// Resolves when Ctrl-C is pressed or the process is asked to terminate, or the [`stoppable`] download of the task is stopped.
+///
+/// The first call installs handlers of SIGINT and, on Unix, SIGTERM for the rest of the process.
+/// A signal that arrives while nothing waits for one still exits right away, with the usual status
+/// of 130 or 143.
+pub async fn interrupted() {
+    let sender = INTERRUPTS.get_or_init(|| {
+        tokio::spawn(listen_interrupt());
+        #[cfg(unix)]
+        tokio::spawn(listen_terminate());
+        watch::channel(0).0
+    });
+    let mut receiver = sender.subscribe();
+    let stop = STOP.try_with(watch::Receiver::clone).ok();
+    let stopped = async move {
+        match stop {
+            Some(mut stop) => {
+                let _ = stop.wait_for(|stop| *stop).await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    // The sender lives in a static, so this only returns on a signal
+    tokio::select! {
+        _ = receiver.changed() => {}
+        _ = stopped => {}
+    }
+}
+
+/// Returns the exit status of the signal that interrupted the download: 130 for SIGINT, 143 for SIGTERM.
+pub fn exit_status() -> i32 {
+    STATUS.load(Ordering::Relaxed)
+}
+
+// Forward every Ctrl-C to the waiting downloads
+// Without a handler, e.g. when signals cannot be caught, `interrupted` never resolves
+async fn listen_interrupt() {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        deliver(INTERRUPT_STATUS);
+    }
+}
+
+// Forward every SIGTERM to the waiting downloads, e.g. from `kill` or a service manager stopping rtget
+#[cfg(unix)]
+async fn listen_terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+        return;
+    };
+    while terminate.recv().await.is_some() {
+        deliver(TERMINATE_STATUS);
+    }
+}
+
+// Stop the waiting downloads, or exit with `status` when there are none
+fn deliver(status: i32) {
+    STATUS.store(status, Ordering::Relaxed);
+    match INTERRUPTS.get() {
+        Some(sender) if sender.receiver_count() > 0 => sender.send_modify(|count| *count += 1),
+        _ => std::process::exit(status),
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::args::{self, InstallLaunchdArgs};
+use crate::error::AppError;
+
+/// Returns the property list of a launchd agent named `label` running `program` with `arguments`.
+///
+/// The agent starts at login, or whenever it exits with `keep_alive`, in `directory`, and appends
+/// its messages to `log`.
+pub fn agent_plist(label: &str, program: &Path, arguments: &[String], directory: &Path, log: &Path, keep_alive: bool) -> String {
+    let string = |value: &str| format!("<string>{}</string>", escape(value));
+    let program_arguments: Vec<String> = std::iter::once(program.to_string_lossy().into_owned())
+        .chain(arguments.iter().cloned())
+        .map(|argument| format!("        {}\n", string(&argument)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n    \
+         <key>Label</key>\n    {}\n    \
+         <key>ProgramArguments</key>\n    <array>\n{}    </array>\n    \
+         <key>WorkingDirectory</key>\n    {}\n    \
+         <key>StandardOutPath</key>\n    {}\n    \
+         <key>StandardErrorPath</key>\n    {}\n    \
+         <key>RunAtLoad</key>\n    <true/>\n    \
+         <key>KeepAlive</key>\n    <{}/>\n\
+         </dict>\n\
+         </plist>\n",
+        string(label),
+        program_arguments.concat(),
+        string(&directory.to_string_lossy()),
+        string(&log.to_string_lossy()),
+        string(&log.to_string_lossy()),
+        keep_alive,
+    )
+}
+
+/// Writes the agent `args` describe to `~/Library/LaunchAgents` and loads it, returning the path of its property list.
+///
+/// The agent runs this program in the current directory, in the foreground as launchd expects,
+/// and logs to `~/Library/Logs/<label>.log`. Only macOS has launchd.
+pub fn install(args: &InstallLaunchdArgs) -> Result<PathBuf, AppError> {
+    if !cfg!(target_os = "macos") {
+        return Err(AppError::StringError("install-launchd registers a launchd agent, which only macOS has".to_string()));
+    }
+    args::service_download_args(&args.args)?;
+    if args.label.is_empty() || args.label.contains(['/', '\0']) {
+        return Err(AppError::StringError(format!("invalid label {:?}", args.label)));
+    }
+    let home = PathBuf::from(std::env::var_os("HOME").ok_or_else(|| AppError::StringError("HOME is not set".to_string()))?);
+    let agents = home.join("Library/LaunchAgents");
+    let logs = home.join("Library/Logs");
+    std::fs::create_dir_all(&agents)?;
+    std::fs::create_dir_all(&logs)?;
+    let plist = agents.join(format!("{}.plist", args.label));
+    let log = logs.join(format!("{}.log", args.label));
+    std::fs::write(&plist, agent_plist(&args.label, &std::env::current_exe()?, &args.args, &std::env::current_dir()?, &log, args.keep_alive))?;
+    let status = Command::new("launchctl").arg("load").arg("-w").arg(&plist).status()?;
+    if !status.success() {
+        return Err(AppError::StringError(format!("launchctl could not load {} ({})", plist.display(), status)));
+    }
+    Ok(plist)
+}
+
+// Escape the text of an XML element
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_plist() {
+        let arguments = ["-u".to_string(), "http://a/file.iso?a=1&b=2".to_string()];
+        let plist = agent_plist("local.rtget", Path::new("/usr/local/bin/rtget"), &arguments, Path::new("/Users/me"), Path::new("/Users/me/Library/Logs/local.rtget.log"), false);
+        assert!(plist.contains("<key>Label</key>\n    <string>local.rtget</string>\n"));
+        assert!(plist.contains(
+            "<array>\n        <string>/usr/local/bin/rtget</string>\n        <string>-u</string>\n        <string>http://a/file.iso?a=1&amp;b=2</string>\n    </array>\n"
+        ));
+        assert!(plist.contains("<key>WorkingDirectory</key>\n    <string>/Users/me</string>\n"));
+        assert!(plist.contains("<key>KeepAlive</key>\n    <false/>\n</dict>\n</plist>\n"));
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use crate::downloader::DEBUG_HTTP_TARGET;
+
+// Time between two checks of whether the log file is due for rotation
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How log messages are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A line of text per message
+    Text,
+    /// A JSON object per message, for log processors
+    Json,
+}
+
+/// The level of the messages shown for `verbose` times -v: warnings, then info, debug and trace messages.
+pub fn level(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Writes the log messages up to `level` to standard error, in `format`, in color when `color` is set and it is a terminal.
+///
+/// Every message carries the fields of the spans it is logged in: the URL of the download, the
+/// index of the part, and the byte range and attempt of the request, so the lines of the
+/// connections of a download can be told apart. With `debug_http` the requests and responses
+/// logged by --debug-http are written too, unless `level` is off.
+pub fn init(level: LevelFilter, debug_http: bool, format: Format, color: bool) {
+    let mut targets = Targets::new().with_default(level);
+    if debug_http && level != LevelFilter::OFF {
+        targets = targets.with_target(DEBUG_HTTP_TARGET, LevelFilter::DEBUG);
+    }
+    let builder = tracing_subscriber::fmt().with_max_level(LevelFilter::TRACE).with_writer(io::stderr);
+    match format {
+        Format::Text => builder.with_ansi(color && io::stderr().is_terminal()).finish().with(targets).init(),
+        Format::Json => builder.json().flatten_event(true).with_current_span(false).finish().with(targets).init(),
+    }
+}
+
+/// When the log file of --log-file is replaced by a new one, and how many old ones are kept
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rotation {
+    /// Size past which the file is rotated, if any
+    pub max_size: Option<u64>,
+    /// Age past which the file is rotated, if any
+    pub interval: Option<Duration>,
+    /// Number of rotated files kept as `<path>.1`, `<path>.2` and so on, the oldest last
+    pub keep: usize,
+}
+
+impl Rotation {
+    /// The rotation of --log-max-size, where 0 means no limit, --log-rotate and --log-keep.
+    pub fn new(max_size: u64, interval: Option<Duration>, keep: usize) -> Rotation {
+        Rotation { max_size: Some(max_size).filter(|max_size| *max_size > 0), interval, keep }
+    }
+}
+
+// The log file currently written to
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    opened: Instant,
+    // Whether standard output and standard error point to the file, and must follow it when it rotates
+    attached: bool,
+}
+
+impl LogFile {
+    // Opens the file at `path` for appending, rotating it first when it is already too big
+    fn open(path: &Path, rotation: Rotation) -> io::Result<LogFile> {
+        let mut log = LogFile { path: path.to_path_buf(), rotation, file: append(path)?, opened: Instant::now(), attached: false };
+        if log.due()? {
+            log.rotate()?;
+        }
+        Ok(log)
+    }
+
+    // Whether the file grew past its size or got older than the interval
+    fn due(&self) -> io::Result<bool> {
+        let size = self.file.metadata()?.len();
+        let too_big = self.rotation.max_size.is_some_and(|max_size| size > 0 && size >= max_size);
+        let too_old = self.rotation.interval.is_some_and(|interval| self.opened.elapsed() >= interval);
+        Ok(too_big || too_old)
+    }
+
+    // Shifts the rotated files by one, dropping the oldest, moves the file to `<path>.1` and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.rotation.keep).rev() {
+                let older = rotated(&self.path, index);
+                if older.exists() {
+                    std::fs::rename(&older, rotated(&self.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = append(&self.path)?;
+        self.opened = Instant::now();
+        if self.attached {
+            self.attach()?;
+        }
+        Ok(())
+    }
+
+    // Points standard output and standard error to the file
+    #[cfg(unix)]
+    fn attach(&mut self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            // SAFETY: both descriptors are valid, and dup2 replaces the target atomically
+            if unsafe { libc::dup2(self.file.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.attached = true;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn attach(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "--log-file is only supported on Unix"))
+    }
+}
+
+// Opens `path` for appending, creating it when missing
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Path of the rotated file number `index`
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Sends everything the process writes to standard output and standard error to the file at `path`, from now on.
+///
+/// The descriptors themselves point to the file, so messages printed right before the process
+/// exits are not lost, and commands run by hooks write there too. A thread checks every second
+/// whether the file is due for rotation according to `rotation`, so it may grow past its size by
+/// what is written within a second.
+pub fn redirect(path: &Path, rotation: Rotation) -> io::Result<()> {
+    let mut log = LogFile::open(path, rotation)?;
+    log.attach()?;
+    std::thread::spawn(move || loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        if let Err(e) = log.due().and_then(|due| if due { log.rotate() } else { Ok(()) }) {
+            tracing::error!("Could not rotate the log file {}: {}", log.path.display(), e);
+        }
+    });
+    Ok(())
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("rtget-test-logfile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rtget.log");
+        let rotation = Rotation { max_size: Some(10), interval: None, keep: 2 };
+
+        let mut log = LogFile::open(&path, rotation).unwrap();
+        assert!(!log.due().unwrap());
+        for line in ["first line\n", "second line\n", "third line\n"] {
+            io::Write::write_all(&mut log.file, line.as_bytes()).unwrap();
+            assert!(log.due().unwrap());
+            log.rotate().unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(rotated(&path, 1)).unwrap(), "third line\n");
+        assert_eq!(std::fs::read_to_string(rotated(&path, 2)).unwrap(), "second line\n");
+        assert!(!rotated(&path, 3).exists());
+
+        // A file already past its size is rotated when it is opened
+        std::fs::write(&path, "left over from the last run\n").unwrap();
+        LogFile::open(&path, rotation).unwrap();
+        assert_eq!(std::fs::read_to_string(rotated(&path, 1)).unwrap(), "left over from the last run\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        let aged = LogFile::open(&path, Rotation { max_size: None, interval: Some(Duration::ZERO), keep: 0 }).unwrap();
+        assert!(aged.due().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,50 +2,1816 @@ mod args;
 mod progress;
 mod error;
 mod concurrency;
+mod config;
 mod downloader;
 mod url_validator;
+mod watch;
 mod daemonize;
-//mod filesystem;
+mod filesystem;
+mod history;
+mod hook;
+mod hsts;
+mod webhook;
+mod metrics;
+mod replay;
+mod robots;
+mod spider;
+mod check;
+mod bench;
+mod batch;
+mod crawler;
+mod dedupe;
+mod extract;
+mod filter;
+mod glob;
+mod manifest;
+mod sequence;
+mod diagnose;
+mod cache;
+mod checksum;
+mod mirrors;
+mod control;
+mod ctl;
+mod daemon;
+mod resume;
+mod rest;
+mod rpc;
+mod scheduler;
+mod server;
+mod interrupt;
+mod launchd;
+mod logfile;
+mod integrity;
+mod mmap;
+mod openpgp;
+mod plan;
+mod quota;
+mod refresh;
+mod tui;
+mod unpack;
+mod users;
+#[cfg(target_os = "linux")]
+mod uring;
+#[cfg(test)]
+mod test_server;
 
-use args::CommandLineArgs;
+use args::{Command, CommandLineArgs, Connections, DaemonArgs};
+use cache::Cache;
+use checksum::{DigestTracker, ExpectedDigest};
+use concurrency::{ChunkSink, ConcurrentDownloader, ConnectionTuner, DownloadTask, RetryPolicy, SegmentScheduler, SourcePool, Termination};
+use control::ControlFile;
+use crawler::Crawler;
+use filter::Filters;
+use downloader::{ClientOptions, Downloader, FileDownloader, RemoteFile, RequestSpec};
+use error::AppError;
+use extract::Selector;
+use filesystem::{FileSystem, IoBackend};
+use hsts::HstsStore;
+use indicatif::ProgressBar;
+use metrics::TransferMetrics;
+use openpgp::SignatureCheck;
+use quota::Quota;
+use progress::ProgressManager;
+use replay::EventKind;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinSet;
+use tracing::level_filters::LevelFilter;
+use url::Url;
 use url_validator::validate_url;
 
+// Capacity of the in-memory pipe between a chunk task and the ordered output writer
+const PIPE_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Most connections a download may use
+const MAX_CONNECTIONS: usize = 100;
+
+// Most connections --connections auto grows a download to, so servers are not hammered
+const AUTO_MAX_CONNECTIONS: usize = 16;
+
+// Size of the ranges a download hashed while it is written is cut into, and the most ranges it gets
+const HASHED_RANGE_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_HASHED_RANGES: usize = 1024;
+
+// How long a stopped download of the daemon may take to end on its own
+const DAEMON_STOP_GRACE: Duration = Duration::from_secs(5);
+
+// Restarts from scratch after the remote file changed size mid-download, before giving up on it
+const MAX_SIZE_RESTARTS: u32 = 3;
+
 // Main function for the application
 // This is the entry point for the application
 #[tokio::main]
 async fn main() {
-    // Parse command line arguments
-    let args: CommandLineArgs = argh::from_env();
+    // Subcommands other than get are handled here, and a download continues below
+    let args: CommandLineArgs = match args::command_from_env() {
+        Command::Replay(args) => {
+            exit_on_error(replay::timeline(args.log.as_ref()).map(|timeline| print!("{}", timeline)));
+            return;
+        }
+        Command::Check(args) => {
+            let report = match validate_url(&args.url) {
+                Ok(url) => check::report(&url, args.connections as usize, args.min_split_size, &ClientOptions::default()).await,
+                Err(error) => Err(error),
+            };
+            exit_on_error(report.map(|report| print!("{}", report)));
+            return;
+        }
+        Command::Bench(args) => {
+            let report = match validate_url(&args.url) {
+                Ok(url) => bench::report(&url, &args.connections, Duration::from_secs(args.seconds), args.bytes, &ClientOptions::default()).await,
+                Err(error) => Err(error),
+            };
+            exit_on_error(report.map(|report| print!("{}", report)));
+            return;
+        }
+        Command::Diagnose(args) => {
+            let url = match validate_url(&args.url) {
+                Ok(url) => url,
+                Err(error) => return exit_on_error(Err(error)),
+            };
+            let diagnosis = diagnose::diagnose(&url, &ClientOptions::default()).await;
+            print!("{}", diagnosis.render());
+            if diagnosis.failed_stage().is_some() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::InstallLaunchd(args) => {
+            let installed = launchd::install(&args).map(|plist| {
+                println!("Installed the launchd agent {} as {}", args.label, plist.display());
+                println!("Remove it with: launchctl unload -w {0} && rm {0}", plist.display());
+            });
+            exit_on_error(installed);
+            return;
+        }
+        Command::Service(args) => {
+            exit_on_error(daemonize::service(&args));
+            return;
+        }
+        Command::Daemon(args) => {
+            if let Some(log_file) = &args.log_file {
+                redirect_output(log_file, logfile::Rotation::new(args.log_max_size, args.log_rotate, args.log_keep));
+            }
+            logfile::init(logfile::level(args.verbose), false, args.log_format, true);
+            return run_daemon(*args).await;
+        }
+        Command::Ctl(args) => {
+            exit_on_error(ctl::run(&args).await.map(|output| print!("{}", output)));
+            return;
+        }
+        Command::History(args) => {
+            exit_on_error(history::query(&args).map(|output| print!("{}", output)));
+            return;
+        }
+        // Resuming continues as a regular download of the recorded URL, or of the new one once it is verified
+        Command::Resume(args) => {
+            let output = PathBuf::from(&args.file);
+            let control = match &args.new_url {
+                Some(new_url) => match validate_url(new_url) {
+                    Ok(new_url) => resume::retarget(&output, &new_url, &ClientOptions::default()).await,
+                    Err(error) => Err(error),
+                },
+                None => resume::load(&output),
+            };
+            let control = match control {
+                Ok(control) => control,
+                Err(error) => return exit_on_error(Err(error)),
+            };
+            // Ranges split off slower ones add up, but the connections stay within their limit
+            let connections = control.segments.len().min(MAX_CONNECTIONS);
+            let defaults = config::Defaults::load(args.config.as_deref(), args.profile.as_deref());
+            match defaults.and_then(|defaults| args.download_args(&control.url, connections, &defaults)) {
+                Ok(download_args) => download_args,
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    std::process::exit(error::EXIT_USAGE);
+                }
+            }
+        }
+        Command::Status(args) => {
+            let paths: Vec<PathBuf> = args.paths.iter().map(PathBuf::from).collect();
+            exit_on_error(resume::status(&paths).map(|status| print!("{}", status)));
+            return;
+        }
+        Command::Get(args) => *args,
+    };
+
+    if let Err(error) = args.check_sources() {
+        eprintln!("Error: {}", error);
+        std::process::exit(error::EXIT_USAGE);
+    }
+    // The detached copy of -b redirects its output itself, once it runs
+    if let Some(log_file) = args.log_file.as_ref().filter(|_| !args.background) {
+        redirect_output(log_file, logfile::Rotation::new(args.log_max_size, args.log_rotate, args.log_keep));
+    }
+
+    // Informational messages are only shown in verbose mode, and warnings not in quiet mode
+    // NO_COLOR set to anything but an empty value turns colors off, see https://no-color.org
+    let color = !args.no_color && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    let level = match args.verbose {
+        // The screen of --tui has no room for log messages
+        _ if args.tui => LevelFilter::OFF,
+        0 if args.quiet => LevelFilter::ERROR,
+        verbose => logfile::level(verbose),
+    };
+    logfile::init(level, args.debug_http, args.log_format, color);
+    progress::set_mode(args.quiet || args.tui);
+    progress::set_theme(progress::Theme { style: args.progress_style, chars: args.progress_chars.clone(), color });
+
+    match args.progress {
+        progress::Format::Bars => {}
+        progress::Format::Compact => progress::compact(),
+        progress::Format::Json => {
+            if let Err(e) = progress::stream_json(args.progress_file.as_deref().map(Path::new)) {
+                eprintln!("Error: could not write the progress to {}: {}", args.progress_file.as_deref().unwrap_or_default(), e);
+                std::process::exit(error::EXIT_IO);
+            }
+        }
+    }
+
+    // With -b a detached copy of the process downloads the file or the batch, and this one exits
+    if args.background {
+        return run_in_background(args.log_file.as_deref()).await;
+    }
+
+    // With --start-at nothing is downloaded before that time, in the background too
+    if let Some(start_at) = args.start_at {
+        let at = start_at.next(SystemTime::now());
+        progress::message(&format!("Waiting until {} to start", scheduler::describe(at)));
+        scheduler::wait_until(at).await;
+    }
+
+    // With --tui the downloads are run as a queue shown in the terminal
+    if args.tui {
+        let status = match batch_entries(&args).await {
+            Ok((entries, _)) => run_tui(&args, entries).await,
+            Err(error) => return exit_on_error(Err(error)),
+        };
+        if status != 0 {
+            std::process::exit(status);
+        }
+        return;
+    }
+
+    // A batch, or with --spider even a single URL, is a queue of downloads run by one loop
+    if args.is_batch() || args.spider {
+        let (entries, crawler) = match batch_entries(&args).await {
+            Ok(batch) => batch,
+            Err(error) => return exit_on_error(Err(error)),
+        };
+        let status = match args.spider {
+            true => spider::check(&args, entries, crawler).await,
+            false => run_batch(&args, entries, crawler).await,
+        };
+        if status != 0 {
+            std::process::exit(status);
+        }
+        return;
+    }
 
     // Validate the URL
-    match validate_url(&args.url) {
+    let url = match validate_url(args.url.first().map(String::as_str).unwrap_or_default()) {
         Ok(valid_url) => {
-            println!("Downloading from {}", valid_url.to_string());
+            progress::message(&format!("Downloading from {}", valid_url));
+            valid_url
         }
-        Err(error) => {
-            eprintln!("Error: {}", error);
-            return;
+        Err(error) => return exit_on_error(Err(error)),
+    };
+
+    if let Some(interval) = args.watch {
+        return exit_on_error(watch_url(&args, &url, interval).await);
+    }
+
+    let started = Instant::now();
+    let (result, _) = run_in_foreground(&args, &url, &Target::of(&args)).await;
+    report_finished(&args, url.as_str(), &result, started.elapsed()).await;
+    let result = result.map(|(downloaded, _)| downloaded);
+    report_metrics(&args, &result, started.elapsed()).await;
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        if let AppError::CouldNotConnect(_) | AppError::HttpStatus(..) = error {
+            report_diagnosis(&args, &url).await;
         }
+        write_event_log(&args, &error);
+        // The status tells the kind of failure; like a shell, an interrupted download reports the status of the signal, SIGINT or SIGTERM
+        std::process::exit(error.exit_code());
     }
+}
 
-    // Run the application in the foreground or background
-    if args.background {
-        run_in_background().await;
+// With -r the website of the URL is a batch that grows with the links of every page, and with -p with their requisites
+// -p alone saves the page of the URL with its requisites
+// With -i the URLs of the input file are downloaded after those of -u if given and before those of --manifest
+// Several -u, and a URL with ranges like [001-120], are a batch of their own
+async fn batch_entries(args: &CommandLineArgs) -> Result<(Vec<batch::Entry>, Option<Crawler>), AppError> {
+    if args.recursive || args.page_requisites {
+        let url = validate_url(&args.url[0])?;
+        let mut crawler = match (args.recursive, args.page_requisites) {
+            (true, false) => Crawler::new(&url, args.level, Filters::from_args(args)),
+            (true, true) => Crawler::new(&url, args.level, Filters::from_args(args)).with_requisites(),
+            (false, _) => Crawler::page_requisites(&url, Filters::from_args(args)),
+        };
+        if !args.no_robots {
+            let downloader = FileDownloader::with_options(&ClientOptions::from_args(args)?)?;
+            crawler = crawler.with_robots(robots::fetch(&downloader, &url).await);
+        }
+        let start = crawler.start(&url);
+        return Ok((vec![start], Some(crawler)));
+    }
+    let mut entries = match &args.extract_links {
+        Some(selector) => index_links(args, selector).await?.into_iter().map(|url| batch::Entry { url: url.to_string(), output: None, checksum: None }).collect(),
+        None => {
+            let urls = match &args.input_file {
+                Some(input_file) => args.url.iter().cloned().chain(batch::read_urls(input_file)?).collect(),
+                None => args.url.clone(),
+            };
+            batch::entries(urls, args.output.as_deref())?
+        }
+    };
+    if let Some(manifest) = &args.manifest {
+        entries.extend(manifest::read(manifest)?);
+    }
+    // Wildcard FTP URLs stand for the files of their directory they match
+    if entries.iter().any(|entry| glob::pattern(&entry.url).is_some()) {
+        let downloader = FileDownloader::with_options(&ClientOptions::from_args(args)?)?;
+        let mut expanded = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match glob::expand(&downloader, &entry.url).await? {
+                Some(urls) => expanded.extend(urls.into_iter().map(|url| batch::Entry { url, output: None, checksum: None })),
+                None => expanded.push(entry),
+            }
+        }
+        entries = expanded;
+    }
+    // URLs that cannot be parsed are kept, to be reported as failed downloads
+    let filters = Filters::from_args(args);
+    entries.retain(|entry| match Url::parse(&entry.url) {
+        Ok(url) if !filters.accepts(&url) => {
+            tracing::info!("Skipping {}, rejected by the filters", url);
+            false
+        }
+        _ => true,
+    });
+    Ok((entries, None))
+}
+
+// Fetch the index page of -u and pick out the links the --extract-links `selector` matches
+// Relative links are resolved against the URL the index redirects to, like a browser does
+async fn index_links(args: &CommandLineArgs, selector: &str) -> Result<Vec<Url>, AppError> {
+    let selector = Selector::parse(selector)?;
+    let url = validate_url(&args.url[0])?;
+    let downloader = FileDownloader::with_options(&ClientOptions::from_args(args)?)?;
+    let index = downloader.probe(url.as_str()).await?.url;
+    let mut body = Vec::new();
+    downloader.download_whole(index.as_str(), &RequestSpec::default(), &mut body, &ProgressBar::hidden(), Some(crawler::MAX_PAGE_SIZE)).await?;
+    let links = selector.links(&String::from_utf8_lossy(&body), &index)?;
+    if links.is_empty() {
+        return Err(AppError::StringError(format!("no link of {} matches {}", index, args.extract_links.as_deref().unwrap_or_default())));
+    }
+    tracing::info!("Found {} links in {}", links.len(), index);
+    Ok(links)
+}
+
+// Download the `entries` of a batch, --jobs of them at a time, each like a download of its own with -u
+// With a `crawler` the links of every downloaded page are queued too, each URL once
+// After Ctrl-C, or the first failure with --halt-on-error, no further downloads start; the summary lists every URL in the order it was queued
+// Returns the exit status: 0 when every download succeeded, the status of the signal when interrupted, EXIT_PARTIAL otherwise
+async fn run_batch(args: &CommandLineArgs, entries: Vec<batch::Entry>, mut crawler: Option<Crawler>) -> i32 {
+    let (jobs, connections) = batch::limits(args, AUTO_MAX_CONNECTIONS);
+    if jobs < args.jobs {
+        tracing::info!("Downloading {} files at a time to stay within {} connections", jobs, args.total_connections.unwrap_or_default());
+    }
+    // A website asking for a pause between requests gets one file at a time
+    let crawl_delay = crawler.as_ref().and_then(Crawler::crawl_delay);
+    let jobs = if crawl_delay.is_some() { 1 } else { jobs };
+    let mut last_start: Option<Instant> = None;
+    let dir = match batch_dir(args) {
+        Ok(dir) => dir,
+        Err(status) => return status,
+    };
+    // Every output is known before anything is downloaded, so URLs saved to the same file are told apart in the order of the input
+    let mut outputs = batch::Outputs::new(args.on_collision);
+    let mut pending = VecDeque::new();
+    let mut collisions = Vec::new();
+    for listed in entries {
+        match planned(args, &mut outputs, listed) {
+            Ok(listed) => pending.push_back(listed),
+            Err(collision) => collisions.push(collision),
+        }
+    }
+    if !collisions.is_empty() {
+        for collision in collisions {
+            eprintln!("Error: {}", collision);
+        }
+        return error::EXIT_USAGE;
+    }
+    let mut quota = args.quota.map(|limit| Quota::new(limit, None));
+    let mut queued = 0;
+    let mut running = JoinSet::new();
+    let mut outcomes = Vec::new();
+    let mut interrupted = false;
+    let mut halted = false;
+    // Each URL is downloaded once, with its target and, once it is saved, its output
+    // A URL listed again waits for that download and is then saved as a link to it or a copy
+    let mut first_downloads: HashMap<String, (Target, Option<PathBuf>)> = HashMap::new();
+    // The files share one client, so small ones reuse the connections of those before them, or one HTTP/2 connection per host
+    let shared_client = ClientOptions::from_args(args).and_then(|options| FileDownloader::shared_client(&options)).ok();
+    let mut repeats: HashMap<String, Vec<(usize, batch::Entry)>> = HashMap::new();
+    // The outputs of a manifest are relative to the directory of -o
+    // Those of the pages of a crawl are the crawler's, and are numbered rather than refused when they exist
+    let crawling = crawler.is_some();
+    let target_of = |output: Option<PathBuf>| match output {
+        Some(output) if crawling => Target::Assigned(dir.as_deref().map_or(output.clone(), |dir| dir.join(&output))),
+        Some(output) => Target::File(dir.as_deref().map_or(output.clone(), |dir| dir.join(&output))),
+        None => Target::Named(dir.clone()),
+    };
+    loop {
+        while !interrupted && !halted && running.len() < jobs && !quota.as_mut().is_some_and(Quota::exhausted) {
+            let Some(listed) = pending.pop_front() else {
+                break;
+            };
+            let index = queued;
+            queued += 1;
+            let target = target_of(listed.output.clone());
+            match first_downloads.get(&listed.url) {
+                Some((first_target, Some(saved))) => {
+                    let result = save_repeat(args, &listed.url, &target, first_target, saved);
+                    outcomes.push((index, batch::Outcome::new(listed.url, result, Duration::ZERO, None)));
+                    continue;
+                }
+                Some((_, None)) => {
+                    repeats.entry(listed.url.clone()).or_default().push((index, listed));
+                    continue;
+                }
+                None => {
+                    first_downloads.insert(listed.url.clone(), (target.clone(), None));
+                }
+            }
+            let batch::Entry { url, checksum, .. } = listed;
+            if let (Some(delay), Some(last_start)) = (crawl_delay, last_start) {
+                tokio::time::sleep(delay.saturating_sub(last_start.elapsed())).await;
+            }
+            last_start = Some(Instant::now());
+            // Each file gets the options of the command line with its own URL, which also scopes the credentials to its host
+            let mut entry = args.clone();
+            entry.url = vec![url.clone()];
+            entry.connections = connections;
+            entry.checksum = checksum;
+            let shared_client = shared_client.clone();
+            running.spawn(async move {
+                let started = Instant::now();
+                let (result, effective) = match validate_url(&url) {
+                    Ok(valid_url) => {
+                        progress::message(&format!("Downloading from {}", valid_url));
+                        let download = run_in_foreground(&entry, &valid_url, &target);
+                        match shared_client {
+                            Some(client) => downloader::multiplexed(client, download).await,
+                            None => download.await,
+                        }
+                    }
+                    Err(error) => (Err(error), None),
+                };
+                report_finished(&entry, &url, &result, started.elapsed()).await;
+                let outcome = batch::Outcome::new(url, result, started.elapsed(), effective);
+                report_metrics(&entry, &outcome.result, outcome.duration).await;
+                (index, outcome)
+            });
+        }
+        let Some(finished) = running.join_next().await else {
+            break;
+        };
+        let (index, outcome) = finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        let waiting = repeats.remove(&outcome.url).unwrap_or_default();
+        if let (Some(quota), Ok(downloaded)) = (quota.as_mut(), &outcome.result) {
+            quota.add(*downloaded);
+        }
+        match (&outcome.result, outcome.output.clone()) {
+            (Ok(_), Some(saved)) => {
+                // A page found while crawling that would overwrite another file fails on its own
+                for listed in crawler.as_mut().map(|crawler| crawler.follow(&outcome.url, &saved)).unwrap_or_default() {
+                    let url = listed.url.clone();
+                    match planned(args, &mut outputs, listed) {
+                        Ok(listed) => pending.push_back(listed),
+                        Err(collision) => {
+                            eprintln!("Error: {}", collision);
+                            outcomes.push((queued, batch::Outcome::new(url, Err(AppError::StringError(collision)), Duration::ZERO, None)));
+                            queued += 1;
+                        }
+                    }
+                }
+                let first = first_downloads.get_mut(&outcome.url).expect("every download is a first download");
+                for (index, listed) in waiting {
+                    let result = save_repeat(args, &listed.url, &target_of(listed.output), &first.0, &saved);
+                    outcomes.push((index, batch::Outcome::new(listed.url, result, Duration::ZERO, None)));
+                }
+                first.1 = Some(saved);
+            }
+            (result, _) => {
+                if let Err(error) = result {
+                    eprintln!("Error: {}: {}", outcome.url, error);
+                    interrupted |= matches!(error, AppError::Interrupted);
+                }
+                // The repeats of a failed download try it again themselves, the first of them in its place
+                first_downloads.remove(&outcome.url);
+                for (_, listed) in waiting.into_iter().rev() {
+                    pending.push_front(listed);
+                }
+            }
+        }
+        halted |= batch::halts_after(args, &outcome.result);
+        outcomes.push((index, outcome));
+    }
+    outcomes.sort_by_key(|(index, _)| *index);
+    let outcomes: Vec<_> = outcomes.into_iter().map(|(_, outcome)| outcome).collect();
+    let not_started: Vec<String> = pending.into_iter().chain(repeats.into_values().flatten().map(|(_, listed)| listed)).map(|listed| listed.url).collect();
+    print!("{}", batch::summary(&outcomes, not_started.len()));
+    // Downloads left out for the quota are listed, so they can be fetched once there is budget again
+    let over_quota = !interrupted && !not_started.is_empty() && quota.as_mut().is_some_and(Quota::exhausted);
+    if over_quota {
+        eprintln!("The quota of {} was reached, these downloads were not started:", quota.as_ref().map(Quota::describe).unwrap_or_default());
+        for url in &not_started {
+            eprintln!("  {}", url);
+        }
+    }
+    if halted && !interrupted && !not_started.is_empty() {
+        eprintln!("A download failed and --halt-on-error was given, these downloads were not started:");
+        for url in &not_started {
+            eprintln!("  {}", url);
+        }
+    }
+    // With --report the results go to a file, or to standard output for -, for scripts to check
+    if let Some(report) = &args.report {
+        let json = batch::report(&outcomes, &not_started);
+        let written = match report.as_str() {
+            "-" => {
+                println!("{}", json);
+                Ok(())
+            }
+            path => std::fs::write(path, json + "\n"),
+        };
+        if let Err(e) = written {
+            eprintln!("Error: could not write the report to {}: {}", report, e);
+            return error::EXIT_IO;
+        }
+    }
+    if let Some(error) = outcomes.iter().find_map(|outcome| outcome.result.as_ref().err()) {
+        write_event_log(args, error);
+    }
+    if interrupted {
+        interrupt::exit_status()
+    } else if over_quota || outcomes.iter().any(|outcome| outcome.result.is_err()) {
+        error::EXIT_PARTIAL
     } else {
-        run_in_foreground().await;
+        0
+    }
+}
+
+// The directory every file of a batch is saved in, created if needed: -o unless it is a template, or else --dir
+// Returns the exit status EXIT_IO if it cannot be created
+fn batch_dir(args: &CommandLineArgs) -> Result<Option<PathBuf>, i32> {
+    let dir = match args.output.as_deref() {
+        Some(output) => Some(output).filter(|output| !sequence::is_template(output)).map(PathBuf::from),
+        None => args.dir.as_ref().map(PathBuf::from),
+    };
+    if let Some(dir) = &dir {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            eprintln!("Error: could not create the output directory {}: {}", dir.display(), e);
+            return Err(error::EXIT_IO);
+        }
+    }
+    Ok(dir)
+}
+
+// Download the `entries` in a queue shown in the terminal, --jobs at a time, where they can be paused, cancelled and reordered
+// Quitting stops the running downloads, which keep their parts; the failed ones are listed then
+// Returns the exit status: 0 when no download failed, EXIT_PARTIAL otherwise
+async fn run_tui(args: &CommandLineArgs, entries: Vec<batch::Entry>) -> i32 {
+    let (jobs, connections) = batch::limits(args, AUTO_MAX_CONNECTIONS);
+    let dir = match batch_dir(args) {
+        Ok(dir) => dir,
+        Err(status) => return status,
+    };
+    let queue = Arc::new(daemon::Queue::new(dir.as_deref().unwrap_or(Path::new(".")), jobs));
+    // Each file gets the options of the command line with its own URL, as in a batch
+    let mut downloads = HashMap::new();
+    for listed in entries {
+        let mut entry = args.clone();
+        entry.url = vec![listed.url.clone()];
+        entry.connections = connections;
+        entry.checksum = listed.checksum;
+        let target = match listed.output {
+            Some(output) => Target::File(dir.as_deref().map_or(output.clone(), |dir| dir.join(&output))),
+            None => Target::Named(dir.clone()),
+        };
+        let gid = queue.add(daemon::Request { urls: vec![listed.url], ..daemon::Request::default() }).expect("a download with a URL and no name is queued");
+        downloads.insert(gid, (entry, target));
+    }
+
+    let screen = tokio::task::spawn_blocking({
+        let queue = queue.clone();
+        move || tui::run(&queue)
+    });
+    tokio::pin!(screen);
+    let mut running = JoinSet::new();
+    let interrupted = interrupt::interrupted();
+    tokio::pin!(interrupted);
+    let closed = loop {
+        while let Some(start) = queue.next_start() {
+            let (entry, target) = downloads[&start.gid].clone();
+            running.spawn(queued_download(entry, target, start));
+        }
+        tokio::select! {
+            _ = queue.changed() => {}
+            Some(finished) = running.join_next() => {
+                let (gid, result) = finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+                queue.finish(&gid, result);
+            }
+            closed = &mut screen => break closed.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())),
+            _ = &mut interrupted => {
+                ratatui::restore();
+                break Ok(());
+            }
+        }
+    };
+    queue.stop_all();
+    while let Some(finished) = running.join_next().await {
+        let (gid, result) = finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        queue.finish(&gid, result);
+    }
+    if let Err(e) = closed {
+        eprintln!("Error: could not draw on the terminal: {}", e);
+        return error::EXIT_IO;
+    }
+    let mut status = 0;
+    for gid in queue.gids(|_| true) {
+        let Some(download) = queue.status(&gid) else {
+            continue;
+        };
+        let (url, error) = (&download["files"][0]["uris"][0]["uri"], &download["errorMessage"]);
+        match download["status"].as_str().unwrap_or_default() {
+            "error" => {
+                eprintln!("Error: {}: {}", url.as_str().unwrap_or_default(), error.as_str().unwrap_or_default());
+                status = error::EXIT_PARTIAL;
+            }
+            "paused" | "waiting" => println!("Not finished, run the same command again to resume: {}", url.as_str().unwrap_or_default()),
+            _ => {}
+        }
+    }
+    status
+}
+
+// The entry of a batch with the output it claims, which --on-collision chose when another URL of the batch has the same one
+// Outputs are relative to the directory of the batch; a URL that is not valid keeps its entry and fails when it is downloaded
+fn planned(args: &CommandLineArgs, outputs: &mut batch::Outputs, listed: batch::Entry) -> Result<batch::Entry, String> {
+    let path = match (&listed.output, validate_url(&listed.url)) {
+        (Some(output), _) => output.clone(),
+        (None, Ok(url)) => output_path(args, &Target::Named(None), &url, None),
+        (None, Err(_)) => return Ok(listed),
+    };
+    let claimed = outputs.claim(&listed.url, &path)?;
+    if claimed == path {
+        return Ok(listed);
+    }
+    progress::message(&format!("{} would be saved as {} like another download of the batch, saving it as {}", listed.url, path.display(), claimed.display()));
+    Ok(batch::Entry { output: Some(claimed), ..listed })
+}
+
+// Save the `target` of a URL listed again in a batch from its first download, saved at `saved` for `first_target`
+// The same target is the same file, left as it is; any other output becomes a link to the file or a copy of it
+// Returns the bytes downloaded, none, and the output
+fn save_repeat(args: &CommandLineArgs, url: &str, target: &Target, first_target: &Target, saved: &Path) -> Result<(u64, PathBuf), AppError> {
+    if target == first_target {
+        tracing::info!("{} is listed again, it is already saved as {}", url, saved.display());
+        return Ok((0, saved.to_path_buf()));
+    }
+    let output = output_path(args, target, &validate_url(url)?, None);
+    dedupe::link_or_copy(saved, &output)?;
+    progress::message(&format!("{} is listed again, saved {} from {}", url, output.display(), saved.display()));
+    Ok((0, output))
+}
+
+// Print the error of a subcommand and exit with the status of its kind
+fn exit_on_error(result: Result<(), AppError>) {
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        std::process::exit(error.exit_code());
+    }
+}
+
+// Keep the file of the URL up to date, checking it again after every `interval` until Ctrl-C
+// A version the output already has is skipped like with --skip-unchanged; a failed check is reported and retried next time
+// With --quota it stops once the new versions used it up, failing with the status of a batch that left downloads out for it
+async fn watch_url(args: &CommandLineArgs, url: &Url, interval: Duration) -> Result<(), AppError> {
+    let mut args = args.clone();
+    args.skip_unchanged = true;
+    let target = Target::of(&args);
+    let mut quota = args.quota.map(|limit| Quota::new(limit, None));
+    loop {
+        let started = Instant::now();
+        let (result, _) = run_in_foreground(&args, url, &target).await;
+        report_finished(&args, url.as_str(), &result, started.elapsed()).await;
+        match result {
+            Ok((downloaded, _)) => {
+                if let Some(quota) = quota.as_mut() {
+                    quota.add(downloaded);
+                    if quota.exhausted() {
+                        return Err(AppError::QuotaReached(format!("{}, no longer checking {}", quota.describe(), url)));
+                    }
+                }
+            }
+            Err(AppError::Interrupted) => return Err(AppError::Interrupted),
+            Err(error) => eprintln!("Error: {}", error),
+        }
+        progress::message(&format!("Checking {} again at {}", url, scheduler::describe(SystemTime::now() + interval)));
+        tokio::time::sleep(interval).await;
     }
 }
 
 // Run the application in the background
-// This function will fork the current process into a daemon process
-// This is required to run the application in the background
-async fn run_in_background() {
-    daemonize::daemonize();
-    return;
+// The download continues in a detached copy of the process, started without -b, writing to --log-file or rtget-log
+async fn run_in_background(log_file: Option<&str>) {
+    exit_on_error(daemonize::daemonize(log_file.unwrap_or(daemonize::LOG_FILE)));
+}
+
+// Write standard output and standard error to `path` from now on, or exit when it cannot be opened
+fn redirect_output(path: &str, rotation: logfile::Rotation) {
+    if let Err(e) = logfile::redirect(Path::new(path), rotation) {
+        eprintln!("Error: could not write to the log file {}: {}", path, e);
+        std::process::exit(error::EXIT_IO);
+    }
+}
+
+// Run until Ctrl-C, downloading what the clients of the JSON-RPC interface and of rtget ctl add, --jobs files at a time
+// Each download runs like one of a batch, and is stopped alone when it is paused or removed
+// With --quota no download starts once the budget of the period is used up, until it is renewed
+// On Ctrl-C the running downloads stop keeping their parts, and the process exits with the status of the signal
+// With --users each user sees only their own downloads, saved in their directory, within their limits
+async fn run_daemon(args: DaemonArgs) {
+    // The defaults of the downloads are read once, and a config file they cannot use stops the daemon before it starts
+    let defaults = config::Defaults::load(args.config.as_deref(), args.profile.as_deref()).and_then(|defaults| defaults.arguments(&[]).map(|_| defaults));
+    let defaults = match defaults {
+        Ok(defaults) => Arc::new(defaults),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(error::EXIT_USAGE);
+        }
+    };
+    let users = match args.users.as_deref().map(|path| users::Users::load(Path::new(path))).transpose() {
+        Ok(users) => Arc::new(users.unwrap_or_default()),
+        Err(error) => return exit_on_error(Err(error)),
+    };
+    let tls = match (&args.rpc_certificate, &args.rpc_private_key) {
+        (Some(certificate), Some(private_key)) => match server::tls_acceptor(Path::new(certificate), Path::new(private_key)) {
+            Ok(tls) => Some(tls),
+            Err(error) => return exit_on_error(Err(error)),
+        },
+        (None, None) => None,
+        _ => {
+            eprintln!("Error: --rpc-certificate and --rpc-private-key go together");
+            std::process::exit(error::EXIT_USAGE);
+        }
+    };
+    let listener = match tokio::net::TcpListener::bind(&args.rpc_listen).await {
+        Ok(listener) => listener,
+        Err(e) => return exit_on_error(Err(AppError::StringError(format!("could not listen on {}: {}", args.rpc_listen, e)))),
+    };
+    // Without TLS the secret would cross the network in clear
+    if let Err(error) = listener.local_addr().map_err(AppError::from).and_then(|address| server::check_listen(address, tls.is_some())) {
+        return exit_on_error(Err(error));
+    }
+    let socket = args.socket.as_ref().map_or_else(ctl::default_socket, PathBuf::from);
+    let control = match ctl::bind(&socket, !users.is_empty()) {
+        Ok(control) => control,
+        Err(error) => return exit_on_error(Err(error)),
+    };
+    // Without a secret anyone reaching the port could queue downloads, so one is made up and shown to the user
+    let secret = match args.rpc_secret.clone() {
+        Some(secret) => secret,
+        None => match server::Access::generate_secret() {
+            Ok(secret) => {
+                println!("No --rpc-secret was given, calls must pass this generated one: {}", secret);
+                secret
+            }
+            Err(e) => return exit_on_error(Err(AppError::IoError(e.to_string()))),
+        },
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("Listening for JSON-RPC on {}://{}/jsonrpc and for rtget ctl on {}", scheme, args.rpc_listen, socket.display());
+    // Clients follow the progress through the interface, so the bars are not drawn
+    progress::hide();
+    let queue = Arc::new(daemon::Queue::new(Path::new(&args.dir), args.jobs).with_user_jobs(users.job_limits()));
+    let user_limits: Arc<HashMap<String, downloader::RateLimiter>> = Arc::new(users.speed_limits().into_iter().map(|(name, speed)| (name, downloader::RateLimiter::new(speed))).collect());
+    let access = server::Access { secret, allowed_origins: args.rpc_allow_origin.clone(), allow_origin_all: args.rpc_allow_origin_all, allowed_ips: args.rpc_allow_ip.clone(), users };
+    tokio::spawn(server::serve(listener, queue.clone(), access.clone(), tls));
+    tokio::spawn(ctl::serve(control, queue.clone(), Arc::new(access)));
+    tokio::spawn(scheduler::run(queue.clone(), args.schedule.clone()));
+
+    let mut quota = args.quota.map(|limit| Quota::new(limit, args.quota_period));
+    let mut over_quota = false;
+    let args = Arc::new(args);
+    let mut running = JoinSet::new();
+    let interrupted = interrupt::interrupted();
+    tokio::pin!(interrupted);
+    loop {
+        while !quota.as_mut().is_some_and(Quota::exhausted) {
+            let Some(start) = queue.next_start() else {
+                break;
+            };
+            running.spawn(daemon_download(args.clone(), defaults.clone(), user_limits.clone(), start));
+        }
+        // Running out of the quota is reported once per period, with the downloads it holds back
+        let exhausted = quota.as_mut().is_some_and(Quota::exhausted);
+        let waiting = queue.gids(|status| status == daemon::Status::Waiting).len();
+        if exhausted && !over_quota && waiting > 0 {
+            let quota = quota.as_ref().expect("only a quota is exhausted");
+            let until = match quota.renewed_at() {
+                Some(renewed) => format!("until {}", scheduler::describe(SystemTime::now() + renewed.saturating_duration_since(Instant::now()))),
+                None => "any more".to_string(),
+            };
+            println!("The quota of {} was reached, {} waiting downloads do not start {}", quota.describe(), waiting, until);
+        }
+        over_quota = exhausted && (over_quota || waiting > 0);
+        let renewed = quota.as_ref().and_then(Quota::renewed_at).filter(|_| exhausted);
+        tokio::select! {
+            _ = queue.changed() => {}
+            Some(finished) = running.join_next() => {
+                let (gid, result) = finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+                if let (Some(quota), Ok((downloaded, _))) = (quota.as_mut(), &result) {
+                    quota.add(*downloaded);
+                }
+                queue.finish(&gid, result);
+            }
+            _ = tokio::time::sleep_until(renewed.unwrap_or_else(Instant::now).into()), if renewed.is_some() => {}
+            _ = &mut interrupted => break,
+        }
+    }
+    queue.stop_all();
+    while let Some(finished) = running.join_next().await {
+        let (gid, result) = finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        queue.finish(&gid, result);
+    }
+    ctl::unbind(&socket);
+    std::process::exit(interrupt::exit_status());
+}
+
+// Run a download of the daemon, returning its GID with the bytes downloaded and the output
+// The download of a user with a speed limit shares it with their other downloads
+async fn daemon_download(
+    args: Arc<DaemonArgs>,
+    defaults: Arc<config::Defaults>,
+    user_limits: Arc<HashMap<String, downloader::RateLimiter>>,
+    start: daemon::Start,
+) -> (String, Result<(u64, PathBuf), AppError>) {
+    let download_args = match args.download_args(&start.request.urls, start.request.connections, &defaults) {
+        Ok(download_args) => download_args,
+        Err(error) => {
+            eprintln!("Error: {}: {}", start.request.urls[0], error);
+            return (start.gid, Err(error));
+        }
+    };
+    let target = match &start.request.out {
+        Some(out) => Target::Assigned(start.dir.join(out)),
+        None => Target::Named(Some(start.dir.clone())),
+    };
+    let url = download_args.url[0].clone();
+    let limit = start.request.owner.as_ref().and_then(|owner| user_limits.get(owner)).cloned();
+    let (gid, result) = match limit {
+        Some(limit) => downloader::limited(limit, queued_download(download_args, target, start)).await,
+        None => queued_download(download_args, target, start).await,
+    };
+    if let Err(error) = &result {
+        eprintln!("Error: {}: {}", url, error);
+    }
+    (gid, result)
+}
+
+// Run the download `start` of a queue with `download_args` into `target`, returning its GID with the bytes downloaded and the output
+// Downloads over a single stream do not wait for interruptions, so once stopped they get a moment to end on their own, then are dropped
+async fn queued_download(download_args: CommandLineArgs, target: Target, start: daemon::Start) -> (String, Result<(u64, PathBuf), AppError>) {
+    let daemon::Start { gid, dir, mut stop, bars, .. } = start;
+    let result = async {
+        std::fs::create_dir_all(&dir)?;
+        let url = validate_url(&download_args.url[0])?;
+        progress::message(&format!("Downloading from {}", url));
+        let download = progress::watched(bars, interrupt::stoppable(stop.clone(), run_in_foreground(&download_args, &url, &target)));
+        tokio::pin!(download);
+        let stopped = async {
+            let _ = stop.wait_for(|stop| *stop).await;
+        };
+        tokio::select! {
+            (result, _) = &mut download => result,
+            _ = stopped => tokio::time::timeout(DAEMON_STOP_GRACE, &mut download).await.map_or(Err(AppError::Interrupted), |(result, _)| result),
+        }
+    };
+    let started = Instant::now();
+    let result = result.await;
+    report_finished(&download_args, &download_args.url[0], &result, started.elapsed()).await;
+    (gid, result)
 }
 
 // Run the application in the foreground
-// This function will run the application in the foreground
-async fn run_in_foreground() {
-    
-}
\ No newline at end of file
+// Returns the number of bytes downloaded and the path of the output, and where the file was served from once its server answered
+// Messages logged meanwhile carry the URL, without its secrets, in the `download` span
+#[tracing::instrument(name = "download", level = "error", skip_all, fields(url = %downloader::redacted_url(url)))]
+async fn run_in_foreground(args: &CommandLineArgs, url: &Url, target: &Target) -> (Result<(u64, PathBuf), AppError>, Option<batch::Effective>) {
+    let mut effective = None;
+    let result = download_and_extract(args, url, target, &mut effective).await;
+    (result, effective)
+}
+
+// Download the file of the URL, setting `effective` once its server answered
+// With --extract an archive that was downloaded is unpacked once verified, and deleted then with --remove-archive
+async fn download_and_extract(args: &CommandLineArgs, url: &Url, target: &Target, effective: &mut Option<batch::Effective>) -> Result<(u64, PathBuf), AppError> {
+    match target {
+        Target::Named(Some(dir)) if !args.dry_run => std::fs::create_dir_all(dir)?,
+        _ => {}
+    }
+    let (downloaded, output_path) = download_file(args, url, target, effective).await?;
+    if !args.extract || args.dry_run || downloaded == 0 {
+        return Ok((downloaded, output_path));
+    }
+    match unpack::Archive::detect(&output_path)? {
+        Some(archive) => {
+            let dir = match &args.extract_dir {
+                Some(dir) => PathBuf::from(dir),
+                None => output_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            };
+            // A decompressed file named after the archive does not replace one that is there, no more than a download would
+            let destination = match archive {
+                unpack::Archive::Compressed(_) => unclobbered(args, &Target::Named(None), &unpack::destination(&output_path, archive, &dir))?,
+                unpack::Archive::Tar | unpack::Archive::Zip => Some(dir),
+            };
+            let Some(destination) = destination else {
+                return Ok((downloaded, output_path));
+            };
+            unpack::unpack(&output_path, archive, &destination)?;
+            progress::message(&format!("Extracted {} into {}", output_path.display(), destination.display()));
+            if args.remove_archive {
+                std::fs::remove_file(&output_path)?;
+            }
+        }
+        None => tracing::warn!("{} is not an archive, nothing to extract", output_path.display()),
+    }
+    Ok((downloaded, output_path))
+}
+
+// Download the file of the URL
+// This function will split the file into byte ranges, download them concurrently and merge the parts
+// Returns the number of bytes downloaded and the path of the output, and sets `effective` once the server answered
+async fn download_file(args: &CommandLineArgs, url: &Url, target: &Target, effective: &mut Option<batch::Effective>) -> Result<(u64, PathBuf), AppError> {
+    let options = ClientOptions::from_args(args)?;
+    let downloader = FileDownloader::for_file(&options)?;
+
+    // Known HSTS hosts are only ever contacted over HTTPS
+    let mut hsts = if args.no_hsts { None } else { HstsStore::default_path().map(HstsStore::load) };
+    let url = match hsts.as_ref().and_then(|store| store.upgrade(url)) {
+        Some(upgraded) => {
+            tracing::info!("HSTS: upgrading {} to {}", url, upgraded);
+            upgraded
+        }
+        None => url.clone(),
+    };
+
+    // The signature and its keys are loaded up front, so a download that cannot be verified does not start
+    let signature = match (&args.signature, &args.keyring) {
+        (Some(location), Some(keyring)) => Some(SignatureCheck::load(&downloader, location, keyring).await?),
+        (Some(_), None) => return Err(AppError::InvalidSignature("--signature needs --keyring with the public keys of the signers".to_string())),
+        (None, Some(_)) => return Err(AppError::InvalidSignature("--keyring is only used with --signature".to_string())),
+        (None, None) => None,
+    };
+
+    // Other requests cannot be probed or split into ranges, so the response is streamed over one connection
+    let request = RequestSpec::from_args(args)?;
+    if !request.is_plain_get() {
+        replay::record(EventKind::Start, format!("{} {}", request.method, url));
+        let output_path = output_path(args, target, &url, None);
+        let Some(output_path) = unclobbered(args, target, &output_path)? else {
+            return Ok((0, output_path));
+        };
+        if args.dry_run {
+            print!("{}", plan::describe(&url, &output_path, None, 1, &[], std::slice::from_ref(&url)));
+            return Ok((0, output_path));
+        }
+        let part_path = part_path(args, &output_path, false)?;
+        let required = required_checksums(args);
+        let digest = digest_tracker(None, &required, signature.as_ref());
+        let file_system = FileSystem::new(part_path.clone()).with_digest(digest.clone());
+        let mut progress = ProgressManager::new(&file_system.file_name());
+        let downloaded = download_single_stream(&downloader, &url, &request, &file_system, &mut progress, None, args.max_filesize).await?;
+        verify_output(&part_path, downloaded, &digest, None, &required, signature.as_ref())?;
+        file_system.commit()?;
+        return Ok((downloaded, output_path));
+    }
+
+    // A cached copy the server confirms as current is reused without downloading it again
+    // A dry run plans the download the cache would save
+    let cache = args.cache_dir.as_ref().map(Cache::new);
+    if let Some(cached) = cache.as_ref().filter(|_| !args.dry_run).and_then(|cache| cache.lookup(&url)) {
+        if downloader.revalidate(url.as_str(), &cached).await? {
+            // A cached copy is held to --max-filesize like a download
+            if let Some(max) = args.max_filesize {
+                if std::fs::metadata(&cached.path)?.len() > max {
+                    return Err(AppError::FileTooLarge(max));
+                }
+            }
+            let output_path = output_path(args, target, &url, cached.content_type.as_deref());
+            let Some(output_path) = unclobbered(args, target, &output_path)? else {
+                return Ok((0, output_path));
+            };
+            let part_path = part_path(args, &output_path, false)?;
+            progress::message(&format!("{} is unchanged, using the cached copy", url));
+            let copied = std::fs::copy(&cached.path, &part_path)?;
+            let required = required_checksums(args);
+            let digest = digest_tracker(None, &required, signature.as_ref());
+            verify_output(&part_path, copied, &digest, None, &required, signature.as_ref())?;
+            FileSystem::new(part_path).commit()?;
+            return Ok((copied, output_path));
+        }
+    }
+
+    let mut restarts = 0;
+    loop {
+        // Probe the size of the file and split it across the connections
+        replay::record(EventKind::Start, url.as_str());
+        let remote = downloader.probe(url.as_str()).await?;
+        if let Some(store) = hsts.as_mut() {
+            record_hsts(store, &remote.url, &remote.headers);
+        }
+        if remote.url != url {
+            replay::record(EventKind::Redirect, format!("{} -> {}", url, remote.url));
+        }
+        replay::record(
+            EventKind::Probe,
+            format!("size {:?}, ranges {}", remote.size, if remote.accepts_ranges { "supported" } else { "not supported" }),
+        );
+        let served = batch::Effective::of(&remote);
+        if args.print_effective_url {
+            println!("{}", served.line());
+        }
+        *effective = Some(served);
+
+        // Refuse oversized files before anything is written
+        if let (Some(size), Some(max)) = (remote.size, args.max_filesize) {
+            if size as u64 > max {
+                return Err(AppError::FileTooLarge(max));
+            }
+        }
+
+        let content_type = remote.headers.get(reqwest::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        let output_path = output_path(args, target, &url, content_type);
+        // With --skip-unchanged a file already downloaded in this version is kept as it is
+        if args.skip_unchanged && refresh::is_unchanged(&output_path, &remote) {
+            progress::message(&format!("{} is unchanged, not downloading it again", output_path.display()));
+            return Ok((0, output_path));
+        }
+        // With --watch and --keep-previous the version about to be replaced is moved aside first
+        if let Some(keep) = args.keep_previous.filter(|_| args.watch.is_some() && !args.dry_run) {
+            if let Some(kept) = watch::keep_previous(&output_path, keep)? {
+                progress::message(&format!("Kept the previous version of {} as {}", output_path.display(), kept.display()));
+            }
+        }
+        let Some(output_path) = unclobbered(args, target, &output_path)? else {
+            return Ok((0, output_path));
+        };
+        // Named pipes cannot be seeked or merged into, so the ranges are streamed in order instead
+        let stream_output = args.fifo || filesystem::is_fifo(&output_path);
+        // The file is hashed while it is written, for the digest announced by the server and the checksums asked for
+        let announced = mirrors::parse_digest(&remote.headers);
+        let mut required = required_checksums(args);
+        if args.auto_checksum {
+            match checksum::discover(&downloader, &remote.url).await {
+                Some((expected, source)) => {
+                    tracing::info!("Found the {} checksum of the file in {}", expected.algorithm.as_str(), source);
+                    required.push((expected, source.to_string()));
+                }
+                None => tracing::warn!("No checksum was found next to {}, the download is not verified", remote.url),
+            }
+        }
+        let digest = digest_tracker(announced.as_ref(), &required, signature.as_ref());
+
+        // With --dry-run the plan of the download is printed instead, before anything is written
+        if args.dry_run {
+            print!("{}", dry_run(args, &downloader, &url, &remote, &output_path, stream_output, &digest).await?);
+            return Ok((0, output_path));
+        }
+
+        // With --dedupe-content a file already saved by another URL of the batch is taken from there, and checked like a download
+        let content_key = if args.dedupe_content && !stream_output { dedupe::content_key(&remote) } else { None };
+        if let Some(saved) = content_key.as_deref().and_then(dedupe::saved) {
+            let size = dedupe::link_or_copy(&saved, &output_path)?;
+            verify_output(&output_path, size, &digest, announced.as_ref(), &required, signature.as_ref())?;
+            progress::message(&format!("{} has the same content as {}, saved {} from it", url, saved.display(), output_path.display()));
+            return Ok((0, output_path));
+        }
+
+        // Fail before anything is written rather than on a full disk in the middle of the download
+        // Streamed output is not kept, so it needs no space
+        let part_path = part_path(args, &output_path, stream_output)?;
+        if let (Some(size), false) = (remote.size, stream_output) {
+            check_disk_space(&part_path, size as u64)?;
+        }
+
+        // With --continue an existing output is the start of the file and only the rest is fetched
+        let partial_size = match stream_output {
+            false if args.continue_download => FileSystem::new(part_path.clone()).partial_output_size(),
+            _ => None,
+        };
+        let transferred = match partial_size {
+            Some(offset) => continue_partial(&downloader, &url, &remote, &part_path, offset, &digest).await,
+            None => transfer(args, &downloader, &url, &remote, &part_path, stream_output, &digest).await,
+        };
+        let (downloaded, size) = match transferred {
+            // The server has no bytes past the end of the continued output, which is the whole file then
+            Err(AppError::RangeNotSatisfiable(Some(remote_size))) if partial_size == Some(remote_size as u64) => {
+                progress::message(&format!("{} is already complete", output_path.display()));
+                (0, remote_size as u64)
+            }
+            // The remote file changed size since it was probed, so the planned ranges are stale
+            Err(AppError::RangeNotSatisfiable(Some(remote_size))) if Some(remote_size) != remote.size && !stream_output => {
+                // A file rewritten all the time would be downloaded over and over
+                if restarts == MAX_SIZE_RESTARTS {
+                    return Err(AppError::SizeKeepsChanging(restarts));
+                }
+                restarts += 1;
+                let planned = remote.size.unwrap_or_default();
+                eprintln!(
+                    "The remote file is now {} bytes instead of {}, restarting the download from scratch",
+                    remote_size, planned
+                );
+                replay::record(EventKind::Fallback, format!("restart: remote size changed from {} to {}", planned, remote_size));
+                continue;
+            }
+            downloaded => {
+                let downloaded = downloaded?;
+                (downloaded, remote.size.map_or(downloaded, |size| size as u64))
+            }
+        };
+        verify_output(&part_path, size, &digest, announced.as_ref(), &required, signature.as_ref())?;
+        // Only a complete and verified file ever appears under the name of the output
+        FileSystem::new(part_path).commit()?;
+        if let Some(content_key) = content_key {
+            dedupe::remember(content_key, &output_path);
+        }
+        if args.skip_unchanged && !stream_output {
+            if let Err(e) = refresh::record(&output_path, &remote.headers) {
+                tracing::warn!("Could not record the version of {} for --skip-unchanged: {}", output_path.display(), e);
+            }
+        }
+        if let Some(cache) = &cache {
+            // Only regular files can be copied into the cache, not pipes
+            if !stream_output {
+                if let Err(e) = cache.store(&url, &remote.headers, &output_path) {
+                    tracing::warn!("Could not store {} in the cache: {}", url, e);
+                }
+            }
+        }
+        return Ok((downloaded, output_path));
+    }
+}
+
+// Where the output goes when a file is already there: nowhere with --no-clobber, which skips the download
+// With --force, or --skip-unchanged for which an existing file is an older version of the download, it is replaced
+// On a terminal the user is asked whether to overwrite it, save the download under the first free number, e.g. `file.iso.1`, or skip it
+// Otherwise a name derived from the URL moves on to the first free number, as does a file named by a client of the daemon, by the crawler
+// or by -o in the background; a file named by -o in the foreground is an error, which the user can answer with --force
+// An unfinished download of the output, resumed from its control file or with --continue, keeps its name
+fn unclobbered(args: &CommandLineArgs, target: &Target, output_path: &Path) -> Result<Option<PathBuf>, AppError> {
+    let exists = std::fs::metadata(output_path).is_ok_and(|m| m.is_file());
+    let unfinished = args.continue_download || FileSystem::new(filesystem::part_path(output_path)).control_path().exists();
+    let skip = || {
+        progress::message(&format!("{} already exists, not downloading it again", output_path.display()));
+        Ok(None)
+    };
+    if !exists || unfinished || args.skip_unchanged || args.force {
+        return Ok(Some(output_path.to_path_buf()));
+    }
+    if args.no_clobber {
+        return skip();
+    }
+    let numbered = filesystem::numbered_path(output_path);
+    // The daemon and the crawler do not stop for the terminal they may have been started from
+    if let Target::Assigned(_) = target {
+        return Ok(Some(numbered));
+    }
+    match (ask_existing(output_path, &numbered), target) {
+        (Some(Existing::Overwrite), _) => Ok(Some(output_path.to_path_buf())),
+        (Some(Existing::Rename), _) | (None, Target::Named(_) | Target::Assigned(_)) => Ok(Some(numbered)),
+        (Some(Existing::Skip), _) => skip(),
+        // A download continuing in the background cannot be told to pass --force any more than it can ask
+        (None, Target::File(_)) if daemonize::in_background() => Ok(Some(numbered)),
+        (None, Target::File(_)) => Err(AppError::IoError(format!("{} already exists, pass --force to replace it", output_path.display()))),
+    }
+}
+
+// What to do with an output that already exists
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Existing {
+    Overwrite,
+    Rename,
+    Skip,
+}
+
+impl Existing {
+    // The choice an answer stands for, by its first letter; an empty answer keeps both files
+    fn from_answer(answer: &str) -> Option<Existing> {
+        match answer.trim().to_ascii_lowercase().chars().next() {
+            Some('o') => Some(Existing::Overwrite),
+            Some('r') | None => Some(Existing::Rename),
+            Some('s') => Some(Existing::Skip),
+            _ => None,
+        }
+    }
+}
+
+// Ask on the terminal what to do with the existing `output_path`, until the answer is one of the choices
+// There is no answer when nobody can give one, and a closed standard input keeps both files
+fn ask_existing(output_path: &Path, numbered: &Path) -> Option<Existing> {
+    let question = format!("{} already exists: [o]verwrite, [r]ename to {} or [s]kip? [r] ", output_path.display(), numbered.display());
+    loop {
+        let answer = progress::ask(&question)?;
+        if let Some(existing) = Existing::from_answer(&answer) {
+            return Some(existing);
+        }
+    }
+}
+
+// Where the output is written until it is complete and verified: `<output>.part` for regular files
+// Pipes and devices are written directly, having nothing to rename
+// An unfinished download left at the output itself, by another tool for --continue or by an interrupted older rtget, moves to the part first
+fn part_path(args: &CommandLineArgs, output_path: &Path, stream_output: bool) -> Result<PathBuf, AppError> {
+    if stream_output || std::fs::metadata(output_path).is_ok_and(|m| !m.is_file()) {
+        return Ok(output_path.to_path_buf());
+    }
+    // Templates lay files out in directories that may not exist yet
+    if let Some(dir) = output_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let part_path = filesystem::part_path(output_path);
+    let unfinished = args.continue_download || FileSystem::new(part_path.clone()).control_path().exists();
+    if unfinished && !part_path.exists() && output_path.is_file() {
+        std::fs::rename(output_path, &part_path)?;
+    }
+    Ok(part_path)
+}
+
+// Check that the file system of the output has room for the `size` bytes of the file
+// When the free space cannot be found out the download goes ahead
+fn check_disk_space(output_path: &Path, size: u64) -> Result<(), AppError> {
+    let file_system = FileSystem::new(output_path.to_path_buf());
+    let needed = file_system.space_needed(size);
+    match file_system.free_space() {
+        Some(free) if free < needed => Err(AppError::InsufficientDiskSpace(format!(
+            "{} needs {} more bytes, but only {} are free on its file system",
+            output_path.display(),
+            needed,
+            free
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// The checksums given on the command line or in a manifest, each with where it came from
+// --checksum cannot be combined with a batch, so the checksum of a file of a batch comes from its manifest
+fn required_checksums(args: &CommandLineArgs) -> Vec<(ExpectedDigest, String)> {
+    let source = if args.manifest.is_some() { "the manifest" } else { "--checksum" };
+    args.checksum.iter().map(|expected| (expected.clone(), source.to_string())).collect()
+}
+
+// Hashes the output while it is written, for every digest and signature it is checked against
+fn digest_tracker(announced: Option<&ExpectedDigest>, required: &[(ExpectedDigest, String)], signature: Option<&SignatureCheck>) -> DigestTracker {
+    let checksums = announced.into_iter().chain(required.iter().map(|(expected, _)| expected)).map(|expected| expected.algorithm);
+    DigestTracker::new(checksums.chain(signature.map(SignatureCheck::algorithms).unwrap_or_default()))
+}
+
+// Complete the digests of the finished output, `size` bytes long, and check them
+// Servers that announce a digest of the file get it checked, and so do the `required` checksums and the signature asked for
+// A file that does not match a required checksum or its signature is removed, so a corrupt download is never mistaken for a good one
+fn verify_output(
+    output_path: &Path,
+    size: u64,
+    digest: &DigestTracker,
+    announced: Option<&ExpectedDigest>,
+    required: &[(ExpectedDigest, String)],
+    signature: Option<&SignatureCheck>,
+) -> Result<(), AppError> {
+    if !digest.is_active() {
+        return Ok(());
+    }
+    let digests = digest.finish(output_path, size)?;
+    if let Some(expected) = announced {
+        if !digests.matches(expected) {
+            return Err(AppError::ChecksumMismatch(format!("{} digest announced by the server", expected.algorithm.as_str())));
+        }
+        tracing::info!("{} digest verified", expected.algorithm.as_str());
+    }
+    for (expected, source) in required {
+        if !digests.matches(expected) {
+            FileSystem::new(output_path.to_path_buf()).remove_output()?;
+            return Err(AppError::ChecksumMismatch(format!("{} checksum from {}", expected.algorithm.as_str(), source)));
+        }
+        progress::message(&format!("{} checksum from {} verified", expected.algorithm.as_str(), source));
+    }
+    if let Some(signature) = signature {
+        match signature.verify(output_path, &digests) {
+            Ok(signer) => progress::message(&format!("Good signature from {}", signer)),
+            Err(error) => {
+                FileSystem::new(output_path.to_path_buf()).remove_output()?;
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Where a download is saved
+#[derive(Clone, PartialEq)]
+enum Target {
+    // This file, named by -o or by the template of a batch
+    File(PathBuf),
+    // This file, named by a client of the daemon or by the crawler, for a download nobody can be asked about
+    Assigned(PathBuf),
+    // A name derived from the URL, in this directory if given
+    Named(Option<PathBuf>),
+}
+
+impl Target {
+    // The file of -o, or a name derived from the URL in the directory of --dir or the current one
+    fn of(args: &CommandLineArgs) -> Target {
+        match &args.output {
+            Some(path) => Target::File(path.into()),
+            None => Target::Named(args.dir.as_ref().map(PathBuf::from)),
+        }
+    }
+}
+
+// Choose the output path: the file of the target, or a name derived from the URL
+// Names derived from URLs like `download?id=1` get an extension from the Content-Type with --auto-extension
+// and are laid out after --output-template, e.g. in a directory per host
+fn output_path(args: &CommandLineArgs, target: &Target, url: &Url, content_type: Option<&str>) -> PathBuf {
+    let named = || {
+        let path = filesystem::default_output_path(url);
+        let path = match content_type {
+            Some(content_type) if args.auto_extension => filesystem::with_mime_extension(&path, content_type),
+            _ => path,
+        };
+        match &args.output_template {
+            Some(template) => filesystem::templated_output_path(template, url, &path),
+            None => path,
+        }
+    };
+    match target {
+        Target::File(path) | Target::Assigned(path) => path.clone(),
+        // The files of a batch keep their own names, in the directory of -o if given
+        Target::Named(Some(dir)) => dir.join(named()),
+        Target::Named(None) => named(),
+    }
+}
+
+// The mirrors given with --mirror, and with --mirrors those advertised by the server
+// Each --mirror is probed first, and one that does not serve ranges of a file of the same size is left out
+async fn extra_sources(args: &CommandLineArgs, downloader: &FileDownloader, url: &Url, remote: &RemoteFile, total_size: usize) -> Result<Vec<Url>, AppError> {
+    let mut sources = Vec::new();
+    for mirror in &args.mirror {
+        let mirror = validate_url(mirror)?;
+        if mirror == *url || sources.contains(&mirror) {
+            continue;
+        }
+        match downloader.probe(mirror.as_str()).await {
+            Ok(copy) if copy.size == Some(total_size) && copy.accepts_ranges => sources.push(mirror),
+            Ok(copy) if copy.size == Some(total_size) => tracing::warn!("{} does not support byte ranges, not using it", mirror),
+            Ok(copy) => match copy.size {
+                Some(size) => tracing::warn!("{} has {} bytes instead of {}, not using it", mirror, size, total_size),
+                None => tracing::warn!("{} did not report a content length, not using it", mirror),
+            },
+            Err(e) => tracing::warn!("{} failed ({}), not using it", mirror, e),
+        }
+    }
+    if args.mirrors {
+        let advertised: Vec<Url> = mirrors::parse_mirrors(&remote.headers, url).into_iter().filter(|mirror| !sources.contains(mirror)).collect();
+        if !advertised.is_empty() {
+            tracing::info!("Using {} mirror(s) advertised by the server", advertised.len());
+        }
+        sources.extend(advertised);
+    }
+    Ok(sources)
+}
+
+// The byte ranges the probed file is split into, none when its size is unknown
+fn byte_ranges(args: &CommandLineArgs, remote: &RemoteFile, stream_output: bool, digest: &DigestTracker) -> Vec<(u64, u64)> {
+    match remote.size {
+        Some(total_size) if total_size > 0 => {
+            // Small files are split into fewer ranges than connections, so no range is below --min-split-size
+            let mut connections = args.connections.initial_for(total_size as u64, args.min_split_size);
+            // A file hashed while it is written gets more, smaller ranges, which the connections take in order
+            // Then the part complete from the start keeps growing during the download and little is left to hash at the end
+            if digest.is_active() && !stream_output {
+                let hashed_ranges = args::max_ranges(total_size as u64, HASHED_RANGE_SIZE).min(MAX_HASHED_RANGES);
+                connections = connections.max(hashed_ranges.min(args::max_ranges(total_size as u64, args.min_split_size)));
+            }
+            FileDownloader::calculate_byte_ranges(connections, total_size)
+        }
+            .into_iter()
+            .map(|(start, end)| (start as u64, end as u64))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The plan of the download of the probed file for --dry-run, with the mirrors it would use
+// Streamed output takes one connection per range, and other downloads the connections of -c
+async fn dry_run(
+    args: &CommandLineArgs,
+    downloader: &FileDownloader,
+    url: &Url,
+    remote: &RemoteFile,
+    output_path: &Path,
+    stream_output: bool,
+    digest: &DigestTracker,
+) -> Result<String, AppError> {
+    let ranges = byte_ranges(args, remote, stream_output, digest);
+    let mut sources = vec![url.clone()];
+    if let (Some(total_size), false) = (remote.size.filter(|_| remote.accepts_ranges), stream_output) {
+        sources.extend(extra_sources(args, downloader, url, remote, total_size).await?);
+    }
+    let connections = if stream_output { ranges.len() } else { args.connections.initial() };
+    Ok(plan::describe(url, output_path, Some(remote), connections, &ranges, &sources))
+}
+
+// Download the probed file into the output
+// Servers without range support send the whole file for every request, so it is fetched once
+// Responses without a length cannot be split either and are streamed until the end
+// Returns the number of bytes downloaded
+async fn transfer(
+    args: &CommandLineArgs,
+    downloader: &FileDownloader,
+    url: &Url,
+    remote: &RemoteFile,
+    output_path: &Path,
+    stream_output: bool,
+    digest: &DigestTracker,
+) -> Result<u64, AppError> {
+    let byte_ranges = byte_ranges(args, remote, stream_output, digest);
+    let file_system = FileSystem::new(output_path.to_path_buf()).with_digest(digest.clone());
+
+    let mut progress = ProgressManager::new(&file_system.file_name());
+    let total_size = match remote.size {
+        Some(total_size) if remote.accepts_ranges && total_size > 0 => total_size,
+        // An empty file has nothing to split
+        Some(0) => return download_single_stream(downloader, url, &RequestSpec::default(), &file_system, &mut progress, remote.size, args.max_filesize).await,
+        Some(_) => {
+            replay::record(EventKind::Fallback, "single stream: ranges not supported");
+            tracing::warn!("The server does not support byte ranges, downloading over a single connection");
+            return download_single_stream(downloader, url, &RequestSpec::default(), &file_system, &mut progress, remote.size, args.max_filesize).await;
+        }
+        None => {
+            replay::record(EventKind::Fallback, "single stream: unknown content length");
+            tracing::info!("The server did not report a content length, streaming until the end");
+            return download_single_stream(downloader, url, &RequestSpec::default(), &file_system, &mut progress, None, args.max_filesize).await;
+        }
+    };
+
+    // An interrupted download of the same file resumes from its control file, keeping its ranges
+    // Streamed output cannot be resumed and always starts afresh
+    let etag = remote.headers.get(reqwest::header::ETAG).and_then(|v| v.to_str().ok());
+    let mut control = match ControlFile::load(&file_system.control_path()) {
+        Some(control) if !stream_output && control.matches(url.as_str(), total_size as u64, etag) => control,
+        // The ranges of another download, or of an older version of this file, cannot be reused
+        _ => ControlFile::new(url.as_str(), etag, total_size as u64, &byte_ranges),
+    };
+    if !stream_output {
+        control.verify(output_path);
+        file_system.allocate(total_size as u64, args.file_allocation)?;
+    }
+    let resumed: u64 = control.segments.iter().map(|segment| segment.written).sum();
+    if resumed > 0 {
+        progress::message(&format!("Resuming {} of {} bytes from {}", resumed, total_size, file_system.control_path().display()));
+    }
+
+    // With --mirror and --mirrors the ranges are spread over the origin and the other copies of the file
+    // A range that fails on one source moves to the next; streamed ranges cannot move and stay on the origin
+    let mut sources = vec![url.clone()];
+    if !stream_output {
+        sources.extend(extra_sources(args, downloader, url, remote, total_size).await?);
+    }
+
+    replay::record(
+        EventKind::Plan,
+        format!("{} bytes in {} ranges from {} source(s), {} bytes resumed", total_size, control.segments.len(), sources.len(), resumed),
+    );
+    // A range that fails is tried again from where it stopped, with a growing wait in between
+    let retries = RetryPolicy::new(args.tries, Duration::from_secs(args.retry_wait));
+    // Where the ranges of the segmented download met, for --verify-boundaries, and whether every range is written
+    let mut boundaries = Vec::new();
+    let mut complete = false;
+    let downloaded = if stream_output {
+        // Create one task and one progress bar per byte range, each streaming into its own pipe
+        // A failing consumer drops the pipes, which in turn stops the chunk tasks
+        let mut tasks = Vec::new();
+        let mut pipes = Vec::new();
+        let ranged = match control.segments.len() {
+            1 => downloader.connection(),
+            _ => downloader.for_ranges()?,
+        };
+        for segment in &control.segments {
+            let bar_index = progress.create_progress_bar(segment.end - segment.start + 1);
+            let bar = progress.bar(bar_index).expect("progress bar was just created");
+            let (writer, reader) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+            pipes.push((reader, segment.end - segment.start + 1));
+            tasks.push(DownloadTask::new(url.to_string(), segment.start as usize, segment.end as usize, ChunkSink::Pipe(writer), bar, &ranged).with_retries(retries));
+        }
+        let (downloaded, streamed) = tokio::join!(
+            ConcurrentDownloader::new(tasks).execute_all(),
+            file_system.stream_in_order(pipes)
+        );
+        // The error of a failed range explains a stream that ended early better than the stream does
+        downloaded.into_result().and(streamed.map_err(AppError::from))
+    } else {
+        // Create one task and one progress bar per connection, taking the ranges from a shared scheduler
+        // A connection that runs out of ranges takes over half of what is left of the slowest one
+        let ranges: Vec<_> = control.segments.iter().map(|segment| (segment.start, segment.end, segment.written)).collect();
+        let scheduler = SegmentScheduler::new(&ranges, args.min_split_size).with_digest(digest.clone());
+        let connections = if scheduler.unfinished() > 0 { args.connections.initial() } else { 0 };
+        // With --io-backend uring all ranges write through one shared ring, with --mmap through one shared mapping
+        let io_backend = if args.mmap { IoBackend::Mmap } else { args.io_backend };
+        let range_output = file_system.range_output(io_backend, total_size as u64);
+        // A file split over several connections does not share the client of its batch
+        let ranged = match connections > 1 || ranges.len() > 1 || args.connections == Connections::Auto {
+            true => downloader.for_ranges()?,
+            false => downloader.connection(),
+        };
+        let connect = {
+            let (mut progress, scheduler, downloader, url) = (progress.clone(), scheduler.clone(), Arc::new(ranged), url.to_string());
+            let sources = SourcePool::new(sources.iter().map(Url::to_string).collect());
+            move |index: usize| {
+                let bar_index = progress.create_progress_bar(0);
+                let bar = progress.bar(bar_index).expect("progress bar was just created");
+                // Connections start on the sources in turn
+                DownloadTask::scheduled(url.clone(), range_output.clone(), scheduler.clone(), bar, &downloader)
+                    .with_retries(retries)
+                    .with_sources(sources.clone(), index % sources.len())
+            }
+        };
+        let tasks = (0..connections).map(connect.clone()).collect();
+        let mut downloader = ConcurrentDownloader::new(tasks);
+        // With --connections auto more connections are added while they make the download faster
+        if args.connections == Connections::Auto && connections > 0 {
+            let max_connections = AUTO_MAX_CONNECTIONS.min(args::max_ranges(total_size as u64, args.min_split_size));
+            downloader = downloader.with_tuner(ConnectionTuner::new(scheduler.clone(), connections, max_connections, connect));
+        }
+
+        // The control file follows the ranges so an interruption at any point can be resumed
+        // Ctrl-C or SIGTERM stops the ranges and saves exactly what they wrote, instead of the last periodic save
+        control.save(&file_system.control_path())?;
+        let ranges = {
+            let scheduler = scheduler.clone();
+            move || scheduler.ranges()
+        };
+        let saver = tokio::spawn(control::save_periodically(control.clone(), ranges, output_path.to_path_buf(), file_system.control_path()));
+        // Bytes written ahead of the hashed start of the file are hashed during the download, not after it
+        let hasher = digest.is_active().then(|| {
+            let scheduler = scheduler.clone();
+            tokio::spawn(digest.clone().follow(output_path.to_path_buf(), move || scheduler.written_prefix()))
+        });
+        let outcome = downloader.execute_until(interrupt::interrupted()).await;
+        saver.abort();
+        if let Some(hasher) = hasher {
+            hasher.abort();
+        }
+        if !matches!(outcome.termination, Termination::Completed) {
+            control.refresh(output_path, &scheduler.ranges())?;
+            control.save(&file_system.control_path())?;
+        }
+        let stopped = match &outcome.termination {
+            Termination::Cancelled => Some("Interrupted"),
+            Termination::Failed(AppError::TimedOut) => Some("Out of time"),
+            _ => None,
+        };
+        boundaries = scheduler.ranges().iter().map(|&(start, _, _)| start).collect();
+        complete = scheduler.unfinished() == 0;
+        if let Some(stopped) = stopped {
+            println!(
+                "{} with {} of {} bytes saved; run the same command again, or `rtget resume {}`, to resume",
+                stopped,
+                outcome.bytes_completed(),
+                total_size,
+                file_system.output_path().display()
+            );
+            for (start, end) in outcome.remaining() {
+                tracing::info!("bytes {}-{} are left to download", start, end);
+            }
+        }
+        outcome.into_result()
+    };
+    match downloaded {
+        // Some servers advertise ranges and still answer ranged requests with the whole file
+        Err(AppError::RangeNotSupported) if !stream_output => {
+            replay::record(EventKind::Fallback, "single stream: ranged request answered with 200");
+            tracing::warn!("The server ignored the byte ranges, downloading over a single connection");
+            return download_single_stream(downloader, url, &RequestSpec::default(), &file_system, &mut progress, Some(total_size), args.max_filesize).await;
+        }
+        // The server has nothing past the end of the file, and every range of it is already written
+        Err(AppError::RangeNotSatisfiable(Some(remote_size))) if remote_size == total_size && complete => {}
+        // The ranges still hold for a file of the planned size, which the control file keeps for a later try
+        Err(error @ AppError::RangeNotSatisfiable(Some(remote_size))) if remote_size == total_size => return Err(error),
+        // The planned ranges are stale, so is what was downloaded so far
+        Err(error @ AppError::RangeNotSatisfiable(_)) if !stream_output => {
+            file_system.remove_control()?;
+            return Err(error);
+        }
+        downloaded => downloaded?,
+    }
+    progress.finish_all("done");
+
+    // Every range is in place, nothing is left to resume
+    if !stream_output {
+        file_system.remove_control()?;
+        // Many connections wrote the file, so its size, and with --verify-boundaries the bytes where the ranges meet, are checked
+        integrity::check_size(output_path, total_size as u64)?;
+        if args.verify_boundaries {
+            integrity::spot_check(downloader, url.as_str(), output_path, &boundaries, total_size as u64).await?;
+            tracing::info!("The range boundaries match the server");
+        }
+    }
+    Ok(total_size as u64)
+}
+
+// Download the whole file over a single connection straight into the output
+// Used when the server does not honour byte ranges or the size is unknown, so there is nothing to split or merge
+// A transfer growing past `max_size` is aborted and its partial output removed
+// Returns the number of bytes downloaded
+async fn download_single_stream(
+    downloader: &FileDownloader,
+    url: &Url,
+    request: &RequestSpec,
+    file_system: &FileSystem,
+    progress: &mut ProgressManager,
+    total_size: Option<usize>,
+    max_size: Option<u64>,
+) -> Result<u64, AppError> {
+    file_system.remove_control()?;
+    let bar_index = match total_size {
+        Some(total_size) => progress.create_progress_bar(total_size as u64),
+        None => progress.create_spinner(),
+    };
+    let bar = progress.bar(bar_index).expect("progress bar was just created");
+    let mut output = file_system.create_output().await?;
+    match downloader.download_whole(url.as_str(), request, &mut output, &bar, max_size).await {
+        Err(error @ AppError::FileTooLarge(_)) => {
+            drop(output);
+            file_system.remove_output()?;
+            return Err(error);
+        }
+        result => result?,
+    }
+    progress.finish_with_message(bar_index, "done");
+    Ok(bar.position())
+}
+
+// Append the missing end of a partial output over a single ranged connection, like `wget -c`
+// Returns the number of bytes downloaded, zero if the output was already complete
+async fn continue_partial(downloader: &FileDownloader, url: &Url, remote: &RemoteFile, output_path: &Path, offset: u64, digest: &DigestTracker) -> Result<u64, AppError> {
+    let file_system = FileSystem::new(output_path.to_path_buf()).with_digest(digest.clone());
+    let Some(total_size) = remote.size.map(|size| size as u64) else {
+        return Err(AppError::StringError(format!("The server did not report the size of {}, it cannot be continued", url)));
+    };
+    if offset == total_size {
+        progress::message(&format!("{} is already complete", file_system.output_path().display()));
+        return Ok(0);
+    }
+    if offset > total_size {
+        return Err(AppError::StringError(format!(
+            "{} is larger than the remote file ({} > {} bytes), not continuing",
+            file_system.output_path().display(), offset, total_size
+        )));
+    }
+    if !remote.accepts_ranges {
+        return Err(AppError::RangeNotSupported);
+    }
+
+    replay::record(EventKind::Plan, format!("continue from byte {} of {}", offset, total_size));
+    progress::message(&format!("Continuing {} from byte {} of {}", file_system.output_path().display(), offset, total_size));
+    let mut progress = ProgressManager::new(&file_system.file_name());
+    let bar_index = progress.create_progress_bar(total_size);
+    let bar = progress.bar(bar_index).expect("progress bar was just created");
+    bar.set_position(offset);
+    let mut output = file_system.append_output().await?;
+    downloader.download_chunk(url.as_str(), offset as usize, total_size as usize - 1, &mut output, &bar).await?;
+    progress.finish_with_message(bar_index, "done");
+    Ok(total_size - offset)
+}
+
+// Explain a connection failure by checking which stage of reaching the server fails
+async fn report_diagnosis(args: &CommandLineArgs, url: &Url) {
+    let options = ClientOptions::from_args(args).unwrap_or_default();
+    let diagnosis = diagnose::diagnose(url, &options).await;
+    eprint!("{}", diagnosis.render());
+    if diagnosis.failed_stage().is_none() {
+        eprintln!("Hint: every stage succeeds now, so the failure may be intermittent; try again or use --event-log");
+    }
+}
+
+// Write the event log of a failed download if requested
+fn write_event_log(args: &CommandLineArgs, error: &AppError) {
+    if let Some(path) = &args.event_log {
+        replay::record(EventKind::Failed, error.to_string());
+        match replay::write(path.as_ref()) {
+            Ok(()) => eprintln!("Event log written to {}, view it with `rtget replay {}`", path, path),
+            Err(e) => eprintln!("Could not write the event log to {}: {}", path, e),
+        }
+    }
+}
+
+// Emit the final transfer metrics of a one-shot run
+// Short-lived processes cannot be scraped, so they are pushed instead; failing to emit is only logged
+async fn report_metrics(args: &CommandLineArgs, result: &Result<u64, AppError>, duration: Duration) {
+    if args.statsd.is_none() && args.pushgateway.is_none() {
+        return;
+    }
+    let metrics = TransferMetrics {
+        bytes: *result.as_ref().unwrap_or(&0),
+        duration,
+        connections: args.connections.initial(),
+        success: result.is_ok(),
+    };
+    if let Some(address) = &args.statsd {
+        if let Err(e) = metrics::send_statsd(address, &metrics) {
+            tracing::warn!("Could not send metrics to statsd at {}: {}", address, e);
+        }
+    }
+    if let Some(gateway) = &args.pushgateway {
+        if let Err(e) = metrics::push_gateway(gateway, &metrics).await {
+            tracing::warn!("Could not push metrics to {}: {}", gateway, e);
+        }
+    }
+}
+
+// Append a download that completed or failed to the history rtget history shows, announce it to --notify-webhook
+// and run the --exec or --exec-on-error command
+// Dry runs, interrupted downloads and outputs kept as they were have nothing to report; failing to report is not fatal
+async fn report_finished(args: &CommandLineArgs, url: &str, result: &Result<(u64, PathBuf), AppError>, duration: Duration) {
+    let command = match result {
+        Ok(_) => &args.exec,
+        Err(_) => &args.exec_on_error,
+    };
+    let result = match result {
+        _ if args.dry_run || (args.no_history && args.notify_webhook.is_none() && command.is_none()) => return,
+        Ok((0, _)) | Err(AppError::Interrupted) => return,
+        Ok((_, output)) => Ok(output.as_path()),
+        Err(error) => Err(error),
+    };
+    let entry = history::Entry::new(url, result, duration).await;
+    if let Some(path) = history::default_path().filter(|_| !args.no_history) {
+        if let Err(e) = history::append(&path, &entry) {
+            tracing::warn!("Could not record the download in {}: {}", path.display(), e);
+        }
+    }
+    if let Some(webhook) = &args.notify_webhook {
+        if let Err(e) = webhook::notify(webhook, &entry).await {
+            tracing::warn!("Could not notify {} of the download: {}", webhook, e);
+        }
+    }
+    if let Some(command) = command {
+        if let Err(e) = hook::run(command, &entry).await {
+            eprintln!("Error: {}: {}", url, e);
+        }
+    }
+}
+
+// Remember the Strict-Transport-Security policy of a host reached over HTTPS
+// Failing to persist the store is not fatal for the download
+fn record_hsts(store: &mut HstsStore, url: &Url, headers: &reqwest::header::HeaderMap) {
+    let policy = headers.get(reqwest::header::STRICT_TRANSPORT_SECURITY).and_then(|v| v.to_str().ok());
+    if let (Some(policy), Some(host), "https") = (policy, url.host_str(), url.scheme()) {
+        store.record(host, policy);
+        if let Err(e) = store.save() {
+            tracing::warn!("Could not save the HSTS database: {}", e);
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use argh::FromArgs;
+
+    #[test]
+    fn test_unclobbered_assigned_output() {
+        let dir = test_server::temp_dir("unclobbered");
+        let path = dir.join("file.iso");
+        std::fs::write(&path, b"old").unwrap();
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/file.iso"]).unwrap();
+        // A file named by a client of the daemon or by the crawler is saved under the first free number
+        assert_eq!(unclobbered(&args, &Target::Assigned(path.clone()), &path).unwrap(), Some(dir.join("file.iso.1")));
+        std::fs::write(dir.join("file.iso.1"), b"older").unwrap();
+        assert_eq!(unclobbered(&args, &Target::Assigned(path.clone()), &path).unwrap(), Some(dir.join("file.iso.2")));
+        // --no-clobber and --force still decide first
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/file.iso", "--no-clobber"]).unwrap();
+        assert_eq!(unclobbered(&args, &Target::Assigned(path.clone()), &path).unwrap(), None);
+        let args = CommandLineArgs::from_args(&["test"], &["-u", "http://a/file.iso", "--force"]).unwrap();
+        assert_eq!(unclobbered(&args, &Target::Assigned(path.clone()), &path).unwrap(), Some(path));
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::Read;
+use crate::batch::Entry;
+use crate::checksum::parse_checksum;
+use crate::error::AppError;
+
+/// Reads the downloads of the manifest at `path`, or of standard input for `-`.
+pub fn read(path: &str) -> Result<Vec<Entry>, AppError> {
+    let text = match path {
+        "-" => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        }
+        path => std::fs::read_to_string(path).map_err(|e| AppError::IoError(format!("{}: {}", path, e)))?,
+    };
+    parse(&text).map_err(|e| AppError::InvalidManifest(format!("{}: {}", path, e)))
+}
+
+/// Parses a manifest mapping each URL to its output path and, optionally, its checksum.
+///
+/// A manifest is either a JSON array of objects with `url`, `output` and `checksum` keys, or one
+/// file per line with the same three columns separated by tabs or commas; CSV fields may be quoted.
+/// A first row starting with `url` is a header, and blank lines and lines starting with `#` are
+/// skipped. A missing or empty output keeps the name of the URL, and checksums look like those of
+/// --checksum, e.g. `sha256=<hex>`.
+pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+    if text.trim_start().starts_with('[') {
+        return parse_json(text);
+    }
+    let tabs = text.contains('\t');
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = match tabs {
+            true => line.split('\t').map(str::to_string).collect(),
+            false => split_csv(line).ok_or_else(|| format!("line {} has an unterminated quote", number + 1))?,
+        };
+        if entries.is_empty() && fields[0].trim().eq_ignore_ascii_case("url") {
+            continue;
+        }
+        let field = |index: usize| fields.get(index).map(|field| field.trim()).filter(|field| !field.is_empty());
+        entries.push(entry(field(0), field(1), field(2)).map_err(|e| format!("line {}: {}", number + 1, e))?);
+    }
+    Ok(entries)
+}
+
+// Parse a JSON array of objects with the columns of a manifest as keys
+fn parse_json(text: &str) -> Result<Vec<Entry>, String> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let items = value.as_array().ok_or("expected an array of objects")?;
+    items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let field = |key: &str| match item.get(key) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(serde_json::Value::String(value)) => Ok(Some(value.trim()).filter(|value| !value.is_empty())),
+                Some(_) => Err(format!("entry {}: {} is not a string", index + 1, key)),
+            };
+            entry(field("url")?, field("output")?, field("checksum")?).map_err(|e| format!("entry {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+// Build the download of one row of a manifest
+fn entry(url: Option<&str>, output: Option<&str>, checksum: Option<&str>) -> Result<Entry, String> {
+    Ok(Entry {
+        url: url.ok_or("the URL is missing")?.to_string(),
+        output: output.map(Into::into),
+        checksum: checksum.map(parse_checksum).transpose()?,
+    })
+}
+
+// Split a line of CSV into its fields, unquoting those in double quotes, where "" stands for a quote
+// Returns None for a quote that is not closed
+fn split_csv(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("there is always a field");
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => field.push(c),
+        }
+    }
+    (!quoted).then_some(fields)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Algorithm;
+
+    const MD5: &str = "md5=d41d8cd98f00b204e9800998ecf8427e";
+
+    #[test]
+    fn test_parse_delimited() {
+        let tsv = format!("url\toutput\tchecksum\nhttp://a/1.bin\teu/1.bin\t{}\n# skipped\n\nhttp://a/2.bin\n", MD5);
+        let entries = parse(&tsv).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].url.as_str(), entries[0].output.as_deref()), ("http://a/1.bin", Some("eu/1.bin".as_ref())));
+        assert_eq!(entries[0].checksum.as_ref().map(|checksum| checksum.algorithm), Some(Algorithm::Md5));
+        assert_eq!((entries[1].output.as_ref(), entries[1].checksum.as_ref()), (None, None));
+
+        let csv = format!("http://a/1.bin,\"sales, 2024.csv\",{}\r\nhttp://a/2.bin,,\n", MD5);
+        let entries = parse(&csv).unwrap();
+        assert_eq!(entries[0].output.as_deref(), Some("sales, 2024.csv".as_ref()));
+        assert_eq!(entries[1].output, None);
+
+        assert_eq!(parse("http://a/1.bin,out,md5=zz").unwrap_err().split(':').next(), Some("line 1"));
+        assert!(parse("http://a/1.bin,\"out").is_err());
+        assert!(parse(",out").is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let json = format!(r#"[{{"url": "http://a/1.bin", "output": "eu/1.bin", "checksum": "{}"}}, {{"url": "http://a/2.bin"}}]"#, MD5);
+        let entries = parse(&json).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].output.as_deref(), Some("eu/1.bin".as_ref()));
+        assert!(entries[0].checksum.is_some() && entries[1].checksum.is_none());
+
+        assert!(parse(r#"[{"output": "x"}]"#).is_err());
+        assert!(parse(r#"[{"url": 1}]"#).is_err());
+        assert!(parse("[").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use reqwest::Client;
+use crate::error::AppError;
+
+// Metric name prefix shared by both emitters
+const PREFIX: &str = "rtget";
+
+/// Final metrics of a single transfer
+pub struct TransferMetrics {
+    /// Bytes written to the output
+    pub bytes: u64,
+    /// Wall clock duration of the transfer
+    pub duration: Duration,
+    /// Number of connections used
+    pub connections: usize,
+    /// Whether the transfer completed
+    pub success: bool,
+}
+
+impl TransferMetrics {
+    // Average throughput in bytes per second
+    fn bytes_per_second(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 { self.bytes as f64 / seconds } else { 0.0 }
+    }
+
+    // Render the metrics as statsd lines
+    fn statsd_lines(&self) -> String {
+        let outcome = if self.success { "success" } else { "failure" };
+        format!(
+            "{p}.bytes:{}|c\n{p}.duration_ms:{}|ms\n{p}.connections:{}|g\n{p}.{}:1|c\n",
+            self.bytes,
+            self.duration.as_millis(),
+            self.connections,
+            outcome,
+            p = PREFIX
+        )
+    }
+
+    // Render the metrics in the Prometheus text exposition format
+    fn prometheus_text(&self) -> String {
+        format!(
+            "# TYPE {p}_bytes_total counter\n{p}_bytes_total {}\n\
+             # TYPE {p}_duration_seconds gauge\n{p}_duration_seconds {:.3}\n\
+             # TYPE {p}_bytes_per_second gauge\n{p}_bytes_per_second {:.0}\n\
+             # TYPE {p}_connections gauge\n{p}_connections {}\n\
+             # TYPE {p}_success gauge\n{p}_success {}\n",
+            self.bytes,
+            self.duration.as_secs_f64(),
+            self.bytes_per_second(),
+            self.connections,
+            self.success as u8,
+            p = PREFIX
+        )
+    }
+}
+
+/// Sends the metrics to a statsd daemon at `address` (`host:port`) over UDP.
+///
+/// The socket is bound in the address family of the daemon, so IPv6 daemons are reached as well.
+pub fn send_statsd(address: &str, metrics: &TransferMetrics) -> Result<(), AppError> {
+    let target = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| AppError::StringError(format!("statsd address {} did not resolve", address)))?;
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.send_to(metrics.statsd_lines().as_bytes(), target)?;
+    Ok(())
+}
+
+/// Pushes the metrics to a Prometheus Pushgateway at `gateway` under the `rtget` job.
+pub async fn push_gateway(gateway: &str, metrics: &TransferMetrics) -> Result<(), AppError> {
+    let url = format!("{}/metrics/job/{}", gateway.trim_end_matches('/'), PREFIX);
+    let response = Client::new().put(url).body(metrics.prometheus_text()).send().await?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(AppError::from(response.status()))
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> TransferMetrics {
+        TransferMetrics { bytes: 2048, duration: Duration::from_millis(2000), connections: 4, success: true }
+    }
+
+    #[test]
+    fn test_statsd_lines() {
+        assert_eq!(
+            sample().statsd_lines(),
+            "rtget.bytes:2048|c\nrtget.duration_ms:2000|ms\nrtget.connections:4|g\nrtget.success:1|c\n"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let text = sample().prometheus_text();
+        assert!(text.contains("rtget_bytes_total 2048\n"));
+        assert!(text.contains("rtget_bytes_per_second 1024\n"));
+        assert!(text.contains("rtget_success 1\n"));
+    }
+
+    #[test]
+    fn test_send_statsd() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_statsd(&receiver.local_addr().unwrap().to_string(), &sample()).unwrap();
+
+        let mut buffer = [0u8; 512];
+        let (len, _) = receiver.recv_from(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..len]).starts_with("rtget.bytes:2048|c"));
+    }
+
+    #[test]
+    fn test_send_statsd_over_ipv6() {
+        // Hosts without IPv6 have nothing to send to
+        let Ok(receiver) = UdpSocket::bind("[::1]:0") else {
+            return;
+        };
+        send_statsd(&receiver.local_addr().unwrap().to_string(), &sample()).unwrap();
+
+        let mut buffer = [0u8; 512];
+        let (len, _) = receiver.recv_from(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..len]).starts_with("rtget.bytes:2048|c"));
+    }
+}
@@ -0,0 +1,124 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::header::{HeaderMap, HeaderName, LINK};
+use url::Url;
+use crate::checksum::{Algorithm, ExpectedDigest};
+
+// The RFC 3230 instance digest header, not among the predefined header names
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// Returns the alternate locations of a file from `Link: <url>; rel=duplicate` headers.
+///
+/// Mirrors are ordered by their `pri` parameter, lowest first as in RFC 6249.
+/// Relative links are resolved against `base` and only HTTP(S) mirrors are kept.
+pub fn parse_mirrors(headers: &HeaderMap, base: &Url) -> Vec<Url> {
+    let mut mirrors: Vec<(u32, Url)> = headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(split_links)
+        .filter_map(|link| {
+            let (target, params) = link.split_once('>')?;
+            let target = target.trim().strip_prefix('<')?;
+            let mut duplicate = false;
+            let mut priority = u32::MAX;
+            for param in params.split(';').map(str::trim) {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                let value = value.trim().trim_matches('"');
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "rel" => duplicate = value.split_whitespace().any(|rel| rel.eq_ignore_ascii_case("duplicate")),
+                    "pri" => priority = value.parse().unwrap_or(u32::MAX),
+                    _ => {}
+                }
+            }
+            let url = base.join(target).ok().filter(|url| matches!(url.scheme(), "http" | "https"))?;
+            (duplicate && url != *base).then_some((priority, url))
+        })
+        .collect();
+    mirrors.sort_by_key(|(priority, _)| *priority);
+    mirrors.into_iter().map(|(_, url)| url).collect()
+}
+
+/// Returns the strongest verifiable hash from the `Digest` headers (RFC 3230), if any.
+///
+/// Only `sha-256` and `sha-512` are trusted; the weaker `md5` and `sha` are ignored.
+pub fn parse_digest(headers: &HeaderMap) -> Option<ExpectedDigest> {
+    let mut digests: Vec<ExpectedDigest> = headers
+        .get_all(DIGEST)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let (name, value) = entry.trim().split_once('=')?;
+            let algorithm = match name.trim().to_ascii_lowercase().as_str() {
+                "sha-256" => Algorithm::Sha256,
+                "sha-512" => Algorithm::Sha512,
+                _ => return None,
+            };
+            Some(ExpectedDigest { algorithm, value: BASE64.decode(value.trim()).ok()? })
+        })
+        .collect();
+    // SHA-512 sorts first
+    digests.sort_by_key(|digest| digest.algorithm == Algorithm::Sha256);
+    digests.into_iter().next()
+}
+
+// Split a Link header value into its links, ignoring commas inside the angle brackets
+fn split_links(value: &str) -> Vec<&str> {
+    let mut links = Vec::new();
+    let mut in_target = false;
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        match c {
+            '<' => in_target = true,
+            '>' => in_target = false,
+            ',' if !in_target => {
+                links.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    links.push(&value[start..]);
+    links
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::DigestTracker;
+    use crate::test_server;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_parse_mirrors() {
+        let base = Url::parse("https://download.example.org/iso/a.iso").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(LINK, HeaderValue::from_static("<https://download.example.org/iso/a.iso.meta4>; rel=describedby; type=\"application/metalink4+xml\""));
+        headers.append(LINK, HeaderValue::from_static("<http://b.example.net/a.iso>; rel=duplicate; pri=2, <https://a.example.com/x,y/a.iso>; rel=duplicate; pri=1"));
+        headers.append(LINK, HeaderValue::from_static("<ftp://c.example.com/a.iso>; rel=duplicate; pri=3"));
+
+        let mirrors = parse_mirrors(&headers, &base);
+        assert_eq!(mirrors, vec![
+            Url::parse("https://a.example.com/x,y/a.iso").unwrap(),
+            Url::parse("http://b.example.net/a.iso").unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_and_verify_digest() {
+        let dir = test_server::temp_dir("digest");
+        let path = dir.join("hello");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(DIGEST, HeaderValue::from_static("md5=XUFAKrxLKna5cZ2REBfFkg==, sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="));
+        let expected = parse_digest(&headers).unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Sha256);
+        assert!(DigestTracker::new([expected.algorithm]).finish(&path, 5).unwrap().matches(&expected));
+
+        std::fs::write(&path, b"hellO").unwrap();
+        assert!(!DigestTracker::new([expected.algorithm]).finish(&path, 5).unwrap().matches(&expected));
+    }
+}
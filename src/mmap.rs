@@ -0,0 +1,129 @@
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use memmap2::MmapRaw;
+use tokio::io::AsyncWrite;
+
+// Largest output mapped on 32-bit targets, leaving address space for everything else
+const MAX_MAPPED_SIZE_32: u64 = 1 << 30;
+
+/// The output of a segmented download mapped into memory
+///
+/// Every range copies its bytes straight into the shared mapping, without a system call per write.
+/// The mapping is shared with the file, so written bytes are visible to every reader of the file
+/// right away, like after a flush. Clones share the mapping.
+#[derive(Clone)]
+pub struct MappedFile {
+    map: Arc<MmapRaw>,
+    // The bytes being copied into the mapping right now
+    writing: Arc<Mutex<Vec<Range<usize>>>>,
+}
+
+impl MappedFile {
+    /// Maps the first `size` bytes of the existing output at `path`.
+    ///
+    /// Fails when the file is shorter than `size`, e.g. with `--file-allocation none`, or when it
+    /// does not fit into the address space, as on 32-bit targets.
+    pub fn open(path: &Path, size: u64) -> io::Result<MappedFile> {
+        let limit = if cfg!(target_pointer_width = "32") { MAX_MAPPED_SIZE_32 } else { usize::MAX as u64 };
+        if size > limit {
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, "the file does not fit into the address space"));
+        }
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+        // Writes past the end of the file would fault instead of growing it
+        if file.metadata()?.len() < size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the file is not allocated to its full size"));
+        }
+        let map = memmap2::MmapOptions::new().len(size as usize).map_raw(&file)?;
+        Ok(MappedFile { map: Arc::new(map), writing: Arc::default() })
+    }
+
+    /// Returns a writer of the output from `offset` on.
+    pub fn writer(&self, offset: u64) -> MappedWriter {
+        MappedWriter { file: self.clone(), offset }
+    }
+
+    // Lock the bytes being copied, ignoring a writer that panicked while holding them
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Range<usize>>> {
+        self.writing.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Writes one range of a [`MappedFile`] sequentially from its start offset
+pub struct MappedWriter {
+    file: MappedFile,
+    offset: u64,
+}
+
+impl AsyncWrite for MappedWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let offset = this.offset as usize;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if offset.checked_add(buf.len()).is_none_or(|end| end > this.file.map.len()) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "write past the end of the mapped file")));
+        }
+        // The scheduler reserves the bytes of every write of a range, so this only fails on a bug
+        let bytes = offset..offset + buf.len();
+        {
+            let mut writing = this.file.lock();
+            if writing.iter().any(|other| other.start < bytes.end && bytes.start < other.end) {
+                return Poll::Ready(Err(io::Error::other("two ranges write the same bytes of the mapped file")));
+            }
+            writing.push(bytes.clone());
+        }
+        // SAFETY: the bytes lie within the mapping, and no other writer copies into them until they
+        // leave `writing`, so the copy does not race with another one
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), this.file.map.as_mut_ptr().add(offset), buf.len()) };
+        this.file.lock().retain(|other| *other != bytes);
+        this.offset += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    // The bytes are in the shared mapping, and so in the file, as soon as they are copied
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use tokio::io::AsyncWriteExt;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_mapped_writes() {
+        let path = test_server::temp_dir("mmap").join("out");
+        std::fs::write(&path, [0u8; 8]).unwrap();
+        let mapped = MappedFile::open(&path, 8).unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            mapped.writer(4).write_all(b"efgh").await.unwrap();
+            mapped.writer(0).write_all(b"abcd").await.unwrap();
+            assert!(mapped.writer(6).write_all(b"xyz").await.is_err());
+
+            // Bytes another writer is copying into are refused instead of raced for
+            mapped.lock().push(2..4);
+            assert!(mapped.writer(3).write_all(b"x").await.is_err());
+            mapped.writer(1).write_all(b"b").await.unwrap();
+            mapped.lock().clear();
+        });
+
+        // Readers of the file see the bytes while the mapping is alive
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefgh");
+        // A file that is not allocated yet is not mapped
+        assert!(MappedFile::open(&path, 9).is_err());
+    }
+}
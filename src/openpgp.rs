@@ -0,0 +1,902 @@
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::VerifyingKey;
+use indicatif::ProgressBar;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use url::Url;
+use crate::checksum::{Algorithm, Digests};
+use crate::downloader::{Downloader, FileDownloader, RequestSpec};
+use crate::error::AppError;
+
+// Largest signature or keyring accepted
+const MAX_SIGNATURE_SIZE: u64 = 1024 * 1024;
+
+// Packet tags
+const TAG_SIGNATURE: u8 = 2;
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_USER_ID: u8 = 13;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+
+// Public key algorithms: RSA (encrypt or sign, and sign only), EdDSA as used by GnuPG, and Ed25519 of RFC 9580
+const RSA: u8 = 1;
+const RSA_SIGN_ONLY: u8 = 3;
+const EDDSA_LEGACY: u8 = 22;
+const ED25519: u8 = 27;
+
+// Curve OID of the EdDSA keys GnuPG creates
+const ED25519_OID: &[u8] = &[0x2B, 0x06, 0x01, 0x04, 0x01, 0xDA, 0x47, 0x0F, 0x01];
+
+// Signature types over a binary document and over text with normalized line endings
+const BINARY_SIGNATURE: u8 = 0x00;
+const TEXT_SIGNATURE: u8 = 0x01;
+
+// Signature types over keys: certifications of a user ID, the binding of a subkey to its primary
+// key and back, and the revocations of a primary key and of a subkey, and a direct key signature
+const CERTIFICATIONS: std::ops::RangeInclusive<u8> = 0x10..=0x13;
+const SUBKEY_BINDING: u8 = 0x18;
+const PRIMARY_KEY_BINDING: u8 = 0x19;
+const DIRECT_KEY: u8 = 0x1F;
+const KEY_REVOCATION: u8 = 0x20;
+const SUBKEY_REVOCATION: u8 = 0x28;
+
+// Key flag of keys that may sign data
+const SIGNING_KEY: u8 = 0x02;
+
+// Largest RSA modulus accepted, in bits
+const MAX_RSA_BITS: usize = 8192;
+
+/// A detached OpenPGP signature and the keys it has to be made with
+///
+/// Only the pieces of OpenPGP needed to check a release signature are implemented: version 4 RSA
+/// and Ed25519 keys and signatures over SHA-256 or SHA-512. Primary keys of the keyring are trusted
+/// as they are, subkeys only once their binding to the primary key checks out, and neither once
+/// revoked or expired according to the self-signatures of the primary key.
+pub struct SignatureCheck {
+    signatures: Vec<Signature>,
+    keyring: Vec<PublicKey>,
+    // Where the signature came from, for messages
+    source: String,
+}
+
+impl SignatureCheck {
+    /// Loads the signature at `location`, a URL or a path, and the public keys of the file at `keyring`.
+    ///
+    /// Both may be ASCII armored. Fails before anything is downloaded if either cannot be used.
+    pub async fn load(downloader: &FileDownloader, location: &str, keyring: &str) -> Result<SignatureCheck, AppError> {
+        let keys = std::fs::read(keyring).map_err(|e| AppError::InvalidSignature(format!("cannot read the keyring {}: {}", keyring, e)))?;
+        let keyring_keys = parse_keyring(&keys).map_err(|e| AppError::InvalidSignature(format!("keyring {}: {}", keyring, e)))?;
+        let data = match Url::parse(location) {
+            Ok(url) if matches!(url.scheme(), "http" | "https" | "ftp" | "sftp") => {
+                let mut data = Vec::new();
+                downloader.download_whole(url.as_str(), &RequestSpec::default(), &mut data, &ProgressBar::hidden(), Some(MAX_SIGNATURE_SIZE)).await?;
+                data
+            }
+            _ => std::fs::read(location).map_err(|e| AppError::InvalidSignature(format!("cannot read the signature {}: {}", location, e)))?,
+        };
+        let signatures = parse_signatures(&data).map_err(|e| AppError::InvalidSignature(format!("{}: {}", location, e)))?;
+        if !signatures.iter().any(|signature| keyring_keys.iter().any(|key| signature.is_issued_by(key))) {
+            return Err(AppError::InvalidSignature(format!("{}: it is not made by any key in the keyring {}", location, keyring)));
+        }
+        Ok(SignatureCheck { signatures, keyring: keyring_keys, source: location.to_string() })
+    }
+
+    /// Hash algorithms the signed file is hashed with.
+    pub fn algorithms(&self) -> Vec<Algorithm> {
+        self.signatures.iter().filter_map(|signature| signature.hash).collect()
+    }
+
+    /// Checks the signature of the finished file at `path`, whose digests were computed while it was written.
+    ///
+    /// Succeeds when one of the signatures is made by a key of the keyring and matches the file,
+    /// and returns who made it.
+    pub fn verify(&self, path: &Path, digests: &Digests) -> Result<String, AppError> {
+        let mut failure = None;
+        for signature in &self.signatures {
+            let Some(key) = self.keyring.iter().find(|key| signature.is_issued_by(key)) else {
+                continue;
+            };
+            if let Some(problem) = key.unusable() {
+                failure = Some(format!("{} cannot be used: {}", key.owner(), problem));
+                continue;
+            }
+            let result = match signature.hash {
+                Some(algorithm) if signature.signature_type == TEXT_SIGNATURE => hash_text(path, algorithm, &signature.trailer()).map_err(|e| e.to_string()),
+                Some(algorithm) => digests.with_suffix(algorithm, &signature.trailer()).ok_or_else(|| "the file was not hashed".to_string()),
+                None => Err(format!("unsupported hash algorithm {}", signature.hash_id)),
+            }
+            .and_then(|digest| key.verify(signature, &digest));
+            match result {
+                Ok(()) => return Ok(key.owner()),
+                Err(e) => failure = Some(format!("bad signature from {}: {}", key.owner(), e)),
+            }
+        }
+        let reason = failure.unwrap_or_else(|| "it is not made by any key in the keyring".to_string());
+        Err(AppError::InvalidSignature(format!("{}: {}", self.source, reason)))
+    }
+}
+
+// A public key of the keyring
+struct PublicKey {
+    fingerprint: [u8; 20],
+    material: KeyMaterial,
+    // The first user ID of the primary key, shared by its subkeys
+    user_id: Option<String>,
+    // Whether the key is a primary key, or a signing subkey bound to its primary key
+    bound: bool,
+    revoked: bool,
+    // When the key expires, in seconds since the Unix epoch
+    expires: Option<u64>,
+}
+
+enum KeyMaterial {
+    Rsa(RsaPublicKey),
+    Ed25519(VerifyingKey),
+}
+
+impl PublicKey {
+    // Why signatures of the key are not to be trusted, if they are not
+    fn unusable(&self) -> Option<&'static str> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default();
+        if self.revoked {
+            Some("it is revoked")
+        } else if !self.bound {
+            Some("it is not bound to its primary key as a signing key")
+        } else if self.expires.is_some_and(|expires| expires <= now) {
+            Some("it expired")
+        } else {
+            None
+        }
+    }
+
+    // Who the key belongs to, for messages
+    fn owner(&self) -> String {
+        let fingerprint: String = self.fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect();
+        match &self.user_id {
+            Some(user_id) => format!("{} (key {})", user_id, fingerprint),
+            None => format!("key {}", fingerprint),
+        }
+    }
+
+    // Check `signature` against the digest of the signed data
+    fn verify(&self, signature: &Signature, digest: &[u8]) -> Result<(), String> {
+        if digest.get(..2) != Some(&signature.digest_prefix[..]) {
+            return Err("the file does not match".to_string());
+        }
+        match (&self.material, signature.hash) {
+            (KeyMaterial::Rsa(key), Some(hash)) => {
+                let scheme = match hash {
+                    Algorithm::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+                    Algorithm::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+                    _ => return Err(format!("unsupported hash algorithm {}", hash.as_str())),
+                };
+                let value = signature.values.first().ok_or("the signature has no value")?;
+                key.verify(scheme, digest, &left_pad(value, key.size())?).map_err(|_| "the file does not match".to_string())
+            }
+            (KeyMaterial::Ed25519(key), _) => {
+                let bytes: Vec<u8> = match &signature.values[..] {
+                    [r, s] => [left_pad(r, 32)?, left_pad(s, 32)?].concat(),
+                    [rs] => rs.clone(),
+                    _ => return Err("malformed Ed25519 signature".to_string()),
+                };
+                let bytes: [u8; 64] = bytes.try_into().map_err(|_| "malformed Ed25519 signature")?;
+                key.verify_strict(digest, &ed25519_dalek::Signature::from_bytes(&bytes)).map_err(|_| "the file does not match".to_string())
+            }
+            (_, None) => Err(format!("unsupported hash algorithm {}", signature.hash_id)),
+        }
+    }
+}
+
+// A version 4 signature packet
+struct Signature {
+    signature_type: u8,
+    key_algorithm: u8,
+    // Hash algorithm of the signature, `None` if it is not supported
+    hash: Option<Algorithm>,
+    hash_id: u8,
+    // The version, type, algorithms and hashed subpackets, which are hashed after the signed data
+    hashed: Vec<u8>,
+    // Key ID or fingerprint of the key that made the signature
+    issuer_key_id: Option<[u8; 8]>,
+    issuer_fingerprint: Option<[u8; 20]>,
+    // When the signature was made, in seconds since the Unix epoch
+    created: u32,
+    // For a self-signature, when the key expires in seconds after its creation, and what it may do
+    key_expiry: Option<u32>,
+    key_flags: Option<u8>,
+    // Signatures embedded in this one, like the binding of a signing subkey back to its primary key
+    embedded: Vec<Vec<u8>>,
+    // The first two bytes of the signed digest
+    digest_prefix: [u8; 2],
+    // The algorithm-specific values, like the RSA signature or the EdDSA r and s
+    values: Vec<Vec<u8>>,
+}
+
+impl Signature {
+    // The data hashed after the signed file
+    fn trailer(&self) -> Vec<u8> {
+        let mut trailer = self.hashed.clone();
+        trailer.extend_from_slice(&[4, 0xFF]);
+        trailer.extend_from_slice(&(self.hashed.len() as u32).to_be_bytes());
+        trailer
+    }
+
+    // Whether `key` may have made the signature
+    fn is_issued_by(&self, key: &PublicKey) -> bool {
+        let algorithm_fits = match key.material {
+            KeyMaterial::Rsa(_) => matches!(self.key_algorithm, RSA | RSA_SIGN_ONLY),
+            KeyMaterial::Ed25519(_) => matches!(self.key_algorithm, EDDSA_LEGACY | ED25519),
+        };
+        let issued = match (self.issuer_fingerprint, self.issuer_key_id) {
+            (Some(fingerprint), _) => fingerprint == key.fingerprint,
+            (None, Some(key_id)) => key_id == key.fingerprint[12..],
+            (None, None) => false,
+        };
+        algorithm_fits && issued
+    }
+}
+
+// Parse the signatures of a detached signature file
+fn parse_signatures(data: &[u8]) -> Result<Vec<Signature>, String> {
+    let data = dearmor(data)?;
+    let mut signatures = Vec::new();
+    for (tag, body) in packets(&data)? {
+        if tag != TAG_SIGNATURE {
+            continue;
+        }
+        match parse_signature(body)? {
+            Some(signature) if matches!(signature.signature_type, BINARY_SIGNATURE | TEXT_SIGNATURE) => signatures.push(signature),
+            _ => {}
+        }
+    }
+    if signatures.is_empty() {
+        return Err("no RSA or Ed25519 signature of a file found".to_string());
+    }
+    Ok(signatures)
+}
+
+// Parse a signature packet, `None` for other versions and algorithms
+fn parse_signature(body: &[u8]) -> Result<Option<Signature>, String> {
+    let mut reader = Reader(body);
+    if reader.byte()? != 4 {
+        return Ok(None);
+    }
+    let signature_type = reader.byte()?;
+    let key_algorithm = reader.byte()?;
+    let hash_id = reader.byte()?;
+    let hashed_length = reader.u16()?;
+    let hashed_area = reader.take(hashed_length)?;
+    let unhashed_length = reader.u16()?;
+    let unhashed_area = reader.take(unhashed_length)?;
+    let hash = match hash_id {
+        8 => Some(Algorithm::Sha256),
+        10 => Some(Algorithm::Sha512),
+        // SHA-1 is broken for signatures and the others are not supported
+        _ => None,
+    };
+    let mut signature = Signature {
+        signature_type,
+        key_algorithm,
+        hash,
+        hash_id,
+        hashed: body[..6 + hashed_length].to_vec(),
+        issuer_key_id: None,
+        issuer_fingerprint: None,
+        created: 0,
+        key_expiry: None,
+        key_flags: None,
+        embedded: Vec::new(),
+        digest_prefix: reader.take(2)?.try_into().unwrap_or_default(),
+        values: Vec::new(),
+    };
+    let hashed = subpackets(hashed_area)?.into_iter().map(|subpacket| (true, subpacket));
+    for (is_hashed, (kind, value)) in hashed.chain(subpackets(unhashed_area)?.into_iter().map(|subpacket| (false, subpacket))) {
+        match (kind, value) {
+            (16, _) => signature.issuer_key_id = value.try_into().ok(),
+            (33, [4, fingerprint @ ..]) => signature.issuer_fingerprint = fingerprint.try_into().ok(),
+            // An embedded signature holds by itself, wherever it is
+            (32, _) => signature.embedded.push(value.to_vec()),
+            // What the signature says about the key counts only when it is signed too
+            (2, _) if is_hashed => signature.created = value.try_into().map(u32::from_be_bytes).unwrap_or_default(),
+            (9, _) if is_hashed => signature.key_expiry = value.try_into().map(u32::from_be_bytes).ok(),
+            (27, [flags, ..]) if is_hashed => signature.key_flags = Some(*flags),
+            _ => {}
+        }
+    }
+    signature.values = match key_algorithm {
+        RSA | RSA_SIGN_ONLY => vec![reader.mpi()?.to_vec()],
+        EDDSA_LEGACY => vec![reader.mpi()?.to_vec(), reader.mpi()?.to_vec()],
+        ED25519 => vec![reader.take(64)?.to_vec()],
+        _ => return Ok(None),
+    };
+    Ok(Some(signature))
+}
+
+// Parse the RSA and Ed25519 public keys of a keyring, skipping keys of other algorithms
+fn parse_keyring(data: &[u8]) -> Result<Vec<PublicKey>, String> {
+    let data = dearmor(data)?;
+    let mut keys = Vec::new();
+    // Each certificate starts at a primary key, followed by its user IDs, subkeys and their signatures
+    for certificate in packets(&data)?.chunk_by(|_, (tag, _)| *tag != TAG_PUBLIC_KEY) {
+        keys.extend(parse_certificate(certificate)?);
+    }
+    if keys.is_empty() {
+        return Err("no RSA or Ed25519 public key found".to_string());
+    }
+    Ok(keys)
+}
+
+// Parse the keys of a certificate, checking what the self-signatures of its primary key say about them
+fn parse_certificate(packets: &[(u8, &[u8])]) -> Result<Vec<PublicKey>, String> {
+    let Some((&(TAG_PUBLIC_KEY, primary_body), rest)) = packets.split_first() else {
+        return Ok(Vec::new());
+    };
+    let mut primary = parse_key(primary_body)?;
+    if let Some(primary) = primary.as_mut() {
+        primary.bound = true;
+    }
+    // The subkeys, each with when its newest binding was made
+    let mut subkeys: Vec<(Option<PublicKey>, &[u8], u32)> = Vec::new();
+    // When the newest self-signature of the primary key was made
+    let mut newest = 0;
+    // The user ID the signatures that follow certify, if they follow one
+    let mut user_id: Option<&[u8]> = None;
+    for &(tag, body) in rest {
+        match tag {
+            TAG_USER_ID => {
+                user_id = Some(body);
+                if let Some(primary) = primary.as_mut().filter(|primary| primary.user_id.is_none()) {
+                    primary.user_id = Some(String::from_utf8_lossy(body).into_owned());
+                }
+            }
+            TAG_PUBLIC_SUBKEY => {
+                user_id = None;
+                subkeys.push((parse_key(body)?, body, 0));
+            }
+            TAG_SIGNATURE => {
+                // Only the signatures of the primary key itself say anything about its keys
+                let (Some(primary), Some(signature)) = (primary.as_mut(), parse_signature(body)?) else {
+                    continue;
+                };
+                if !signature.is_issued_by(primary) {
+                    continue;
+                }
+                let primary_framed = framed_key(primary_body);
+                match (signature.signature_type, subkeys.last_mut(), user_id) {
+                    (KEY_REVOCATION, _, _) => primary.revoked |= is_made_by(primary, &signature, &[&primary_framed]),
+                    (DIRECT_KEY, None, None) if signature.created >= newest && is_made_by(primary, &signature, &[&primary_framed]) => {
+                        newest = signature.created;
+                        primary.expires = expiry(primary_body, &signature);
+                    }
+                    (kind, None, Some(user_id)) if CERTIFICATIONS.contains(&kind) && signature.created >= newest && is_made_by(primary, &signature, &[&primary_framed, &framed_user_id(user_id)]) => {
+                        newest = signature.created;
+                        primary.expires = expiry(primary_body, &signature);
+                    }
+                    (SUBKEY_REVOCATION, Some((Some(subkey), subkey_body, _)), _) => {
+                        subkey.revoked |= is_made_by(primary, &signature, &[&primary_framed, &framed_key(subkey_body)]);
+                    }
+                    (SUBKEY_BINDING, Some((Some(subkey), subkey_body, bound_at)), _) if signature.created >= *bound_at => {
+                        let signed = [&primary_framed[..], &framed_key(subkey_body)];
+                        if !is_made_by(primary, &signature, &signed) {
+                            continue;
+                        }
+                        // A signing subkey has to sign its binding back, so nobody can claim the subkey of someone else
+                        let signs = signature.key_flags.is_none_or(|flags| flags & SIGNING_KEY != 0);
+                        let signed_back = signature.embedded.iter().filter_map(|embedded| parse_signature(embedded).ok().flatten()).any(|back| back.signature_type == PRIMARY_KEY_BINDING && is_made_by(subkey, &back, &signed));
+                        *bound_at = signature.created;
+                        subkey.bound = signs && signed_back;
+                        subkey.expires = expiry(subkey_body, &signature);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut keys: Vec<PublicKey> = subkeys.into_iter().filter_map(|(subkey, _, _)| subkey).collect();
+    // Subkeys share the user ID of their primary key, and go with it when it is revoked or expires
+    if let Some(primary) = &primary {
+        for subkey in &mut keys {
+            subkey.user_id = primary.user_id.clone();
+            subkey.revoked |= primary.revoked;
+            subkey.expires = match (subkey.expires, primary.expires) {
+                (Some(expires), Some(primary_expires)) => Some(expires.min(primary_expires)),
+                (expires, primary_expires) => expires.or(primary_expires),
+            };
+        }
+    }
+    keys.splice(0..0, primary);
+    Ok(keys)
+}
+
+// The public key of a key packet, `None` for other versions and algorithms, trusted as a primary key
+fn parse_key(body: &[u8]) -> Result<Option<PublicKey>, String> {
+    let key = parse_key_material(body)?.map(|material| PublicKey { fingerprint: fingerprint(body), material, user_id: None, bound: false, revoked: false, expires: None });
+    Ok(key)
+}
+
+// When the key of the packet `body` expires according to its self-signature `signature`
+fn expiry(body: &[u8], signature: &Signature) -> Option<u64> {
+    let created = body.get(1..5)?.try_into().map(u32::from_be_bytes).ok()?;
+    signature.key_expiry.filter(|expiry| *expiry > 0).map(|expiry| created as u64 + expiry as u64)
+}
+
+// Whether `signature` over the concatenation of `signed` checks out with `key`
+fn is_made_by(key: &PublicKey, signature: &Signature, signed: &[&[u8]]) -> bool {
+    let digest = match signature.hash {
+        Some(Algorithm::Sha256) => hash_parts::<Sha256>(signed, &signature.trailer()),
+        Some(Algorithm::Sha512) => hash_parts::<Sha512>(signed, &signature.trailer()),
+        _ => return false,
+    };
+    key.verify(signature, &digest).is_ok()
+}
+
+fn hash_parts<D: Digest>(parts: &[&[u8]], trailer: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.update(trailer);
+    hasher.finalize().to_vec()
+}
+
+// A key packet as signatures over it hash it
+fn framed_key(body: &[u8]) -> Vec<u8> {
+    let mut framed = vec![0x99];
+    framed.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    framed.extend_from_slice(body);
+    framed
+}
+
+// A user ID packet as certifications of it hash it
+fn framed_user_id(user_id: &[u8]) -> Vec<u8> {
+    let mut framed = vec![0xB4];
+    framed.extend_from_slice(&(user_id.len() as u32).to_be_bytes());
+    framed.extend_from_slice(user_id);
+    framed
+}
+
+// The key of a version 4 public key packet, `None` for other versions and algorithms
+fn parse_key_material(body: &[u8]) -> Result<Option<KeyMaterial>, String> {
+    let mut reader = Reader(body);
+    if reader.byte()? != 4 {
+        return Ok(None);
+    }
+    reader.take(4)?;
+    let material = match reader.byte()? {
+        RSA | RSA_SIGN_ONLY => {
+            let (n, e) = (reader.mpi()?, reader.mpi()?);
+            let key = RsaPublicKey::new_with_max_size(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e), MAX_RSA_BITS);
+            KeyMaterial::Rsa(key.map_err(|e| format!("invalid RSA key: {}", e))?)
+        }
+        EDDSA_LEGACY => {
+            let oid_length = reader.byte()? as usize;
+            if reader.take(oid_length)? != ED25519_OID {
+                return Ok(None);
+            }
+            // The point is prefixed by 0x40 for its native encoding
+            match reader.mpi()? {
+                [0x40, point @ ..] => ed25519_key(point)?,
+                _ => return Err("invalid Ed25519 key".to_string()),
+            }
+        }
+        ED25519 => ed25519_key(reader.take(32)?)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(material))
+}
+
+fn ed25519_key(point: &[u8]) -> Result<KeyMaterial, String> {
+    let point: [u8; 32] = point.try_into().map_err(|_| "invalid Ed25519 key")?;
+    VerifyingKey::from_bytes(&point).map(KeyMaterial::Ed25519).map_err(|e| format!("invalid Ed25519 key: {}", e))
+}
+
+// The version 4 fingerprint of a public key packet
+fn fingerprint(body: &[u8]) -> [u8; 20] {
+    Sha1::digest(framed_key(body)).into()
+}
+
+// Hash the file at `path` as text, with every line ending normalized to CR LF, followed by `trailer`
+fn hash_text(path: &Path, algorithm: Algorithm, trailer: &[u8]) -> io::Result<Vec<u8>> {
+    match algorithm {
+        Algorithm::Sha512 => hash_text_with::<Sha512>(path, trailer),
+        _ => hash_text_with::<Sha256>(path, trailer),
+    }
+}
+
+fn hash_text_with<D: Digest>(path: &Path, trailer: &[u8]) -> io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 64 * 1024];
+    // A CR at the end of one block may be followed by the LF starting the next one
+    let mut after_cr = false;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        let mut start = 0;
+        for (index, &byte) in buffer[..read].iter().enumerate() {
+            match byte {
+                b'\n' if !after_cr => {
+                    hasher.update(&buffer[start..index]);
+                    hasher.update(b"\r\n");
+                    start = index + 1;
+                }
+                _ => {}
+            }
+            after_cr = byte == b'\r';
+        }
+        hasher.update(&buffer[start..read]);
+    }
+    hasher.update(trailer);
+    Ok(hasher.finalize().to_vec())
+}
+
+// Left-pad a big-endian number with zeros to `length` bytes
+fn left_pad(value: &[u8], length: usize) -> Result<Vec<u8>, String> {
+    if value.len() > length {
+        return Err("the signature value is too long".to_string());
+    }
+    let mut padded = vec![0; length - value.len()];
+    padded.extend_from_slice(value);
+    Ok(padded)
+}
+
+// The binary form of OpenPGP data, decoding the ASCII armor if it has one
+// Several armored blocks, like keys exported one by one, are joined together
+fn dearmor(data: &[u8]) -> Result<Vec<u8>, String> {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) if text.contains("-----BEGIN PGP ") => text,
+        _ => return Ok(data.to_vec()),
+    };
+    let mut binary = Vec::new();
+    let mut block: Option<String> = None;
+    let mut in_headers = false;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with("-----BEGIN PGP ") {
+            block = Some(String::new());
+            in_headers = true;
+        } else if line.starts_with("-----END PGP ") {
+            let encoded = block.take().unwrap_or_default();
+            binary.extend(BASE64.decode(encoded).map_err(|e| format!("invalid ASCII armor: {}", e))?);
+        } else if let Some(encoded) = block.as_mut() {
+            // Armor headers like `Version:` end with an empty line, and the CRC24 line starts with '='
+            if in_headers && (line.is_empty() || line.contains(':')) {
+                in_headers = !line.is_empty();
+            } else if !line.starts_with('=') {
+                in_headers = false;
+                encoded.push_str(line);
+            }
+        }
+    }
+    Ok(binary)
+}
+
+// Split OpenPGP data into its packets, as tag and body
+fn packets(data: &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    let mut reader = Reader(data);
+    let mut packets = Vec::new();
+    while !reader.0.is_empty() {
+        let header = reader.byte()?;
+        if header & 0x80 == 0 {
+            return Err("not OpenPGP data".to_string());
+        }
+        let (tag, length) = if header & 0x40 != 0 {
+            // New format: the length is encoded like a subpacket length, partial lengths are not used by keys and signatures
+            let first = reader.byte()?;
+            if (224..255).contains(&first) {
+                return Err("partial packet lengths are not supported".to_string());
+            }
+            (header & 0x3F, reader.length(first)?)
+        } else {
+            // Old format: the length type is in the header, 3 means up to the end
+            let length = match header & 0x03 {
+                0 => reader.byte()? as usize,
+                1 => reader.u16()?,
+                2 => reader.u32()?,
+                _ => reader.0.len(),
+            };
+            ((header >> 2) & 0x0F, length)
+        };
+        packets.push((tag, reader.take(length)?));
+    }
+    Ok(packets)
+}
+
+// Split a subpacket area into its subpackets, as type without the critical bit and value
+fn subpackets(area: &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    let mut reader = Reader(area);
+    let mut subpackets = Vec::new();
+    while !reader.0.is_empty() {
+        let first = reader.byte()?;
+        let length = reader.length(first)?;
+        let subpacket = reader.take(length)?;
+        if let Some((kind, value)) = subpacket.split_first() {
+            subpackets.push((kind & 0x7F, value));
+        }
+    }
+    Ok(subpackets)
+}
+
+// Reads the fields of a packet front to back
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if length > self.0.len() {
+            return Err("truncated OpenPGP data".to_string());
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, String> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap_or_default()) as usize)
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap_or_default()) as usize)
+    }
+
+    // A length in one, two or five bytes, the first of which is already read
+    fn length(&mut self, first: u8) -> Result<usize, String> {
+        match first {
+            0..192 => Ok(first as usize),
+            192..255 => Ok(((first as usize - 192) << 8) + self.byte()? as usize + 192),
+            255 => self.u32(),
+        }
+    }
+
+    // A multiprecision integer: its length in bits, then its big-endian bytes
+    fn mpi(&mut self) -> Result<&'a [u8], String> {
+        let bits = self.u16()?;
+        self.take(bits.div_ceil(8))
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::DigestTracker;
+    use crate::test_server;
+
+    // Keys and detached signatures of "hello\n" made with GnuPG
+    const ED25519_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatMPWRYJKwYBBAHaRw8BAQdAo24cYtzK5TTGh0EmD1rupGAdGeqnqBG0Kpjq
+PWr6Uaq0LFJlbGVhc2UgU2lnbmluZyAodGVzdCkgPHJlbGVhc2VAZXhhbXBsZS5v
+cmc+iJAEExYIADgWIQSBcJIMleXFvzwrxfHqWNz996rjZAUCatMPWQIbAwULCQgH
+AgYVCgkICwIEFgIDAQIeAQIXgAAKCRDqWNz996rjZDE0AP9fcqf/t4VHyktbhFRJ
+BvraofHC6OVhj5DJ1z/IuUhS+gEAsn438fz4PEjABp9/PspSNtoPsHHI9smGfnJO
+6sBq8gE=
+=RlmW
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const RSA_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mQENBGrTD1kBCAC5JkQP1CmdcIsJ2dKfSU2ULLKBxyjAtD+lp368CJl/hcm/aPmV
+ruqs8/A8HYaS8+tob44Z3pOeEkaayUFhuAFKj0QXdhs22WOFgU8j+yz2TZmgdK7r
+Xs0J/pJUTp+DGfRNMC1ZlmIcnfBZGxYoAv+9WkviVLlBjpNRs/Zl8PkfAEZnsjgW
+jxZdw26zcOVP5GSUyD5XkJd0FrfgMjyJPcRpe0Kd1WeT22zCCJgvXyWj7SisrJrO
+QPj4J/no0OdrRC2zXLhxabk1iDp6BfqaGPU8YTX6g8RKw6tWaSMYgqc7eRy7WF4q
+FKGP8lGc/Z/IK9pZAFbe83Y6r9Hs2+YBA4VzABEBAAG0JFJTQSBTaWduaW5nICh0
+ZXN0KSA8cnNhQGV4YW1wbGUub3JnPokBTgQTAQoAOBYhBDkfvODqkjOScy0OLx3c
+y0l3p84fBQJq0w9ZAhsDBQsJCAcCBhUKCQgLAgQWAgMBAh4BAheAAAoJEB3cy0l3
+p84flnoIAKlzsJayer6iCyEa89fZFpD7me9qn0bRNSkGrlujqOXfiuBFtt2l+Rl4
+rPZs4cNhy7asbZSIzF5tN2u+cO20/55VmMuykHtL192mBWEZM0tMS5380LFCKgxR
+IEinuyDLm5rQdc72ipxOF+LdYIiGvvrNWeJ1Ug5NDYAd7aLyMbhuyhPKBCuIqyd+
+OJa+xqWGTyCO9SqnPAs+pZ+GDRnHF27yuHsNugjSM59qCVtsW7VOVOA+hwPVloN2
+xhxROMNEscBcAG01789XVR5pERaMg6ziOdn6wLLxJY7aTqum61TI2snV7TbJ9Knt
+7lO3qLSpw5boQLCkL32DPF0sa/UKZqM=
+=7Od5
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const ED25519_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iIoEABYIADIWIQSBcJIMleXFvzwrxfHqWNz996rjZAUCatMPWRQccmVsZWFzZUBl
+eGFtcGxlLm9yZwAKCRDqWNz996rjZAzDAP9U87HzDOBD6dV5RjKc/jJAGKLVEkwU
+K1VvjLSNd/wIlQEA49T7WidNQYDu/WXLE5sPgZCUExM5rZkeZhuj1cxSOgU=
+=8map
+-----END PGP SIGNATURE-----
+";
+    const ED25519_TEXT_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iIoEARYIADIWIQSBcJIMleXFvzwrxfHqWNz996rjZAUCatMPjhQccmVsZWFzZUBl
+eGFtcGxlLm9yZwAKCRDqWNz996rjZIkyAQDpe635k6mU1LnmvxmRIjUPUPRLnar4
+2gK90Exat8FEVAD+LdP3HpNybOipvIwt97g1wO0whZGj7ChXKCS5EfSMEws=
+=c0jz
+-----END PGP SIGNATURE-----
+";
+    // Binary, as made by `gpg --detach-sign`
+    const RSA_SIGNATURE: &str = "iQFEBAABCgAuFiEEOR+84OqSM5JzLQ4vHdzLSXenzh8FAmrTD1kQHHJzYUBleGFtcGxlLm9yZwAKCRAd3MtJd6fOH/cqCACDtO/wGHYtq4BPBELIpeWMhnXI1UqONCiARSRKrpo5RJfpxEp24IBw2CGTZ/uHVo8TpUkHGgy1nC0PqJYChtr6nMc08X5oMWlnPoCHJtRAghPByzd3qe3sqV2KrJJkWzugBSj9xLQ78LhnMoKlmdvnQ9x4rpF0URGVhfPacCmXVCPy+RARsHjXxxKcGvM3kp0NMc8duP5+bFPWR4bjOKi9AmrPXQIbF/+SMlrZQ5yHimfZrR0RAwvrd7IPoq7kHPIQj9CV/a+RVGrWY+rn2RHtQZgoznOaXsua5nkEflabd+T1spOlYs3lcvMulyX35hOUcfB/ZMPMZFAeP/YxyBJ0";
+
+    // A primary key only certifying, with a subkey signing "hello\n"
+    const SUBKEY_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatNH3xYJKwYBBAHaRw8BAQdAVtWBBdmf9NTNWRmks0wKqGmPVae5nUTW1N4Q
+HKylhsG0KlN1YmtleSBTaWduaW5nICh0ZXN0KSA8c3Via2V5QGV4YW1wbGUub3Jn
+PoiQBBMWCAA4FiEEu/i0+iFiCENug8suLcmh8XgKLWAFAmrTR98CGwEFCwkIBwIG
+FQoJCAsCBBYCAwECHgECF4AACgkQLcmh8XgKLWAUvwD/WySFfFLqW+wgF6QYnqwA
+c+G73IG9paLH651dwtsfvWkBAMuUo+CB4/xMfk479qg/o+/2A3SYJ4uyhMryNw+j
+wq0GuDMEatNH3xYJKwYBBAHaRw8BAQdAasJfBGVVEL/lrdYk7oo9u5d5jKBEnAsP
+jaLqHt1Osw2I7wQYFggAIBYhBLv4tPohYghDboPLLi3JofF4Ci1gBQJq00ffAhsC
+AIEJEC3JofF4Ci1gdiAEGRYIAB0WIQSZy1spwJOYM7SNU8B9pD+kU1EXFAUCatNH
+3wAKCRB9pD+kU1EXFPIgAQDBht56Bu3XElOfomMNOcWkcHFppS32MUZh9PFuRZcf
+KgEA4tJG9IJhR5wXh+fRdDS7HuDWdntJefnR75R8IsXJTwA7DAEA05LHiGZwVqng
+EeCoeo8Tc2dr78W8OTwatxjeRrABSD4BAM5NkF5AyEcM9gtkwBWhz5sxrm3OeAv+
+N6SdlOZgufYI
+=hYFC
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const SUBKEY_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iIkEABYIADEWIQSZy1spwJOYM7SNU8B9pD+kU1EXFAUCatNH3xMcc3Via2V5QGV4
+YW1wbGUub3JnAAoJEH2kP6RTURcU+zQBAJMps3vxES/mQ6vo+2ChJXn4aAm6+r2M
+3NETKdNoBjMvAQDlbSuQFQaaA87NFNVSUU8YCx6KW0td71v3Q3m9TRJBBQ==
+=klAs
+-----END PGP SIGNATURE-----
+";
+    // A key revoked after signing, and a key whose signing subkey is revoked
+    const REVOKED_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatNH3xYJKwYBBAHaRw8BAQdA/kWqinoEI9FQnLZDXvm3EpuLuaLjwNN4nzjm
+w4rkBSKIeAQgFggAIBYhBIrcKQey1aTJbCBt+q8xIwxPmKkgBQJq00ffAh0AAAoJ
+EK8xIwxPmKkgx4YA/3oNEmTSNetys+ADqMULHmgoAryzZ5VCp/tmGQhxm00XAQD2
+2OLIU6lwz1iyUh9vtfl1qhyREZ8vFiD0kQYWPb1TDrQsUmV2b2tlZCBTaWduaW5n
+ICh0ZXN0KSA8cmV2b2tlZEBleGFtcGxlLm9yZz6IkAQTFggAOBYhBIrcKQey1aTJ
+bCBt+q8xIwxPmKkgBQJq00ffAhsDBQsJCAcCBhUKCQgLAgQWAgMBAh4BAheAAAoJ
+EK8xIwxPmKkgXSwBAJxFCAHaGQ21IK9jnywFtsLkTgk7eVXmhWF2gVIowr7EAQD1
+n7ep2MevwxKzLiB5YRyNpv6t/pSWNvRwroD3INqYBw==
+=wOaO
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const REVOKED_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iIoEABYIADIWIQSK3CkHstWkyWwgbfqvMSMMT5ipIAUCatNH3xQccmV2b2tlZEBl
+eGFtcGxlLm9yZwAKCRCvMSMMT5ipILRGAQDANVSf6mh8I4SzZBCGBVnD+T/9eelG
+f8jgHDZy8ci1VAD/dSmj032YYgFanr//Dn51aHrpD9Y2Fe/gQpYnqecyJAU=
+=heYH
+-----END PGP SIGNATURE-----
+";
+    const REVOKED_SUBKEY_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatNH4xYJKwYBBAHaRw8BAQdAmolNZ6cDUrh9TE54FFRxFtLunvvJmFT88TC+
+Pfu8yGm0KlJldm9rZWQgU3Via2V5ICh0ZXN0KSA8cmV2c3ViQGV4YW1wbGUub3Jn
+PoiQBBMWCAA4FiEEVccGgXoYNLHCuyxM6I1m95NA0IAFAmrTR+MCGwEFCwkIBwIG
+FQoJCAsCBBYCAwECHgECF4AACgkQ6I1m95NA0IAlyQD/ar2sbMusBXq5ZYoph39Q
+d2/JFQKSd/inQyBoLKIS5T8A/3M+7J+H1LfZZMBSJBhSbE7c6FOapR+BV28y0/jm
+/9YKuDMEatNH4xYJKwYBBAHaRw8BAQdAR97yWEfsvF8hzkHebJx2Fn08u60umkay
+ufOPfErksPCIeAQoFggAIBYhBFXHBoF6GDSxwrssTOiNZveTQNCABQJq00fjAh0A
+AAoJEOiNZveTQNCATDcBAOgPGuuvPbuNR5gmd2GJ2qo3LWvN4ji3yxScu/1xe+vM
+AQCc0QqzheKvYUKmoeJHEnk2oe3OwFgnerKcCEJCDsrxBojvBBgWCAAgFiEEVccG
+gXoYNLHCuyxM6I1m95NA0IAFAmrTR+MCGwIAgQkQ6I1m95NA0IB2IAQZFggAHRYh
+BLmN3b3tKKQY4Aq2fkr21Cnb09sDBQJq00fjAAoJEEr21Cnb09sDeCYBAIgYuOGV
+jazEpRmkqsXHhbhRrAOo0WByTP12sxWUrqfiAQDyGNcw3kyNJQ8m5Vmx9KPeC7EL
+fkJcwvPRibmrWqqKA/xkAQCTMsHmOFjW7j+ay0FlFMnTfrme2KLoRt978C2X8BXh
+/QD/fCdRv9JEg4eoVc1QE8z+G8fJDBW0i91OlDhbP6MtRQU=
+=KA+n
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const REVOKED_SUBKEY_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iIkEABYIADEWIQS5jd297SikGOAKtn5K9tQp29PbAwUCatNH4xMccmV2c3ViQGV4
+YW1wbGUub3JnAAoJEEr21Cnb09sDt8YBAL0q2G3+DsyrAtMRuRkfcYQ0ImL/LcpK
+aM2OHNOZ7BvtAP47Hts0eu7LYb0O5VcBunon1bw9+KVG0dx7dYX50XLFAA==
+=2TkY
+-----END PGP SIGNATURE-----
+";
+    // A key that expired a day after it was made and signed, in 2020
+    const EXPIRED_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEXgvhABYJKwYBBAHaRw8BAQdAT+/AXR/UzT09f8yupTUiDC3mEyuxy0jkLTrA
+L5Om3Oq0LEV4cGlyZWQgU2lnbmluZyAodGVzdCkgPGV4cGlyZWRAZXhhbXBsZS5v
+cmc+iJYEExYIAD4WIQQ+QQhoKyrWdZrnItvGGUaaFm6bmQUCXgvhAAIbAwUJAAFR
+gAULCQgHAgYVCgkICwIEFgIDAQIeAQIXgAAKCRDGGUaaFm6bmQ28AQDBOMd7FvgY
+/YfZdsz+Dh8ZQOKvwbfV/bhc6T/CCzPqrAEAt0NdkWC4GpsxLoKV2bwEOjen2a4i
+257nNdhuvAPGvws=
+=KnHH
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const EXPIRED_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iIoEABYIADIWIQQ+QQhoKyrWdZrnItvGGUaaFm6bmQUCXgvhPBQcZXhwaXJlZEBl
+eGFtcGxlLm9yZwAKCRDGGUaaFm6bmfJtAQCGJaZvfVPC4jIHb8Ad4AsFhTJ+Hz5B
+UOkhcnPWzPDA0AD7Btni33dL5eszbcCdYht5HA/T3/VRpcczbiPaRnlpDQs=
+=LUpc
+-----END PGP SIGNATURE-----
+";
+
+    // Check `signature` of the file holding `content` against the keys of `keyring`
+    fn check(signature: &[u8], keyring: &str, content: &[u8]) -> Result<String, AppError> {
+        let path = test_server::temp_dir("openpgp").join("signed");
+        std::fs::write(&path, content).unwrap();
+        let check = SignatureCheck { signatures: parse_signatures(signature).unwrap(), keyring: parse_keyring(keyring.as_bytes()).unwrap(), source: "test".to_string() };
+        let digests = DigestTracker::new(check.algorithms()).finish(&path, content.len() as u64).unwrap();
+        check.verify(&path, &digests)
+    }
+
+    #[test]
+    fn test_parse_keyring() {
+        let keys = parse_keyring(format!("{}{}", ED25519_KEY, RSA_KEY).as_bytes()).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].owner(), "Release Signing (test) <release@example.org> (key 8170920C95E5C5BF3C2BC5F1EA58DCFDF7AAE364)");
+        assert!(matches!(keys[1].material, KeyMaterial::Rsa(_)));
+        assert!(parse_keyring(b"not a keyring").is_err());
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let rsa_signature = BASE64.decode(RSA_SIGNATURE).unwrap();
+        assert!(check(ED25519_SIGNATURE.as_bytes(), ED25519_KEY, b"hello\n").unwrap().starts_with("Release Signing"));
+        assert!(check(&rsa_signature, RSA_KEY, b"hello\n").unwrap().starts_with("RSA Signing"));
+        // A text signature holds for the file with either line ending
+        assert!(check(ED25519_TEXT_SIGNATURE.as_bytes(), ED25519_KEY, b"hello\r\n").is_ok());
+        assert!(check(ED25519_TEXT_SIGNATURE.as_bytes(), ED25519_KEY, b"hello\n").is_ok());
+
+        let bad = check(ED25519_SIGNATURE.as_bytes(), ED25519_KEY, b"hellO\n").unwrap_err();
+        assert!(bad.to_string().contains("bad signature from Release Signing"), "{}", bad);
+        assert!(check(&rsa_signature, RSA_KEY, b"hello").is_err());
+        let unknown = check(ED25519_SIGNATURE.as_bytes(), RSA_KEY, b"hello\n").unwrap_err();
+        assert!(unknown.to_string().contains("not made by any key in the keyring"), "{}", unknown);
+    }
+
+    // Encode `packets` back into a keyring
+    fn repacked(packets: &[(u8, &[u8])]) -> String {
+        let mut data = Vec::new();
+        for (tag, body) in packets {
+            data.push(0xC0 | tag);
+            match body.len() {
+                length @ 0..192 => data.push(length as u8),
+                length => data.extend_from_slice(&[((length - 192) >> 8) as u8 + 192, (length - 192) as u8]),
+            }
+            data.extend_from_slice(body);
+        }
+        format!("-----BEGIN PGP PUBLIC KEY BLOCK-----\n\n{}\n-----END PGP PUBLIC KEY BLOCK-----\n", BASE64.encode(data))
+    }
+
+    #[test]
+    fn test_subkey_binding() {
+        assert!(check(SUBKEY_SIGNATURE.as_bytes(), SUBKEY_KEY, b"hello\n").unwrap().starts_with("Subkey Signing"));
+
+        let data = dearmor(SUBKEY_KEY.as_bytes()).unwrap();
+        let certificate = packets(&data).unwrap();
+        let subkey = certificate.iter().position(|(tag, _)| *tag == TAG_PUBLIC_SUBKEY).unwrap();
+        // Without its binding signature, anyone could append their subkey to the key
+        let unbound = check(SUBKEY_SIGNATURE.as_bytes(), &repacked(&certificate[..=subkey]), b"hello\n").unwrap_err();
+        assert!(unbound.to_string().contains("not bound to its primary key"), "{}", unbound);
+        // Nor does the binding hold for another primary key
+        let other = dearmor(ED25519_KEY.as_bytes()).unwrap();
+        let claimed = [packets(&other).unwrap(), certificate[subkey..].to_vec()].concat();
+        assert!(check(SUBKEY_SIGNATURE.as_bytes(), &repacked(&claimed), b"hello\n").is_err());
+    }
+
+    #[test]
+    fn test_revoked_keys() {
+        let revoked = check(REVOKED_SIGNATURE.as_bytes(), REVOKED_KEY, b"hello\n").unwrap_err();
+        assert!(revoked.to_string().contains("Revoked Signing (test) <revoked@example.org> (key 8ADC2907B2D5A4C96C206DFAAF31230C4F98A920) cannot be used: it is revoked"), "{}", revoked);
+        let revoked = check(REVOKED_SUBKEY_SIGNATURE.as_bytes(), REVOKED_SUBKEY_KEY, b"hello\n").unwrap_err();
+        assert!(revoked.to_string().contains("it is revoked"), "{}", revoked);
+    }
+
+    #[test]
+    fn test_expired_keys() {
+        let expired = check(EXPIRED_SIGNATURE.as_bytes(), EXPIRED_KEY, b"hello\n").unwrap_err();
+        assert!(expired.to_string().contains("cannot be used: it expired"), "{}", expired);
+        let keys = parse_keyring(EXPIRED_KEY.as_bytes()).unwrap();
+        // Made on 2020-01-01 and expiring a day later
+        assert_eq!(keys[0].expires, Some(1_577_836_800 + 86_400));
+        assert_eq!(parse_keyring(ED25519_KEY.as_bytes()).unwrap()[0].expires, None);
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt::Write as _;
+use std::path::Path;
+use url::Url;
+use crate::downloader::RemoteFile;
+
+/// Describes the download --dry-run stands for: where the file of `url` goes and how it is split.
+///
+/// `remote` is the probed file, or None for a request that is not probed. The first `connections` of
+/// the `ranges` start one per connection, on the `sources` in turn, and the others are queued for the
+/// first connection to finish.
+pub fn describe(url: &Url, output_path: &Path, remote: Option<&RemoteFile>, connections: usize, ranges: &[(u64, u64)], sources: &[Url]) -> String {
+    let mut output = String::new();
+    let _ = writeln!(output, "URL:            {}", url);
+    if let Some(remote) = remote.filter(|remote| remote.url != *url) {
+        let _ = writeln!(output, "Redirected to:  {}", remote.url);
+    }
+    let _ = writeln!(output, "Output:         {}", output_path.display());
+    let size = remote.and_then(|remote| remote.size);
+    let _ = writeln!(output, "Size:           {}", size.map_or_else(|| "unknown".to_string(), |size| format!("{} bytes", size)));
+    let mirrors: Vec<String> = sources.iter().skip(1).map(Url::to_string).collect();
+    let _ = writeln!(output, "Mirrors:        {}", if mirrors.is_empty() { "none".to_string() } else { mirrors.join(", ") });
+    let single_stream = match remote {
+        None => Some("the request is not a plain GET"),
+        Some(remote) if remote.size == Some(0) => Some("empty file"),
+        Some(remote) if remote.size.is_none() => Some("the server did not report a content length"),
+        Some(remote) if !remote.accepts_ranges => Some("the server does not support byte ranges"),
+        Some(_) => None,
+    };
+    if let Some(reason) = single_stream {
+        let _ = writeln!(output, "Plan:           single stream ({})", reason);
+        return output;
+    }
+    let connections = connections.clamp(1, ranges.len().max(1));
+    let _ = writeln!(output, "Plan:           {} connection(s), {} range(s)", connections, ranges.len());
+    for (index, (start, end)) in ranges.iter().enumerate() {
+        let _ = match index < connections {
+            true => writeln!(output, "  connection {}: bytes {}-{} from {}", index + 1, start, end, sources[index % sources.len()]),
+            false => writeln!(output, "  queued:       bytes {}-{}", start, end),
+        };
+    }
+    output
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderMap;
+
+    #[test]
+    fn test_describe() {
+        let url = Url::parse("http://a/file.bin").unwrap();
+        let mirror = Url::parse("http://b/file.bin").unwrap();
+        let remote = RemoteFile { url: url.clone(), size: Some(1000), accepts_ranges: true, headers: HeaderMap::new(), address: None, version: None };
+        let plan = describe(&url, Path::new("file.bin"), Some(&remote), 2, &[(0, 332), (333, 665), (666, 999)], &[url.clone(), mirror]);
+        assert_eq!(
+            plan,
+            "URL:            http://a/file.bin\n\
+             Output:         file.bin\n\
+             Size:           1000 bytes\n\
+             Mirrors:        http://b/file.bin\n\
+             Plan:           2 connection(s), 3 range(s)\n  \
+             connection 1: bytes 0-332 from http://a/file.bin\n  \
+             connection 2: bytes 333-665 from http://b/file.bin\n  \
+             queued:       bytes 666-999\n"
+        );
+
+        let unranged = RemoteFile { accepts_ranges: false, ..remote };
+        let plan = describe(&url, Path::new("file.bin"), Some(&unranged), 4, &[], std::slice::from_ref(&url));
+        assert!(plan.ends_with("Mirrors:        none\nPlan:           single stream (the server does not support byte ranges)\n"));
+        assert!(describe(&url, Path::new("file.bin"), None, 4, &[], std::slice::from_ref(&url)).contains("Size:           unknown\n"));
+    }
+}
@@ -1,11 +1,252 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde_json::{json, Value};
+use unicode_width::UnicodeWidthChar;
+
+// Terminal columns reserved for the file name in front of every bar
+const LABEL_WIDTH: usize = 24;
+
+// Time between the status lines printed instead of bars
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Time between the objects of --progress json
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+// The bars of every download of the process, so the files of a batch are drawn together
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+// How progress is shown, bars unless set_mode chose otherwise
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+// The look of the bars, classic unless set_theme chose otherwise
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+// Whether --progress compact shows a single bar per download instead of a bar per part
+static COMPACT: AtomicBool = AtomicBool::new(false);
+
+// Where --progress json writes its objects, and whether that is standard output
+static JSON_OUTPUT: OnceLock<(Mutex<Box<dyn Write + Send>>, bool)> = OnceLock::new();
+
+/// What --progress shows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Progress bars, or status lines when not on a terminal
+    Bars,
+    /// A single bar per download, with the total of its parts
+    Compact,
+    /// A JSON object per download every second, for programs wrapping rtget
+    Json,
+}
+
+/// How the progress of downloads is shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Progress bars redrawn in place, on a terminal
+    Bars,
+    /// A plain line per download every few seconds, for logs
+    Lines,
+    /// Nothing but errors, with --quiet
+    Quiet,
+}
+
+/// Which fields the bars show, from --progress-style
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Style {
+    /// The bar, the percentage and the throughput
+    Minimal,
+    /// The elapsed time, the bar, the bytes, the throughput and the ETA
+    #[default]
+    Classic,
+    /// Everything of classic, with the percentage and the precise ETA
+    Detailed,
+}
+
+/// The look of the bars
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    /// The fields shown
+    pub style: Style,
+    /// The characters of the filled part, the tip and the empty part of the bar, like `#>-`
+    pub chars: String,
+    /// Whether the bars are colored
+    pub color: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme { style: Style::Classic, chars: "#>-".to_string(), color: true }
+    }
+}
+
+impl Theme {
+    // The style of a bar tagged `tag`, like `[Part 1]`, its bar in `color`
+    fn bar(&self, tag: &str, color: &str) -> ProgressStyle {
+        let template = match self.style {
+            Style::Minimal => format!("{{prefix}} {} {{bar:30.{}}} {{percent:>3}}% [{{binary_bytes_per_sec}}] {{msg}}", tag, color),
+            Style::Classic => format!("{{prefix}} {} {{spinner.green}} [{{elapsed_precise}}] {{bar:40.{}}} {{bytes}}/{{total_bytes}} [{{binary_bytes_per_sec}}] ({{eta}}) {{msg}}", tag, color),
+            Style::Detailed => format!(
+                "{{prefix}} {} {{spinner.green}} [{{elapsed_precise}}] {{bar:40.{}}} {{percent:>3}}% {{bytes}}/{{total_bytes}} [{{binary_bytes_per_sec}}] (ETA {{eta_precise}}) {{msg}}",
+                tag, color
+            ),
+        };
+        ProgressStyle::default_bar().template(&self.colored(&template)).unwrap().progress_chars(&self.chars)
+    }
+
+    // The style of a spinner, for a download of unknown size
+    fn spinner(&self) -> ProgressStyle {
+        let template = match self.style {
+            Style::Minimal => "{prefix} {spinner.green} {bytes} [{binary_bytes_per_sec}] {msg}",
+            Style::Classic | Style::Detailed => "{prefix} {spinner.green} [{elapsed_precise}] {bytes} [{binary_bytes_per_sec}] {msg}",
+        };
+        ProgressStyle::default_spinner().template(&self.colored(template)).unwrap()
+    }
+
+    // `template` as it is, or without its colors, like {bar:40} for {bar:40.cyan/blue}
+    fn colored(&self, template: &str) -> String {
+        if self.color {
+            return template.to_string();
+        }
+        let mut plain = String::new();
+        let mut in_color = false;
+        for c in template.chars() {
+            match c {
+                '.' if plain.rfind('{') > plain.rfind('}') => in_color = true,
+                '}' => {
+                    in_color = false;
+                    plain.push(c);
+                }
+                _ if !in_color => plain.push(c),
+                _ => {}
+            }
+        }
+        plain
+    }
+}
+
+/// The bars of one download, shared with whoever reports its progress
+pub type Bars = Arc<Mutex<Vec<ProgressBar>>>;
+
+tokio::task_local! {
+    // The bars the download of the current task adds its bars to, when something watches it
+    static WATCHED: Bars;
+}
+
+/// Runs the download `future` with its bars kept in `bars`, for a daemon to report its progress.
+///
+/// A download that starts over, e.g. on a single stream after its ranges failed, replaces the bars.
+pub async fn watched<F: Future>(bars: Bars, future: F) -> F::Output {
+    WATCHED.scope(bars, future).await
+}
+
+/// Stops drawing the bars of the process, which keep counting for [`watched`] downloads.
+pub fn hide() {
+    MULTI_PROGRESS.get_or_init(MultiProgress::new).set_draw_target(ProgressDrawTarget::hidden());
+}
+
+/// Asks `question` on standard error and returns the line answered, with the bars hidden meanwhile.
+///
+/// Downloads of a batch ask one at a time. There is no answer when nobody can give one: standard
+/// input or standard error is not a terminal, or nothing but errors is shown. A closed standard
+/// input answers an empty line.
+pub fn ask(question: &str) -> Option<String> {
+    static ASKING: Mutex<()> = Mutex::new(());
+    if mode() != Mode::Bars || !io::stdin().is_terminal() {
+        return None;
+    }
+    let _asking = ASKING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut answer = String::new();
+    MULTI_PROGRESS.get_or_init(MultiProgress::new).suspend(|| {
+        eprint!("{}", question);
+        tokio::task::block_in_place(|| io::stdin().read_line(&mut answer))
+    }).ok()?;
+    Some(answer)
+}
+
+/// Chooses how the progress of the downloads of the process is shown: bars on a terminal, status lines
+/// when standard error is not one, like in CI logs or with -b, and nothing with `quiet`.
+pub fn set_mode(quiet: bool) {
+    let mode = match quiet {
+        true => Mode::Quiet,
+        false if std::io::stderr().is_terminal() => Mode::Bars,
+        false => Mode::Lines,
+    };
+    if MODE.get_or_init(|| mode) != &Mode::Bars {
+        hide();
+    }
+}
+
+/// Streams the progress of every download as JSON lines into the file at `path`, or standard output.
+///
+/// On standard output the bars are not drawn and messages go to standard error instead, so every
+/// line of standard output is an object.
+pub fn stream_json(path: Option<&Path>) -> io::Result<()> {
+    let output: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => {
+            hide();
+            Box::new(io::stdout())
+        }
+    };
+    let _ = JSON_OUTPUT.set((Mutex::new(output), path.is_none()));
+    Ok(())
+}
+
+/// Draws the bars with `theme` from now on.
+pub fn set_theme(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+fn theme() -> &'static Theme {
+    THEME.get_or_init(Theme::default)
+}
+
+/// Draws a single bar per download from now on, with the total of its parts.
+pub fn compact() {
+    COMPACT.store(true, Ordering::Relaxed);
+}
+
+/// Prints an informational message about a download, unless --quiet.
+pub fn message(text: &str) {
+    match (mode(), JSON_OUTPUT.get()) {
+        (Mode::Quiet, _) => {}
+        (_, Some((_, true))) => eprintln!("{}", text),
+        _ => println!("{}", text),
+    }
+}
+
+fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Bars)
+}
+
+/// Returns the bytes downloaded, the total size if every bar knows its own, and the bytes per second of `bars`.
+pub fn totals(bars: &Bars) -> (u64, Option<u64>, u64) {
+    let bars = bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let completed = bars.iter().map(ProgressBar::position).sum();
+    let total = bars.iter().map(ProgressBar::length).sum::<Option<u64>>().filter(|_| !bars.is_empty());
+    let speed = bars.iter().filter(|bar| !bar.is_finished()).map(|bar| bar.per_sec() as u64).sum();
+    (completed, total, speed)
+}
 
 /// Manages multiple progress bars for concurrent tasks.
+///
+/// Clones share the bars, so bars can be added from wherever a download starts another connection.
+#[derive(Clone)]
 pub struct ProgressManager {
     // Manages a collection of progress bars.
     multi_progress: MultiProgress,
     // Stores individual progress bars
-    bars: Vec<ProgressBar>,
+    bars: Arc<Mutex<Vec<ProgressBar>>>,
+    // File name shown in front of every bar, already fitted to LABEL_WIDTH columns
+    label: String,
+    // File name in status lines and JSON objects
+    name: String,
+    // Bar of the whole download above those of its parts, once it has several
+    total: Arc<OnceLock<ProgressBar>>,
 }
 
 // Implement ProgressManager
@@ -13,37 +254,94 @@ pub struct ProgressManager {
 impl ProgressManager {
     /// Creates a new `ProgressManager`.
     ///
+    /// `label` is the file name shown in front of every bar.
     /// Returns an instance of `ProgressManager` with no progress bars initially.
-    pub fn new() -> ProgressManager {
-        ProgressManager {
-            multi_progress: MultiProgress::new(),
-            bars: Vec::new()
-        }
+    pub fn new(label: &str) -> ProgressManager {
+        let bars = WATCHED.try_with(|bars| {
+            bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+            bars.clone()
+        });
+        let manager = ProgressManager {
+            multi_progress: MULTI_PROGRESS.get_or_init(MultiProgress::new).clone(),
+            bars: bars.unwrap_or_default(),
+            label: fit_width(label, LABEL_WIDTH),
+            name: label.to_string(),
+            total: Arc::default(),
+        };
+        report(label.to_string(), Arc::downgrade(&manager.bars));
+        manager
     }
 
     /// Creates and adds a new progress bar.
     ///
     /// `total_size` is the total size of the task for the new progress bar.
     /// Returns the index of the newly created progress bar.
+    /// The bar of the whole download is added along with the second one, or the first one with
+    /// --progress compact, which does not draw the bars of the parts.
     pub fn create_progress_bar(&mut self, total_size: u64) -> usize {
-        let bar = self.multi_progress.add(ProgressBar::new(total_size));
-        let index = self.bars.len();
-        bar.set_style(ProgressStyle::default_bar()
-            .template(&format!("[Part {}] {{spinner.green}} [{{elapsed_precise}}] {{bar:40.cyan/blue}} {{bytes}}/{{total_bytes}} [{{binary_bytes_per_sec}}] ({{eta}}) {{msg}}", index + 1))
-            .unwrap()
-            .progress_chars("#>-"));
-        self.bars.push(bar);
-        self.bars.len() - 1 // Return the index of the new bar
+        let mut bars = self.bars();
+        let compact = COMPACT.load(Ordering::Relaxed);
+        let bar = match compact {
+            true => ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden()),
+            false => self.multi_progress.add(ProgressBar::new(total_size)),
+        };
+        let index = bars.len();
+        bar.set_style(theme().bar(&format!("[Part {}]", index + 1), "cyan/blue"));
+        bar.set_prefix(self.label.clone());
+        bars.push(bar);
+        if (bars.len() == 2 || compact) && !self.multi_progress.is_hidden() {
+            let first = bars[0].clone();
+            drop(bars);
+            self.create_total_bar(&first, compact);
+            return index;
+        }
+        bars.len() - 1 // Return the index of the new bar
     }
 
-    /// Updates the progress of a specific progress bar.
-    ///
-    /// `bar_index` specifies which progress bar to update.
-    /// `progress` is the new progress value for the specified bar.
-    pub fn update(&mut self, bar_index: usize, progress: u64) {
-        if let Some(bar) = self.bars.get(bar_index) {
-            bar.set_position(progress);
+    // Add the bar of the whole download above the bar of its first part, kept up to date by a thread
+    fn create_total_bar(&self, first: &ProgressBar, compact: bool) {
+        let total = ProgressBar::new(0);
+        if self.total.set(total.clone()).is_err() {
+            return;
         }
+        let total = match compact {
+            true => self.multi_progress.add(total),
+            false => self.multi_progress.insert_before(first, total),
+        };
+        total.set_style(theme().bar("[Total] ", "green/blue"));
+        total.set_prefix(self.label.clone());
+        let bars = Arc::downgrade(&self.bars);
+        std::thread::spawn(move || {
+            while let Some(bars) = bars.upgrade() {
+                if update_total(&total, &bars) {
+                    return;
+                }
+                drop(bars);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+    }
+
+    /// Creates and adds a spinner for a task of unknown size.
+    ///
+    /// The spinner shows the bytes transferred and the throughput instead of a percentage.
+    /// Returns the index of the newly created spinner.
+    pub fn create_spinner(&mut self) -> usize {
+        let bar = self.multi_progress.add(ProgressBar::new_spinner());
+        bar.set_style(theme().spinner());
+        bar.set_prefix(self.label.clone());
+        bar.enable_steady_tick(std::time::Duration::from_millis(100));
+        let mut bars = self.bars();
+        bars.push(bar);
+        bars.len() - 1
+    }
+
+    /// Returns a handle to a specific progress bar.
+    ///
+    /// `bar_index` specifies which progress bar to return.
+    /// The handle can be moved into a download task to advance the bar as bytes arrive.
+    pub fn bar(&self, bar_index: usize) -> Option<ProgressBar> {
+        self.bars().get(bar_index).cloned()
     }
 
     /// Completes a progress bar and displays a final message.
@@ -51,8 +349,273 @@ impl ProgressManager {
     /// `bar_index` specifies which progress bar to finish.
     /// `msg` is the message to display upon completion.
     pub fn finish_with_message(&mut self, bar_index: usize, msg: &str) {
-        if let Some(bar) = self.bars.get(bar_index) {
+        if let Some(bar) = self.bars().get(bar_index) {
+            bar.finish_with_message(msg.to_string());
+        }
+        self.report_finished();
+    }
+
+    /// Completes every progress bar with the same final message.
+    pub fn finish_all(&mut self, msg: &str) {
+        for bar in self.bars().iter() {
             bar.finish_with_message(msg.to_string());
         }
+        self.report_finished();
+    }
+
+    // Finish the bar of the whole download, and print the last status line and JSON object, once every bar is finished
+    fn report_finished(&self) {
+        let bars = self.bars();
+        if !bars.iter().all(ProgressBar::is_finished) {
+            return;
+        }
+        if let Some(total) = self.total.get() {
+            let (completed, size) = (bars.iter().map(ProgressBar::position).sum(), bars.iter().filter_map(ProgressBar::length).sum());
+            total.set_length(size);
+            total.set_position(completed);
+            total.finish_with_message(bars.first().map(|bar| bar.message()).unwrap_or_default());
+        }
+        let elapsed = bars.iter().map(ProgressBar::elapsed).max();
+        drop(bars);
+        if mode() == Mode::Lines {
+            eprintln!("{}", status_line(&self.name, totals(&self.bars), elapsed));
+        }
+        write_json(&json_status(&self.name, &self.bars));
+    }
+
+    // Lock the bars, ignoring a thread that panicked while holding them
+    fn bars(&self) -> MutexGuard<'_, Vec<ProgressBar>> {
+        self.bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Set `total` to the bytes and size of `bars`, and finish it once they are finished, which it returns
+fn update_total(total: &ProgressBar, bars: &Bars) -> bool {
+    let bars = bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    total.set_length(bars.iter().filter_map(ProgressBar::length).sum());
+    total.set_position(bars.iter().map(ProgressBar::position).sum());
+    let finished = !bars.is_empty() && bars.iter().all(ProgressBar::is_finished);
+    if finished {
+        total.finish_with_message(bars[0].message());
+    }
+    finished || total.is_finished()
+}
+
+// Report the progress of `bars` until they are finished or dropped: without bars a status line
+// every STATUS_INTERVAL, and with --progress json an object every JSON_INTERVAL
+fn report(name: String, bars: Weak<Mutex<Vec<ProgressBar>>>) {
+    let (lines, json) = (mode() == Mode::Lines, JSON_OUTPUT.get().is_some());
+    if !lines && !json {
+        return;
+    }
+    std::thread::spawn(move || {
+        let (mut printed, mut written) = (Instant::now(), Instant::now());
+        loop {
+            std::thread::sleep(Duration::from_millis(250));
+            let Some(bars) = bars.upgrade() else {
+                return;
+            };
+            let finished = {
+                let bars = bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                !bars.is_empty() && bars.iter().all(ProgressBar::is_finished)
+            };
+            if finished {
+                return;
+            }
+            if lines && printed.elapsed() >= STATUS_INTERVAL {
+                eprintln!("{}", status_line(&name, totals(&bars), None));
+                printed = Instant::now();
+            }
+            if json && written.elapsed() >= JSON_INTERVAL {
+                write_json(&json_status(&name, &bars));
+                written = Instant::now();
+            }
+        }
+    });
+}
+
+// A line like `file.iso: 120.00 MiB of 1.00 GiB (11%), 5.00 MiB/s, 3 minutes left`, or once the
+// download took `finished`, like `file.iso: 1.00 GiB done in 3 minutes`
+fn status_line(name: &str, (completed, total, speed): (u64, Option<u64>, u64), finished: Option<Duration>) -> String {
+    if let Some(elapsed) = finished {
+        return format!("{}: {} done in {}", name, HumanBytes(completed), HumanDuration(elapsed));
     }
-}
\ No newline at end of file
+    let mut line = format!("{}: {}", name, HumanBytes(completed));
+    if let Some(total) = total {
+        line.push_str(&format!(" of {} ({}%)", HumanBytes(total), completed * 100 / total.max(1)));
+    }
+    line.push_str(&format!(", {}/s", HumanBytes(speed)));
+    if let (Some(total), 1..) = (total, speed) {
+        line.push_str(&format!(", {} left", HumanDuration(Duration::from_secs(total.saturating_sub(completed) / speed))));
+    }
+    line
+}
+
+// The progress of a download as an object of --progress json, with the bytes, total, speed, ETA and
+// state of the whole download and of each of its parts
+fn json_status(name: &str, bars: &Bars) -> Value {
+    let object = |bytes: u64, total: Option<u64>, speed: u64, finished: bool| {
+        let eta = total.filter(|_| speed > 0).map(|total| total.saturating_sub(bytes) / speed.max(1));
+        let state = if finished { "done" } else { "downloading" };
+        json!({"bytes": bytes, "total": total, "speed": speed, "eta": eta.filter(|_| !finished), "state": state})
+    };
+    let parts: Vec<Value> = {
+        let bars = bars.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        bars.iter()
+            .enumerate()
+            .map(|(index, bar)| {
+                let speed = if bar.is_finished() { 0 } else { bar.per_sec() as u64 };
+                let mut part = object(bar.position(), bar.length(), speed, bar.is_finished());
+                part["part"] = json!(index + 1);
+                part
+            })
+            .collect()
+    };
+    let (bytes, total, speed) = totals(bars);
+    let finished = !parts.is_empty() && parts.iter().all(|part| part["state"] == "done");
+    let mut status = object(bytes, total, speed, finished);
+    status["file"] = json!(name);
+    status["time"] = json!(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs());
+    status["parts"] = Value::Array(parts);
+    status
+}
+
+// Write a line of --progress json, if it is on
+fn write_json(status: &Value) {
+    if let Some((output, _)) = JSON_OUTPUT.get() {
+        let mut output = output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = writeln!(output, "{}", status).and_then(|_| output.flush());
+    }
+}
+
+// Fit `text` into exactly `width` terminal columns
+// Wide (e.g. CJK) characters count as two columns and combining marks stay attached to their base character;
+// long names lose their middle so both the start and the extension remain visible
+fn fit_width(text: &str, width: usize) -> String {
+    // Group every character with the zero-width marks that follow it
+    let mut clusters: Vec<(String, usize)> = Vec::new();
+    for c in text.chars().filter(|c| !c.is_control()) {
+        let columns = c.width().unwrap_or(0);
+        match clusters.last_mut() {
+            Some((cluster, _)) if columns == 0 => cluster.push(c),
+            _ => clusters.push((c.to_string(), columns)),
+        }
+    }
+
+    let total: usize = clusters.iter().map(|(_, columns)| columns).sum();
+    let (mut fitted, used) = if total <= width {
+        (clusters.into_iter().map(|(cluster, _)| cluster).collect::<String>(), total)
+    } else {
+        // One column goes to the ellipsis, the tail gets the smaller half
+        let budget = width.saturating_sub(1);
+        let tail = take_columns(clusters.iter().rev(), budget / 2);
+        let head = take_columns(clusters.iter(), budget - tail.1);
+        let tail_text: String = tail.0.into_iter().rev().collect();
+        (format!("{}\u{2026}{}", head.0.concat(), tail_text), head.1 + 1 + tail.1)
+    };
+    fitted.extend(std::iter::repeat_n(' ', width.saturating_sub(used)));
+    fitted
+}
+
+// Take clusters until `budget` columns are used, never splitting a wide character
+fn take_columns<'a>(clusters: impl Iterator<Item = &'a (String, usize)>, budget: usize) -> (Vec<&'a str>, usize) {
+    let mut taken = Vec::new();
+    let mut used = 0;
+    for (cluster, columns) in clusters {
+        if used + columns > budget {
+            break;
+        }
+        taken.push(cluster.as_str());
+        used += columns;
+    }
+    (taken, used)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unicode_width::UnicodeWidthStr;
+
+    #[test]
+    fn test_fit_width_pads_short_names() {
+        assert_eq!(fit_width("a.iso", 8), "a.iso   ");
+        assert_eq!(fit_width("データ.zip", 12), "データ.zip  ");
+    }
+
+    #[test]
+    fn test_fit_width_truncates_wide_names() {
+        let fitted = fit_width("非常に長いファイル名のアーカイブ.tar.gz", 16);
+        assert_eq!(fitted.width(), 16);
+        assert!(fitted.starts_with("非常に"));
+        assert!(fitted.ends_with(".tar.gz"));
+    }
+
+    #[test]
+    fn test_status_line() {
+        let mib = 1024 * 1024;
+        assert_eq!(status_line("a.iso", (120 * mib, Some(1024 * mib), 5 * mib), None), "a.iso: 120.00 MiB of 1.00 GiB (11%), 5.00 MiB/s, 3 minutes left");
+        assert_eq!(status_line("a.iso", (120 * mib, None, 0), None), "a.iso: 120.00 MiB, 0 B/s");
+        assert_eq!(status_line("a.iso", (1024 * mib, Some(1024 * mib), 0), Some(Duration::from_secs(200))), "a.iso: 1.00 GiB done in 3 minutes");
+    }
+
+    #[test]
+    fn test_json_status() {
+        let bars: Bars = Arc::new(Mutex::new(vec![ProgressBar::hidden(), ProgressBar::hidden()]));
+        {
+            let bars = bars.lock().unwrap();
+            bars[0].set_length(100);
+            bars[0].set_position(100);
+            bars[0].finish();
+            bars[1].set_length(300);
+            bars[1].set_position(50);
+        }
+        let status = json_status("a.iso", &bars);
+        assert_eq!((&status["file"], &status["state"], &status["bytes"], &status["total"]), (&json!("a.iso"), &json!("downloading"), &json!(150), &json!(400)));
+        assert_eq!(status["parts"][0], json!({"part": 1, "bytes": 100, "total": 100, "speed": 0, "eta": null, "state": "done"}));
+        assert_eq!((&status["parts"][1]["part"], &status["parts"][1]["state"]), (&json!(2), &json!("downloading")));
+
+        bars.lock().unwrap()[1].finish();
+        assert_eq!(json_status("a.iso", &bars)["state"], "done");
+    }
+
+    #[test]
+    fn test_theme() {
+        let plain = Theme { color: false, ..Theme::default() };
+        assert_eq!(plain.colored("{prefix} {spinner.green} {bar:40.cyan/blue} {percent:>3}%"), "{prefix} {spinner} {bar:40} {percent:>3}%");
+        assert_eq!(Theme::default().colored("{bar:40.cyan/blue}"), "{bar:40.cyan/blue}");
+        // The templates of every style are valid, with and without colors
+        for (style, color) in [(Style::Minimal, true), (Style::Classic, false), (Style::Detailed, true), (Style::Detailed, false)] {
+            let theme = Theme { style, chars: "=> ".to_string(), color };
+            theme.bar("[Part 1]", "cyan/blue");
+            theme.spinner();
+        }
+    }
+
+    #[test]
+    fn test_update_total() {
+        let bars: Bars = Arc::new(Mutex::new(vec![ProgressBar::hidden(), ProgressBar::hidden()]));
+        {
+            let bars = bars.lock().unwrap();
+            bars[0].set_length(100);
+            bars[0].set_position(40);
+            bars[1].set_length(300);
+            bars[1].set_position(60);
+        }
+        let total = ProgressBar::hidden();
+        assert!(!update_total(&total, &bars));
+        assert_eq!((total.position(), total.length()), (100, Some(400)));
+
+        for bar in bars.lock().unwrap().iter() {
+            bar.finish_with_message("done");
+        }
+        assert!(update_total(&total, &bars));
+        assert_eq!((total.position(), total.message().as_str()), (400, "done"));
+    }
+
+    #[test]
+    fn test_fit_width_keeps_combining_marks() {
+        // "e" followed by a combining acute accent must not be split
+        assert_eq!(fit_width("cafe\u{301}-cafe\u{301}-cafe\u{301}.txt", 9), "cafe\u{301}\u{2026}.txt");
+    }
+}
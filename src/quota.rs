@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+use indicatif::HumanBytes;
+
+/// The byte budget of --quota: once the downloads used it up, no new download starts.
+///
+/// Downloads already running when it runs out finish, so the bytes used can exceed it by them.
+/// With a period the budget is renewed after every period, counted from when it was created.
+#[derive(Debug)]
+pub struct Quota {
+    limit: u64,
+    period: Option<Duration>,
+    used: u64,
+    started: Instant,
+}
+
+impl Quota {
+    /// Creates a budget of `limit` bytes, renewed after every `period` if given.
+    pub fn new(limit: u64, period: Option<Duration>) -> Quota {
+        Quota { limit, period, used: 0, started: Instant::now() }
+    }
+
+    /// Counts the bytes a finished download used.
+    pub fn add(&mut self, bytes: u64) {
+        self.renew(Instant::now());
+        self.used = self.used.saturating_add(bytes);
+    }
+
+    /// Returns whether the budget is used up, so no new download may start.
+    pub fn exhausted(&mut self) -> bool {
+        self.renew(Instant::now());
+        self.used >= self.limit
+    }
+
+    /// Returns when the budget is renewed, unless it covers the whole run.
+    pub fn renewed_at(&self) -> Option<Instant> {
+        let period = self.period?;
+        let periods = (self.started.elapsed().as_secs_f64() / period.as_secs_f64()).floor() as u32;
+        Some(self.started + period * (periods + 1))
+    }
+
+    /// Describes the budget and how much of it was used, like `10.00 GiB (10.20 GiB used)`.
+    pub fn describe(&self) -> String {
+        format!("{} ({} used)", HumanBytes(self.limit), HumanBytes(self.used))
+    }
+
+    // Start a new period once the current one is over
+    fn renew(&mut self, now: Instant) {
+        if let Some(period) = self.period {
+            let elapsed = now.saturating_duration_since(self.started);
+            if elapsed >= period {
+                self.started += period * (elapsed.as_secs_f64() / period.as_secs_f64()).floor() as u32;
+                self.used = 0;
+            }
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota() {
+        let mut quota = Quota::new(1000, None);
+        assert!(!quota.exhausted());
+        quota.add(600);
+        assert!(!quota.exhausted());
+        quota.add(600);
+        assert!(quota.exhausted());
+        assert_eq!(quota.describe(), "1000 B (1.17 KiB used)");
+        assert_eq!(quota.renewed_at(), None);
+
+        // The budget of a period is renewed once it is over
+        let mut quota = Quota::new(1000, Some(Duration::from_secs(3600)));
+        quota.add(1000);
+        assert!(quota.exhausted());
+        let started = quota.started;
+        assert_eq!(quota.renewed_at(), Some(started + Duration::from_secs(3600)));
+        quota.renew(started + Duration::from_secs(2 * 3600 + 60));
+        assert_eq!((quota.used, quota.started), (0, started + Duration::from_secs(2 * 3600)));
+    }
+}
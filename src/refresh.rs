@@ -0,0 +1,136 @@
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use reqwest::header::{HeaderMap, HeaderName, ETAG, LAST_MODIFIED};
+use crate::downloader::RemoteFile;
+use crate::scheduler;
+
+// Extended attribute keeping the ETag of a downloaded file
+#[cfg(target_os = "linux")]
+const ETAG_ATTRIBUTE: &std::ffi::CStr = c"user.rtget.etag";
+
+// Month names of HTTP dates
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Returns whether the file at `path` is the version of the remote file it was downloaded as.
+///
+/// It must have the size of the remote file, and the same ETag, or when either ETag is missing a
+/// modification time equal to the `Last-Modified` of the server. A file without either is changed.
+pub fn is_unchanged(path: &Path, remote: &RemoteFile) -> bool {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return false;
+    };
+    if !metadata.is_file() || remote.size != Some(metadata.len() as usize) {
+        return false;
+    }
+    if let (Some(etag), Some(recorded)) = (header(&remote.headers, ETAG), read_etag(path)) {
+        return etag == recorded;
+    }
+    let last_modified = header(&remote.headers, LAST_MODIFIED).and_then(parse_http_date);
+    last_modified.is_some_and(|last_modified| metadata.modified().is_ok_and(|modified| modified == last_modified))
+}
+
+/// Records the validators of `headers` on the file downloaded at `path`, for [`is_unchanged`] to compare.
+///
+/// The file gets the `Last-Modified` of the server as its modification time, and keeps the ETag in
+/// an extended attribute on file systems that support them.
+pub fn record(path: &Path, headers: &HeaderMap) -> io::Result<()> {
+    if let Some(last_modified) = header(headers, LAST_MODIFIED).and_then(parse_http_date) {
+        std::fs::File::options().write(true).open(path)?.set_modified(last_modified)?;
+    }
+    if let Some(etag) = header(headers, ETAG) {
+        write_etag(path, etag)?;
+    }
+    Ok(())
+}
+
+// Value of a header, if it is text
+fn header(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// Parse an HTTP date like `Sun, 06 Nov 1994 08:49:37 GMT`, the only format servers may send today
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let mut fields = value.split_whitespace().skip(1);
+    let day: i64 = fields.next()?.parse().ok()?;
+    let month = fields.next().and_then(|month| MONTHS.iter().position(|name| name.eq_ignore_ascii_case(month)))? as i64 + 1;
+    let year: i64 = fields.next()?.parse().ok().filter(|year| *year >= 1970)?;
+    let time: Vec<u64> = fields.next()?.split(':').map(str::parse).collect::<Result<_, _>>().ok()?;
+    if time.len() != 3 || fields.next() != Some("GMT") || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = u64::try_from(scheduler::days_from_civil(year, month, day)).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2]))
+}
+
+// The ETag recorded on the file, if any
+#[cfg(target_os = "linux")]
+fn read_etag(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut value = [0u8; 1024];
+    // SAFETY: the path and name are NUL terminated and getxattr writes at most the length of the buffer
+    let length = unsafe { libc::getxattr(path.as_ptr(), ETAG_ATTRIBUTE.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+    let length = usize::try_from(length).ok()?;
+    Some(String::from_utf8_lossy(&value[..length]).into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_etag(_path: &Path) -> Option<String> {
+    None
+}
+
+// Keep the ETag on the file, silently skipped where the file system has no extended attributes
+#[cfg(target_os = "linux")]
+fn write_etag(path: &Path, etag: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: the path and name are NUL terminated and setxattr only reads the length of the value
+    if unsafe { libc::setxattr(path.as_ptr(), ETAG_ATTRIBUTE.as_ptr(), etag.as_ptr().cast(), etag.len(), 0) } != 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ENOTSUP) {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_etag(_path: &Path, _etag: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use reqwest::header::HeaderValue;
+    use url::Url;
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777)));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_164_800)));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37"), None);
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let path = test_server::temp_dir("refresh").join("artifact.tar.gz");
+        std::fs::write(&path, b"artifact").unwrap();
+        let remote = |size, last_modified| {
+            let mut headers = HeaderMap::new();
+            headers.insert(LAST_MODIFIED, HeaderValue::from_static(last_modified));
+            RemoteFile { size: Some(size), url: Url::parse("http://a/artifact.tar.gz").unwrap(), headers, accepts_ranges: true, address: None, version: None }
+        };
+        let downloaded = remote(8, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(!is_unchanged(&path, &downloaded));
+
+        record(&path, &downloaded.headers).unwrap();
+        assert!(is_unchanged(&path, &downloaded));
+        assert!(!is_unchanged(&path, &remote(9, "Sun, 06 Nov 1994 08:49:37 GMT")));
+        assert!(!is_unchanged(&path, &remote(8, "Mon, 07 Nov 1994 08:49:37 GMT")));
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+use crate::error::AppError;
+
+/// The kinds of events recorded during a download
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// The download was started
+    Start,
+    /// A probe request was answered
+    Probe,
+    /// The request ended up at a different URL
+    Redirect,
+    /// The file was split into ranges
+    Plan,
+    /// A chunk request was started
+    ChunkStart,
+    /// A chunk finished downloading
+    ChunkDone,
+    /// A chunk failed
+    ChunkFailed,
+    /// A failed chunk is tried again after a backoff
+    Retry,
+    /// The download switched strategy (single stream, restart, ...)
+    Fallback,
+    /// The download failed
+    Failed,
+}
+
+impl EventKind {
+    const ALL: [EventKind; 10] = [
+        EventKind::Start,
+        EventKind::Probe,
+        EventKind::Redirect,
+        EventKind::Plan,
+        EventKind::ChunkStart,
+        EventKind::ChunkDone,
+        EventKind::ChunkFailed,
+        EventKind::Retry,
+        EventKind::Fallback,
+        EventKind::Failed,
+    ];
+
+    // Name used in the log file and the timeline
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Start => "start",
+            EventKind::Probe => "probe",
+            EventKind::Redirect => "redirect",
+            EventKind::Plan => "plan",
+            EventKind::ChunkStart => "chunk-start",
+            EventKind::ChunkDone => "chunk-done",
+            EventKind::ChunkFailed => "chunk-failed",
+            EventKind::Retry => "retry",
+            EventKind::Fallback => "fallback",
+            EventKind::Failed => "failed",
+        }
+    }
+
+    // Parse a name written by `as_str`
+    fn parse(name: &str) -> Option<EventKind> {
+        EventKind::ALL.into_iter().find(|kind| kind.as_str() == name)
+    }
+}
+
+/// A single recorded event
+struct Event {
+    // Milliseconds since the first event
+    offset_ms: u128,
+    kind: EventKind,
+    detail: String,
+}
+
+// Events of the current process, recorded from every task
+static EVENTS: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Records an event in the process-wide event log.
+pub fn record(kind: EventKind, detail: impl Into<String>) {
+    let offset_ms = STARTED.get_or_init(Instant::now).elapsed().as_millis();
+    // Tabs and newlines are field and record separators in the log file
+    let detail = detail.into().replace(['\t', '\n'], " ");
+    if let Ok(mut events) = EVENTS.lock() {
+        events.push(Event { offset_ms, kind, detail });
+    }
+}
+
+/// Writes the recorded events to `path`, one `offset<TAB>kind<TAB>detail` line per event.
+pub fn write(path: &Path) -> io::Result<()> {
+    let events = EVENTS.lock().map_err(|_| io::Error::other("event log is poisoned"))?;
+    let mut contents = String::from("# rtget event log v1\n");
+    for event in events.iter() {
+        let _ = writeln!(contents, "{}\t{}\t{}", event.offset_ms, event.kind.as_str(), event.detail);
+    }
+    std::fs::write(path, contents)
+}
+
+/// Reads an event log written by `write` and renders it as a timeline.
+pub fn timeline(path: &Path) -> Result<String, AppError> {
+    let contents = std::fs::read_to_string(path)?;
+    render(&contents)
+}
+
+// Render the contents of an event log as an aligned, human readable timeline
+fn render(contents: &str) -> Result<String, AppError> {
+    let mut output = String::new();
+    for (number, line) in contents.lines().enumerate().filter(|(_, line)| !line.starts_with('#') && !line.is_empty()) {
+        let malformed = || AppError::StringError(format!("malformed event log line {}: {}", number + 1, line));
+        let mut fields = line.splitn(3, '\t');
+        let offset_ms: u128 = fields.next().and_then(|v| v.parse().ok()).ok_or_else(malformed)?;
+        let kind = fields.next().and_then(EventKind::parse).ok_or_else(malformed)?;
+        let detail = fields.next().unwrap_or_default();
+
+        // Failures stand out in the timeline
+        let marker = match kind {
+            EventKind::ChunkFailed | EventKind::Failed => "!!",
+            EventKind::Retry | EventKind::Fallback | EventKind::Redirect => "->",
+            _ => "  ",
+        };
+        let _ = writeln!(output, "+{:>4}.{:03}s {} {:<12} {}", offset_ms / 1000, offset_ms % 1000, marker, kind.as_str(), detail);
+    }
+    Ok(output)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_timeline() {
+        let log = "# rtget event log v1\n0\tstart\thttp://example.com/a\n1500\tchunk-failed\tbytes 0-99: reset\n";
+        let timeline = render(log).unwrap();
+        assert_eq!(
+            timeline,
+            "+   0.000s    start        http://example.com/a\n+   1.500s !! chunk-failed bytes 0-99: reset\n"
+        );
+    }
+
+    #[test]
+    fn test_render_rejects_unknown_events() {
+        assert!(render("12\tteleport\tsomewhere\n").is_err());
+    }
+
+    #[test]
+    fn test_event_kind_round_trip() {
+        for kind in EventKind::ALL {
+            assert_eq!(EventKind::parse(kind.as_str()), Some(kind));
+        }
+    }
+}
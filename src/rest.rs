@@ -0,0 +1,190 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+use serde_json::{json, Value};
+use crate::daemon::{Queue, Request};
+use crate::scheduler::StartAt;
+use crate::server::{Access, HttpRequest};
+
+/// What the REST API of the daemon answers to a request.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// A JSON document, with its HTTP status
+    Json(&'static str, Value),
+    /// No content, with its HTTP status
+    Empty(&'static str),
+    /// A stream of server-sent events with the progress of the download of this GID
+    Progress(String),
+}
+
+/// Answers a request to the REST API of the daemon, or returns None if its path is not part of it.
+///
+/// - `GET /downloads` lists the downloads, `POST /downloads` adds one
+/// - `GET /downloads/{id}` describes a download, `DELETE /downloads/{id}` cancels it, or drops it once stopped
+/// - `GET /downloads/{id}/progress` streams its state every second until it stops
+///
+/// Requests must pass the secret of `access` as `Authorization: Bearer <secret>` or, for browsers
+/// following a progress stream, as `?token=<secret>`, the same one JSON-RPC calls pass. A user of
+/// --users passes their own token instead, and sees only their downloads, as over JSON-RPC.
+pub fn route(queue: &Queue, access: &Access, request: &HttpRequest) -> Option<Reply> {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    if segments[0] != "downloads" {
+        return None;
+    }
+    let Some(identity) = access.identify_request(request) else {
+        return Some(error("401 Unauthorized", "a valid token is required"));
+    };
+    let user = identity.user();
+    let found = |gid: &str| queue.status(gid).filter(|_| queue.visible(gid, user)).ok_or_else(|| error("404 Not Found", &format!("no download has the ID {}", gid)));
+    Some(match (request.method.as_str(), &segments[1..]) {
+        ("GET", []) => {
+            let gids = queue.gids_of(user, |_| true);
+            Reply::Json("200 OK", Value::Array(gids.iter().filter_map(|gid| queue.status(gid)).map(|status| download(&status)).collect()))
+        }
+        ("POST", []) => {
+            let request = match parse_request(&request.body) {
+                Ok(request) => Request { owner: user.map(str::to_string), ..request },
+                Err(message) => return Some(error("400 Bad Request", &message)),
+            };
+            match queue.add(request) {
+                Ok(gid) => Reply::Json("201 Created", download(&queue.status(&gid)?)),
+                Err(message) => error("400 Bad Request", &message),
+            }
+        }
+        ("GET", [gid]) => match found(gid) {
+            Ok(status) => Reply::Json("200 OK", download(&status)),
+            Err(reply) => reply,
+        },
+        ("DELETE", [gid]) => match found(gid) {
+            // A stopped download is dropped from the list, any other is cancelled, which a running one takes a moment to do
+            Ok(status) => match status["status"].as_str() {
+                Some("complete" | "error" | "removed") => match queue.forget(gid) {
+                    Ok(()) => Reply::Empty("204 No Content"),
+                    Err(message) => error("409 Conflict", &message),
+                },
+                _ => match queue.remove(gid) {
+                    Ok(()) => Reply::Json("202 Accepted", download(&queue.status(gid)?)),
+                    Err(message) => error("409 Conflict", &message),
+                },
+            },
+            Err(reply) => reply,
+        },
+        ("GET", [gid, "progress"]) => match found(gid) {
+            Ok(_) => Reply::Progress(gid.to_string()),
+            Err(reply) => reply,
+        },
+        (_, [] | [_] | [_, "progress"]) => error("405 Method Not Allowed", &format!("{} is not allowed on {}", request.method, request.path)),
+        _ => error("404 Not Found", &format!("no resource at {}", request.path)),
+    })
+}
+
+/// Describes a download for the REST API from its aria2.tellStatus state, with numbers as numbers.
+pub fn download(status: &Value) -> Value {
+    let number = |value: &Value| value.as_str().and_then(|value| value.parse::<u64>().ok()).unwrap_or_default();
+    let file = &status["files"][0];
+    let urls: Vec<&str> = file["uris"].as_array().into_iter().flatten().filter_map(|uri| uri["uri"].as_str()).collect();
+    json!({
+        "id": status["gid"],
+        "status": status["status"],
+        "url": urls.first(),
+        "mirrors": urls.get(1..).unwrap_or_default(),
+        "dir": status["dir"],
+        "file": file["path"].as_str().filter(|path| !path.is_empty()),
+        "total_bytes": number(&status["totalLength"]),
+        "downloaded_bytes": number(&status["completedLength"]),
+        "speed": number(&status["downloadSpeed"]),
+        "connections": number(&status["connections"]),
+        "start_at": status.get("startAt"),
+        "error": status.get("errorMessage"),
+    })
+}
+
+// The download a POST asks for: {"url": ..., "mirrors": [...], "dir": ..., "out": ..., "connections": n, "start_at": "02:00"}
+fn parse_request(body: &[u8]) -> Result<Request, String> {
+    let body: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let url = body["url"].as_str().ok_or("the url of the download is missing")?;
+    let mirrors = match &body["mirrors"] {
+        Value::Null => Vec::new(),
+        Value::Array(mirrors) => mirrors.iter().map(|mirror| mirror.as_str().map(str::to_string).ok_or("mirrors must be URLs")).collect::<Result<_, _>>()?,
+        _ => return Err("mirrors must be a list of URLs".to_string()),
+    };
+    let connections = match &body["connections"] {
+        Value::Null => None,
+        connections => Some(connections.as_u64().and_then(|connections| u8::try_from(connections).ok()).filter(|connections| *connections > 0).ok_or("connections must be a number from 1 to 255")?),
+    };
+    let start_at = match &body["start_at"] {
+        Value::Null => None,
+        start_at => Some(StartAt::parse(start_at.as_str().ok_or("start_at must be a time like 02:00")?)?.next(SystemTime::now())),
+    };
+    Ok(Request {
+        urls: std::iter::once(url.to_string()).chain(mirrors).collect(),
+        dir: body["dir"].as_str().map(PathBuf::from),
+        out: body["out"].as_str().map(str::to_string),
+        connections,
+        start_at,
+        ..Request::default()
+    })
+}
+
+// An error answer
+fn error(status: &'static str, message: &str) -> Reply {
+    Reply::Json(status, json!({"error": message}))
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn request(method: &str, target: &str, headers: &[(&str, &str)], body: &str) -> HttpRequest {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        HttpRequest { method: method.to_string(), path, query, headers, body: body.as_bytes().to_vec() }
+    }
+
+    #[test]
+    fn test_route() {
+        let queue = Queue::new(Path::new("downloads"), 1);
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: Vec::new(), allow_origin_all: false, allowed_ips: Vec::new(), users: std::sync::Arc::new(crate::users::Users::parse("[users.alice]\ntoken = \"t0ken\"").unwrap()) };
+        let auth = [("authorization", "Bearer s3cret")];
+        assert_eq!(route(&queue, &access, &request("GET", "/jsonrpc", &[], "")), None);
+        assert!(matches!(route(&queue, &access, &request("GET", "/downloads", &[], "")), Some(Reply::Json("401 Unauthorized", _))));
+
+        let added = route(&queue, &access, &request("POST", "/downloads", &auth, r#"{"url": "http://a/1.iso", "mirrors": ["http://b/1.iso"], "out": "one.iso", "connections": 4}"#));
+        let Some(Reply::Json("201 Created", added)) = added else {
+            panic!("unexpected reply {:?}", added);
+        };
+        assert_eq!((added["id"].as_str(), added["status"].as_str(), added["file"].as_str()), (Some("0000000000000001"), Some("waiting"), Some("downloads/one.iso")));
+        assert_eq!(added["mirrors"], json!(["http://b/1.iso"]));
+        assert!(matches!(route(&queue, &access, &request("POST", "/downloads", &auth, r#"{"url": "http://a/1.iso", "connections": 0}"#)), Some(Reply::Json("400 Bad Request", _))));
+
+        assert_eq!(route(&queue, &access, &request("GET", "/downloads/0000000000000001/progress?token=s3cret", &[], "")), Some(Reply::Progress("0000000000000001".to_string())));
+        assert!(matches!(route(&queue, &access, &request("GET", "/downloads/0000000000000002", &auth, "")), Some(Reply::Json("404 Not Found", _))));
+        assert!(matches!(route(&queue, &access, &request("PUT", "/downloads/0000000000000001", &auth, "")), Some(Reply::Json("405 Method Not Allowed", _))));
+
+        // Cancelling a waiting download removes it at once, and deleting it again drops it from the list
+        let Some(Reply::Json("202 Accepted", cancelled)) = route(&queue, &access, &request("DELETE", "/downloads/0000000000000001", &auth, "")) else {
+            panic!("the download was not cancelled");
+        };
+        assert_eq!(cancelled["status"], "removed");
+        assert_eq!(route(&queue, &access, &request("DELETE", "/downloads/0000000000000001", &auth, "")), Some(Reply::Empty("204 No Content")));
+        assert_eq!(route(&queue, &access, &request("GET", "/downloads", &auth, "")), Some(Reply::Json("200 OK", json!([]))));
+        assert!(matches!(route(&queue, &access, &request("GET", "/downloads?token=wrong", &[("authorization", "Bearer ")], "")), Some(Reply::Json("401 Unauthorized", _))));
+
+        // A user sees only their downloads, saved in their directory
+        let alice = [("authorization", "Bearer t0ken")];
+        assert!(matches!(route(&queue, &access, &request("POST", "/downloads", &auth, r#"{"url": "http://a/2.iso"}"#)), Some(Reply::Json("201 Created", _))));
+        let Some(Reply::Json("201 Created", added)) = route(&queue, &access, &request("POST", "/downloads", &alice, r#"{"url": "http://a/3.iso"}"#)) else {
+            panic!("the download of the user was not added");
+        };
+        assert_eq!(added["dir"], "downloads/alice");
+        let Some(Reply::Json("200 OK", listed)) = route(&queue, &access, &request("GET", "/downloads", &alice, "")) else {
+            panic!("the downloads of the user were not listed");
+        };
+        assert_eq!(listed.as_array().map(|listed| listed.len()), Some(1));
+        assert!(matches!(route(&queue, &access, &request("DELETE", "/downloads/0000000000000002", &alice, "")), Some(Reply::Json("404 Not Found", _))));
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use indicatif::{HumanBytes, ProgressBar};
+use reqwest::header::ETAG;
+use url::Url;
+use crate::control::{self, ControlFile};
+use crate::downloader::{ClientOptions, Downloader, FileDownloader};
+use crate::error::AppError;
+use crate::filesystem::{self, FileSystem};
+
+// Suffix of the control file next to the output of an interrupted download
+const CONTROL_SUFFIX: &str = ".rtget";
+
+/// Points the interrupted download of `output` at `new_url`, e.g. a mirror or a fresh signed URL.
+///
+/// The new URL must serve a file of the recorded size. When both servers send an ETag and they agree
+/// that is enough; otherwise the tail of a downloaded range is fetched again from the new URL and
+/// compared with its recorded checksum. Returns the updated state, already saved.
+pub async fn retarget(output: &Path, new_url: &Url, options: &ClientOptions) -> Result<ControlFile, AppError> {
+    let mut control = load(output)?;
+    let downloader = FileDownloader::with_options(options)?;
+    let remote = downloader.probe(new_url.as_str()).await?;
+    let etag = remote.headers.get(ETAG).and_then(|v| v.to_str().ok());
+
+    let size = remote.size.unwrap_or_default() as u64;
+    if size != control.total_size {
+        return Err(AppError::StringError(format!(
+            "{} serves {} bytes, the interrupted download has {}",
+            new_url, size, control.total_size
+        )));
+    }
+    let same_etag = matches!((control.etag.as_deref(), etag), (Some(saved), Some(current)) if saved == current);
+    if !same_etag {
+        if let Some((start, end, expected)) = control.tail_piece() {
+            let mut piece = Vec::new();
+            downloader.download_chunk(new_url.as_str(), start as usize, end as usize, &mut piece, &ProgressBar::hidden()).await?;
+            if control::checksum(&piece) != expected {
+                return Err(AppError::StringError(format!("{} does not serve the same content as {}", new_url, control.url)));
+            }
+        }
+    }
+
+    tracing::info!("Resuming {} from {} instead of {}", output.display(), new_url, control.url);
+    control.url = new_url.to_string();
+    control.etag = etag.map(str::to_string);
+    control.save(&control_path(output))?;
+    Ok(control)
+}
+
+/// Loads the state of the interrupted download of `output`.
+pub fn load(output: &Path) -> Result<ControlFile, AppError> {
+    ControlFile::load(&control_path(output))
+        .ok_or_else(|| AppError::StringError(format!("{} has no interrupted download to resume", output.display())))
+}
+
+/// Describes how far the interrupted downloads of `paths` got: output files, or directories holding them.
+///
+/// Without paths the current directory is searched. Each download is shown with its progress, size,
+/// the ranges it was split into and its URL.
+pub fn status(paths: &[PathBuf]) -> Result<String, AppError> {
+    let mut outputs = Vec::new();
+    for path in paths.iter().map(PathBuf::as_path).chain(paths.is_empty().then_some(Path::new("."))) {
+        if !path.is_dir() {
+            outputs.push((path.to_path_buf(), load(path)?));
+            continue;
+        }
+        let mut found: Vec<PathBuf> = std::fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let output = name.strip_suffix(CONTROL_SUFFIX).filter(|output| !output.is_empty())?;
+                Some(if path == Path::new(".") { PathBuf::from(output) } else { path.join(output) })
+            })
+            .collect();
+        found.sort();
+        outputs.extend(found.into_iter().filter_map(|output| load(&output).ok().map(|control| (output, control))));
+    }
+    if outputs.is_empty() {
+        return Ok("No interrupted downloads\n".to_string());
+    }
+    let mut output = String::new();
+    let _ = writeln!(output, "{:>5} {:>10} {:>6}  File", "Done", "Size", "Ranges");
+    for (path, control) in outputs {
+        let written: u64 = control.segments.iter().map(|segment| segment.written).sum();
+        let done = (written as f64 / control.total_size.max(1) as f64 * 100.0).min(100.0);
+        let size = HumanBytes(control.total_size).to_string();
+        let _ = writeln!(output, "{:>4.0}% {:>10} {:>6}  {} from {}", done, size, control.segments.len(), path.display(), control.url);
+    }
+    Ok(output)
+}
+
+// Control file of the download of `output`, which is written to `<output>.part` until it is complete
+fn control_path(output: &Path) -> PathBuf {
+    FileSystem::new(filesystem::part_path(output)).control_path()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Quirks};
+
+    // An interrupted download of `body` with the first 100 bytes of its only range written
+    fn interrupted(name: &str, body: &[u8], etag: Option<&str>) -> std::path::PathBuf {
+        let output = test_server::temp_dir(name).join("file.bin");
+        let mut contents = body[..100].to_vec();
+        contents.resize(body.len(), 0);
+        std::fs::write(&output, contents).unwrap();
+        let mut control = ControlFile::new("http://127.0.0.1:1/expired", etag, body.len() as u64, &[(0, body.len() as u64 - 1)]);
+        control.refresh(&output, &[(0, body.len() as u64 - 1, 100)]).unwrap();
+        control.save(&FileSystem::new(output.clone()).control_path()).unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn test_retarget_verifies_content() {
+        let body: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let output = interrupted("resume_same", &body, Some("\"old\""));
+
+        // A different ETag alone is not fatal, the downloaded tail decides
+        let mirror = Url::parse(&test_server::serve_with(body.clone(), Quirks { etag: Some("\"mirror\""), ..Quirks::default() })).unwrap();
+        let control = retarget(&output, &mirror, &ClientOptions::default()).await.unwrap();
+        assert_eq!(control.url, mirror.as_str());
+        assert_eq!(control.etag.as_deref(), Some("\"mirror\""));
+        assert_eq!(load(&output).unwrap(), control);
+    }
+
+    #[test]
+    fn test_status() {
+        let body: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let output = interrupted("resume_status", &body, None);
+        let dir = output.parent().unwrap().to_path_buf();
+        std::fs::write(dir.join(".rtget-history"), "").unwrap();
+        let listed = status(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(listed.lines().count(), 2, "{}", listed);
+        assert!(listed.lines().nth(1).unwrap().starts_with("  10%     1000 B      1  "), "{}", listed);
+        assert!(listed.ends_with("file.bin from http://127.0.0.1:1/expired\n"), "{}", listed);
+        assert_eq!(status(&[output]).unwrap(), listed);
+        assert!(status(&[dir.join("other.bin")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retarget_rejects_other_content() {
+        let body: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let output = interrupted("resume_other", &body, None);
+
+        let other = Url::parse(&test_server::serve(vec![0; 1000])).unwrap();
+        assert!(retarget(&output, &other, &ClientOptions::default()).await.is_err());
+        let shorter = Url::parse(&test_server::serve(body[..999].to_vec())).unwrap();
+        assert!(retarget(&output, &shorter, &ClientOptions::default()).await.is_err());
+        assert_eq!(load(&output).unwrap().url, "http://127.0.0.1:1/expired");
+    }
+}
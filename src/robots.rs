@@ -0,0 +1,168 @@
+use std::time::Duration;
+use indicatif::ProgressBar;
+use url::Url;
+use crate::downloader::{Downloader, FileDownloader, RequestSpec};
+use crate::error::AppError;
+
+// The name rtget looks for in the User-agent lines of robots.txt
+const AGENT: &str = "rtget";
+
+// Most bytes of a robots.txt read, as search engines do
+const MAX_ROBOTS_SIZE: u64 = 500 * 1024;
+
+/// The rules of a robots.txt that apply to rtget
+#[derive(Debug, Default, PartialEq)]
+pub struct Robots {
+    /// Path patterns with whether they are allowed
+    rules: Vec<(String, bool)>,
+    /// How long to wait between requests
+    pub crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Parses a robots.txt, keeping the group of rules for rtget, or for every robot (`*`) if there is none.
+    pub fn parse(text: &str) -> Robots {
+        let (mut ours, mut everyone) = (None::<Robots>, None::<Robots>);
+        // The agents of the group being read, and whether its rules started, which ends the list of agents
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        let mut group = Robots::default();
+        let mut finish = |agents: &[String], group: Robots| {
+            if agents.iter().any(|agent| agent.contains(AGENT)) {
+                ours.get_or_insert_with(Robots::default).merge(group);
+            } else if agents.iter().any(|agent| agent == "*") {
+                everyone.get_or_insert_with(Robots::default).merge(group);
+            }
+        };
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match field.trim().to_ascii_lowercase().as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        finish(&agents, std::mem::take(&mut group));
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_ascii_lowercase());
+                }
+                "allow" | "disallow" if !value.is_empty() => {
+                    in_rules = true;
+                    group.rules.push((value.to_string(), field.trim().eq_ignore_ascii_case("allow")));
+                }
+                "crawl-delay" => {
+                    in_rules = true;
+                    group.crawl_delay = value.parse::<f64>().ok().filter(|delay| delay.is_finite() && *delay >= 0.0).map(Duration::from_secs_f64);
+                }
+                // An empty Disallow allows everything, but still ends the list of agents
+                "disallow" => in_rules = true,
+                _ => {}
+            }
+        }
+        finish(&agents, group);
+        ours.or(everyone).unwrap_or_default()
+    }
+
+    // Add the rules of another group for the same robot
+    fn merge(&mut self, other: Robots) {
+        self.rules.extend(other.rules);
+        self.crawl_delay = self.crawl_delay.or(other.crawl_delay);
+    }
+
+    /// Returns whether `url` may be fetched: the longest rule matching its path decides, and Allow wins a tie.
+    pub fn allows(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        self.rules
+            .iter()
+            .filter(|(pattern, _)| matches(pattern, &path))
+            .max_by_key(|(pattern, allowed)| (pattern.len(), *allowed))
+            .is_none_or(|(_, allowed)| *allowed)
+    }
+}
+
+/// Fetches the robots.txt of the host of `url`.
+///
+/// A site without one, or with one that cannot be fetched, allows everything.
+pub async fn fetch(downloader: &FileDownloader, url: &Url) -> Robots {
+    let Ok(location) = url.join("/robots.txt") else {
+        return Robots::default();
+    };
+    let mut body = Vec::new();
+    match downloader.download_whole(location.as_str(), &RequestSpec::default(), &mut body, &ProgressBar::hidden(), Some(MAX_ROBOTS_SIZE)).await {
+        Ok(()) => Robots::parse(&String::from_utf8_lossy(&body)),
+        Err(AppError::HttpStatus(400..=499, _)) => Robots::default(),
+        Err(e) => {
+            tracing::warn!("Could not fetch {}, following every link: {}", location, e);
+            Robots::default()
+        }
+    }
+}
+
+// Match a path against a rule, a prefix where `*` stands for anything and a final `$` for the end of the path
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut pieces = pattern.split('*');
+    let Some(rest) = path.strip_prefix(pieces.next().unwrap_or_default()) else {
+        return false;
+    };
+    let mut rest = rest;
+    let pieces: Vec<&str> = pieces.collect();
+    for (index, piece) in pieces.iter().enumerate() {
+        // The last piece of an anchored rule has to end the path; the others match as early as possible
+        if anchored && index + 1 == pieces.len() {
+            return rest.ends_with(piece);
+        }
+        match rest.find(piece) {
+            Some(found) => rest = &rest[found + piece.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(robots: &Robots, path: &str) -> bool {
+        robots.allows(&Url::parse(&format!("http://example.com{}", path)).unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        let robots = Robots::parse(
+            "User-agent: *\nDisallow: /\n\n# rtget may look around\nUser-agent: wget\nUser-agent: RTGET/1.0\nDisallow: /private/\nAllow: /private/shared\nDisallow: /*.tmp$\nCrawl-delay: 1.5\n",
+        );
+        assert_eq!(robots.crawl_delay, Some(Duration::from_millis(1500)));
+        assert!(allows(&robots, "/docs/index.html"));
+        assert!(!allows(&robots, "/private/notes.html"));
+        assert!(allows(&robots, "/private/shared/notes.html"));
+        assert!(!allows(&robots, "/build/a.tmp"));
+        assert!(allows(&robots, "/build/a.tmp.html"));
+
+        // Without a group of its own, rtget follows that of every robot
+        let robots = Robots::parse("User-agent: googlebot\nDisallow: /\n\nUser-agent: *\nDisallow: /cgi-bin/\nDisallow:\n");
+        assert!(!allows(&robots, "/cgi-bin/search?q=1"));
+        assert!(allows(&robots, "/"));
+        assert!(allows(&Robots::parse(""), "/anything"));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("/search", "/search?q=1"));
+        assert!(matches("/*/private", "/a/b/private/x"));
+        assert!(matches("/*.php$", "/index.php"));
+        assert!(!matches("/*.php$", "/index.php?x=1"));
+        assert!(!matches("/a$", "/ab"));
+    }
+}
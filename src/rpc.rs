@@ -0,0 +1,310 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+use serde_json::{json, Map, Value};
+use crate::daemon::{Queue, Request, Status};
+use crate::scheduler::StartAt;
+use crate::server::Access;
+use crate::users::Identity;
+
+// Methods of aria2 the daemon implements, listed by system.listMethods
+const METHODS: [&str; 15] = [
+    "aria2.addUri",
+    "aria2.tellStatus",
+    "aria2.pause",
+    "aria2.forcePause",
+    "aria2.unpause",
+    "aria2.remove",
+    "aria2.forceRemove",
+    "aria2.removeDownloadResult",
+    "aria2.getGlobalStat",
+    "aria2.tellActive",
+    "aria2.tellWaiting",
+    "aria2.tellStopped",
+    "aria2.getVersion",
+    "system.listMethods",
+    "system.multicall",
+];
+
+// Error codes of JSON-RPC 2.0, and the one aria2 uses for every failed call
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const CALL_FAILED: i64 = 1;
+
+/// How a client reached the JSON-RPC interface, which tells who it is
+pub enum Caller<'a> {
+    /// Over the network, or over the control socket by someone its login does not tell: the token
+    /// passed first in every call says who, see [`Access::identify`]
+    Network(&'a Access),
+    /// Over the control socket, as its login tells
+    Known(Identity),
+}
+
+/// Answers a JSON-RPC request of aria2 frontends, or a batch of them, on the downloads of `queue`.
+///
+/// Clients the `caller` does not tell apart must pass `token:<secret>` as the first parameter of
+/// every call, like aria2 --rpc-secret asks. Users of --users only see and change their own
+/// downloads. Returns None for notifications, which get no answer.
+pub fn handle(queue: &Queue, caller: &Caller, body: &str) -> Option<String> {
+    let answer = match serde_json::from_str::<Value>(body) {
+        Ok(Value::Array(requests)) if !requests.is_empty() => {
+            let answers: Vec<Value> = requests.iter().filter_map(|request| answer(queue, caller, request)).collect();
+            (!answers.is_empty()).then_some(Value::Array(answers))
+        }
+        Ok(request) => answer(queue, caller, &request),
+        Err(e) => Some(error(Value::Null, PARSE_ERROR, &format!("Parse error: {}", e))),
+    };
+    answer.map(|answer| answer.to_string())
+}
+
+/// Returns who makes the first call of the request `body`, from the token it passes, if it passes a valid one.
+pub fn identify(access: &Access, body: &str) -> Option<Identity> {
+    let body: Value = serde_json::from_str(body).ok()?;
+    let request = match &body {
+        Value::Array(requests) => requests.first()?,
+        request => request,
+    };
+    let mut params = request.get("params")?;
+    // system.multicall passes the token in each call
+    if request.get("method").and_then(Value::as_str) == Some("system.multicall") {
+        params = params.get(0)?.get(0)?.get("params")?;
+    }
+    access.identify(params.get(0)?.as_str()?.strip_prefix("token:"))
+}
+
+/// Renders an event of the queue as the notification aria2 sends over WebSocket.
+pub fn notification(method: &str, gid: &str) -> String {
+    json!({"jsonrpc": "2.0", "method": method, "params": [{"gid": gid}]}).to_string()
+}
+
+// The answer to one request, or None for a notification
+fn answer(queue: &Queue, caller: &Caller, request: &Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error(id.unwrap_or_default(), INVALID_REQUEST, "Invalid Request"));
+    };
+    let params = match request.get("params") {
+        Some(Value::Array(params)) => params.clone(),
+        None => Vec::new(),
+        Some(_) => return Some(error(id.unwrap_or_default(), INVALID_REQUEST, "Invalid Request: params must be an array")),
+    };
+    let result = call(queue, caller, method, params);
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err((code, message)) => error(id, code, &message),
+    })
+}
+
+// Run one method with its parameters
+// A user only reaches their own downloads, and only the owner of the daemon saves files outside its directory
+fn call(queue: &Queue, caller: &Caller, method: &str, mut params: Vec<Value>) -> Result<Value, (i64, String)> {
+    // system.multicall passes the token in each call, and system.listMethods needs none
+    let identity = match method {
+        "system.multicall" | "system.listMethods" => None,
+        _ => {
+            let token = match params.first().and_then(Value::as_str).and_then(|param| param.strip_prefix("token:")) {
+                Some(token) => {
+                    let token = token.to_string();
+                    params.remove(0);
+                    Some(token)
+                }
+                None => None,
+            };
+            let identity = match caller {
+                Caller::Known(identity) => Some(identity.clone()),
+                Caller::Network(access) => access.identify(token.as_deref()),
+            };
+            Some(identity.ok_or_else(|| (CALL_FAILED, "Unauthorized".to_string()))?)
+        }
+    };
+    let user = identity.as_ref().and_then(Identity::user);
+    let failed = |message: String| (CALL_FAILED, message);
+    let gid = |index: usize| match params.get(index).and_then(Value::as_str) {
+        Some(gid) if queue.visible(gid, user) => Ok(gid.to_string()),
+        Some(gid) => Err(failed(format!("no download has the GID {}", gid))),
+        None => Err(failed("the GID is missing".to_string())),
+    };
+    match method {
+        "aria2.addUri" => {
+            let urls: Vec<String> = params.first().and_then(Value::as_array).map(|urls| urls.iter().filter_map(Value::as_str).map(str::to_string).collect()).unwrap_or_default();
+            let options = params.get(1).and_then(Value::as_object).cloned().unwrap_or_default();
+            let request = Request { owner: user.map(str::to_string), trusted: identity == Some(Identity::Owner), ..request(urls, &options).map_err(failed)? };
+            queue.add(request).map(Value::from).map_err(failed)
+        }
+        "aria2.tellStatus" => {
+            let gid = gid(0)?;
+            let status = queue.status(&gid).ok_or_else(|| failed(format!("no download has the GID {}", gid)))?;
+            Ok(select_keys(status, params.get(1)))
+        }
+        "aria2.pause" | "aria2.forcePause" => {
+            let gid = gid(0)?;
+            queue.pause(&gid).map(|()| Value::from(gid)).map_err(failed)
+        }
+        "aria2.unpause" => {
+            let gid = gid(0)?;
+            queue.unpause(&gid).map(|()| Value::from(gid)).map_err(failed)
+        }
+        "aria2.remove" | "aria2.forceRemove" => {
+            let gid = gid(0)?;
+            queue.remove(&gid).map(|()| Value::from(gid)).map_err(failed)
+        }
+        "aria2.removeDownloadResult" => {
+            let gid = gid(0)?;
+            queue.forget(&gid).map(|()| Value::from("OK")).map_err(failed)
+        }
+        "aria2.getGlobalStat" => Ok(queue.global_stat(user)),
+        "aria2.tellActive" => Ok(statuses(queue, queue.gids_of(user, |status| status == Status::Active), params.first())),
+        "aria2.tellWaiting" | "aria2.tellStopped" => {
+            let wanted: fn(Status) -> bool = match method {
+                "aria2.tellWaiting" => |status| matches!(status, Status::Waiting | Status::Paused),
+                _ => |status| status.is_stopped(),
+            };
+            let gids = queue.gids_of(user, wanted);
+            let offset = params.first().and_then(Value::as_i64).unwrap_or(0);
+            let count = params.get(1).and_then(Value::as_u64).unwrap_or(u64::MAX) as usize;
+            // A negative offset counts from the end, listing the downloads backwards
+            let gids: Vec<String> = match usize::try_from(offset) {
+                Ok(offset) => gids.into_iter().skip(offset).take(count).collect(),
+                Err(_) => gids.into_iter().rev().skip(usize::try_from(offset.unsigned_abs() - 1).unwrap_or(usize::MAX)).take(count).collect(),
+            };
+            Ok(statuses(queue, gids, params.get(2)))
+        }
+        "aria2.getVersion" => Ok(json!({"version": env!("CARGO_PKG_VERSION"), "enabledFeatures": ["HTTPS", "FTP"]})),
+        "system.listMethods" => Ok(json!(METHODS)),
+        "system.multicall" => {
+            let calls = params.first().and_then(Value::as_array).cloned().unwrap_or_default();
+            let results = calls.into_iter().map(|call_request| {
+                let method = call_request.get("methodName").and_then(Value::as_str).unwrap_or_default().to_string();
+                let params = call_request.get("params").and_then(Value::as_array).cloned().unwrap_or_default();
+                match method.as_str() {
+                    "system.multicall" => json!({"code": CALL_FAILED, "message": "system.multicall cannot be nested"}),
+                    _ => match call(queue, caller, &method, params) {
+                        Ok(result) => json!([result]),
+                        Err((code, message)) => json!({"code": code, "message": message}),
+                    },
+                }
+            });
+            Ok(Value::Array(results.collect()))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+    }
+}
+
+// The download aria2.addUri asks for, from the URLs and the options aria2 frontends send
+fn request(urls: Vec<String>, options: &Map<String, Value>) -> Result<Request, String> {
+    let option = |name: &str| options.get(name).and_then(|value| value.as_str().map(str::to_string).or_else(|| value.as_u64().map(|value| value.to_string())));
+    let connections = match option("split").or_else(|| option("max-connection-per-server")) {
+        Some(connections) => Some(connections.parse::<u8>().ok().filter(|connections| *connections > 0).ok_or_else(|| format!("invalid number of connections {}", connections))?),
+        None => None,
+    };
+    // rtget also takes a start time, see --start-at
+    let start_at = option("start-at").map(|start_at| StartAt::parse(&start_at)).transpose()?.map(|start_at| start_at.next(SystemTime::now()));
+    Ok(Request { urls, dir: option("dir").map(PathBuf::from), out: option("out"), connections, start_at, ..Request::default() })
+}
+
+// The statuses of `gids`, with the `keys` asked for only
+fn statuses(queue: &Queue, gids: Vec<String>, keys: Option<&Value>) -> Value {
+    Value::Array(gids.iter().filter_map(|gid| queue.status(gid)).map(|status| select_keys(status, keys)).collect())
+}
+
+// Keep the `keys` of `status` listed, or every key without a list
+fn select_keys(status: Value, keys: Option<&Value>) -> Value {
+    match (status, keys.and_then(Value::as_array)) {
+        (Value::Object(fields), Some(keys)) => Value::Object(fields.into_iter().filter(|(name, _)| keys.iter().any(|key| key.as_str() == Some(name))).collect()),
+        (status, _) => status,
+    }
+}
+
+// A JSON-RPC error answer
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::users::Users;
+
+    fn call_json(queue: &Queue, body: Value) -> Value {
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: Vec::new(), allow_origin_all: false, allowed_ips: Vec::new(), users: Arc::new(Users::parse("[users.alice]\ntoken = \"t0ken\"").unwrap()) };
+        serde_json::from_str(&handle(queue, &Caller::Network(&access), &body.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_handle() {
+        let queue = Queue::new(Path::new("downloads"), 2);
+        let added = call_json(&queue, json!({"jsonrpc": "2.0", "id": "1", "method": "aria2.addUri", "params": ["token:s3cret", ["http://a/1.iso", "http://b/1.iso"], {"dir": "isos", "split": "4"}]}));
+        assert_eq!(added, json!({"jsonrpc": "2.0", "id": "1", "result": "0000000000000001"}));
+
+        let status = call_json(&queue, json!({"jsonrpc": "2.0", "id": 2, "method": "aria2.tellStatus", "params": ["token:s3cret", "0000000000000001", ["gid", "status", "dir"]]}));
+        assert_eq!(status["result"], json!({"gid": "0000000000000001", "status": "waiting", "dir": "downloads/isos"}));
+
+        let unauthorized = call_json(&queue, json!({"jsonrpc": "2.0", "id": 3, "method": "aria2.getGlobalStat", "params": ["token:wrong"]}));
+        assert_eq!(unauthorized["error"]["message"], "Unauthorized");
+
+        // A batch, with a notification that gets no answer
+        let answers = call_json(&queue, json!([
+            {"jsonrpc": "2.0", "id": 4, "method": "aria2.pause", "params": ["token:s3cret", "0000000000000001"]},
+            {"jsonrpc": "2.0", "method": "aria2.getGlobalStat", "params": ["token:s3cret"]},
+            {"jsonrpc": "2.0", "id": 5, "method": "aria2.tellWaiting", "params": ["token:s3cret", 0, 10, ["status"]]},
+            {"jsonrpc": "2.0", "id": 6, "method": "aria2.unknown", "params": ["token:s3cret"]},
+        ]));
+        assert_eq!(answers[0]["result"], "0000000000000001");
+        assert_eq!(answers[1]["result"], json!([{"status": "paused"}]));
+        assert_eq!(answers[2]["error"]["code"], METHOD_NOT_FOUND);
+
+        let multicall = call_json(&queue, json!({"jsonrpc": "2.0", "id": 7, "method": "system.multicall", "params": [[
+            {"methodName": "aria2.remove", "params": ["token:s3cret", "0000000000000001"]},
+            {"methodName": "aria2.remove", "params": ["token:s3cret", "0000000000000001"]},
+        ]]}));
+        assert_eq!(multicall["result"][0], json!(["0000000000000001"]));
+        assert_eq!(multicall["result"][1]["code"], CALL_FAILED);
+        assert_eq!(call_json(&queue, json!({"jsonrpc": "2.0", "id": 8, "method": "aria2.getGlobalStat", "params": ["token:s3cret"]}))["result"]["numStopped"], "1");
+
+        assert_eq!(handle(&queue, &Caller::Known(Identity::Owner), "{").map(|answer| answer.contains("-32700")), Some(true));
+    }
+
+    #[test]
+    fn test_list_offsets() {
+        let queue = Queue::new(Path::new("downloads"), 2);
+        for url in ["http://a/1.iso", "http://a/2.iso", "http://a/3.iso"] {
+            call_json(&queue, json!({"jsonrpc": "2.0", "id": 1, "method": "aria2.addUri", "params": ["token:s3cret", [url]]}));
+        }
+        let waiting = |offset: i64| call_json(&queue, json!({"jsonrpc": "2.0", "id": 2, "method": "aria2.tellWaiting", "params": ["token:s3cret", offset, 2, ["gid"]]}))["result"].clone();
+        assert_eq!(waiting(1), json!([{"gid": "0000000000000002"}, {"gid": "0000000000000003"}]));
+        // A negative offset counts backwards from the last download
+        assert_eq!(waiting(-1), json!([{"gid": "0000000000000003"}, {"gid": "0000000000000002"}]));
+        assert_eq!(waiting(-3), json!([{"gid": "0000000000000001"}]));
+        assert_eq!(waiting(i64::MIN), json!([]));
+        assert_eq!(waiting(i64::MAX), json!([]));
+    }
+
+    #[test]
+    fn test_users() {
+        let queue = Queue::new(Path::new("downloads"), 2);
+        let add = |token: &str, url: &str| call_json(&queue, json!({"jsonrpc": "2.0", "id": 1, "method": "aria2.addUri", "params": [token, [url]]}));
+        assert_eq!(add("token:s3cret", "http://a/1.iso")["result"], "0000000000000001");
+        assert_eq!(add("token:t0ken", "http://a/2.iso")["result"], "0000000000000002");
+
+        // A user sees their own downloads only, saved in their directory, and cannot reach those of others
+        let stopped = call_json(&queue, json!({"jsonrpc": "2.0", "id": 2, "method": "aria2.tellWaiting", "params": ["token:t0ken", 0, 10, ["gid", "dir"]]}));
+        assert_eq!(stopped["result"], json!([{"gid": "0000000000000002", "dir": "downloads/alice"}]));
+        let other = call_json(&queue, json!({"jsonrpc": "2.0", "id": 3, "method": "aria2.remove", "params": ["token:t0ken", "0000000000000001"]}));
+        assert_eq!(other["error"]["message"], "no download has the GID 0000000000000001");
+        assert_eq!(call_json(&queue, json!({"jsonrpc": "2.0", "id": 4, "method": "aria2.getGlobalStat", "params": ["token:t0ken"]}))["result"]["numWaiting"], "1");
+        let outside = call_json(&queue, json!({"jsonrpc": "2.0", "id": 5, "method": "aria2.addUri", "params": ["token:t0ken", ["http://a/3.iso"], {"dir": "../bob"}]}));
+        assert!(outside["error"].is_object());
+
+        // The admin sees every download, and the owner on the control socket saves anywhere
+        assert_eq!(call_json(&queue, json!({"jsonrpc": "2.0", "id": 6, "method": "aria2.getGlobalStat", "params": ["token:s3cret"]}))["result"]["numWaiting"], "2");
+        let owner = handle(&queue, &Caller::Known(Identity::Owner), &json!({"jsonrpc": "2.0", "id": 7, "method": "aria2.addUri", "params": [["http://a/4.iso"], {"dir": "/srv/isos"}]}).to_string()).unwrap();
+        assert!(owner.contains("0000000000000003"), "{}", owner);
+        let alice = Caller::Known(Identity::User("alice".to_string()));
+        let listed = handle(&queue, &alice, &json!({"jsonrpc": "2.0", "id": 8, "method": "aria2.tellWaiting", "params": [0, 10, ["gid"]]}).to_string()).unwrap();
+        assert_eq!(listed, r#"{"id":8,"jsonrpc":"2.0","result":[{"gid":"0000000000000002"}]}"#);
+    }
+}
@@ -0,0 +1,424 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use url::Url;
+use crate::daemon::{Queue, Request};
+use crate::filesystem;
+
+// Longest the scheduler sleeps at once, so a change of the system clock is noticed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+// Most steps the search for the next time of a cron schedule takes, enough for a 29th of February
+const MAX_CRON_STEPS: usize = 100_000;
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// When a download given --start-at starts, in local time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartAt {
+    /// The next time the clock shows this second of the day, e.g. `02:00`
+    Daily(i64),
+    /// This local date and time, in seconds since the epoch, e.g. `2024-12-24 18:30`
+    Once(i64),
+}
+
+impl StartAt {
+    /// Parses `HH:MM`, `HH:MM:SS`, or either after a date like `2024-12-24 ` or `2024-12-24T`.
+    pub fn parse(value: &str) -> Result<StartAt, String> {
+        let invalid = || format!("invalid start time {}, expected HH:MM or YYYY-MM-DD HH:MM", value);
+        let value = value.trim();
+        let (date, time) = match value.split_once([' ', 'T']) {
+            Some((date, time)) => (Some(date), time.trim()),
+            None => (None, value),
+        };
+        let time: Vec<i64> = time.split(':').map(|part| part.parse().ok().filter(|_| part.len() == 2)).collect::<Option<_>>().ok_or_else(invalid)?;
+        let seconds = match time[..] {
+            [hour, minute] if hour < 24 && minute < 60 => hour * 3600 + minute * 60,
+            [hour, minute, second] if hour < 24 && minute < 60 && second < 60 => hour * 3600 + minute * 60 + second,
+            _ => return Err(invalid()),
+        };
+        let Some(date) = date else {
+            return Ok(StartAt::Daily(seconds));
+        };
+        let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>().ok_or_else(invalid)?;
+        match date[..] {
+            [year, month, day] if (1970..=9999).contains(&year) && (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day) => {
+                Ok(StartAt::Once(days_from_civil(year, month, day) * 86_400 + seconds))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Returns when a download asked for `now` starts: right away for a date that passed.
+    pub fn next(&self, now: SystemTime) -> SystemTime {
+        from_seconds(self.next_after(seconds(now), &utc_offset))
+    }
+
+    // The next start at or after `now`, with the UTC offset of local time at each instant from `offset`
+    fn next_after(&self, now: i64, offset: &dyn Fn(i64) -> i64) -> i64 {
+        match *self {
+            StartAt::Daily(seconds) => {
+                let today = (now + offset(now)).div_euclid(86_400);
+                let start = to_utc(today * 86_400 + seconds, offset);
+                match start > now {
+                    true => start,
+                    false => to_utc((today + 1) * 86_400 + seconds, offset),
+                }
+            }
+            StartAt::Once(local) => to_utc(local, offset).max(now),
+        }
+    }
+}
+
+/// A recurring schedule in the syntax of cron: `minute hour day-of-month month day-of-week`.
+///
+/// Fields take `*`, numbers, ranges like `1-5`, steps like `*/15` and lists of them; months and
+/// weekdays also take names like `jan` and `mon`, and Sunday is 0 or 7. A day matches if either
+/// day field does when both are restricted, as in cron. `@hourly`, `@daily`, `@weekly`, `@monthly`
+/// and `@yearly` stand for the usual expressions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    /// Parses a cron expression, which must match at least one time.
+    pub fn parse(expression: &str) -> Result<Cron, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("invalid schedule {}, expected 5 fields: minute hour day-of-month month day-of-week", expression));
+        };
+        let weekdays = cron_field(weekdays, 0, 7, &WEEKDAYS)?;
+        let cron = Cron {
+            minutes: cron_field(minutes, 0, 59, &[])?,
+            hours: cron_field(hours, 0, 23, &[])?,
+            days: cron_field(days, 1, 31, &[])?,
+            months: cron_field(months, 1, 12, &MONTHS)?,
+            // Sunday is both 0 and 7
+            weekdays: (weekdays | weekdays >> 7) & 0x7F,
+            any_day: days.starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        };
+        match cron.next_after(0, &|_| 0) {
+            Some(_) => Ok(cron),
+            None => Err(format!("the schedule {} never matches a date", expression)),
+        }
+    }
+
+    /// Returns the first time after `after` the schedule matches, in local time.
+    pub fn next(&self, after: SystemTime) -> Option<SystemTime> {
+        self.next_after(seconds(after), &utc_offset).map(from_seconds)
+    }
+
+    // The first whole minute after `after` that matches, with the UTC offset of local time at each instant from `offset`
+    fn next_after(&self, after: i64, offset: &dyn Fn(i64) -> i64) -> Option<i64> {
+        let mut local = ((after + offset(after)).div_euclid(60) + 1) * 60;
+        for _ in 0..MAX_CRON_STEPS {
+            let days = local.div_euclid(86_400);
+            let (year, month, day) = civil_from_days(days);
+            // The epoch was a Thursday
+            let weekday = (days + 4).rem_euclid(7);
+            let (hour, minute) = (local.rem_euclid(86_400) / 3600, local.rem_euclid(3600) / 60);
+            let day_matches = match (self.any_day, self.any_weekday) {
+                (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+                _ => has(self.days, day) && has(self.weekdays, weekday),
+            };
+            local = if !has(self.months, month) {
+                match month {
+                    12 => days_from_civil(year + 1, 1, 1) * 86_400,
+                    month => days_from_civil(year, month + 1, 1) * 86_400,
+                }
+            } else if !day_matches {
+                (days + 1) * 86_400
+            } else if !has(self.hours, hour) {
+                days * 86_400 + (hour + 1) * 3600
+            } else if !has(self.minutes, minute) {
+                local + 60
+            } else {
+                return Some(to_utc(local, offset).max(after + 1));
+            };
+        }
+        None
+    }
+}
+
+/// A download the daemon adds on a schedule, given as `--schedule "<cron> <url> [<mirror>...]"`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recurring {
+    pub cron: Cron,
+    /// The URL of the file, then its mirrors
+    pub urls: Vec<String>,
+}
+
+impl Recurring {
+    /// Parses the cron expression, five fields or an `@` name, followed by the URLs.
+    pub fn parse(value: &str) -> Result<Recurring, String> {
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let count = if fields.first().is_some_and(|field| field.starts_with('@')) { 1 } else { 5 };
+        if fields.len() <= count {
+            return Err(format!("invalid schedule {}, expected a cron expression followed by a URL", value));
+        }
+        let cron = Cron::parse(&fields[..count].join(" "))?;
+        let urls: Vec<String> = fields[count..].iter().map(|url| url.to_string()).collect();
+        if let Some(url) = urls.iter().find(|url| Url::parse(url).is_err()) {
+            return Err(format!("invalid URL {} in the schedule {}", url, value));
+        }
+        Ok(Recurring { cron, urls })
+    }
+
+    /// Returns the download the schedule adds, saved under the name of its URL so each run replaces the file of the last.
+    pub fn request(&self) -> Request {
+        let out = Url::parse(&self.urls[0]).ok().map(|url| filesystem::default_output_path(&url).display().to_string());
+        Request { urls: self.urls.clone(), out, ..Request::default() }
+    }
+}
+
+/// Sleeps until `at`, waking up regularly in case the system clock changes.
+pub async fn wait_until(at: SystemTime) {
+    while let Ok(left) = at.duration_since(SystemTime::now()) {
+        tokio::time::sleep(left.min(MAX_SLEEP)).await;
+    }
+}
+
+/// Activates the deferred downloads of `queue` once their time comes, and adds the `recurring`
+/// downloads whenever their schedule matches.
+pub async fn run(queue: Arc<Queue>, recurring: Vec<Recurring>) {
+    let mut next: Vec<Option<SystemTime>> = recurring.iter().map(|schedule| schedule.cron.next(SystemTime::now())).collect();
+    loop {
+        let wake = next.iter().flatten().copied().chain(queue.next_deferred()).min();
+        let sleep = async {
+            match wake {
+                Some(at) => tokio::time::sleep(at.duration_since(SystemTime::now()).unwrap_or_default().min(MAX_SLEEP)).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = sleep => {}
+            // A deferred download was added, which may start before the time waited for
+            _ = queue.deferred() => continue,
+        }
+        let now = SystemTime::now();
+        for (schedule, at) in recurring.iter().zip(next.iter_mut()) {
+            if at.is_some_and(|at| at <= now) {
+                match queue.add(schedule.request()) {
+                    Ok(gid) => tracing::info!("Scheduled download {} of {} added", gid, schedule.urls[0]),
+                    Err(e) => tracing::warn!("Could not add the scheduled download of {}: {}", schedule.urls[0], e),
+                }
+                *at = schedule.cron.next(now);
+            }
+        }
+        queue.wake();
+    }
+}
+
+/// Renders `at` in local time, e.g. `2024-12-24 18:30:00`.
+pub fn describe(at: SystemTime) -> String {
+    let at = seconds(at);
+    let local = at + utc_offset(at);
+    let (year, month, day) = civil_from_days(local.div_euclid(86_400));
+    let time = local.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+// Parse a field of a cron expression into a bit per allowed value
+fn cron_field(field: &str, min: i64, max: i64, names: &[&str]) -> Result<u64, String> {
+    let invalid = || format!("invalid schedule field {}, expected values from {} to {}", field, min, max);
+    let value = |value: &str| match names.iter().position(|name| name.eq_ignore_ascii_case(value)) {
+        Some(index) => Ok(index as i64 + min),
+        None => value.parse::<i64>().ok().filter(|value| (min..=max).contains(value)).ok_or_else(invalid),
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<i64>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // A step after a single value runs to the end, as in `5/15`
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+// Whether the bits of a field allow `value`
+fn has(bits: u64, value: i64) -> bool {
+    bits & 1 << value != 0
+}
+
+fn seconds(at: SystemTime) -> i64 {
+    match at.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn from_seconds(seconds: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+// The UTC instant of a local time, using the offset in effect then
+// A time skipped when clocks move forward lands after the gap
+fn to_utc(local: i64, offset: &dyn Fn(i64) -> i64) -> i64 {
+    let guess = local - offset(local);
+    local - offset(guess)
+}
+
+/// Returns the days since the epoch of the civil date `year`-`month`-`day`, negative before it.
+///
+/// Years count from March, so leap days come last.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, year_of_era) = (year.div_euclid(400), year.rem_euclid(400));
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The civil date of a number of days since the epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let (era, day_of_era) = (days.div_euclid(146_097), days.rem_euclid(146_097));
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    (era * 400 + year_of_era + i64::from(month <= 2), month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        12 => 31,
+        month => days_from_civil(year, month + 1, 1) - days_from_civil(year, month, 1),
+    }
+}
+
+// Seconds local time is ahead of UTC at the instant `at`
+#[cfg(unix)]
+fn utc_offset(at: i64) -> i64 {
+    let time = at as libc::time_t;
+    // SAFETY: localtime_r only writes the tm it is given
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        match libc::localtime_r(&time, &mut tm).is_null() {
+            true => 0,
+            false => tm.tm_gmtoff as i64,
+        }
+    }
+}
+
+// Seconds local time is ahead of UTC at the instant `at`
+#[cfg(windows)]
+fn utc_offset(at: i64) -> i64 {
+    use windows_sys::Win32::Foundation::SYSTEMTIME;
+    use windows_sys::Win32::System::Time::SystemTimeToTzSpecificLocalTime;
+    let (year, month, day) = civil_from_days(at.div_euclid(86_400));
+    let time = at.rem_euclid(86_400);
+    let utc = SYSTEMTIME {
+        wYear: year as u16,
+        wMonth: month as u16,
+        wDayOfWeek: 0,
+        wDay: day as u16,
+        wHour: (time / 3600) as u16,
+        wMinute: (time % 3600 / 60) as u16,
+        wSecond: (time % 60) as u16,
+        wMilliseconds: 0,
+    };
+    // SAFETY: both times are valid SYSTEMTIMEs, and a null time zone is the current one
+    let mut local: SYSTEMTIME = unsafe { std::mem::zeroed() };
+    if unsafe { SystemTimeToTzSpecificLocalTime(std::ptr::null(), &utc, &mut local) } == 0 {
+        return 0;
+    }
+    let local = days_from_civil(local.wYear as i64, local.wMonth as i64, local.wDay as i64) * 86_400
+        + local.wHour as i64 * 3600
+        + local.wMinute as i64 * 60
+        + local.wSecond as i64;
+    local - at
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-10 12:00 UTC, a Sunday
+    const NOW: i64 = 1_710_072_000;
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(days_from_civil(2024, 3, 10) * 86_400 + 12 * 3600, NOW);
+        assert_eq!((days_in_month(2024, 2), days_in_month(2023, 2), days_in_month(2024, 12)), (29, 28, 31));
+    }
+
+    #[test]
+    fn test_start_at() {
+        let utc = |_| 0;
+        assert_eq!(StartAt::parse("02:00").unwrap().next_after(NOW, &utc), NOW + 14 * 3600);
+        assert_eq!(StartAt::parse("18:30:15").unwrap().next_after(NOW, &utc), NOW + 6 * 3600 + 30 * 60 + 15);
+        // In UTC+1, 14:00 local is 13:00 UTC
+        assert_eq!(StartAt::parse("14:00").unwrap().next_after(NOW, &|_| 3600), NOW + 3600);
+        assert_eq!(StartAt::parse("2024-03-11 08:00").unwrap().next_after(NOW, &utc), NOW + 20 * 3600);
+        assert_eq!(StartAt::parse("2024-03-11T08:00").unwrap(), StartAt::parse("2024-03-11 08:00").unwrap());
+        // A date that passed starts right away
+        assert_eq!(StartAt::parse("2020-01-01 00:00").unwrap().next_after(NOW, &utc), NOW);
+        for invalid in ["2:00", "24:00", "12:60", "2024-02-30 10:00", "tomorrow"] {
+            assert!(StartAt::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_cron() {
+        let utc = |_| 0;
+        let next = |expression: &str| Cron::parse(expression).unwrap().next_after(NOW, &utc).map(|at| at - NOW);
+        assert_eq!(next("0 2 * * *"), Some(14 * 3600));
+        assert_eq!(next("*/15 * * * *"), Some(15 * 60));
+        assert_eq!(next("@hourly"), Some(3600));
+        // Monday to Friday only: the next day is a Monday
+        assert_eq!(next("30 1 * * mon-fri"), Some(13 * 3600 + 30 * 60));
+        // Sunday is 0 and 7, so today still matches
+        assert_eq!(next("0 13 * * 7"), Some(3600));
+        // Either day field matches when both are restricted
+        assert_eq!(next("0 0 1 * sat"), Some(6 * 86_400 - 12 * 3600));
+        assert_eq!(next("0 0 29 feb *").map(|at| civil_from_days((NOW + at) / 86_400)), Some((2028, 2, 29)));
+        assert_eq!(next("0 0 1 jan,jul *").map(|at| civil_from_days((NOW + at) / 86_400)), Some((2024, 7, 1)));
+        for invalid in ["0 2 * *", "60 * * * *", "0 0 31 feb *", "5-1 * * * *", "*/0 * * * *", "0 0 * foo *"] {
+            assert!(Cron::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_recurring() {
+        let schedule = Recurring::parse("0 3 * * sun https://a/nightly.iso https://b/nightly.iso").unwrap();
+        assert_eq!(schedule.cron, Cron::parse("0 3 * * 0").unwrap());
+        let request = schedule.request();
+        assert_eq!((request.urls.len(), request.out.as_deref()), (2, Some("nightly.iso")));
+        assert_eq!(Recurring::parse("@daily https://a/x").unwrap().urls, ["https://a/x"]);
+        assert!(Recurring::parse("0 3 * * sun").is_err());
+        assert!(Recurring::parse("0 3 * * sun not-a-url").is_err());
+    }
+}
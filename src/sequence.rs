@@ -0,0 +1,185 @@
+// Most URLs one pattern may expand into, so a typo cannot start millions of downloads
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// One range of a URL pattern, e.g. `[001-120]`, `[a-z]`, `[0-100:10]` or `{eu,us}`
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// Numbers from `start` to `end` in steps of `step`, zero-padded to `width` digits
+    Numeric { start: u64, end: u64, step: u64, width: usize },
+    /// Letters from `start` to `end` in steps of `step`
+    Alpha { start: u8, end: u8, step: u8 },
+    /// The words of a brace expansion like `{eu,us,asia}`
+    List(Vec<String>),
+}
+
+impl Range {
+    // Parse the text between the brackets, or None if it is not a range
+    fn parse(spec: &str) -> Option<Range> {
+        let (bounds, step) = match spec.split_once(':') {
+            Some((bounds, step)) => (bounds, Some(step)),
+            None => (spec, None),
+        };
+        let (start, end) = bounds.split_once('-')?;
+        if let (Ok(first), Ok(last)) = (start.parse::<u64>(), end.parse::<u64>()) {
+            let step = step.map_or(Some(1), |step| step.parse().ok())?;
+            // A leading zero asks for every number to be as wide as the first one
+            let width = if start.len() > 1 && start.starts_with('0') { start.len() } else { 0 };
+            return (first <= last && step > 0).then_some(Range::Numeric { start: first, end: last, step, width });
+        }
+        let (&[first], &[last]) = (start.as_bytes(), end.as_bytes()) else {
+            return None;
+        };
+        let step = step.map_or(Some(1), |step| step.parse().ok())?;
+        let same_case = (first.is_ascii_lowercase() && last.is_ascii_lowercase()) || (first.is_ascii_uppercase() && last.is_ascii_uppercase());
+        (same_case && first <= last && step > 0).then_some(Range::Alpha { start: first, end: last, step })
+    }
+
+    // Parse the text between the braces, or None if it is not a list of words
+    fn parse_list(spec: &str) -> Option<Range> {
+        spec.contains(',').then(|| Range::List(spec.split(',').map(str::to_string).collect()))
+    }
+
+    // Every value of the range, in order
+    fn values(&self) -> Vec<String> {
+        match self {
+            &Range::Numeric { start, end, step, width } => {
+                (start..=end).step_by(step as usize).take(MAX_EXPANSIONS + 1).map(|n| format!("{:0width$}", n, width = width)).collect()
+            }
+            &Range::Alpha { start, end, step } => (start..=end).step_by(step as usize).map(|c| (c as char).to_string()).collect(),
+            Range::List(words) => words.clone(),
+        }
+    }
+}
+
+/// One URL a pattern expanded into, with the values its ranges took, for `#1`, `#2`, ... in the output
+#[derive(Debug, PartialEq)]
+pub struct Expansion {
+    pub url: String,
+    pub values: Vec<String>,
+}
+
+/// Expands the curl-style ranges of `url`, e.g. `https://host/part[001-120].bin` into 120 URLs.
+///
+/// Numeric ranges keep the zero-padding of their first number, `[a-z]` counts letters and `:N`
+/// sets a step; `{eu,us}` lists the words to use in turn. Several ranges combine, the first one
+/// changing slowest. Brackets in the host, as around an IPv6 address, and brackets or braces that
+/// do not hold a range are kept as they are. Returns `None` for a URL without ranges.
+pub fn expand(url: &str) -> Result<Option<Vec<Expansion>>, String> {
+    // The path starts at the first slash after the scheme and host
+    let path_start = url.find("://").map_or(0, |scheme| url[scheme + 3..].find('/').map_or(url.len(), |slash| scheme + 3 + slash));
+    let mut pieces = vec![url[..path_start].to_string()];
+    let mut ranges = Vec::new();
+    let mut rest = &url[path_start..];
+    while let Some(open) = rest.find(['[', '{']) {
+        let (closing, parse): (char, fn(&str) -> Option<Range>) = match rest.as_bytes()[open] {
+            b'[' => (']', Range::parse),
+            _ => ('}', Range::parse_list),
+        };
+        let Some(close) = rest[open..].find(closing).map(|close| open + close) else {
+            pieces.last_mut().expect("there is always a piece").push_str(&rest[..=open]);
+            rest = &rest[open + 1..];
+            continue;
+        };
+        match parse(&rest[open + 1..close]) {
+            Some(range) => {
+                pieces.last_mut().expect("there is always a piece").push_str(&rest[..open]);
+                ranges.push(range.values());
+                pieces.push(String::new());
+            }
+            None => pieces.last_mut().expect("there is always a piece").push_str(&rest[..=close]),
+        }
+        rest = &rest[close + 1..];
+    }
+    pieces.last_mut().expect("there is always a piece").push_str(rest);
+    if ranges.is_empty() {
+        return Ok(None);
+    }
+    let count = ranges.iter().try_fold(1usize, |count, values| count.checked_mul(values.len())).filter(|&count| count <= MAX_EXPANSIONS);
+    if count.is_none() {
+        return Err(format!("{} expands into more than {} URLs", url, MAX_EXPANSIONS));
+    }
+
+    // Count through the ranges like an odometer, the last one changing fastest
+    let mut expansions = Vec::new();
+    let mut positions = vec![0; ranges.len()];
+    loop {
+        let values: Vec<String> = positions.iter().zip(&ranges).map(|(&position, values)| values[position].clone()).collect();
+        let mut url = pieces[0].clone();
+        for (value, piece) in values.iter().zip(&pieces[1..]) {
+            url.push_str(value);
+            url.push_str(piece);
+        }
+        expansions.push(Expansion { url, values });
+        let Some(changing) = (0..ranges.len()).rev().find(|&index| positions[index] + 1 < ranges[index].len()) else {
+            return Ok(Some(expansions));
+        };
+        positions[changing] += 1;
+        positions[changing + 1..].fill(0);
+    }
+}
+
+/// Replaces `#1`, `#2`, ... in the output `template` with the values of the ranges of an expansion.
+///
+/// A `#` not followed by the number of a range is kept.
+pub fn fill(template: &str, values: &[String]) -> String {
+    let mut output = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        let value = match (c, chars.peek().and_then(|digit| digit.to_digit(10))) {
+            ('#', Some(number)) => values.get((number as usize).wrapping_sub(1)),
+            _ => None,
+        };
+        match value {
+            Some(value) => {
+                output.push_str(value);
+                chars.next();
+            }
+            None => output.push(c),
+        }
+    }
+    output
+}
+
+/// Returns whether the output `template` refers to the values of the ranges, one file per URL.
+pub fn is_template(template: &str) -> bool {
+    template.as_bytes().windows(2).any(|pair| pair[0] == b'#' && (b'1'..=b'9').contains(&pair[1]))
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The URLs `url` expands into
+    fn urls(url: &str) -> Vec<String> {
+        expand(url).unwrap().unwrap().into_iter().map(|expansion| expansion.url).collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(urls("https://host/part[001-003].bin"), ["https://host/part001.bin", "https://host/part002.bin", "https://host/part003.bin"]);
+        assert_eq!(urls("http://host/[0-20:10]"), ["http://host/0", "http://host/10", "http://host/20"]);
+        assert_eq!(urls("http://host/[1-2]/[a-b]"), ["http://host/1/a", "http://host/1/b", "http://host/2/a", "http://host/2/b"]);
+        assert_eq!(urls("http://host/[A-E:2].txt"), ["http://host/A.txt", "http://host/C.txt", "http://host/E.txt"]);
+
+        // Brackets in the host and brackets without a range are not patterns
+        assert_eq!(expand("http://[::1]:8080/file[x].bin").unwrap(), None);
+        assert_eq!(urls("http://[::1]/[9-10]"), ["http://[::1]/9", "http://[::1]/10"]);
+        assert!(expand("http://host/[1-1000000]").is_err());
+        assert_eq!(expand("http://host/[5-1]").unwrap(), None);
+
+        // Braces list words, and combine with ranges
+        assert_eq!(urls("http://host/{eu,us}/[1-2].csv"), ["http://host/eu/1.csv", "http://host/eu/2.csv", "http://host/us/1.csv", "http://host/us/2.csv"]);
+        assert_eq!(urls("http://host/[{x}/{a,b}"), ["http://host/[{x}/a", "http://host/[{x}/b"]);
+    }
+
+    #[test]
+    fn test_fill() {
+        let expansion = expand("https://host/[01-02]/[a-b].bin").unwrap().unwrap().remove(1);
+        assert_eq!(expansion.values, ["01", "b"]);
+        assert_eq!(fill("out/#1-#2.bin", &expansion.values), "out/01-b.bin");
+        assert_eq!(fill("#3 #x #", &expansion.values), "#3 #x #");
+        assert!(is_template("part#1.bin"));
+        assert!(!is_template("part#.bin"));
+    }
+}
@@ -0,0 +1,697 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_rustls::TlsAcceptor;
+use crate::daemon::Queue;
+use crate::error::AppError;
+use crate::rest::{self, Reply};
+use crate::rpc::{self, Caller};
+use crate::users::{self, Identity, Users};
+
+// Largest request line and headers, and largest body, the daemon reads
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Most header lines of a request
+const MAX_HEADERS: usize = 100;
+
+// How long a client has for the TLS handshake, and for each request once it started or the connection is idle
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often a progress stream of the REST API sends the state of its download
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Appended to the key of a WebSocket handshake before hashing it (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// WebSocket opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Random bytes of a generated secret
+const SECRET_SIZE: usize = 16;
+
+/// How the interface of the daemon accepts its clients
+#[derive(Clone)]
+pub struct Access {
+    /// The token every call must carry, like aria2 --rpc-secret
+    pub secret: String,
+    /// Origins of the web pages that may call the interface, e.g. `http://localhost:8080`
+    pub allowed_origins: Vec<String>,
+    /// Whether web pages of any origin may call the interface, like aria2 --rpc-allow-origin-all
+    pub allow_origin_all: bool,
+    /// Addresses clients may connect from, any when empty
+    pub allowed_ips: Vec<IpRange>,
+    /// The users of --users, who pass their own token
+    pub users: Arc<Users>,
+}
+
+impl Access {
+    /// Returns a random secret for a daemon started without --rpc-secret, as 32 hexadecimal digits.
+    pub fn generate_secret() -> std::io::Result<String> {
+        let mut bytes = [0; SECRET_SIZE];
+        getrandom::getrandom(&mut bytes).map_err(std::io::Error::other)?;
+        Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Returns who a client passing `token` is: the admin with the secret, a user of --users with
+    /// theirs, or None when it may not call the interface.
+    ///
+    /// Tokens are compared in constant time, so the time taken does not tell how much of one matched.
+    pub fn identify(&self, token: Option<&str>) -> Option<Identity> {
+        let token = token?;
+        match users::same_secret(token, &self.secret) {
+            true => Some(Identity::Admin),
+            false => self.users.by_token(token).map(|user| Identity::User(user.name.clone())),
+        }
+    }
+
+    /// Returns who makes the HTTP `request`, from the token it passes as `Authorization: Bearer <token>`
+    /// or, for browsers, as `?token=<token>`.
+    pub fn identify_request(&self, request: &HttpRequest) -> Option<Identity> {
+        let bearer = request.header("authorization").and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+        let token = request.query.as_deref().and_then(|query| url::form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "token").map(|(_, value)| value.into_owned()));
+        self.identify(bearer).or_else(|| self.identify(token.as_deref()))
+    }
+
+    /// Returns whether a request with the Origin header `origin` may be answered.
+    ///
+    /// Browsers send one with the requests of web pages, which could otherwise make any page the
+    /// user visits call the daemon. Other clients send none.
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        origin.is_none_or(|origin| self.allow_origin_all || self.allowed_origins.iter().any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)))
+    }
+
+    /// Returns whether a client connecting from `ip` may reach the interface.
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        self.allowed_ips.is_empty() || self.allowed_ips.iter().any(|range| range.contains(ip))
+    }
+
+    // The CORS header letting the web page of `origin` read the answer, if it may
+    fn cors(&self, origin: Option<&str>) -> Option<(&'static str, String)> {
+        match origin {
+            _ if self.allow_origin_all => Some(("Access-Control-Allow-Origin", "*".to_string())),
+            Some(origin) if self.allows_origin(Some(origin)) => Some(("Access-Control-Allow-Origin", origin.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Addresses clients may connect from, a single address like `192.168.1.20` or a network like `192.168.1.0/24`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Parses an address, or a network as an address with the length of its prefix.
+    pub fn parse(value: &str) -> Result<IpRange, String> {
+        let invalid = || format!("invalid address {}, expected an IP address like 192.168.1.20 or a network like 192.168.1.0/24", value);
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(IpRange { network, prefix })
+    }
+
+    /// Returns whether `ip` is inside the range, an IPv4 address mapped to IPv6 counting as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let masked = |bits: u128, width: u8| match self.prefix {
+            0 => 0,
+            prefix => bits >> (width - prefix),
+        };
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => masked(u32::from(network).into(), 32) == masked(u32::from(ip).into(), 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => masked(network.into(), 128) == masked(ip.into(), 128),
+            _ => false,
+        }
+    }
+}
+
+/// Loads the certificate chain and private key the interface serves HTTPS and secure WebSocket with,
+/// both PEM files as made by Let's Encrypt or openssl.
+pub fn tls_acceptor(certificate: &Path, private_key: &Path) -> Result<TlsAcceptor, AppError> {
+    let invalid = |path: &Path, message: String| AppError::StringError(format!("could not load {}: {}", path.display(), message));
+    let chain = CertificateDer::pem_file_iter(certificate).and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>()).map_err(|e| invalid(certificate, e.to_string()))?;
+    if chain.is_empty() {
+        return Err(invalid(certificate, "no certificate found".to_string()));
+    }
+    let key = PrivateKeyDer::from_pem_file(private_key).map_err(|e| invalid(private_key, e.to_string()))?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| invalid(private_key, e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Returns an error when the interface would listen on `address` beyond this machine without TLS.
+///
+/// The secret and the calls would cross the network in clear, so only loopback addresses may be
+/// served over plain HTTP.
+pub fn check_listen(address: SocketAddr, tls: bool) -> Result<(), AppError> {
+    match tls || address.ip().to_canonical().is_loopback() {
+        true => Ok(()),
+        false => Err(AppError::StringError(format!("listening on {} needs TLS, pass --rpc-certificate and --rpc-private-key, or listen on 127.0.0.1", address))),
+    }
+}
+
+/// An HTTP request to the daemon
+pub struct HttpRequest {
+    pub method: String,
+    /// The path, without the query
+    pub path: String,
+    pub query: Option<String>,
+    /// The headers, with lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the value of the header `name`, given in lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Serves the JSON-RPC interface of aria2 on `listener` at `/jsonrpc`, over HTTP POST and WebSocket,
+/// and the REST API of [`rest::route`] at `/downloads`.
+///
+/// WebSocket clients also receive the notifications of aria2, like `aria2.onDownloadComplete`.
+/// Requests of web pages whose origin `access` does not allow are refused, whatever they ask for,
+/// and clients connecting from an address it does not allow are disconnected at once. With `tls`
+/// every connection is HTTPS, or secure WebSocket.
+pub async fn serve(listener: TcpListener, queue: Arc<Queue>, access: Access, tls: Option<TlsAcceptor>) {
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        if !access.allows_ip(peer.ip()) {
+            tracing::debug!("Connection from {} refused, the address is not allowed", peer);
+            continue;
+        }
+        let (queue, access, tls) = (queue.clone(), access.clone(), tls.clone());
+        tokio::spawn(async move {
+            let served = match tls {
+                Some(tls) => match tokio::time::timeout(REQUEST_TIMEOUT, tls.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let (reader, writer) = tokio::io::split(stream);
+                        connection(BufReader::new(reader), writer, &queue, &access).await
+                    }
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out")),
+                },
+                None => {
+                    let (reader, writer) = stream.into_split();
+                    connection(BufReader::new(reader), writer, &queue, &access).await
+                }
+            };
+            if let Err(e) = served {
+                tracing::debug!("Connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// Answer the requests of one connection until it closes, or hand it over to WebSocket
+async fn connection<R, W>(mut reader: BufReader<R>, mut writer: W, queue: &Queue, access: &Access) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(request) = read_request(&mut reader).await? {
+        let origin = request.header("origin");
+        if !access.allows_origin(origin) {
+            writer.write_all(&response("403 Forbidden", "text/plain", b"origin not allowed", &[Some(("Vary", "Origin".to_string()))])).await?;
+            continue;
+        }
+        let cors = access.cors(origin);
+        let response = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/jsonrpc") if request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) => {
+                let Some(key) = request.header("sec-websocket-key") else {
+                    writer.write_all(&response("400 Bad Request", "text/plain", b"missing Sec-WebSocket-Key", &[])).await?;
+                    continue;
+                };
+                let accept = base64::engine::general_purpose::STANDARD.encode(Sha1::digest(format!("{}{}", key.trim(), WEBSOCKET_GUID)));
+                let handshake = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+                writer.write_all(handshake.as_bytes()).await?;
+                return websocket(reader, writer, queue, access, access.identify_request(&request)).await;
+            }
+            ("POST", "/jsonrpc") => {
+                let body = String::from_utf8_lossy(&request.body);
+                match rpc::handle(queue, &Caller::Network(access), &body) {
+                    Some(answer) => response("200 OK", "application/json", answer.as_bytes(), &[cors]),
+                    None => response("204 No Content", "text/plain", b"", &[cors]),
+                }
+            }
+            ("OPTIONS", _) if cors.is_some() => response(
+                "204 No Content",
+                "text/plain",
+                b"",
+                &[cors, Some(("Access-Control-Allow-Methods", "GET, POST, DELETE, OPTIONS".to_string())), Some(("Access-Control-Allow-Headers", "Content-Type, Authorization".to_string()))],
+            ),
+            _ => match rest::route(queue, access, &request) {
+                Some(Reply::Json(status, value)) => response(status, "application/json", value.to_string().as_bytes(), &[cors]),
+                Some(Reply::Empty(status)) => response(status, "text/plain", b"", &[cors]),
+                Some(Reply::Progress(gid)) => return progress_stream(writer, queue, &gid, cors).await,
+                None => response("404 Not Found", "text/plain", b"not found", &[]),
+            },
+        };
+        writer.write_all(&response).await?;
+    }
+    Ok(())
+}
+
+/// Reads the next request of a connection, or None once the client closed it.
+///
+/// A request that does not arrive in full within a while, or whose head is too large, is an error.
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> std::io::Result<Option<HttpRequest>> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, read_request_in_time(reader)).await {
+        Ok(request) => request,
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no complete request in time")),
+    }
+}
+
+async fn read_request_in_time<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> std::io::Result<Option<HttpRequest>> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+    let mut head = Vec::new();
+    // Bytes of the head read so far, empty lines before it included
+    let mut total = 0;
+    loop {
+        let mut line = Vec::new();
+        let read = (&mut *reader).take((MAX_HEAD_SIZE - total) as u64).read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            return match head.is_empty() && total == 0 {
+                true => Ok(None),
+                false => Err(invalid("incomplete request")),
+            };
+        }
+        total += read;
+        if !line.ends_with(b"\n") {
+            return Err(invalid("request head too long"));
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        if line.is_empty() {
+            if head.is_empty() {
+                continue;
+            }
+            break;
+        }
+        if head.len() > MAX_HEADERS {
+            return Err(invalid("too many header lines"));
+        }
+        head.push(line);
+    }
+    let mut request_line = head[0].split_whitespace();
+    let (method, target) = (request_line.next().unwrap_or_default().to_string(), request_line.next().unwrap_or("/"));
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    let headers: Vec<(String, String)> =
+        head[1..].iter().filter_map(|line| line.split_once(':')).map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string())).collect();
+    let length = headers.iter().find(|(name, _)| name == "content-length").map_or(Ok(0), |(_, value)| value.parse::<usize>()).map_err(|_| invalid("invalid Content-Length"))?;
+    if length > MAX_BODY_SIZE {
+        return Err(invalid("request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(HttpRequest { method, path, query, headers, body }))
+}
+
+/// Renders an HTTP response with `body` and the `headers` given.
+pub fn response(status: &str, content_type: &str, body: &[u8], headers: &[Option<(&str, String)>]) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n", status, content_type, body.len());
+    for (name, value) in headers.iter().flatten() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let mut response = head.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// Send the state of the download `gid` as a server-sent event every second, and once more when it stops
+// The connection closes with the stream
+async fn progress_stream<W: AsyncWrite + Unpin>(mut writer: W, queue: &Queue, gid: &str, cors: Option<(&str, String)>) -> std::io::Result<()> {
+    let mut head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n".to_string();
+    if let Some((name, value)) = cors {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(status) = queue.status(gid) else {
+            return Ok(());
+        };
+        let stopped = status["status"].as_str().is_some_and(|status| matches!(status, "complete" | "error" | "removed"));
+        let event = if stopped { "end" } else { "progress" };
+        writer.write_all(format!("event: {}\ndata: {}\n\n", event, rest::download(&status)).as_bytes()).await?;
+        writer.flush().await?;
+        if stopped {
+            return Ok(());
+        }
+    }
+}
+
+// Answer the JSON-RPC messages of a WebSocket client, and send it the notifications of the queue
+// Notifications only go to a client that passed a token, in the handshake or in its first call, and only
+// about the downloads it sees
+async fn websocket<R, W>(mut reader: BufReader<R>, mut writer: W, queue: &Queue, access: &Access, mut identity: Option<Identity>) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut events = queue.subscribe();
+    let mut message = Vec::new();
+    loop {
+        tokio::select! {
+            received = read_frame(&mut reader) => {
+                let (fin, opcode, payload) = received?;
+                match opcode {
+                    TEXT | CONTINUATION => {
+                        message.extend_from_slice(&payload);
+                        if message.len() > MAX_BODY_SIZE {
+                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "message too large"));
+                        }
+                        if fin {
+                            let text = String::from_utf8_lossy(&std::mem::take(&mut message)).into_owned();
+                            if identity.is_none() {
+                                identity = rpc::identify(access, &text);
+                            }
+                            if let Some(answer) = rpc::handle(queue, &Caller::Network(access), &text) {
+                                writer.write_all(&frame(TEXT, answer.as_bytes())).await?;
+                            }
+                        }
+                    }
+                    PING => writer.write_all(&frame(PONG, &payload)).await?,
+                    CLOSE => {
+                        writer.write_all(&frame(CLOSE, &payload)).await?;
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) if identity.as_ref().is_some_and(|identity| queue.visible(&event.gid, identity.user())) => {
+                    writer.write_all(&frame(TEXT, rpc::notification(event.method, &event.gid).as_bytes())).await?
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+// Read one frame from a client: whether it ends its message, its opcode and its unmasked payload
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let (fin, opcode, masked) = (header[0] & 0x80 != 0, header[0] & 0x0F, header[1] & 0x80 != 0);
+    let length = match header[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        length => length as u64,
+    };
+    if length > MAX_BODY_SIZE as u64 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload).await?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+// A whole, unmasked frame from the server
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tokio::net::TcpStream;
+    use crate::daemon::Request;
+    use crate::test_server;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let queue = Arc::new(Queue::new(Path::new("downloads"), 1));
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: vec!["http://localhost:8080".to_string()], allow_origin_all: false, allowed_ips: Vec::new(), users: Arc::default() };
+        tokio::spawn(serve(listener, queue.clone(), access, None));
+
+        let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        let body = r#"{"jsonrpc":"2.0","id":"1","method":"aria2.addUri","params":["token:s3cret",["http://a/1.iso"]]}"#;
+        writer.write_all(format!("POST /jsonrpc HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).await.unwrap();
+        let mut status = String::new();
+        reader.read_line(&mut status).await.unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+
+        // The handshake example of RFC 6455, then a masked call and the notification of the queue
+        let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"GET /jsonrpc HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").await.unwrap();
+        let mut handshake = String::new();
+        while !handshake.ends_with("\r\n\r\n") {
+            reader.read_line(&mut handshake).await.unwrap();
+        }
+        assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        let call = br#"{"jsonrpc":"2.0","id":2,"method":"aria2.tellStatus","params":["token:s3cret","0000000000000001",["status"]]}"#;
+        let mask = [1, 2, 3, 4];
+        let mut masked = vec![0x81, 0x80 | call.len() as u8];
+        masked.extend_from_slice(&mask);
+        masked.extend(call.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+        writer.write_all(&masked).await.unwrap();
+        let (fin, opcode, answer) = read_frame(&mut reader).await.unwrap();
+        assert_eq!((fin, opcode), (true, TEXT));
+        assert_eq!(String::from_utf8(answer).unwrap(), r#"{"id":2,"jsonrpc":"2.0","result":{"status":"waiting"}}"#);
+
+        queue.next_start().unwrap();
+        let (_, _, notification) = read_frame(&mut reader).await.unwrap();
+        assert_eq!(String::from_utf8(notification).unwrap(), r#"{"jsonrpc":"2.0","method":"aria2.onDownloadStart","params":[{"gid":"0000000000000001"}]}"#);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_head() {
+        // Lines within the limit add up to more than the head may take
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..MAX_HEADERS / 2 {
+            request.extend(format!("X: {}\r\n", "y".repeat(MAX_HEAD_SIZE / MAX_HEADERS * 4)).as_bytes());
+        }
+        let Err(error) = read_request(&mut BufReader::new(request.as_slice())).await else {
+            panic!("an oversized head was read");
+        };
+        assert_eq!(error.to_string(), "request head too long");
+
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        request.extend(b"X: y\r\n".repeat(MAX_HEADERS + 1));
+        request.extend(b"\r\n");
+        let Err(error) = read_request(&mut BufReader::new(request.as_slice())).await else {
+            panic!("a head with too many lines was read");
+        };
+        assert_eq!(error.to_string(), "too many header lines");
+
+        let request = b"GET / HTTP/1.1\r\nX: y\r\n\r\n";
+        assert_eq!(read_request(&mut BufReader::new(&request[..])).await.unwrap().unwrap().headers, [("x".to_string(), "y".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_origin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let queue = Arc::new(Queue::new(Path::new("downloads"), 1));
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: vec!["http://localhost:8080".to_string()], allow_origin_all: false, allowed_ips: Vec::new(), users: Arc::default() };
+        tokio::spawn(serve(listener, queue.clone(), access, None));
+
+        // The simple POST any web page can send is refused before it is read, and one from an allowed page is answered
+        let body = r#"{"jsonrpc":"2.0","id":"1","method":"aria2.addUri","params":["token:s3cret",["http://a/1.iso"]]}"#;
+        for (origin, expected) in [("http://evil.example", "HTTP/1.1 403 Forbidden\r\n"), ("http://localhost:8080", "HTTP/1.1 200 OK\r\n")] {
+            let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+            let mut reader = BufReader::new(reader);
+            let request = format!("POST /jsonrpc HTTP/1.1\r\nHost: x\r\nOrigin: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}", origin, body.len(), body);
+            writer.write_all(request.as_bytes()).await.unwrap();
+            let mut status = String::new();
+            reader.read_line(&mut status).await.unwrap();
+            assert_eq!(status, expected);
+        }
+        assert_eq!(queue.gids(|_| true).len(), 1);
+    }
+
+    #[test]
+    fn test_access() {
+        let users = Arc::new(Users::parse("[users.alice]\ntoken = \"t0ken\"").unwrap());
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: vec!["http://localhost:8080/".to_string()], allow_origin_all: false, allowed_ips: Vec::new(), users };
+        assert_eq!(access.identify(Some("s3cret")), Some(Identity::Admin));
+        assert_eq!(access.identify(Some("t0ken")), Some(Identity::User("alice".to_string())));
+        assert!(access.identify(Some("s3cre")).is_none() && access.identify(Some("s3cret!")).is_none() && access.identify(None).is_none());
+        assert!(Access { secret: String::new(), ..access.clone() }.identify(Some("")).is_none());
+        assert!(access.allows_origin(None) && access.allows_origin(Some("http://localhost:8080")));
+        assert!(!access.allows_origin(Some("http://localhost:8081")));
+        assert_eq!(access.cors(Some("http://localhost:8080")), Some(("Access-Control-Allow-Origin", "http://localhost:8080".to_string())));
+        assert!(Access { allow_origin_all: true, ..access }.allows_origin(Some("http://evil.example")));
+        assert_eq!(Access::generate_secret().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let network = IpRange::parse("192.168.1.0/24").unwrap();
+        assert!(network.contains("192.168.1.77".parse().unwrap()) && network.contains("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!network.contains("192.168.2.1".parse().unwrap()) && !network.contains("::1".parse().unwrap()));
+        assert!(IpRange::parse("fd00::/8").unwrap().contains("fd12::1".parse().unwrap()));
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(IpRange::parse("10.0.0.1").unwrap(), IpRange::parse("10.0.0.1/32").unwrap());
+        for invalid in ["10.0.0.0/33", "example.com", "10.0.0.0/", "::1/129"] {
+            assert!(IpRange::parse(invalid).is_err(), "{} was accepted", invalid);
+        }
+
+        assert!(check_listen("127.0.0.1:6800".parse().unwrap(), false).is_ok() && check_listen("[::1]:6800".parse().unwrap(), false).is_ok());
+        assert!(check_listen("0.0.0.0:6800".parse().unwrap(), false).is_err() && check_listen("192.168.1.2:6800".parse().unwrap(), false).is_err());
+        assert!(check_listen("0.0.0.0:6800".parse().unwrap(), true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let dir = crate::test_server::temp_dir("server-tls");
+        let (certificate, private_key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&certificate, test_server::CERTIFICATE).unwrap();
+        std::fs::write(&private_key, test_server::PRIVATE_KEY).unwrap();
+        assert!(tls_acceptor(&private_key, &private_key).is_err());
+        let tls = tls_acceptor(&certificate, &private_key).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let queue = Arc::new(Queue::new(Path::new("downloads"), 1));
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: Vec::new(), allow_origin_all: false, allowed_ips: Vec::new(), users: Arc::default() };
+        tokio::spawn(serve(listener, queue.clone(), access, Some(tls)));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(test_server::CERTIFICATE.as_bytes()).unwrap()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider())).with_safe_default_protocol_versions().unwrap().with_root_certificates(roots).with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = connector.connect("localhost".try_into().unwrap(), TcpStream::connect(address).await.unwrap()).await.unwrap();
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"GET /downloads HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer s3cret\r\n\r\n").await.unwrap();
+        let mut status = String::new();
+        reader.read_line(&mut status).await.unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+
+        // A client speaking plain HTTP gets no answer
+        let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        writer.write_all(b"GET /downloads HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut answer = String::new();
+        assert!(BufReader::new(reader).read_line(&mut answer).await.is_err() || !answer.starts_with("HTTP"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_ips() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let queue = Arc::new(Queue::new(Path::new("downloads"), 1));
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: Vec::new(), allow_origin_all: false, allowed_ips: vec![IpRange::parse("10.0.0.0/8").unwrap()], users: Arc::default() };
+        tokio::spawn(serve(listener, queue, access, None));
+
+        // The connection is closed before anything is read
+        let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let _ = writer.write_all(b"GET /downloads HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer s3cret\r\n\r\n").await;
+        let mut answer = String::new();
+        assert!(BufReader::new(reader).read_line(&mut answer).await.is_err() || answer.is_empty());
+    }
+    // A WebSocket client of `address` at `target`, once the handshake is done
+    async fn websocket_client(address: SocketAddr, target: &str) -> (BufReader<tokio::net::tcp::OwnedReadHalf>, tokio::net::tcp::OwnedWriteHalf) {
+        let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        let handshake = format!("GET {} HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", target);
+        writer.write_all(handshake.as_bytes()).await.unwrap();
+        let mut answer = String::new();
+        while !answer.ends_with("\r\n\r\n") {
+            reader.read_line(&mut answer).await.unwrap();
+        }
+        (reader, writer)
+    }
+
+    // Make a call over WebSocket and return its answer
+    async fn websocket_call(reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>, writer: &mut tokio::net::tcp::OwnedWriteHalf, call: &str) -> String {
+        let mut masked = vec![0x81, 0x80 | call.len() as u8, 0, 0, 0, 0];
+        masked.extend_from_slice(call.as_bytes());
+        writer.write_all(&masked).await.unwrap();
+        String::from_utf8(read_frame(reader).await.unwrap().2).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_notifications_per_user() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let queue = Arc::new(Queue::new(Path::new("downloads"), 2));
+        let users = Users::parse("[users.alice]\ntoken = \"a1ice\"\n[users.bob]\ntoken = \"b0b\"").unwrap();
+        let access = Access { secret: "s3cret".to_string(), allowed_origins: Vec::new(), allow_origin_all: false, allowed_ips: Vec::new(), users: Arc::new(users) };
+        tokio::spawn(serve(listener, queue.clone(), access, None));
+        for owner in ["alice", "bob"] {
+            queue.add(Request { urls: vec![format!("http://a/{}.iso", owner)], owner: Some(owner.to_string()), ..Request::default() }).unwrap();
+        }
+
+        // Alice passes her token in the handshake, Bob in his first call, and the last client passes none
+        // Each call is answered once the client is subscribed to the notifications
+        let list = r#"{"jsonrpc":"2.0","id":1,"method":"system.listMethods"}"#;
+        let (mut alice, mut alice_writer) = websocket_client(address, "/jsonrpc?token=a1ice").await;
+        websocket_call(&mut alice, &mut alice_writer, list).await;
+        let (mut bob, mut bob_writer) = websocket_client(address, "/jsonrpc").await;
+        websocket_call(&mut bob, &mut bob_writer, r#"{"jsonrpc":"2.0","id":1,"method":"aria2.getGlobalStat","params":["token:b0b"]}"#).await;
+        let (mut anonymous, mut anonymous_writer) = websocket_client(address, "/jsonrpc").await;
+        websocket_call(&mut anonymous, &mut anonymous_writer, list).await;
+
+        queue.next_start().unwrap();
+        queue.next_start().unwrap();
+        let start = |gid: &str| format!(r#"{{"jsonrpc":"2.0","method":"aria2.onDownloadStart","params":[{{"gid":"{}"}}]}}"#, gid);
+        assert_eq!(String::from_utf8(read_frame(&mut alice).await.unwrap().2).unwrap(), start("0000000000000001"));
+        assert_eq!(String::from_utf8(read_frame(&mut bob).await.unwrap().2).unwrap(), start("0000000000000002"));
+        let quiet = Duration::from_millis(200);
+        assert!(tokio::time::timeout(quiet, read_frame(&mut alice)).await.is_err());
+        assert!(tokio::time::timeout(quiet, read_frame(&mut bob)).await.is_err());
+        assert!(tokio::time::timeout(quiet, read_frame(&mut anonymous)).await.is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::time::Instant;
+use indicatif::ProgressBar;
+use serde_json::json;
+use crate::args::CommandLineArgs;
+use crate::batch::Entry;
+use crate::crawler::{self, Crawler};
+use crate::downloader::{ClientOptions, Downloader, FileDownloader, RemoteFile, RequestSpec};
+use crate::error::AppError;
+use crate::url_validator::validate_url;
+
+/// The result of checking one URL
+pub struct Check {
+    pub url: String,
+    /// The page the URL was found in, with -r
+    pub referrer: Option<String>,
+    pub result: Result<RemoteFile, AppError>,
+}
+
+impl Check {
+    /// Renders the check as one line of JSON, with the size, type and final URL of the file or the error.
+    pub fn to_json(&self) -> String {
+        let value = match &self.result {
+            Ok(remote) => json!({
+                "url": self.url,
+                "ok": true,
+                "size": remote.size,
+                "content_type": remote.headers.get(reqwest::header::CONTENT_TYPE).and_then(|value| value.to_str().ok()),
+                "redirect": (remote.url.as_str() != self.url).then(|| remote.url.to_string()),
+                "referrer": self.referrer,
+            }),
+            Err(e) => json!({ "url": self.url, "ok": false, "error": e.to_string(), "referrer": self.referrer }),
+        };
+        value.to_string()
+    }
+}
+
+/// Checks the `entries` without downloading them, and with a `crawler` the links of every page too.
+///
+/// Every URL is probed like a download would be, and pages are read into memory for their links
+/// only. One line of JSON per URL goes to standard output, and the broken links to standard error.
+/// Returns the exit status: 1 when any link is broken, 0 otherwise.
+pub async fn check(args: &CommandLineArgs, entries: Vec<Entry>, mut crawler: Option<Crawler>) -> i32 {
+    let crawl_delay = crawler.as_ref().and_then(Crawler::crawl_delay);
+    let mut last_start: Option<Instant> = None;
+    let mut pending = VecDeque::from(entries);
+    let mut checks = Vec::new();
+    while let Some(entry) = pending.pop_front() {
+        if let (Some(delay), Some(last_start)) = (crawl_delay, last_start) {
+            tokio::time::sleep(delay.saturating_sub(last_start.elapsed())).await;
+        }
+        last_start = Some(Instant::now());
+        // Each URL gets the options of the command line with its own URL, which also scopes the credentials to its host
+        let mut entry_args = args.clone();
+        entry_args.url = vec![entry.url.clone()];
+        let result = probe(&entry_args, &entry.url, crawler.as_mut()).await.map(|(remote, links)| {
+            pending.extend(links);
+            remote
+        });
+        let referrer = crawler.as_ref().and_then(|crawler| crawler.referrer(&entry.url)).map(|url| url.to_string());
+        let check = Check { url: entry.url, referrer, result };
+        println!("{}", check.to_json());
+        checks.push(check);
+    }
+    eprint!("{}", summary(&checks));
+    i32::from(checks.iter().any(|check| check.result.is_err()))
+}
+
+// Probe `url` and, if it is a page whose links the crawler follows, read it for them
+async fn probe(args: &CommandLineArgs, url: &str, crawler: Option<&mut Crawler>) -> Result<(RemoteFile, Vec<Entry>), AppError> {
+    let valid_url = validate_url(url)?;
+    let downloader = FileDownloader::with_options(&ClientOptions::from_args(args)?)?;
+    let remote = downloader.probe(valid_url.as_str()).await?;
+    let Some(crawler) = crawler.filter(|crawler| crawler.follows(url)) else {
+        return Ok((remote, Vec::new()));
+    };
+    // A page is told by its Content-Type, or by its name and content when the server does not say
+    let content_type = remote.headers.get(reqwest::header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_ascii_lowercase();
+    let html = content_type.contains("html");
+    let untyped = content_type.is_empty() || content_type.starts_with("application/octet-stream");
+    if !(html || untyped && crawler::may_be_page(&valid_url)) {
+        return Ok((remote, Vec::new()));
+    }
+    let mut page = Vec::new();
+    let links = match downloader.download_whole(valid_url.as_str(), &RequestSpec::default(), &mut page, &ProgressBar::hidden(), Some(crawler::MAX_PAGE_SIZE)).await {
+        Ok(()) if html || crawler::looks_like_html(&page) => crawler.follow_page(url, &String::from_utf8_lossy(&page)),
+        Ok(()) => Vec::new(),
+        Err(e) => {
+            tracing::warn!("Could not read {} for its links: {}", url, e);
+            Vec::new()
+        }
+    };
+    Ok((remote, links))
+}
+
+/// Summarizes the checks: how many URLs were checked, and every broken one with the page linking to it.
+pub fn summary(checks: &[Check]) -> String {
+    let broken: Vec<&Check> = checks.iter().filter(|check| check.result.is_err()).collect();
+    let mut output = String::new();
+    let _ = writeln!(output, "{} URLs checked, {} broken", checks.len(), broken.len());
+    for check in broken {
+        let error = check.result.as_ref().err().map(ToString::to_string).unwrap_or_default();
+        let _ = match &check.referrer {
+            Some(referrer) => writeln!(output, "  {} (linked from {}): {}", check.url, referrer, error),
+            None => writeln!(output, "  {}: {}", check.url, error),
+        };
+    }
+    output
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filters;
+    use crate::test_server::{self, Quirks};
+    use argh::FromArgs;
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_probe_follows_pages() {
+        let page = b"<html><a href=\"other.html\">other</a></html>".to_vec();
+        let url = Url::parse(&test_server::serve_with(page.clone(), Quirks { content_type: Some("text/html"), ..Quirks::default() })).unwrap();
+        let args = CommandLineArgs::from_args(&["rtget"], &["--spider", "-u", url.as_str()]).unwrap();
+        let mut crawler = Crawler::new(&url, 1, Filters::default());
+        crawler.start(&url);
+        let (remote, links) = probe(&args, url.as_str(), Some(&mut crawler)).await.unwrap();
+        assert_eq!(remote.size, Some(page.len()));
+        assert_eq!(links.len(), 1);
+        assert_eq!(crawler.referrer(&links[0].url), Some(&url));
+
+        // Without a Content-Type the page is told by its name and content
+        let url = Url::parse(&test_server::serve(page)).unwrap().join("index").unwrap();
+        let mut crawler = Crawler::new(&url, 1, Filters::default());
+        crawler.start(&url);
+        assert_eq!(probe(&args, url.as_str(), Some(&mut crawler)).await.unwrap().1.len(), 1);
+
+        // A file of another type is not read, whatever its content
+        let url = Url::parse(&test_server::serve_with(b"<html></html>".to_vec(), Quirks { content_type: Some("image/png"), ..Quirks::default() })).unwrap();
+        let mut crawler = Crawler::new(&url, 1, Filters::default());
+        crawler.start(&url);
+        assert!(probe(&args, url.as_str(), Some(&mut crawler)).await.unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn test_summary() {
+        let checks = [
+            Check { url: "http://a/x.html".to_string(), referrer: None, result: Err(AppError::TimedOut) },
+            Check { url: "http://a/y.html".to_string(), referrer: Some("http://a/".to_string()), result: Err(AppError::HttpStatus(404, "404 Not Found".to_string())) },
+        ];
+        assert_eq!(
+            summary(&checks),
+            "2 URLs checked, 2 broken\n  http://a/x.html: The download did not finish within the time allowed by --max-time\n  http://a/y.html (linked from http://a/): Could not connect to the server: 404 Not Found\n"
+        );
+        assert_eq!(checks[1].to_json(), r#"{"error":"Could not connect to the server: 404 Not Found","ok":false,"referrer":"http://a/","url":"http://a/y.html"}"#);
+    }
+}
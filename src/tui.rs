@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use indicatif::{HumanBytes, HumanDuration};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+use crate::daemon::Queue;
+
+// Time between two redraws, unless a key is pressed
+const REFRESH: Duration = Duration::from_millis(250);
+
+// Time between two samples of the speed graph
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Samples the speed graph keeps, more than the widest terminal shows
+const MAX_SAMPLES: usize = 600;
+
+const HELP: &str = "↑/↓ select  p pause/resume  c cancel  +/- move up/down in the queue  q quit";
+
+/// Shows the downloads of `queue` in the terminal until the user quits, letting them pause, cancel and reorder them.
+///
+/// Returns once q, Esc or Ctrl-C is pressed; the downloads keep running until the caller stops them.
+pub fn run(queue: &Queue) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = View::default().run(&mut terminal, queue);
+    ratatui::restore();
+    result
+}
+
+// A download of the queue as a row of the table
+struct Download {
+    gid: String,
+    name: String,
+    status: String,
+    completed: u64,
+    total: u64,
+    speed: u64,
+    connections: String,
+}
+
+impl Download {
+    // The download described by `status`, as Queue::status describes it
+    fn from_status(status: &Value) -> Download {
+        let text = |key: &str| status[key].as_str().unwrap_or_default().to_string();
+        let number = |key: &str| status[key].as_str().and_then(|value| value.parse().ok()).unwrap_or_default();
+        let file = &status["files"][0];
+        let name = match file["path"].as_str().and_then(|path| Path::new(path).file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => file["uris"][0]["uri"].as_str().unwrap_or_default().trim_end_matches('/').rsplit('/').next().unwrap_or_default().to_string(),
+        };
+        Download {
+            gid: text("gid"),
+            name,
+            status: text("status"),
+            completed: number("completedLength"),
+            total: number("totalLength"),
+            speed: number("downloadSpeed"),
+            connections: text("connections"),
+        }
+    }
+
+    fn row(&self) -> Row<'static> {
+        let percent = match self.total {
+            0 => String::new(),
+            total => format!("{}%", self.completed * 100 / total),
+        };
+        let size = match self.total {
+            0 => HumanBytes(self.completed).to_string(),
+            total => format!("{} / {}", HumanBytes(self.completed), HumanBytes(total)),
+        };
+        let (speed, eta, connections) = match self.status.as_str() {
+            "active" => (format!("{}/s", HumanBytes(self.speed)), eta(self.completed, self.total, self.speed), self.connections.clone()),
+            _ => Default::default(),
+        };
+        Row::new([self.name.clone(), self.status.clone(), percent, size, speed, eta, connections])
+    }
+}
+
+// The time left at `speed`, when it is known
+fn eta(completed: u64, total: u64, speed: u64) -> String {
+    match (total, speed) {
+        (1.., 1..) => HumanDuration(Duration::from_secs(total.saturating_sub(completed) / speed)).to_string(),
+        _ => String::new(),
+    }
+}
+
+// What the screen shows besides the downloads
+#[derive(Default)]
+struct View {
+    table: TableState,
+    // Overall bytes per second, one sample every SAMPLE_INTERVAL
+    speeds: VecDeque<u64>,
+    sampled: Option<Instant>,
+    // Why the last key did nothing, shown instead of the help
+    notice: Option<String>,
+}
+
+impl View {
+    fn run(&mut self, terminal: &mut DefaultTerminal, queue: &Queue) -> io::Result<()> {
+        loop {
+            let downloads: Vec<Download> = queue.gids(|_| true).iter().filter_map(|gid| queue.status(gid)).map(|status| Download::from_status(&status)).collect();
+            if self.sampled.is_none_or(|sampled| sampled.elapsed() >= SAMPLE_INTERVAL) {
+                self.speeds.push_back(downloads.iter().map(|download| download.speed).sum());
+                if self.speeds.len() > MAX_SAMPLES {
+                    self.speeds.pop_front();
+                }
+                self.sampled = Some(Instant::now());
+            }
+            let selected = match downloads.len() {
+                0 => None,
+                len => Some(self.table.selected().unwrap_or_default().min(len - 1)),
+            };
+            self.table.select(selected);
+            terminal.draw(|frame| self.draw(frame, queue, &downloads))?;
+
+            if !event::poll(REFRESH)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let selected = selected.map(|index| &downloads[index]);
+            let result = match (key.code, selected) {
+                (KeyCode::Char('q') | KeyCode::Esc, _) => return Ok(()),
+                (KeyCode::Char('c'), _) if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                (KeyCode::Up | KeyCode::Char('k'), _) => {
+                    self.table.select_previous();
+                    Ok(())
+                }
+                (KeyCode::Down | KeyCode::Char('j'), _) => {
+                    self.table.select_next();
+                    Ok(())
+                }
+                (KeyCode::Char('p' | ' '), Some(download)) if download.status == "paused" => queue.unpause(&download.gid),
+                (KeyCode::Char('p' | ' '), Some(download)) => queue.pause(&download.gid),
+                (KeyCode::Char('c') | KeyCode::Delete, Some(download)) => queue.remove(&download.gid),
+                (KeyCode::Char('+' | 'K'), Some(download)) => queue.move_by(&download.gid, -1).map(|_| self.table.select_previous()),
+                (KeyCode::Char('-' | 'J'), Some(download)) => queue.move_by(&download.gid, 1).map(|_| self.table.select_next()),
+                _ => Ok(()),
+            };
+            self.notice = result.err();
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, queue: &Queue, downloads: &[Download]) {
+        let [header, list, details, footer] = Layout::vertical([Constraint::Length(1), Constraint::Min(4), Constraint::Length(8), Constraint::Length(1)]).areas(frame.area());
+        let count = |status: &str| downloads.iter().filter(|download| download.status == status).count();
+        let speed: u64 = downloads.iter().map(|download| download.speed).sum();
+        let summary = format!(
+            "rtget  {} active, {} waiting, {} paused, {} complete, {} failed  {}/s",
+            count("active"),
+            count("waiting"),
+            count("paused"),
+            count("complete"),
+            count("error"),
+            HumanBytes(speed)
+        );
+        frame.render_widget(Paragraph::new(summary).style(Style::new().add_modifier(Modifier::BOLD)), header);
+
+        let widths = [
+            Constraint::Fill(1),
+            Constraint::Length(9),
+            Constraint::Length(5),
+            Constraint::Length(23),
+            Constraint::Length(13),
+            Constraint::Length(12),
+            Constraint::Length(5),
+        ];
+        let table = Table::new(downloads.iter().map(Download::row), widths)
+            .header(Row::new(["File", "Status", "Done", "Size", "Speed", "ETA", "Conn"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::bordered().title("Downloads"));
+        frame.render_stateful_widget(table, list, &mut self.table);
+
+        let [connections, graph] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(details);
+        let selected = self.table.selected().and_then(|index| downloads.get(index));
+        let parts: Vec<Line> = match selected {
+            Some(download) => queue
+                .parts(&download.gid)
+                .iter()
+                .enumerate()
+                .map(|(index, (bytes, size, speed))| {
+                    let percent = size.filter(|size| *size > 0).map(|size| format!("{:>3}%", bytes * 100 / size)).unwrap_or_default();
+                    Line::from(format!("Part {:<3} {:>4}  {:>11}/s", index + 1, percent, HumanBytes(*speed).to_string()))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let title = selected.map_or_else(|| "Connections".to_string(), |download| format!("Connections of {}", download.name));
+        frame.render_widget(Paragraph::new(parts).block(Block::bordered().title(title)), connections);
+
+        let shown = usize::from(graph.width.saturating_sub(2));
+        let samples: Vec<u64> = self.speeds.iter().skip(self.speeds.len().saturating_sub(shown)).copied().collect();
+        let peak = samples.iter().copied().max().unwrap_or_default();
+        let title = format!("Speed, last {} s, peak {}/s", samples.len(), HumanBytes(peak));
+        frame.render_widget(Sparkline::default().data(samples).block(Block::bordered().title(title)), graph);
+
+        frame.render_widget(Paragraph::new(self.notice.as_deref().unwrap_or(HELP)), footer);
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_download_row() {
+        let status = json!({
+            "gid": "0000000000000001",
+            "status": "active",
+            "totalLength": "4096",
+            "completedLength": "1024",
+            "downloadSpeed": "1024",
+            "connections": "2",
+            "files": [{"path": "", "uris": [{"uri": "http://a/dir/file.iso", "status": "used"}]}],
+        });
+        let download = Download::from_status(&status);
+        assert_eq!((download.name.as_str(), download.completed, download.total, download.speed), ("file.iso", 1024, 4096, 1024));
+        assert_eq!(eta(download.completed, download.total, download.speed), "3 seconds");
+        assert_eq!(eta(0, 0, 1024), "");
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use crate::error::AppError;
+
+// File names of compressed tar archives, which are unpacked as a whole instead of decompressed into a single file
+const TAR_SUFFIXES: [&str; 11] = [".tar.gz", ".tgz", ".taz", ".tar.xz", ".txz", ".tar.zst", ".tzst", ".tar.bz2", ".tbz", ".tbz2", ".tar.z"];
+
+/// What --extract recognizes a downloaded file as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Archive {
+    /// A tar archive, compressed or not
+    Tar,
+    /// A zip archive
+    Zip,
+    /// A single file compressed with gzip, xz, zstd or bzip2, named after the tool that decompresses it
+    Compressed(&'static str),
+}
+
+impl Archive {
+    /// Recognizes the archive at `path` from its first bytes, and a compressed tar from its name.
+    ///
+    /// Returns None for other files, and for pipes or devices, which cannot be read again.
+    pub fn detect(path: &Path) -> io::Result<Option<Archive>> {
+        if !std::fs::metadata(path)?.is_file() {
+            return Ok(None);
+        }
+        let mut head = Vec::with_capacity(512);
+        std::fs::File::open(path)?.take(512).read_to_end(&mut head)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+        let compressed = match head.as_slice() {
+            [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => return Ok(Some(Archive::Zip)),
+            [0x1f, 0x8b, ..] => "gzip",
+            [0xfd, b'7', b'z', b'X', b'Z', 0, ..] => "xz",
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => "zstd",
+            [b'B', b'Z', b'h', ..] => "bzip2",
+            // The ustar magic of POSIX tar headers, also written by GNU tar
+            head if head.get(257..262) == Some(b"ustar") => return Ok(Some(Archive::Tar)),
+            _ => return Ok(None),
+        };
+        Ok(Some(match TAR_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+            true => Archive::Tar,
+            false => Archive::Compressed(compressed),
+        }))
+    }
+}
+
+/// Returns where [`unpack`] puts the content of the `archive` at `path` when extracting it into `dir`:
+/// `dir` itself, or for a single compressed file the file in `dir` named like it without the compression extension.
+pub fn destination(path: &Path, archive: Archive, dir: &Path) -> PathBuf {
+    match archive {
+        Archive::Compressed(_) => dir.join(decompressed_name(path)),
+        Archive::Tar | Archive::Zip => dir.to_path_buf(),
+    }
+}
+
+/// Unpacks the `archive` at `path` into its `destination`, as given by [`destination`].
+///
+/// Archives are handed to the tools of the system: `tar`, which also decompresses, `unzip`, or on Windows
+/// the bundled `tar` for zip files too, and `gzip`, `xz`, `zstd` or `bzip2` for a single compressed file,
+/// which replaces the file at `destination` if there is one.
+pub fn unpack(path: &Path, archive: Archive, destination: &Path) -> Result<(), AppError> {
+    let dir = match archive {
+        Archive::Compressed(_) => destination.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")),
+        Archive::Tar | Archive::Zip => destination,
+    };
+    std::fs::create_dir_all(dir)?;
+    let (path, dir) = (operand(path), operand(dir));
+    let mut command = match archive {
+        Archive::Tar => tar(&path, &dir),
+        Archive::Zip if cfg!(windows) => tar(&path, &dir),
+        Archive::Zip => {
+            let mut unzip = Command::new("unzip");
+            unzip.args(["-o", "-q"]).arg(&path).arg("-d").arg(&dir);
+            unzip
+        }
+        Archive::Compressed(tool) => {
+            let mut decompress = Command::new(tool);
+            decompress.arg("-dc").arg(&path).stdout(std::fs::File::create(destination)?);
+            decompress
+        }
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command.stdin(Stdio::null()).status().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => AppError::StringError(format!("could not extract {}: {} is not installed", path.display(), program)),
+        _ => AppError::IoError(e.to_string()),
+    })?;
+    if !status.success() {
+        if let Archive::Compressed(_) = archive {
+            let _ = std::fs::remove_file(destination);
+        }
+        return Err(AppError::StringError(format!("could not extract {}: {} failed with {}", path.display(), program, status)));
+    }
+    Ok(())
+}
+
+// tar detects the compression by itself
+fn tar(path: &Path, dir: &Path) -> Command {
+    let mut tar = Command::new("tar");
+    tar.arg("-xf").arg(path).arg("-C").arg(dir);
+    tar
+}
+
+// `path` as an argument of a tool, which would take a relative path starting with a dash for an option
+// Not every tool knows `--`, unzip for one, so relative paths get a `./` prefix instead
+fn operand(path: &Path) -> PathBuf {
+    match path.is_relative() {
+        true => Path::new(".").join(path),
+        false => path.to_path_buf(),
+    }
+}
+
+// The name of a compressed file without its compression extension, like data.json for data.json.gz
+fn decompressed_name(path: &Path) -> PathBuf {
+    match path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).as_deref() {
+        Some("gz" | "xz" | "zst" | "bz2" | "z") => PathBuf::from(path.file_stem().unwrap_or_default()),
+        _ => {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(".out");
+            PathBuf::from(name)
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    #[test]
+    fn test_detect() {
+        let dir = test_server::temp_dir("unpack-detect");
+        let cases: [(&str, &[u8], Option<Archive>); 6] = [
+            ("a.zip", b"PK\x03\x04rest", Some(Archive::Zip)),
+            ("a.tgz", b"\x1f\x8b\x08rest", Some(Archive::Tar)),
+            ("a.json.gz", b"\x1f\x8b\x08rest", Some(Archive::Compressed("gzip"))),
+            ("a.TAR.ZST", b"\x28\xb5\x2f\xfdrest", Some(Archive::Tar)),
+            ("a.img.xz", b"\xfd7zXZ\x00rest", Some(Archive::Compressed("xz"))),
+            ("a.iso", b"plain", None),
+        ];
+        for (name, head, expected) in cases {
+            std::fs::write(dir.join(name), head).unwrap();
+            assert_eq!(Archive::detect(&dir.join(name)).unwrap(), expected, "{}", name);
+        }
+        let mut tar = vec![0; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        std::fs::write(dir.join("a.bin"), tar).unwrap();
+        assert_eq!(Archive::detect(&dir.join("a.bin")).unwrap(), Some(Archive::Tar));
+        assert_eq!(decompressed_name(Path::new("d/data.json.gz")), Path::new("data.json"));
+        assert_eq!(decompressed_name(Path::new("d/download")), Path::new("download.out"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_unpack() {
+        let dir = test_server::temp_dir("unpack");
+        let source = dir.join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("data.json"), b"{}").unwrap();
+        let run = |command: &mut Command| assert!(command.current_dir(&source).status().unwrap().success());
+
+        // A compressed tar is unpacked into the directory
+        run(Command::new("tar").args(["-czf", "../-data.tar.gz", "data.json"]));
+        let archive = dir.join("-data.tar.gz");
+        assert_eq!(Archive::detect(&archive).unwrap(), Some(Archive::Tar));
+        unpack(&archive, Archive::Tar, &dir.join("tar")).unwrap();
+        assert_eq!(std::fs::read(dir.join("tar/data.json")).unwrap(), b"{}");
+
+        // A single compressed file is decompressed next to it under its name without the extension
+        run(Command::new("gzip").args(["-k", "data.json"]));
+        let archive = source.join("data.json.gz");
+        let destination = destination(&archive, Archive::Compressed("gzip"), &dir);
+        assert_eq!(destination, dir.join("data.json"));
+        unpack(&archive, Archive::Compressed("gzip"), &destination).unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), b"{}");
+
+        // A broken archive leaves nothing behind
+        std::fs::write(dir.join("broken.gz"), b"\x1f\x8bnot gzip").unwrap();
+        assert!(unpack(&dir.join("broken.gz"), Archive::Compressed("gzip"), &dir.join("broken")).is_err());
+        assert!(!dir.join("broken").exists());
+
+        // Relative paths cannot pass for options
+        assert_eq!(operand(Path::new("-data.tar.gz")), Path::new("./-data.tar.gz"));
+        assert_eq!(operand(&archive), archive);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
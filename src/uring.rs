@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{ready, Context, Poll};
+use io_uring::{opcode, types, IoUring};
+use tokio::io::AsyncWrite;
+use tokio::sync::oneshot;
+
+// Number of writes submitted to the kernel in one batch
+const RING_ENTRIES: u32 = 64;
+
+// Largest single write, so its length fits into a submission entry
+const MAX_WRITE: usize = 1 << 30;
+
+// A positioned write waiting for the ring
+struct WriteRequest {
+    offset: u64,
+    data: Vec<u8>,
+    done: oneshot::Sender<io::Result<usize>>,
+}
+
+/// The output of a segmented download written through io_uring
+///
+/// One thread owns the ring and submits the writes of all connections in batches, so many
+/// connections writing at once share one system call per batch instead of one each per write.
+/// Clones share the ring; the thread ends once the last clone and writer are gone.
+#[derive(Clone)]
+pub struct RingFile {
+    requests: mpsc::Sender<WriteRequest>,
+}
+
+impl RingFile {
+    /// Opens the existing output at `path` and sets up its ring.
+    ///
+    /// Fails when the kernel does not offer io_uring, e.g. before Linux 5.6 or where it is disabled.
+    pub fn open(path: &Path) -> io::Result<RingFile> {
+        let file = std::fs::OpenOptions::new().write(true).open(path)?;
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let (requests, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("rtget-uring".to_string())
+            .spawn(move || submit(file, ring, receiver))?;
+        Ok(RingFile { requests })
+    }
+
+    /// Returns a writer of the output from `offset` on.
+    pub fn writer(&self, offset: u64) -> RingWriter {
+        RingWriter { requests: self.requests.clone(), offset, in_flight: None }
+    }
+}
+
+// Submit the queued writes in batches until every writer is gone
+// A write completes once the kernel finished it, so completed bytes are in the file like after a flush
+fn submit(file: File, mut ring: IoUring, requests: mpsc::Receiver<WriteRequest>) {
+    let fd = types::Fd(file.as_raw_fd());
+    while let Ok(first) = requests.recv() {
+        let mut batch = vec![first];
+        batch.extend(requests.try_iter().take(RING_ENTRIES as usize - 1));
+        for (index, request) in batch.iter().enumerate() {
+            let entry = opcode::Write::new(fd, request.data.as_ptr(), request.data.len() as u32)
+                .offset(request.offset)
+                .build()
+                .user_data(index as u64);
+            // SAFETY: the buffers stay in `batch` until the kernel completed every write of the batch
+            unsafe { ring.submission().push(&entry) }.expect("a batch fits into the ring");
+        }
+
+        let mut results: Vec<Option<io::Result<usize>>> = batch.iter().map(|_| None).collect();
+        let mut pending = batch.len();
+        while pending > 0 {
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // The kernel may still be reading some buffers, so they are leaked instead of freed
+                    for request in batch {
+                        let _ = request.done.send(Err(io::Error::new(e.kind(), e.to_string())));
+                        std::mem::forget(request.data);
+                    }
+                    return;
+                }
+            }
+            for completion in ring.completion() {
+                let written = completion.result();
+                results[completion.user_data() as usize] =
+                    Some(if written < 0 { Err(io::Error::from_raw_os_error(-written)) } else { Ok(written as usize) });
+                pending -= 1;
+            }
+        }
+        for (request, result) in batch.into_iter().zip(results) {
+            let _ = request.done.send(result.expect("every write of the batch completed"));
+        }
+    }
+}
+
+/// Writes one range of a [`RingFile`] sequentially from its start offset
+pub struct RingWriter {
+    requests: mpsc::Sender<WriteRequest>,
+    offset: u64,
+    // The write handed to the ring and not completed yet
+    in_flight: Option<InFlight>,
+}
+
+// A write handed to the ring, with the address and length of the buffer it was copied from
+struct InFlight {
+    buffer: (usize, usize),
+    completion: oneshot::Receiver<io::Result<usize>>,
+}
+
+impl AsyncWrite for RingWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let data = &buf[..buf.len().min(MAX_WRITE)];
+        let buffer = (data.as_ptr() as usize, data.len());
+        // Polled with another buffer, the caller gave up the write in flight without learning its result
+        // Once it completed, the new buffer is written at the same offset over its bytes
+        if let Some(stale) = this.in_flight.as_mut().filter(|in_flight| in_flight.buffer != buffer) {
+            let _ = ready!(Pin::new(&mut stale.completion).poll(cx));
+            this.in_flight = None;
+        }
+        let in_flight = match &mut this.in_flight {
+            Some(in_flight) => in_flight,
+            None => {
+                let (done, completion) = oneshot::channel();
+                this.requests.send(WriteRequest { offset: this.offset, data: data.to_vec(), done }).map_err(|_| stopped())?;
+                this.in_flight.insert(InFlight { buffer, completion })
+            }
+        };
+        let result = ready!(Pin::new(&mut in_flight.completion).poll(cx));
+        this.in_flight = None;
+        let written = result.map_err(|_| stopped())??;
+        this.offset += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    // Completed writes are already in the file
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The error of a write whose ring thread is gone
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the io_uring thread stopped")
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use tokio::io::AsyncWriteExt;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_ring_writes_in_place() {
+        let path = test_server::temp_dir("uring").join("out");
+        std::fs::write(&path, [0u8; 8]).unwrap();
+        // Kernels or sandboxes without io_uring are covered by the fallback to regular writes
+        let Ok(ring) = RingFile::open(&path) else {
+            return;
+        };
+
+        Runtime::new().unwrap().block_on(async {
+            let (mut high, mut low) = (ring.writer(4), ring.writer(0));
+            let (high, low) = tokio::join!(high.write_all(b"efgh"), low.write_all(b"abcd"));
+            high.unwrap();
+            low.unwrap();
+        });
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefgh");
+    }
+
+    #[test]
+    fn test_abandoned_write_is_not_reported_for_another_buffer() {
+        let path = test_server::temp_dir("uring_abandoned").join("out");
+        std::fs::write(&path, [0u8; 8]).unwrap();
+        let Ok(ring) = RingFile::open(&path) else {
+            return;
+        };
+
+        let expected = Runtime::new().unwrap().block_on(async {
+            let mut writer = ring.writer(0);
+            // The first write is polled once and given up, unless it completed right away
+            let first = std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut writer).poll_write(cx, b"wxyz"))).await;
+            writer.write_all(b"abcd").await.unwrap();
+            match first {
+                Poll::Ready(written) => [&b"wxyz"[..written.unwrap()], b"abcd"].concat(),
+                Poll::Pending => b"abcd".to_vec(),
+            }
+        });
+
+        assert_eq!(std::fs::read(&path).unwrap()[..expected.len()], expected[..]);
+    }
+}
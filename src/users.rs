@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::path::Path;
+use crate::args;
+use crate::error::AppError;
+
+/// A user of a system-wide daemon, from the file of --users
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    /// The login name of the user, which names their directory inside the one of the daemon
+    pub name: String,
+    /// The token the user passes over the network, or over the control socket instead of their login
+    pub token: Option<String>,
+    /// Most downloads of the user running at once
+    pub max_jobs: Option<usize>,
+    /// Bytes per second shared by the downloads of the user
+    pub max_speed: Option<u64>,
+}
+
+/// The users of a daemon, none when it serves only the user running it.
+///
+/// The file of --users is TOML, with a table per user named after their login:
+///
+/// ```toml
+/// [users.alice]
+/// token = "a long random string"
+/// max_jobs = 2
+/// max_speed = "5M"
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Users {
+    users: Vec<User>,
+}
+
+/// Who a client of the daemon is, which decides the downloads it sees and where it may save files
+#[derive(Clone, Debug, PartialEq)]
+pub enum Identity {
+    /// The user running the daemon, over the control socket: every download, saved anywhere
+    Owner,
+    /// A client passing the --rpc-secret: every download, saved inside the directory of the daemon
+    Admin,
+    /// A user of --users: their own downloads, saved inside their directory of the daemon
+    User(String),
+}
+
+impl Identity {
+    /// Returns the user whose downloads the client sees, or None when it sees all of them.
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Identity::Owner | Identity::Admin => None,
+            Identity::User(name) => Some(name),
+        }
+    }
+}
+
+impl Users {
+    /// Reads the users of the file at `path`.
+    pub fn load(path: &Path) -> Result<Users, AppError> {
+        let invalid = |message: String| AppError::StringError(format!("invalid users file {}: {}", path.display(), message));
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        Users::parse(&text).map_err(invalid)
+    }
+
+    /// Reads the users of `text`, the content of a users file.
+    pub fn parse(text: &str) -> Result<Users, String> {
+        let file: toml::Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        let Some(tables) = file.get("users") else {
+            return Ok(Users::default());
+        };
+        let tables = tables.as_table().ok_or("users must be a table of users")?;
+        let mut users = Vec::new();
+        for (name, settings) in tables {
+            // The name is a directory of its own inside the one of the daemon
+            if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+                return Err(format!("{} cannot name a user", name));
+            }
+            let settings = settings.as_table().ok_or_else(|| format!("the settings of {} must be a table", name))?;
+            let mut user = User { name: name.clone(), token: None, max_jobs: None, max_speed: None };
+            for (key, value) in settings {
+                let invalid = || format!("invalid {} of {}", key, name);
+                match key.as_str() {
+                    "token" => user.token = Some(value.as_str().filter(|token| !token.is_empty()).ok_or_else(invalid)?.to_string()),
+                    "max_jobs" => user.max_jobs = Some(value.as_integer().and_then(|jobs| usize::try_from(jobs).ok()).filter(|jobs| *jobs > 0).ok_or_else(invalid)?),
+                    "max_speed" => {
+                        let speed = match value {
+                            toml::Value::Integer(speed) => u64::try_from(*speed).map_err(|_| invalid())?,
+                            toml::Value::String(speed) => args::parse_size(speed).map_err(|_| invalid())?,
+                            _ => return Err(invalid()),
+                        };
+                        user.max_speed = Some(speed).filter(|speed| *speed > 0);
+                    }
+                    _ => return Err(format!("unknown setting {} of {}", key, name)),
+                }
+            }
+            users.push(user);
+        }
+        Ok(Users { users })
+    }
+
+    /// Returns whether the daemon serves other users than the one running it.
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Returns the user with the login `name`, if listed.
+    pub fn by_name(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.name == name)
+    }
+
+    /// Returns the user whose token is `token`, if any.
+    pub fn by_token(&self, token: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.token.as_deref().is_some_and(|secret| same_secret(token, secret)))
+    }
+
+    /// Returns the most downloads each user with a limit may run at once.
+    pub fn job_limits(&self) -> HashMap<String, usize> {
+        self.users.iter().filter_map(|user| Some((user.name.clone(), user.max_jobs?))).collect()
+    }
+
+    /// Returns the bytes per second each user with a limit may download at.
+    pub fn speed_limits(&self) -> HashMap<String, u64> {
+        self.users.iter().filter_map(|user| Some((user.name.clone(), user.max_speed?))).collect()
+    }
+}
+
+/// Returns whether `token` is `secret`, in a time that does not tell how much of it matched.
+pub fn same_secret(token: &str, secret: &str) -> bool {
+    let (token, secret) = (token.as_bytes(), secret.as_bytes());
+    let difference = token.iter().zip(secret).fold(token.len() ^ secret.len(), |difference, (a, b)| difference | usize::from(a ^ b));
+    !secret.is_empty() && difference == 0
+}
+
+/// Returns who a client of the control socket connected as `uid` is: the owner of the daemon, or
+/// one of `users` by their login, or None for anyone else, who has to pass a token.
+#[cfg(unix)]
+pub fn identify_uid(users: &Users, uid: u32) -> Option<Identity> {
+    // SAFETY: getuid cannot fail
+    if uid == 0 || uid == unsafe { libc::getuid() } {
+        return Some(Identity::Owner);
+    }
+    let name = login(uid)?;
+    users.by_name(&name).map(|user| Identity::User(user.name.clone()))
+}
+
+// The login name of `uid`, from the user database
+#[cfg(unix)]
+fn login(uid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    // SAFETY: passwd is plain data, and getpwuid_r fills it with pointers into `buffer`, which outlives them
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the length given
+    let status = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    // SAFETY: pw_name points to a NUL-terminated string inside `buffer`
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let users = Users::parse("[users.alice]\ntoken = \"t0ken\"\nmax_jobs = 2\nmax_speed = \"5M\"\n\n[users.bob]\nmax_speed = 1000\n").unwrap();
+        assert_eq!(users.by_name("alice"), Some(&User { name: "alice".to_string(), token: Some("t0ken".to_string()), max_jobs: Some(2), max_speed: Some(5 << 20) }));
+        assert_eq!(users.by_name("bob").and_then(|bob| bob.max_speed), Some(1000));
+        assert_eq!(users.by_token("t0ken").map(|user| user.name.as_str()), Some("alice"));
+        assert!(users.by_token("t0ke").is_none() && users.by_token("").is_none());
+        assert_eq!(users.job_limits(), HashMap::from([("alice".to_string(), 2)]));
+        assert_eq!(users.speed_limits(), HashMap::from([("alice".to_string(), 5 << 20), ("bob".to_string(), 1000)]));
+        assert!(Users::parse("").unwrap().is_empty());
+
+        for invalid in ["[users.alice]\nmax_jobs = 0", "[users.alice]\nquota = 1", "[users.\"../root\"]", "users = 1", "[users.alice]\ntoken = \"\""] {
+            assert!(Users::parse(invalid).is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_identify_uid() {
+        let users = Users::parse("[users.root]\n").unwrap();
+        // SAFETY: getuid cannot fail
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(identify_uid(&users, uid), Some(Identity::Owner));
+        assert_eq!(identify_uid(&users, 0), Some(Identity::Owner));
+        assert_eq!(identify_uid(&Users::default(), 4_000_000), None);
+        assert_eq!(Identity::User("alice".to_string()).user(), Some("alice"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::filesystem;
+use crate::scheduler;
+
+/// What --keep-previous does with the version of a watched file that a new one replaces
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeepPrevious {
+    /// Keep this many versions as file.1, file.2 and so on, the most recent first
+    Rotate(usize),
+    /// Keep every version, named after its modification time like file.2024-03-10-120000
+    Dated,
+}
+
+impl KeepPrevious {
+    /// Parses a number of versions to rotate, or `dated`.
+    pub fn parse(value: &str) -> Result<KeepPrevious, String> {
+        match value {
+            "dated" => Ok(KeepPrevious::Dated),
+            count => match count.parse::<usize>() {
+                Ok(count) if count > 0 => Ok(KeepPrevious::Rotate(count)),
+                _ => Err(format!("invalid --keep-previous {}, expected a number of versions or dated", value)),
+            },
+        }
+    }
+}
+
+/// Moves the file at `path` aside before a new version replaces it, and returns where it went.
+///
+/// Rotating drops the oldest version once there are as many as asked for. A file that does not
+/// exist yet has nothing to keep, and neither have pipes or devices.
+pub fn keep_previous(path: &Path, keep: KeepPrevious) -> io::Result<Option<PathBuf>> {
+    let Some(metadata) = std::fs::metadata(path).ok().filter(|metadata| metadata.is_file()) else {
+        return Ok(None);
+    };
+    let kept = match keep {
+        KeepPrevious::Rotate(count) => {
+            let _ = std::fs::remove_file(versioned(path, &count.to_string()));
+            for number in (1..count).rev() {
+                let older = versioned(path, &number.to_string());
+                if older.exists() {
+                    std::fs::rename(&older, versioned(path, &(number + 1).to_string()))?;
+                }
+            }
+            versioned(path, "1")
+        }
+        KeepPrevious::Dated => {
+            // The version is known by when it was modified, which is the Last-Modified of the server once recorded
+            let modified = scheduler::describe(metadata.modified()?).replace(' ', "-").replace(':', "");
+            let dated = versioned(path, &modified);
+            match dated.exists() {
+                true => filesystem::numbered_path(&dated),
+                false => dated,
+            }
+        }
+    };
+    std::fs::rename(path, &kept)?;
+    Ok(Some(kept))
+}
+
+// The path with `.suffix` appended to its file name
+fn versioned(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", suffix));
+    path.with_file_name(name)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(KeepPrevious::parse("3"), Ok(KeepPrevious::Rotate(3)));
+        assert_eq!(KeepPrevious::parse("dated"), Ok(KeepPrevious::Dated));
+        assert!(KeepPrevious::parse("0").is_err());
+        assert!(KeepPrevious::parse("all").is_err());
+    }
+
+    #[test]
+    fn test_keep_previous() {
+        let dir = test_server::temp_dir("watch");
+        let path = dir.join("nightly.iso");
+        assert_eq!(keep_previous(&path, KeepPrevious::Rotate(2)).unwrap(), None);
+
+        // Three versions rotated through two backups keep the two most recent
+        for version in ["one", "two", "three"] {
+            std::fs::write(&path, version).unwrap();
+            assert_eq!(keep_previous(&path, KeepPrevious::Rotate(2)).unwrap(), Some(dir.join("nightly.iso.1")));
+        }
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(dir.join("nightly.iso.1")).unwrap(), "three");
+        assert_eq!(std::fs::read_to_string(dir.join("nightly.iso.2")).unwrap(), "two");
+        assert!(!dir.join("nightly.iso.3").exists());
+
+        // Versions modified at the same time keep distinct names
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_710_072_000);
+        let mut kept = Vec::new();
+        for version in ["one", "two"] {
+            std::fs::write(&path, version).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+            kept.push(keep_previous(&path, KeepPrevious::Dated).unwrap().unwrap());
+        }
+        let name = kept[0].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("nightly.iso.2024-03-") && name.len() == "nightly.iso.2024-03-10-120000".len(), "{}", name);
+        assert_eq!(kept[1].file_name().unwrap().to_string_lossy(), format!("{}.1", name));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
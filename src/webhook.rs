@@ -0,0 +1,60 @@
+use reqwest::Client;
+use serde_json::Value;
+use crate::error::AppError;
+use crate::history::Entry;
+
+/// Posts the outcome of a finished download to the webhook at `url` as JSON.
+///
+/// The payload is the entry of the download history with an `event` of `download.complete` or `download.failed`.
+pub async fn notify(url: &str, entry: &Entry) -> Result<(), AppError> {
+    let response = Client::new()
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload(entry).to_string())
+        .send()
+        .await?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(AppError::from(response.status()))
+    }
+}
+
+// The history entry of the download, with the name of the event it is about
+fn payload(entry: &Entry) -> Value {
+    let mut payload = entry.to_json();
+    payload["event"] = match entry.error {
+        None => "download.complete".into(),
+        Some(_) => "download.failed".into(),
+    };
+    payload
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::Duration;
+    use crate::test_server;
+
+    #[tokio::test]
+    async fn test_payload() {
+        let failed = Entry::new("http://a/x.iso", Err(&AppError::TimedOut), Duration::from_secs(2)).await;
+        let payload = payload(&failed);
+        assert_eq!((payload["event"].as_str(), payload["status"].as_str(), payload["url"].as_str()), (Some("download.failed"), Some("failed"), Some("http://a/x.iso")));
+        assert!(payload["path"].is_null() && payload["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let dir = test_server::temp_dir("webhook");
+        let file = dir.join("x.iso");
+        std::fs::write(&file, "hello").unwrap();
+        let entry = Entry::new("http://a/x.iso", Ok(Path::new(&file)), Duration::from_secs(1)).await;
+        notify(&test_server::serve(Vec::new()), &entry).await.unwrap();
+        // Nothing listens on the discard port
+        assert!(notify("http://127.0.0.1:9/hook", &entry).await.is_err());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}